@@ -822,8 +822,31 @@ impl IntoRawFd for Counter {
 
 impl Group {
     /// Construct a new, empty `Group`.
-    #[allow(unused_parens)]
     pub fn new() -> io::Result<Group> {
+        Group::open(0, -1, 0)
+    }
+
+    /// Construct a new, empty `Group` that observes the tasks of the given
+    /// [cgroup][man-cgroups] on `cpu`. The kernel only lets a `Counter` join
+    /// a group that observes the same tasks and CPU, so members should be
+    /// built with [`observe_cgroup`] and [`one_cpu`] on the same arguments.
+    ///
+    /// Not in upstream perf-event, see the `[patch.crates-io]` section of
+    /// test-prefetch's `Cargo.toml`.
+    ///
+    /// [man-cgroups]: http://man7.org/linux/man-pages/man7/cgroups.7.html
+    /// [`observe_cgroup`]: Builder::observe_cgroup
+    /// [`one_cpu`]: Builder::one_cpu
+    pub fn for_cgroup(cgroup: &File, cpu: usize) -> io::Result<Group> {
+        Group::open(
+            cgroup.as_raw_fd(),
+            cpu as c_int,
+            sys::bindings::PERF_FLAG_PID_CGROUP,
+        )
+    }
+
+    #[allow(unused_parens)]
+    fn open(pid: pid_t, cpu: c_int, flags: u32) -> io::Result<Group> {
         // Open a placeholder perf counter that we can add other events to.
         let mut attrs = perf_event_attr {
             size: std::mem::size_of::<perf_event_attr>() as u32,
@@ -844,7 +867,7 @@ impl Group {
 
         let file = unsafe {
             File::from_raw_fd(check_errno_syscall(|| {
-                sys::perf_event_open(&mut attrs, 0, -1, -1, 0)
+                sys::perf_event_open(&mut attrs, pid, cpu, -1, flags as c_ulong)
             })?)
         };
 
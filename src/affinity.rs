@@ -0,0 +1,97 @@
+/// A set of CPUs a thread may run on.
+#[cfg(target_os = "linux")]
+#[derive(Clone, Copy)]
+pub struct Affinity(libc::cpu_set_t);
+
+#[cfg(target_os = "linux")]
+impl Affinity {
+    /// The CPUs the current thread may run on.
+    pub fn current() -> std::io::Result<Affinity> {
+        // SAFETY: an all-zero cpu_set_t is an empty set, and
+        // sched_getaffinity writes at most the size we pass it.
+        unsafe {
+            let mut set: libc::cpu_set_t = std::mem::zeroed();
+            if libc::sched_getaffinity(0, std::mem::size_of_val(&set), &mut set) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(Affinity(set))
+        }
+    }
+
+    /// Only `cpu`.
+    pub fn only(cpu: usize) -> Affinity {
+        // SAFETY: an all-zero cpu_set_t is an empty set, and CPU_SET ignores
+        // CPUs that don't fit in it.
+        unsafe {
+            let mut set: libc::cpu_set_t = std::mem::zeroed();
+            libc::CPU_SET(cpu, &mut set);
+            Affinity(set)
+        }
+    }
+
+    /// The CPUs in the set, in increasing order.
+    pub fn cpus(&self) -> Vec<usize> {
+        (0..libc::CPU_SETSIZE as usize)
+            // SAFETY: CPU_ISSET only reads the set.
+            .filter(|&cpu| unsafe { libc::CPU_ISSET(cpu, &self.0) })
+            .collect()
+    }
+
+    /// Restrict the current thread to the CPUs in the set.
+    pub fn apply(&self) -> std::io::Result<()> {
+        // SAFETY: sched_setaffinity only reads the set we pass it.
+        if unsafe { libc::sched_setaffinity(0, std::mem::size_of_val(&self.0), &self.0) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// The CPU the current thread is running on.
+#[cfg(target_os = "linux")]
+pub fn current_cpu() -> std::io::Result<usize> {
+    // SAFETY: sched_getcpu has no preconditions.
+    let cpu = unsafe { libc::sched_getcpu() };
+    if cpu < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(cpu as usize)
+}
+
+/// Without sched_setaffinity, threads can't be pinned, so the set is always
+/// empty, and the benchmarks that pin threads to CPUs skip themselves.
+#[cfg(not(target_os = "linux"))]
+#[derive(Clone, Copy)]
+pub struct Affinity(());
+
+#[cfg(not(target_os = "linux"))]
+impl Affinity {
+    pub fn current() -> std::io::Result<Affinity> {
+        Ok(Affinity(()))
+    }
+
+    pub fn only(_cpu: usize) -> Affinity {
+        Affinity(())
+    }
+
+    pub fn cpus(&self) -> Vec<usize> {
+        Vec::new()
+    }
+
+    pub fn apply(&self) -> std::io::Result<()> {
+        Err(unsupported("pinning threads to CPUs"))
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn current_cpu() -> std::io::Result<usize> {
+    Err(unsupported("finding the current CPU"))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unsupported(what: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        format!("{} is only supported on Linux", what),
+    )
+}
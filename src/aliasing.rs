@@ -0,0 +1,136 @@
+use std::hint::black_box;
+
+#[cfg(target_os = "linux")]
+use perf_event::Builder;
+
+#[cfg(target_arch = "x86_64")]
+use crate::benchmarks::bench_4k_aliasing;
+use crate::runner::{measure, OutputFormat};
+
+/// Raw event code for Intel's `LD_BLOCKS_PARTIAL.ADDRESS_ALIAS`, which counts
+/// loads falsely blocked by a store with the same low 12 address bits.
+const LD_BLOCKS_PARTIAL_ADDRESS_ALIAS: u64 = 0x0107;
+
+/// The vendor string of the CPU, eg "GenuineIntel" or "AuthenticAMD".
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn cpu_vendor() -> Vec<u8> {
+    let cpuid = std::arch::x86_64::__cpuid(0);
+    let mut vendor = Vec::new();
+    for register in [cpuid.ebx, cpuid.edx, cpuid.ecx] {
+        vendor.extend_from_slice(&register.to_le_bytes());
+    }
+    vendor
+}
+
+/// There's no cpuid outside of x86, so no vendor string either.
+#[cfg(not(target_arch = "x86_64"))]
+pub(crate) fn cpu_vendor() -> Vec<u8> {
+    Vec::new()
+}
+
+/// The family and model of the CPU, with the extended fields of cpuid leaf 1
+/// folded in the way both vendors document.
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn cpu_family_model() -> (u32, u32) {
+    let eax = std::arch::x86_64::__cpuid(1).eax;
+    let (family, model) = ((eax >> 8) & 0xf, (eax >> 4) & 0xf);
+    let (extended_family, extended_model) = ((eax >> 20) & 0xff, (eax >> 16) & 0xf);
+    match family {
+        0xf => (family + extended_family, model | extended_model << 4),
+        6 => (family, model | extended_model << 4),
+        _ => (family, model),
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+pub(crate) fn cpu_family_model() -> (u32, u32) {
+    (0, 0)
+}
+
+/// Whether we're running on an Intel CPU, where
+/// [`LD_BLOCKS_PARTIAL_ADDRESS_ALIAS`] means what we think it means.
+pub(crate) fn is_intel() -> bool {
+    cpu_vendor() == b"GenuineIntel"
+}
+
+/// Count the loads blocked by 4K aliasing while running `callback`, or return
+/// `None` if the CPU doesn't have a counter for it.
+#[cfg(target_os = "linux")]
+fn count_address_alias(mut callback: impl FnMut()) -> Option<u64> {
+    if !is_intel() {
+        return None;
+    }
+    let mut counter = Builder::new()
+        .raw_config(LD_BLOCKS_PARTIAL_ADDRESS_ALIAS)
+        .build()
+        .ok()?;
+    counter.enable().ok()?;
+    callback();
+    counter.disable().ok()?;
+    counter.read().ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn count_address_alias(_callback: impl FnMut()) -> Option<u64> {
+    None
+}
+
+/// Measure the cost of 4K aliasing, by running [`bench_4k_aliasing`] over
+/// `buffer` with a store-to-load distance of `aliasing_offset`, which should
+/// be a multiple of 4096, and of `other_offset`, which shouldn't.
+///
+/// Returns the extra cycles per load caused by the aliasing.
+#[cfg(target_arch = "x86_64")]
+pub fn aliasing_penalty<const N: usize>(
+    buffer: &mut [u8; N],
+    aliasing_offset: usize,
+    other_offset: usize,
+    repeats: usize,
+) -> std::io::Result<f64> {
+    let mut run = |offset: usize| {
+        for _ in 0..repeats {
+            black_box(bench_4k_aliasing(buffer, offset));
+        }
+    };
+
+    // Make sure the buffer is resident before measuring anything.
+    run(aliasing_offset);
+    run(other_offset);
+
+    let aliasing = measure(|| run(aliasing_offset))?;
+    let other = measure(|| run(other_offset))?;
+
+    let loads = (4096 / 8 * repeats) as f64;
+    let cycles_aliasing = aliasing.cycles as f64 / loads;
+    let cycles_other = other.cycles as f64 / loads;
+    let penalty = cycles_aliasing - cycles_other;
+
+    if OutputFormat::from_args()? != OutputFormat::Table {
+        return Ok(penalty);
+    }
+    println!("====================================================================");
+    println!("4K aliasing penalty");
+    println!(
+        "Cycles per load with a {} byte offset: {:.3}",
+        aliasing_offset, cycles_aliasing
+    );
+    println!(
+        "Cycles per load with a {} byte offset: {:.3}",
+        other_offset, cycles_other
+    );
+    println!("Cycles per aliasing load: {:.3}", penalty);
+    match (
+        count_address_alias(|| run(aliasing_offset)),
+        count_address_alias(|| run(other_offset)),
+    ) {
+        (Some(blocked_aliasing), Some(blocked_other)) => println!(
+            "Loads blocked by address aliasing: {:.3} vs {:.3} per load",
+            blocked_aliasing as f64 / loads,
+            blocked_other as f64 / loads,
+        ),
+        _ => println!("Loads blocked by address aliasing: (no counter on this CPU)"),
+    }
+    println!();
+
+    Ok(penalty)
+}
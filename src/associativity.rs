@@ -0,0 +1,80 @@
+use std::hint::black_box;
+
+use crate::benchmarks::bench_assoc_conflict;
+use crate::runner::{measure, OutputFormat};
+use crate::topology::CacheInfo;
+
+/// The L1D miss rates of [`assoc_conflict`], in percent.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AssocConflict {
+    /// With every load mapping to the same set.
+    pub conflicting: f64,
+    /// With every load mapping to a different set.
+    pub spread: f64,
+}
+
+/// Measure conflict misses in `cache`, by loading twice as many lines as it
+/// has ways, `repeats` times, first one way apart, so they all map to the
+/// same set, then one way and one line apart, so they don't. Print the miss
+/// rates of both.
+///
+/// Returns `None` if sysfs doesn't say how many ways the cache has.
+pub fn assoc_conflict(cache: &CacheInfo, repeats: usize) -> std::io::Result<Option<AssocConflict>> {
+    let table = OutputFormat::from_args()? == OutputFormat::Table;
+    let (Some(ways), Some(way_stride)) = (cache.ways, cache.way_stride()) else {
+        if table {
+            println!("====================================================================");
+            println!(
+                "Associativity conflicts in the L{} cache: (unknown associativity)",
+                cache.level
+            );
+            println!();
+        }
+        return Ok(None);
+    };
+
+    let lines = 2 * ways;
+    let spread_stride = way_stride + cache.line_size;
+    let buffer = black_box(vec![1u8; lines * spread_stride]);
+    let run = |stride: usize| {
+        for _ in 0..repeats {
+            black_box(bench_assoc_conflict(&buffer, stride, lines));
+        }
+    };
+
+    // Make sure the buffer is resident before measuring anything.
+    run(way_stride);
+    run(spread_stride);
+
+    let conflicting = measure(|| run(way_stride))?;
+    let spread = measure(|| run(spread_stride))?;
+    let result = AssocConflict {
+        conflicting: conflicting.l1_miss_rate(),
+        spread: spread.l1_miss_rate(),
+    };
+
+    if !table {
+        return Ok(Some(result));
+    }
+    let loads = (lines * repeats) as f64;
+    println!("====================================================================");
+    println!(
+        "Associativity conflicts in the L{} cache, {}-way, {} lines",
+        cache.level, ways, lines
+    );
+    println!(
+        "{} byte stride, same set: {:.3}% L1D misses, {:.3} cycles per load",
+        way_stride,
+        result.conflicting,
+        conflicting.cycles as f64 / loads
+    );
+    println!(
+        "{} byte stride, one set each: {:.3}% L1D misses, {:.3} cycles per load",
+        spread_stride,
+        result.spread,
+        spread.cycles as f64 / loads
+    );
+    println!();
+
+    Ok(Some(result))
+}
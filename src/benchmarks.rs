@@ -0,0 +1,1902 @@
+use std::arch::asm;
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::{
+    __m128i, _mm_add_epi64, _mm_clflush, _mm_cvtsi128_si64, _mm_loadu_si128, _mm_mfence,
+    _mm_prefetch, _mm_setzero_si128, _mm_sfence, _mm_stream_si64, _mm_unpackhi_epi64, _MM_HINT_T0,
+};
+use std::hint::{black_box, spin_loop};
+use std::io::{Read, Write};
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+
+use crate::affinity::Affinity;
+use crate::clobber::{clobber_read, clobber_rw};
+
+/// Prefetch the line holding `ptr` into every level of the cache, with
+/// `prefetcht0` on x86_64 and `prfm pldl1keep` on aarch64.
+///
+/// Prefetches never fault, so `ptr` doesn't have to point to valid memory.
+#[cfg(target_arch = "x86_64")]
+#[inline(always)]
+pub fn prefetch_t0(ptr: *const u8) {
+    // SAFETY: prefetching an invalid address is a no-op.
+    unsafe { _mm_prefetch(ptr as *const i8, _MM_HINT_T0) }
+}
+
+/// Prefetch the line holding `ptr` into every level of the cache, with
+/// `prefetcht0` on x86_64 and `prfm pldl1keep` on aarch64.
+///
+/// Prefetches never fault, so `ptr` doesn't have to point to valid memory.
+#[cfg(target_arch = "aarch64")]
+#[inline(always)]
+pub fn prefetch_t0(ptr: *const u8) {
+    // SAFETY: prefetching an invalid address is a no-op.
+    unsafe {
+        asm!("prfm pldl1keep, [{}]", in(reg) ptr, options(nostack, preserves_flags));
+    }
+}
+
+// Unlike prefetch_t0, which the compiler is free to schedule anywhere in the
+// loop body, this stays where it's written relative to the surrounding asm.
+#[cfg(target_arch = "x86_64")]
+macro_rules! pinned_prefetch_t0 {
+    ($ptr:expr) => {
+        unsafe {
+            asm!("prefetcht0 [{}]", in(reg) $ptr, options(nostack, preserves_flags));
+        }
+    };
+}
+
+#[cfg(target_arch = "aarch64")]
+macro_rules! pinned_prefetch_t0 {
+    ($ptr:expr) => {
+        unsafe {
+            asm!("prfm pldl1keep, [{}]", in(reg) $ptr, options(nostack, preserves_flags));
+        }
+    };
+}
+
+// The ruler benchmarks run the same empty loop, with and without one
+// invocation of black_box or of a clobber function per iteration. The
+// difference with bench_ruler_empty is what each invocation costs, which the
+// other benchmarks pay too.
+#[inline(never)]
+pub fn bench_ruler_empty<const N: usize>() -> u64 {
+    for _ in 0..N {
+        // Emits nothing, but keeps the loop from being optimized away.
+        unsafe {
+            asm!("", options(nomem, nostack, preserves_flags));
+        }
+    }
+    0
+}
+
+#[inline(never)]
+pub fn bench_ruler_black_box<const N: usize>() -> u64 {
+    let x: u64 = 3;
+    for _ in 0..N {
+        black_box(x);
+    }
+    x
+}
+
+#[inline(never)]
+pub fn bench_ruler_clobber_read<const N: usize>() -> u64 {
+    let x: u64 = 3;
+    for _ in 0..N {
+        clobber_read(&x);
+    }
+    x
+}
+
+#[inline(never)]
+pub fn bench_ruler_clobber_rw<const N: usize>() -> u64 {
+    let mut x: u64 = 3;
+    for _ in 0..N {
+        clobber_rw(&mut x);
+    }
+    x
+}
+
+#[inline(never)]
+pub fn bench_noops<T, const N: usize>(_array: &[T; N]) -> u64 {
+    let sum = black_box(0);
+
+    // This loop's instr/cycle throughput should peak at whatever
+    // the CPU's pipeline width is. For Intel CPUs this is usually 4.
+    // It might be a little higher because of µops fusion.
+    for _ in 0..N {
+        unsafe {
+            asm!("nop", "nop", "nop", "nop", "nop", "nop");
+        }
+    }
+
+    sum
+}
+
+#[inline(never)]
+pub fn bench_alu_ops<T, const N: usize>(_array: &[T; N]) -> u64 {
+    let mut sum = 0;
+
+    // This loop's throughput should peak at 3 instr/cycle.
+    // This is because the loop to a 3-instructions body, and can only
+    // run one iteration per cycle, because of the dependency chain
+    // between the `sum +=` instructions.
+    for _ in 0..N {
+        sum += 3;
+        clobber_read(&sum);
+    }
+
+    sum
+}
+
+#[inline(never)]
+pub fn bench_alu_ops_unrolled<T, const N: usize>(_array: &[T; N]) -> u64 {
+    let x = black_box(3);
+
+    let mut sum_1 = 0;
+    let mut sum_2 = 0;
+    let mut sum_3 = 0;
+    let mut sum_4 = 0;
+
+    // For some reason, the throughput of this loop peaks on a Zen CPU at
+    // 3.3 instr/cycle, against an expected 4 instr/cycle.
+    // By contrast, an Intel i7-5600u (Broadwell archictecture) peaks at 4.0,
+    // as expected.
+    // As far as I could figure out, the bottleneck is somewhere in register
+    // renaming: bench_alu_ops_super_unrolled, which uses more virtual GPUs,
+    // peaks at 3.9 instr/cycle on Zen; and this loop's throughput can also be
+    // increased to 3.9 instr/cycle by setting every variable to zero every
+    // loop iteration, which breaks the dependency chain and "resets" register
+    // renaming.
+    for _ in 0..N {
+        sum_1 += x;
+        sum_2 += x;
+        sum_3 += x;
+        sum_4 += x;
+        clobber_rw(&mut sum_1);
+        clobber_rw(&mut sum_2);
+        clobber_rw(&mut sum_3);
+        clobber_rw(&mut sum_4);
+    }
+
+    sum_1 + sum_2 + sum_3 + sum_4
+}
+
+#[cfg(target_arch = "x86_64")]
+#[inline(never)]
+pub fn bench_alu_ops_super_unrolled<T, const N: usize>(_array: &[T; N]) -> u64 {
+    let x = black_box(3);
+
+    let mut sum_1 = 0;
+    let mut sum_2 = 0;
+    let mut sum_3 = 0;
+    let mut sum_4 = 0;
+    let mut sum_5 = 0;
+    let mut sum_6 = 0;
+    let mut sum_7 = 0;
+    let mut sum_8 = 0;
+
+    // This loop's throughput should peak at the CPU's number of ALU slots.
+    // Most CPUs have 4 ALU slots and will thus peak at 4 instr/cycle.
+    for _ in 0..N {
+        unsafe {
+            asm!(
+                "add {sum_1}, {x}",
+                "add {sum_2}, {x}",
+                "add {sum_3}, {x}",
+                "add {sum_4}, {x}",
+                "add {sum_5}, {x}",
+                "add {sum_6}, {x}",
+                "add {sum_7}, {x}",
+                "add {sum_8}, {x}",
+                sum_1 = inout(reg) sum_1,
+                sum_2 = inout(reg) sum_2,
+                sum_3 = inout(reg) sum_3,
+                sum_4 = inout(reg) sum_4,
+                sum_5 = inout(reg) sum_5,
+                sum_6 = inout(reg) sum_6,
+                sum_7 = inout(reg) sum_7,
+                sum_8 = inout(reg) sum_8,
+                x = in(reg) x as u64,
+            );
+        }
+    }
+
+    sum_1 + sum_2 + sum_3 + sum_4 + sum_5 + sum_6 + sum_7 + sum_8
+}
+
+/// One block of the loop overhead benchmarks: eight independent adds, the
+/// useful work the loop overhead is compared with.
+#[cfg(target_arch = "x86_64")]
+macro_rules! eight_adds {
+    () => {
+        concat!(
+            "add {sum_1}, {x}\n",
+            "add {sum_2}, {x}\n",
+            "add {sum_3}, {x}\n",
+            "add {sum_4}, {x}\n",
+            "add {sum_5}, {x}\n",
+            "add {sum_6}, {x}\n",
+            "add {sum_7}, {x}\n",
+            "add {sum_8}, {x}\n",
+        )
+    };
+}
+
+#[cfg(target_arch = "aarch64")]
+macro_rules! eight_adds {
+    () => {
+        concat!(
+            "add {sum_1}, {sum_1}, {x}\n",
+            "add {sum_2}, {sum_2}, {x}\n",
+            "add {sum_3}, {sum_3}, {x}\n",
+            "add {sum_4}, {sum_4}, {x}\n",
+            "add {sum_5}, {sum_5}, {x}\n",
+            "add {sum_6}, {sum_6}, {x}\n",
+            "add {sum_7}, {sum_7}, {x}\n",
+            "add {sum_8}, {sum_8}, {x}\n",
+        )
+    };
+}
+
+/// The number of useful adds in each block of the loop overhead benchmarks.
+pub const LOOP_OVERHEAD_OPS: usize = 8;
+
+/// The number of blocks [`bench_loop_overhead_unrolled`] runs.
+pub const UNROLLED_BLOCKS: usize = 256;
+
+// Runs `blocks` blocks of eight independent adds, in a loop counted down to
+// zero: a `dec` and a `jnz` for every eight useful instructions, which the
+// front end may fuse into one uop. Compare instructions per iteration, where
+// an iteration is one useful add, with the pointer-bump and unrolled
+// variants.
+#[cfg(target_arch = "x86_64")]
+#[inline(never)]
+pub fn bench_loop_overhead_counted(blocks: usize) -> u64 {
+    let x = black_box(1u64);
+    let [mut sum_1, mut sum_2, mut sum_3, mut sum_4] = [0u64; 4];
+    let [mut sum_5, mut sum_6, mut sum_7, mut sum_8] = [0u64; 4];
+    if blocks == 0 {
+        return 0;
+    }
+
+    unsafe {
+        asm!(
+            "2:",
+            eight_adds!(),
+            "dec {counter}",
+            "jnz 2b",
+            counter = inout(reg) blocks => _,
+            sum_1 = inout(reg) sum_1,
+            sum_2 = inout(reg) sum_2,
+            sum_3 = inout(reg) sum_3,
+            sum_4 = inout(reg) sum_4,
+            sum_5 = inout(reg) sum_5,
+            sum_6 = inout(reg) sum_6,
+            sum_7 = inout(reg) sum_7,
+            sum_8 = inout(reg) sum_8,
+            x = in(reg) x,
+            options(nostack),
+        );
+    }
+
+    sum_1 + sum_2 + sum_3 + sum_4 + sum_5 + sum_6 + sum_7 + sum_8
+}
+
+// Same as bench_loop_overhead_counted, with one block per element of
+// `array`, in a loop that bumps a pointer and compares it with the end of the
+// array, like an iterator: one more instruction per block than counting down,
+// and the loop doesn't even load the elements.
+#[cfg(target_arch = "x86_64")]
+#[inline(never)]
+pub fn bench_loop_overhead_pointer(array: &[u64]) -> u64 {
+    let x = black_box(1u64);
+    let [mut sum_1, mut sum_2, mut sum_3, mut sum_4] = [0u64; 4];
+    let [mut sum_5, mut sum_6, mut sum_7, mut sum_8] = [0u64; 4];
+    if array.is_empty() {
+        return 0;
+    }
+
+    unsafe {
+        asm!(
+            "2:",
+            eight_adds!(),
+            "add {pointer}, 8",
+            "cmp {pointer}, {end}",
+            "jne 2b",
+            pointer = inout(reg) array.as_ptr() => _,
+            end = in(reg) array.as_ptr().add(array.len()),
+            sum_1 = inout(reg) sum_1,
+            sum_2 = inout(reg) sum_2,
+            sum_3 = inout(reg) sum_3,
+            sum_4 = inout(reg) sum_4,
+            sum_5 = inout(reg) sum_5,
+            sum_6 = inout(reg) sum_6,
+            sum_7 = inout(reg) sum_7,
+            sum_8 = inout(reg) sum_8,
+            x = in(reg) x,
+            options(nostack),
+        );
+    }
+
+    sum_1 + sum_2 + sum_3 + sum_4 + sum_5 + sum_6 + sum_7 + sum_8
+}
+
+// UNROLLED_BLOCKS blocks of eight independent adds, written out one after
+// the other, with no loop at all: every instruction is useful. That's 6KB of
+// code, which fits in the L1I but not in the uop cache of most CPUs, so the
+// legacy decoders may become the bottleneck instead of the loop overhead.
+#[inline(never)]
+pub fn bench_loop_overhead_unrolled() -> u64 {
+    let x = black_box(1u64);
+    let [mut sum_1, mut sum_2, mut sum_3, mut sum_4] = [0u64; 4];
+    let [mut sum_5, mut sum_6, mut sum_7, mut sum_8] = [0u64; 4];
+
+    unsafe {
+        asm!(
+            ".rept {blocks}",
+            eight_adds!(),
+            ".endr",
+            blocks = const UNROLLED_BLOCKS,
+            sum_1 = inout(reg) sum_1,
+            sum_2 = inout(reg) sum_2,
+            sum_3 = inout(reg) sum_3,
+            sum_4 = inout(reg) sum_4,
+            sum_5 = inout(reg) sum_5,
+            sum_6 = inout(reg) sum_6,
+            sum_7 = inout(reg) sum_7,
+            sum_8 = inout(reg) sum_8,
+            x = in(reg) x,
+            options(nostack),
+        );
+    }
+
+    sum_1 + sum_2 + sum_3 + sum_4 + sum_5 + sum_6 + sum_7 + sum_8
+}
+
+/// The number of elements each iteration of the addressing mode benchmarks
+/// loads. Their arrays must hold a multiple of it.
+pub const ADDRESSING_UNROLL: usize = 4;
+
+// Sums `array` with loads addressed as base + index * 8, like `array[i]`
+// compiles to when the compiler keeps the index around. On many Intel cores,
+// an add with an indexed memory operand is micro-fused in the decoders but
+// un-laminated before the scheduler, so it takes two issue slots where the
+// pointer-bump variant's takes one, for the same number of instructions.
+#[cfg(target_arch = "x86_64")]
+#[inline(never)]
+pub fn bench_addressing_indexed(array: &[u64]) -> u64 {
+    let [mut sum_1, mut sum_2, mut sum_3, mut sum_4] = [0u64; 4];
+    assert!(array.len().is_multiple_of(ADDRESSING_UNROLL));
+    if array.is_empty() {
+        return 0;
+    }
+
+    unsafe {
+        asm!(
+            "2:",
+            "add {sum_1}, [{base} + {index} * 8]",
+            "add {sum_2}, [{base} + {index} * 8 + 8]",
+            "add {sum_3}, [{base} + {index} * 8 + 16]",
+            "add {sum_4}, [{base} + {index} * 8 + 24]",
+            "add {index}, 4",
+            "cmp {index}, {len}",
+            "jne 2b",
+            base = in(reg) array.as_ptr(),
+            index = inout(reg) 0usize => _,
+            len = in(reg) array.len(),
+            sum_1 = inout(reg) sum_1,
+            sum_2 = inout(reg) sum_2,
+            sum_3 = inout(reg) sum_3,
+            sum_4 = inout(reg) sum_4,
+            options(nostack, readonly),
+        );
+    }
+
+    sum_1 + sum_2 + sum_3 + sum_4
+}
+
+// Same as bench_addressing_indexed, with loads addressed as a pointer plus a
+// constant offset, bumped after each group of loads, like an iterator
+// compiles to. Same instructions, same loads, simpler addressing.
+#[cfg(target_arch = "x86_64")]
+#[inline(never)]
+pub fn bench_addressing_pointer(array: &[u64]) -> u64 {
+    let [mut sum_1, mut sum_2, mut sum_3, mut sum_4] = [0u64; 4];
+    assert!(array.len().is_multiple_of(ADDRESSING_UNROLL));
+    if array.is_empty() {
+        return 0;
+    }
+
+    unsafe {
+        asm!(
+            "2:",
+            "add {sum_1}, [{pointer}]",
+            "add {sum_2}, [{pointer} + 8]",
+            "add {sum_3}, [{pointer} + 16]",
+            "add {sum_4}, [{pointer} + 24]",
+            "add {pointer}, 32",
+            "cmp {pointer}, {end}",
+            "jne 2b",
+            pointer = inout(reg) array.as_ptr() => _,
+            end = in(reg) array.as_ptr().add(array.len()),
+            sum_1 = inout(reg) sum_1,
+            sum_2 = inout(reg) sum_2,
+            sum_3 = inout(reg) sum_3,
+            sum_4 = inout(reg) sum_4,
+            options(nostack, readonly),
+        );
+    }
+
+    sum_1 + sum_2 + sum_3 + sum_4
+}
+
+/// The number of dependent multiplies each burst of
+/// [`bench_store_buffer_fill`] puts ahead of its stores.
+pub const STORE_BUFFER_CHAIN: usize = 64;
+
+// For each of `bursts` bursts, runs a chain of STORE_BUFFER_CHAIN dependent
+// multiplies, then stores once to each of the STORES lines of `lines`. A
+// store only leaves the store buffer after it retires, and it can't retire
+// before the multiplies ahead of it, so each burst's stores pile up in the
+// store buffer. As long as they fit, the next burst's chain starts while the
+// previous one is still running; once they don't, allocation stalls until
+// the chain retires, and the cycles per burst jump to about twice the chain's
+// latency. The chain is short enough that the reorder buffer isn't what runs
+// out first, up to a few hundred stores.
+#[cfg(target_arch = "x86_64")]
+#[inline(never)]
+pub fn bench_store_buffer_fill<const STORES: usize>(
+    lines: &mut [CacheAligned<u64>; STORES],
+    bursts: usize,
+) -> u64 {
+    let mut x = black_box(3u64);
+    if bursts == 0 {
+        return x;
+    }
+
+    unsafe {
+        asm!(
+            "2:",
+            ".rept {chain}",
+            "imul {x}, {x}",
+            ".endr",
+            ".set store_offset, 0",
+            ".rept {stores}",
+            "mov qword ptr [{base} + store_offset], {bursts}",
+            ".set store_offset, store_offset + 64",
+            ".endr",
+            "dec {bursts}",
+            "jnz 2b",
+            chain = const STORE_BUFFER_CHAIN,
+            stores = const STORES,
+            base = in(reg) lines.as_mut_ptr(),
+            bursts = inout(reg) bursts => _,
+            x = inout(reg) x,
+            options(nostack),
+        );
+    }
+
+    x
+}
+
+#[cfg(target_arch = "x86_64")]
+#[inline(never)]
+pub fn bench_code_footprint<T, const N: usize, const BLOCKS: usize>(_array: &[T; N]) -> u64 {
+    let mut sum_1 = 0;
+    let mut sum_2 = 0;
+    let mut sum_3 = 0;
+    let mut sum_4 = 0;
+
+    // Each block is 16 bytes of code, and every block is at a distinct
+    // address, so this loop's body takes up BLOCKS * 16 bytes of instruction
+    // cache. Once that exceeds the L1I (usually 32KB), the front-end has to
+    // fetch code from L2 every iteration and the L1I miss count climbs,
+    // even though the number of instructions executed stays the same.
+    for _ in 0..N {
+        unsafe {
+            asm!(
+                ".rept {blocks}",
+                "add {sum_1}, 1",
+                "add {sum_2}, 1",
+                "add {sum_3}, 1",
+                "add {sum_4}, 1",
+                ".endr",
+                blocks = const BLOCKS,
+                sum_1 = inout(reg) sum_1,
+                sum_2 = inout(reg) sum_2,
+                sum_3 = inout(reg) sum_3,
+                sum_4 = inout(reg) sum_4,
+            );
+        }
+    }
+
+    sum_1 + sum_2 + sum_3 + sum_4
+}
+
+#[inline(never)]
+pub fn bench_mul_ops<T, const N: usize>(_array: &[T; N]) -> u64 {
+    let x = black_box(3);
+
+    // This loop's throughput will peak at one iteration per mul latency.
+    // Most CPUs have a mul latency of 3, so the loop body will run 0.33 times
+    // per cycle.
+    // With the loop counter, this translates to roughly 0.4 instr/cycle.
+    let mut product = black_box(0);
+    for _ in 0..N {
+        product *= x;
+    }
+
+    product
+}
+
+// The indexed loop is deliberate: we want the bounds-checked load in the body.
+#[allow(clippy::needless_range_loop)]
+#[inline(never)]
+pub fn bench_sum_of_array(array: &[u8]) -> u8 {
+    let mut sum: u8 = 0;
+
+    // Like bench_alu_ops, this loop should peak at 1 iteration per cycle.
+    // Given the loop has a 5-instuction body, this translates to 5 instr/cycle.
+    // On Zen, it instead peaks at 0.6 iteration per cycle, eg 3 instr/cycle.
+    for i in 0..array.len() {
+        sum = sum.wrapping_add(array[i]);
+        clobber_read(&(sum as u64));
+    }
+
+    sum
+}
+
+#[inline(never)]
+pub fn bench_sum_of_array_unrolled(array: &[u8]) -> u8 {
+    let x = black_box(3);
+    let mut sum_1: u8 = 0;
+    let mut sum_2: u8 = 0;
+
+    let mut i = 0;
+    while i < array.len() {
+        sum_1 = sum_1.wrapping_add(array[i] & x);
+        sum_2 = sum_2.wrapping_add(array[i + 1] & x);
+        i += 2;
+    }
+
+    sum_1.wrapping_add(sum_2)
+}
+
+#[inline(never)]
+pub fn bench_sum_of_array_with_stride<const N: usize>(array: &[u8; N], stride: usize) -> u8 {
+    let x = black_box(3);
+    let mut sum: u8 = 0;
+
+    let mut i = 0;
+    while i < N {
+        sum = sum.wrapping_add(array[i] & x);
+        i += stride;
+    }
+
+    sum
+}
+
+// Same as bench_sum_of_array, but does `ops_per_element` dependent adds on
+// every element before summing it. With no extra work, a large array makes
+// this memory-bound; as the work grows, the core eventually spends longer
+// computing than waiting for the next line, and the loop becomes
+// compute-bound. Past that crossover, bandwidth drops and prefetching can't
+// help anymore. The adds are asm so the compiler can't fold them into a
+// multiplication.
+#[inline(never)]
+pub fn bench_sum_with_work(array: &[u8], ops_per_element: usize) -> u64 {
+    let x: u64 = black_box(3);
+    let mut sum = 0;
+
+    for &element in array {
+        let mut value = element as u64;
+        for _ in 0..ops_per_element {
+            #[cfg(target_arch = "x86_64")]
+            unsafe {
+                asm!("add {}, {}", inout(reg) value, in(reg) x, options(nomem, nostack));
+            }
+            #[cfg(target_arch = "aarch64")]
+            unsafe {
+                asm!("add {0}, {0}, {1}", inout(reg) value, in(reg) x, options(nomem, nostack));
+            }
+        }
+        sum += value;
+    }
+
+    sum
+}
+
+// Same as bench_sum_of_array_with_stride, but takes a slice, so that the size
+// can be picked at runtime and the array can live on the heap.
+#[inline(never)]
+pub fn bench_sum_of_array_with_stride_slice(array: &[u8], stride: usize) -> u8 {
+    let x = black_box(3);
+    let mut sum: u8 = 0;
+
+    let mut i = 0;
+    while i < array.len() {
+        sum = sum.wrapping_add(array[i] & x);
+        i += stride;
+    }
+
+    sum
+}
+
+// Same access pattern as bench_sum_of_array_with_stride, but writes back every
+// byte it reads. On write-allocate caches the lines still have to be fetched,
+// but they come back dirty and have to be written out when evicted, and the
+// hardware prefetcher may treat the store stream differently from loads.
+#[inline(never)]
+pub fn bench_rmw_array_with_stride(array: &mut [u8], stride: usize) -> u8 {
+    let x = black_box(3);
+
+    let mut i = 0;
+    while i < array.len() {
+        array[i] &= x;
+        i += stride;
+    }
+
+    array[0]
+}
+
+/// Whether the CPU supports `prefetchw`.
+#[cfg(target_arch = "x86_64")]
+pub fn has_prefetchw() -> bool {
+    // Only query leaf 0x80000001 if the CPU has it.
+    let (max_leaf, _) = std::arch::x86_64::__get_cpuid_max(0x8000_0000);
+    max_leaf >= 0x8000_0001 && std::arch::x86_64::__cpuid(0x8000_0001).ecx & (1 << 8) != 0
+}
+
+// Same as bench_rmw_array_with_stride, but prefetches the byte P accesses
+// ahead with prefetchw, which fetches its line in exclusive state, as if
+// about to write it. A read prefetch gets the line shared, so the store
+// that follows has to ask for ownership again; prefetchw does both at once.
+// Callers check has_prefetchw once beforehand, rather than running cpuid on
+// every measured call.
+#[cfg(target_arch = "x86_64")]
+#[inline(never)]
+pub fn bench_rmw_array_with_stride_prefetchw<const P: usize>(
+    array: &mut [u8],
+    stride: usize,
+) -> u8 {
+    let x = black_box(3);
+
+    let mut i = 0;
+    while i < array.len() {
+        unsafe {
+            asm!(
+                "prefetchw [{}]",
+                in(reg) array.as_ptr().wrapping_add(i + P * stride),
+                options(nostack, readonly, preserves_flags),
+            );
+        }
+        array[i] &= x;
+        i += stride;
+    }
+
+    array[0]
+}
+
+#[inline(never)]
+pub fn bench_sum_of_array_with_stride_prefetch<const N: usize, const P: usize>(
+    array: &[u8; N],
+    stride: usize,
+) -> u8 {
+    let x = black_box(3);
+    let mut sum: u8 = 0;
+
+    let mut i = 0;
+    while i < N {
+        sum = sum.wrapping_add(array[i] & x);
+        prefetch_t0(array.as_ptr().wrapping_add(i + P * stride));
+        i += stride;
+    }
+
+    sum
+}
+
+/// How many cache lines ahead of the current access the first prefetch of
+/// [`bench_sum_with_prefetches`] targets.
+pub const PREFETCHES_LINES_AHEAD: usize = 8;
+
+// Same as bench_sum_of_array_with_stride_prefetch, but issues PREFETCHES
+// prefetches per access, to consecutive lines starting PREFETCHES_LINES_AHEAD
+// lines ahead. Most of them target lines an earlier access already
+// prefetched, so past one or two, they don't bring anything in earlier; they
+// only compete with the demand loads for the load ports and fill buffers.
+#[inline(never)]
+pub fn bench_sum_with_prefetches<const N: usize, const PREFETCHES: usize>(
+    array: &[u8; N],
+    stride: usize,
+) -> u8 {
+    let x = black_box(3);
+    let mut sum: u8 = 0;
+
+    let mut i = 0;
+    while i < N {
+        sum = sum.wrapping_add(array[i] & x);
+        for prefetch in 0..PREFETCHES {
+            let ahead = i + (PREFETCHES_LINES_AHEAD + prefetch) * 64;
+            prefetch_t0(array.as_ptr().wrapping_add(ahead));
+        }
+        i += stride;
+    }
+
+    sum
+}
+
+// Same as bench_sum_of_array_with_stride_prefetch, but takes a slice, like
+// bench_sum_of_array_with_stride_slice.
+#[inline(never)]
+pub fn bench_sum_of_array_with_stride_prefetch_slice<const P: usize>(
+    array: &[u8],
+    stride: usize,
+) -> u8 {
+    let x = black_box(3);
+    let mut sum: u8 = 0;
+
+    let mut i = 0;
+    while i < array.len() {
+        sum = sum.wrapping_add(array[i] & x);
+        prefetch_t0(array.as_ptr().wrapping_add(i + P * stride));
+        i += stride;
+    }
+
+    sum
+}
+
+// Same as bench_sum_of_array_with_stride_prefetch, but the prefetch distance
+// is LINES cache lines ahead of the current access, rather than P accesses
+// ahead. "4 lines ahead" then means the same thing whatever the stride, which
+// makes results comparable across strides.
+#[inline(never)]
+pub fn bench_sum_of_array_with_stride_prefetch_lines<const N: usize, const LINES: usize>(
+    array: &[u8; N],
+    stride: usize,
+) -> u8 {
+    let x = black_box(3);
+    let mut sum: u8 = 0;
+
+    let mut i = 0;
+    while i < N {
+        sum = sum.wrapping_add(array[i] & x);
+        prefetch_t0(array.as_ptr().wrapping_add(i + LINES * 64));
+        i += stride;
+    }
+
+    sum
+}
+
+/// How many cache lines ahead a prefetch `elements` accesses ahead lands,
+/// when accessing every `stride` bytes.
+pub fn prefetch_distance_in_lines(elements: usize, stride: usize) -> f64 {
+    (elements * stride) as f64 / 64.0
+}
+
+/// How many accesses ahead, when accessing every `stride` bytes, a prefetch
+/// has to be to land at least `lines` cache lines ahead.
+pub fn prefetch_distance_in_elements(lines: usize, stride: usize) -> usize {
+    (lines * 64).div_ceil(stride)
+}
+
+/// A value aligned to the start of a cache line.
+#[repr(C, align(64))]
+#[derive(Clone, Copy, Debug)]
+pub struct CacheAligned<T>(pub T);
+
+// Same as bench_sum_of_array_with_stride_prefetch, but starts at byte `offset`
+// of an array aligned to a cache line, eg the start of an AlignedBuffer. With
+// a stride of 64, every access (and every prefetch) lands at the same position
+// within its cache line, and a prefetch distance of one access always targets
+// the next line. Any difference between offsets then comes from the position
+// within the line alone, eg a prefetch near the end of a line racing the load
+// of the line after it.
+#[inline(never)]
+pub fn bench_sum_of_array_with_stride_prefetch_offset<const N: usize, const P: usize>(
+    array: &[u8; N],
+    stride: usize,
+    offset: usize,
+) -> u8 {
+    debug_assert_eq!(array.as_ptr() as usize % 64, 0);
+    let x = black_box(3);
+    let mut sum: u8 = 0;
+
+    let mut i = offset;
+    while i < N {
+        sum = sum.wrapping_add(array[i] & x);
+        prefetch_t0(array.as_ptr().wrapping_add(i + P * stride));
+        i += stride;
+    }
+
+    sum
+}
+
+// Loads the first byte of every cache line of `buffer`. The address of each
+// load depends on the value of the previous one (through a mask that is
+// always zero, but the compiler can't know that), so the loop runs at the
+// latency of a load rather than at its throughput.
+#[inline(never)]
+pub fn bench_load_lines<const N: usize>(buffer: &CacheAligned<[u8; N]>) -> u8 {
+    let buffer = &buffer.0;
+    let zero = black_box(0);
+    let mut sum: u8 = 0;
+
+    let mut i = 0;
+    while i < N {
+        sum = sum.wrapping_add(buffer[i + (sum & zero) as usize]);
+        i += 64;
+    }
+
+    sum
+}
+
+/// Whether the CPU supports `clflushopt`.
+#[cfg(target_arch = "x86_64")]
+pub fn has_clflushopt() -> bool {
+    // Only query leaf 7 if the CPU has it.
+    let (max_leaf, _) = std::arch::x86_64::__get_cpuid_max(0);
+    max_leaf >= 7 && std::arch::x86_64::__cpuid_count(7, 0).ebx & (1 << 23) != 0
+}
+
+// Evicts every cache line of `buffer` from the whole cache hierarchy, with
+// clflushopt if OPTIMIZED and clflush otherwise, then waits for the flushes
+// to complete. clflush is ordered with respect to other clflush instructions,
+// so they run one at a time, whereas clflushopt flushes can overlap.
+// OPTIMIZED needs has_clflushopt, which flush_latency checks before timing
+// anything, so that no cpuid ends up in the measured region.
+#[cfg(target_arch = "x86_64")]
+#[inline(never)]
+pub fn bench_flush_lines<const N: usize, const OPTIMIZED: bool>(buffer: &CacheAligned<[u8; N]>) {
+    let buffer = &buffer.0;
+
+    let mut i = 0;
+    while i < N {
+        unsafe {
+            if OPTIMIZED {
+                asm!("clflushopt [{}]", in(reg) buffer.as_ptr().add(i), options(nostack, preserves_flags));
+            } else {
+                _mm_clflush(buffer.as_ptr().add(i));
+            }
+        }
+        i += 64;
+    }
+    unsafe {
+        _mm_mfence();
+    }
+}
+
+// Same as bench_sum_of_array_with_stride_prefetch, but HINT selects the
+// cache level the prefetch targets: _MM_HINT_T0 brings lines into every
+// level, _MM_HINT_T1 into the L2 and below, _MM_HINT_T2 into the L3 only, and
+// _MM_HINT_NTA close to the core, bypassing what it can. Which level is worth
+// targeting depends on how the working set compares with each cache: on
+// buffers larger than the LLC, T0 prefetches evict lines we'd like to keep,
+// whereas NTA ones minimize cache pollution. On buffers that fit in cache and
+// get re-read, T0 should win.
+#[cfg(target_arch = "x86_64")]
+#[inline(never)]
+pub fn bench_sum_of_array_with_stride_prefetch_hint<
+    const N: usize,
+    const P: usize,
+    const HINT: i32,
+>(
+    array: &[u8; N],
+    stride: usize,
+) -> u8 {
+    let x = black_box(3);
+    let mut sum: u8 = 0;
+
+    let mut i = 0;
+    while i < N {
+        sum = sum.wrapping_add(array[i] & x);
+        unsafe {
+            _mm_prefetch::<HINT>(array.as_ptr().wrapping_add(i + P * stride) as *const i8);
+        };
+        i += stride;
+    }
+
+    sum
+}
+
+// Same as bench_sum_of_array_with_stride_prefetch, but only issues a prefetch
+// every K accesses. A prefetch brings in a whole cache line, so when several
+// accesses fall in the same line (eg stride 16 and K = 4), one prefetch per
+// line should give the same miss rate as one per access, for fewer
+// instructions. Compare the "L1D cache prefetches" and instruction counts.
+#[inline(never)]
+pub fn bench_sum_of_array_with_stride_prefetch_every<
+    const N: usize,
+    const P: usize,
+    const K: usize,
+>(
+    array: &[u8; N],
+    stride: usize,
+) -> u8 {
+    // With K = 0, the inner loop would never advance i.
+    const { assert!(K > 0, "K must be at least 1") };
+    let x = black_box(3);
+    let mut sum: u8 = 0;
+
+    let mut i = 0;
+    while i < N {
+        prefetch_t0(array.as_ptr().wrapping_add(i + P * stride));
+        let end = (i + K * stride).min(N);
+        while i < end {
+            sum = sum.wrapping_add(array[i] & x);
+            i += stride;
+        }
+    }
+
+    sum
+}
+
+// Same as bench_sum_of_array_with_stride, but prefetches the line we're about
+// to read, which is already in L1 if the array is small enough. The prefetch
+// is useless, so any difference with bench_sum_of_array_with_stride is the
+// cost of issuing the prefetch instruction itself.
+#[inline(never)]
+pub fn bench_prefetch_overhead<const N: usize>(array: &[u8; N], stride: usize) -> u8 {
+    let x = black_box(3);
+    let mut sum: u8 = 0;
+
+    let mut i = 0;
+    while i < N {
+        prefetch_t0(array.as_ptr().wrapping_add(i));
+        sum = sum.wrapping_add(array[i] & x);
+        i += stride;
+    }
+
+    sum
+}
+
+pub const PREFETCH_AT_TOP: u8 = 0;
+pub const PREFETCH_AFTER_LOAD: u8 = 1;
+pub const PREFETCH_AFTER_COMPUTE: u8 = 2;
+pub const PREFETCH_SPLIT: u8 = 3;
+
+// Same as bench_sum_of_array_with_stride_prefetch, but PLACEMENT selects where
+// in the loop body the prefetch is issued. The prefetch distance is the same
+// in every case; only its position relative to the load changes.
+// PREFETCH_SPLIT computes the prefetch address at the end of one iteration and
+// issues the prefetch at the start of the next one.
+#[inline(never)]
+pub fn bench_sum_of_array_with_prefetch_placement<
+    const N: usize,
+    const P: usize,
+    const PLACEMENT: u8,
+>(
+    array: &[u8; N],
+    stride: usize,
+) -> u8 {
+    let x = black_box(3);
+    let mut sum: u64 = 0;
+    let mut prefetch_ptr = array.as_ptr().wrapping_add(P * stride);
+
+    let mut i = 0;
+    while i < N {
+        if PLACEMENT == PREFETCH_AT_TOP {
+            pinned_prefetch_t0!(array.as_ptr().wrapping_add(i + P * stride));
+        }
+        if PLACEMENT == PREFETCH_SPLIT {
+            pinned_prefetch_t0!(prefetch_ptr);
+        }
+
+        let value = array[i] as u64;
+        clobber_read(&value);
+
+        if PLACEMENT == PREFETCH_AFTER_LOAD {
+            pinned_prefetch_t0!(array.as_ptr().wrapping_add(i + P * stride));
+        }
+
+        sum += value & x;
+        clobber_rw(&mut sum);
+
+        if PLACEMENT == PREFETCH_AFTER_COMPUTE {
+            pinned_prefetch_t0!(array.as_ptr().wrapping_add(i + P * stride));
+        }
+        if PLACEMENT == PREFETCH_SPLIT {
+            prefetch_ptr = array.as_ptr().wrapping_add(i + stride + P * stride);
+        }
+
+        i += stride;
+    }
+
+    sum as u8
+}
+
+#[cfg(target_arch = "x86_64")]
+#[inline(never)]
+pub fn bench_sum_array_stride_and_pad<const N: usize>(array: &[u8; N], stride: usize) -> u8 {
+    let x = black_box(3);
+    let mut sum: u64 = 0;
+
+    let mut i = 0;
+    while i < N {
+        sum += array[i] as u64;
+        unsafe {
+            asm!(
+                "xor {sum}, {sum}",
+                "add {sum}, {x}",
+                "add {sum}, {x}",
+                "add {i}, {stride}",
+                sum = inout(reg) sum,
+                i = inout(reg) i,
+                x = in(reg) x as u64,
+                stride = in(reg) stride,
+            );
+        }
+    }
+
+    sum as u8
+}
+
+#[cfg(target_arch = "x86_64")]
+#[inline(never)]
+pub fn bench_sum_array_changing_stride<const N: usize>(array: &[u8; N]) -> u8 {
+    let mut sum: u64 = 0;
+    let mut stride: u64 = 0;
+
+    let mut i = 0;
+    while i < N {
+        sum += array[i] as u64;
+        // The block updates {stride} in place, so it has to be an inout
+        // operand: with a plain `in`, the compiler may assume the register
+        // still holds the old value and the stride never changes.
+        unsafe {
+            asm!(
+                "xor {sum}, {sum}",
+                "add {stride}, 9",
+                "and {stride}, 31",
+                "add {i}, 110",
+                "add {i}, {stride}",
+                sum = inout(reg) sum,
+                i = inout(reg) i,
+                stride = inout(reg) stride,
+            );
+        }
+    }
+
+    sum as u8
+}
+
+#[inline(never)]
+pub fn bench_sequential_store<const N: usize>(array: &mut [u64; N]) -> u64 {
+    let mut x = black_box(3);
+
+    // On write-allocate caches, every line we store to has to be read in
+    // first (read-for-ownership), so this loop moves twice as much data
+    // between the core and memory as it writes. Compare with
+    // bench_sequential_store_nt.
+    // The asm block keeps the compiler from turning this loop into a memset.
+    for element in array.iter_mut() {
+        *element = x;
+        clobber_rw(&mut x);
+    }
+
+    x
+}
+
+#[cfg(target_arch = "x86_64")]
+#[inline(never)]
+pub fn bench_sequential_store_nt<const N: usize>(array: &mut [u64; N]) -> u64 {
+    let mut x = black_box(3);
+
+    // Non-temporal stores go through write-combining buffers straight to
+    // memory, without reading the line in first or polluting the caches.
+    for i in 0..N {
+        unsafe {
+            _mm_stream_si64(array.as_mut_ptr().add(i) as *mut i64, x as i64);
+        }
+        clobber_rw(&mut x);
+    }
+    unsafe {
+        _mm_sfence();
+    }
+
+    x
+}
+
+// Scales each element before adding it, like the integer sums mask it. When
+// the array holds denormals, every product is denormal too, and on most CPUs
+// each one takes a microcode assist costing on the order of a hundred cycles.
+// Compare the cycles per iteration with normal and denormal arrays.
+// The compiler can't reorder float additions, so this loop doesn't vectorize.
+#[inline(never)]
+pub fn bench_sum_floats(array: &[f32]) -> f32 {
+    let x = black_box(0.5);
+    let mut sum = 0.0;
+
+    for &element in array {
+        sum += element * x;
+    }
+
+    sum
+}
+
+// Same as bench_sum_floats, but with flush-to-zero and denormals-are-zero
+// set, so denormal inputs and results are replaced with zero instead of
+// taking an assist. Denormal arrays should then run as fast as normal ones.
+#[cfg(target_arch = "x86_64")]
+#[inline(never)]
+pub fn bench_sum_floats_ftz(array: &[f32]) -> f32 {
+    // FTZ is bit 15 of MXCSR, DAZ bit 6.
+    const FTZ_DAZ: u32 = 1 << 15 | 1 << 6;
+
+    let mut mxcsr: u32 = 0;
+    unsafe {
+        asm!("stmxcsr [{}]", in(reg) &mut mxcsr, options(nostack, preserves_flags));
+    }
+    let flushed = mxcsr | FTZ_DAZ;
+    unsafe {
+        asm!("ldmxcsr [{}]", in(reg) &flushed, options(nostack, preserves_flags));
+    }
+
+    let sum = bench_sum_floats(array);
+
+    unsafe {
+        asm!("ldmxcsr [{}]", in(reg) &mxcsr, options(nostack, preserves_flags));
+    }
+
+    sum
+}
+
+// Alternates a 256-bit AVX add, which leaves the upper halves of the ymm
+// registers dirty, with a legacy SSE add, `iterations` times. Legacy SSE
+// instructions don't know about the upper halves, so with them dirty, older
+// Intel CPUs save and restore them on every switch, and Skylake and later add
+// a merge with the upper half to every SSE instruction instead. With
+// VZEROUPPER, the upper halves are cleared before the SSE add, which avoids
+// both. CPUs without the penalty, like AMD's, run both variants alike.
+// Needs AVX, see is_x86_feature_detected!("avx").
+#[cfg(target_arch = "x86_64")]
+#[inline(never)]
+pub fn bench_avx_sse_transition<const VZEROUPPER: bool>(iterations: u64) -> u64 {
+    if iterations == 0 {
+        return 0;
+    }
+    let mut remaining = iterations;
+    unsafe {
+        if VZEROUPPER {
+            asm!(
+                "2:",
+                "vaddps ymm0, ymm0, ymm1",
+                "vzeroupper",
+                "addps xmm2, xmm3",
+                "dec {remaining}",
+                "jnz 2b",
+                remaining = inout(reg) remaining,
+                out("xmm0") _, out("xmm1") _, out("xmm2") _, out("xmm3") _,
+                options(nostack),
+            );
+        } else {
+            asm!(
+                "2:",
+                "vaddps ymm0, ymm0, ymm1",
+                "addps xmm2, xmm3",
+                "dec {remaining}",
+                "jnz 2b",
+                // Leave the CPU as clean as we found it.
+                "vzeroupper",
+                remaining = inout(reg) remaining,
+                out("xmm0") _, out("xmm1") _, out("xmm2") _, out("xmm3") _,
+                options(nostack),
+            );
+        }
+    }
+    iterations - remaining
+}
+
+// Loads `lines` bytes of `buffer`, `stride` bytes apart. With a stride of
+// one way of a cache, every load maps to the same set, so once `lines` is
+// more than the cache's associativity, they evict each other and miss every
+// time, even though they'd fit in a handful of sets. Add a line to the
+// stride, and each load gets a set of its own.
+#[inline(never)]
+pub fn bench_assoc_conflict(buffer: &[u8], stride: usize, lines: usize) -> u64 {
+    let mut sum: u64 = 0;
+    for line in 0..lines {
+        sum += buffer[line * stride] as u64;
+    }
+    sum
+}
+
+/// A value aligned to the start of a 4KB page.
+#[repr(C, align(4096))]
+#[derive(Clone, Copy, Debug)]
+pub struct PageAligned<T>(pub T);
+
+/// A zeroed heap buffer that starts at a multiple of a power of two, like a
+/// page or a cache line.
+///
+/// Unlike [`PageAligned`], the alignment is picked at runtime, and the
+/// buffer is never on the stack. Where an array starts relative to lines and
+/// pages changes how strided accesses and prefetches behave, so this keeps
+/// it from shifting between runs.
+pub struct AlignedBuffer {
+    ptr: *mut u8,
+    len: usize,
+    layout: std::alloc::Layout,
+}
+
+impl AlignedBuffer {
+    pub fn new(len: usize, align: usize) -> std::io::Result<AlignedBuffer> {
+        let layout = std::alloc::Layout::from_size_align(len.max(1), align)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+        // SAFETY: the layout has a non-zero size, and we only hand out
+        // slices of the allocation while it's alive.
+        let ptr = unsafe { std::alloc::alloc_zeroed(layout) };
+        if ptr.is_null() {
+            return Err(std::io::ErrorKind::OutOfMemory.into());
+        }
+        Ok(AlignedBuffer { ptr, len, layout })
+    }
+
+    /// Same as [`AlignedBuffer::new`], aligned to the start of a 4KB page.
+    pub fn page_aligned(len: usize) -> std::io::Result<AlignedBuffer> {
+        AlignedBuffer::new(len, 4096)
+    }
+}
+
+impl Deref for AlignedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: the allocation is at least `len` bytes long, zeroed, and
+        // lives as long as self.
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl DerefMut for AlignedBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        // SAFETY: same as deref, and we have unique access to self.
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        // SAFETY: new allocated it with this layout, and no slice of it
+        // outlives self.
+        unsafe { std::alloc::dealloc(self.ptr, self.layout) }
+    }
+}
+
+// Does one unaligned 16-byte load at byte `offset` of every page of `buffer`.
+// An offset of 0 is the aligned baseline, and an offset of 56 splits every
+// load across two cache lines. An offset of 4088 splits every load across two
+// pages, which also takes two TLB lookups, and on some CPUs costs far more
+// than a line split. Compare the cycles per iteration and the DTLB misses.
+#[cfg(target_arch = "x86_64")]
+#[inline(never)]
+pub fn bench_page_split_loads<const N: usize>(buffer: &PageAligned<[u8; N]>, offset: usize) -> u64 {
+    let mut sum = unsafe { _mm_setzero_si128() };
+
+    let mut page = 0;
+    while page + offset + 16 <= N {
+        unsafe {
+            let load = _mm_loadu_si128(buffer.0.as_ptr().add(page + offset) as *const __m128i);
+            sum = _mm_add_epi64(sum, load);
+        }
+        page += 4096;
+    }
+
+    unsafe {
+        let low = _mm_cvtsi128_si64(sum) as u64;
+        let high = _mm_cvtsi128_si64(_mm_unpackhi_epi64(sum, sum)) as u64;
+        low.wrapping_add(high)
+    }
+}
+
+// Loads one byte every `page_stride` bytes of `buffer`. With a 2MB stride
+// over 4KB pages, every load needs its own page table as well as its own TLB
+// entry, so once the buffer spans more than the page-structure caches cover,
+// the page walker has to fetch the upper levels of the page table from
+// memory too. Each load is also shifted by one more cache line than the
+// previous one, so that they don't all fight over the same cache set.
+#[inline(never)]
+pub fn bench_page_walk_stress(buffer: &[u8], page_stride: usize) -> u8 {
+    let x = black_box(3);
+    let mut sum: u8 = 0;
+
+    let mut page = 0;
+    let mut i = 0;
+    while i < buffer.len() {
+        sum = sum.wrapping_add(buffer[i] & x);
+        page += 1;
+        i = page * page_stride + (page % 64) * 64;
+    }
+
+    sum
+}
+
+// Stores to buffer[offset + i] then loads buffer[i], for each 8-byte word i
+// of a 4KB window. When offset is a multiple of 4096, every load has the same
+// low 12 address bits as the store right before it, and the CPU wrongly
+// assumes the load depends on the store until it has checked the full
+// addresses ("4K aliasing"). Any other offset should run at full speed.
+#[cfg(target_arch = "x86_64")]
+#[inline(never)]
+pub fn bench_4k_aliasing<const N: usize>(buffer: &mut [u8; N], offset: usize) -> u64 {
+    const WINDOW: usize = 4096;
+    assert!(offset + WINDOW <= N);
+
+    let x = black_box(3_u64);
+    let mut sum: u64 = 0;
+    let loads = buffer.as_mut_ptr();
+    let stores = unsafe { loads.add(offset) };
+
+    let mut i = 0;
+    while i < WINDOW {
+        // Pinning the store before the load in asm keeps the compiler from
+        // reordering them, which would hide the stall.
+        unsafe {
+            asm!(
+                "mov qword ptr [{stores} + {i}], {x}",
+                "add {sum}, qword ptr [{loads} + {i}]",
+                sum = inout(reg) sum,
+                stores = in(reg) stores,
+                loads = in(reg) loads,
+                i = in(reg) i,
+                x = in(reg) x,
+                options(nostack),
+            );
+        }
+        i += 8;
+    }
+
+    sum
+}
+
+/// Indices into an array of `N` elements, all checked to be in bounds, so
+/// that [`bench_sum_array_indirect`] doesn't have to check them as it goes.
+#[derive(Clone, Copy, Debug)]
+pub struct CheckedIndices<'a, const N: usize, const M: usize>(&'a [usize; M]);
+
+impl<'a, const N: usize, const M: usize> CheckedIndices<'a, N, M> {
+    /// Check every index of `indices`, in a pass of its own, or say which
+    /// one is out of bounds.
+    pub fn new(indices: &'a [usize; M]) -> std::io::Result<CheckedIndices<'a, N, M>> {
+        if let Some(position) = indices.iter().position(|&index| index >= N) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "index {} at position {} is out of bounds of {}",
+                    indices[position], position, N
+                ),
+            ));
+        }
+        Ok(CheckedIndices(indices))
+    }
+}
+
+// Sums one element of `array` every 64 indices. The indices were checked
+// when building `indices`, so the loop can skip the bounds checks, and the
+// only branch left is the loop's own.
+#[inline(never)]
+pub fn bench_sum_array_indirect<const N: usize, const M: usize>(
+    array: &[u8; N],
+    indices: CheckedIndices<N, M>,
+) -> u8 {
+    let indices = indices.0;
+    let mut sum: u64 = 0;
+
+    let mut i = 0;
+    while i < M {
+        let index = indices[i];
+        debug_assert!(index < N);
+        // SAFETY: CheckedIndices::new checked that every index is below N.
+        sum += unsafe { *array.get_unchecked(index) } as u64;
+        i += 64;
+    }
+
+    sum as u8
+}
+
+// Same as bench_sum_array_indirect, but prefetches the element P iterations
+// ahead, so P * 64 indices ahead, before loading the current one. The indices
+// themselves are read sequentially, so the hardware prefetchers cover them,
+// and only the loads from `array` are left to software prefetching.
+#[inline(never)]
+pub fn bench_sum_array_indirect_prefetch<const N: usize, const M: usize, const P: usize>(
+    array: &[u8; N],
+    indices: CheckedIndices<N, M>,
+) -> u8 {
+    let indices = indices.0;
+    let mut sum: u64 = 0;
+
+    let mut i = 0;
+    while i < M {
+        if i + P * 64 < M {
+            prefetch_t0(array.as_ptr().wrapping_add(indices[i + P * 64]));
+        }
+        let index = indices[i];
+        debug_assert!(index < N);
+        // SAFETY: CheckedIndices::new checked that every index is below N.
+        sum += unsafe { *array.get_unchecked(index) } as u64;
+        i += 64;
+    }
+
+    sum as u8
+}
+
+/// How many indices ahead [`bench_gather`] prefetches with PREFETCH.
+pub const GATHER_PREFETCH_DISTANCE: usize = 16;
+
+// Sums the elements of `array` at every index of `indices`, like a sparse
+// matrix or a graph kernel gathers values. How often the loads miss depends
+// on how close consecutive indices are, see clustered_indices. With
+// PREFETCH, each iteration also prefetches the element
+// GATHER_PREFETCH_DISTANCE indices ahead, which the hardware prefetcher
+// can't predict unless the indices are sequential.
+#[inline(never)]
+pub fn bench_gather<const PREFETCH: bool>(array: &[u8], indices: &[usize]) -> u64 {
+    let mut sum: u64 = 0;
+    for (i, &index) in indices.iter().enumerate() {
+        if PREFETCH {
+            if let Some(&ahead) = indices.get(i + GATHER_PREFETCH_DISTANCE) {
+                prefetch_t0(array.as_ptr().wrapping_add(ahead));
+            }
+        }
+        sum += array[index] as u64;
+    }
+
+    sum
+}
+
+/// Run `main_side` on the current thread pinned to `cpus[0]`, and
+/// `other_side` on a new thread pinned to `cpus[1]`, like [`on_cpus`].
+fn on_two_cpus(
+    cpus: [usize; 2],
+    main_side: impl Fn() + Sync,
+    other_side: impl Fn() + Sync,
+) -> std::io::Result<()> {
+    on_cpus(&cpus, |index| {
+        if index == 0 {
+            main_side()
+        } else {
+            other_side()
+        }
+    })
+}
+
+// Two threads, pinned to `cpus`, take turns writing the same cache line
+// `rounds` times each. Every write has to take the line away from the other
+// core's cache, so each round costs two ownership transfers, which is about as
+// bad as contention gets.
+// Only the current thread is counted, but it spends the whole run either
+// writing or waiting for the line to come back.
+#[inline(never)]
+pub fn bench_cache_pingpong(rounds: u64, cpus: [usize; 2]) -> std::io::Result<u64> {
+    let turn = CacheAligned(AtomicU64::new(0));
+
+    on_two_cpus(
+        cpus,
+        || {
+            for round in 0..rounds {
+                turn.0.store(2 * round + 1, Ordering::Release);
+                while turn.0.load(Ordering::Acquire) != 2 * round + 2 {
+                    spin_loop();
+                }
+            }
+        },
+        || {
+            for round in 0..rounds {
+                while turn.0.load(Ordering::Acquire) != 2 * round + 1 {
+                    spin_loop();
+                }
+                turn.0.store(2 * round + 2, Ordering::Release);
+            }
+        },
+    )?;
+
+    Ok(turn.0.into_inner())
+}
+
+// Two threads, pinned to `cpus`, each increment their own counter `rounds`
+// times, without ever waiting for each other. With SAME_LINE, the counters
+// share a cache line, which bounces between the cores as in
+// bench_cache_pingpong even though no data is shared ("false sharing").
+// Otherwise each counter has its own line, which stays in its core's cache.
+#[inline(never)]
+pub fn bench_false_sharing<const SAME_LINE: bool>(
+    rounds: u64,
+    cpus: [usize; 2],
+) -> std::io::Result<u64> {
+    let counters = CacheAligned([const { AtomicU64::new(0) }; 16]);
+    let (main_counter, other_counter) = if SAME_LINE {
+        (&counters.0[0], &counters.0[1])
+    } else {
+        (&counters.0[0], &counters.0[8])
+    };
+
+    let increment = |counter: &AtomicU64| {
+        for _ in 0..rounds {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+    };
+    on_two_cpus(
+        cpus,
+        || increment(main_counter),
+        || increment(other_counter),
+    )?;
+
+    Ok(main_counter.load(Ordering::Relaxed) + other_counter.load(Ordering::Relaxed))
+}
+
+// Sums the bytes of `array` that are at least 128, with a branch on each
+// byte. On random bytes, the branch is unpredictable, and about half of the
+// iterations are mispredicted; the work issued down the wrong path is thrown
+// away. On sorted bytes, the predictor gets every branch but one right.
+// The black_box keeps the compiler from turning the branch into a cmov.
+#[inline(never)]
+pub fn bench_branchy(array: &[u8]) -> u64 {
+    let mut sum: u64 = 0;
+    for &byte in array {
+        if byte >= 128 {
+            sum += black_box(byte as u64);
+        }
+    }
+    sum
+}
+
+/// Run `side(0)` on the current thread pinned to `cpus[0]`, and `side(i)` on
+/// a new thread pinned to `cpus[i]` for every other CPU, then restore the
+/// current thread's affinity.
+///
+/// A thread that can't be pinned still runs its side, so that sides waiting
+/// on each other don't deadlock, and the error is returned once they're all
+/// done.
+fn on_cpus(cpus: &[usize], side: impl Fn(usize) + Sync) -> std::io::Result<()> {
+    let affinity = Affinity::current()?;
+    Affinity::only(cpus[0]).apply()?;
+    let pinned = std::thread::scope(|scope| {
+        let threads: Vec<_> = cpus
+            .iter()
+            .enumerate()
+            .skip(1)
+            .map(|(index, &cpu)| {
+                let side = &side;
+                scope.spawn(move || {
+                    let pinned = Affinity::only(cpu).apply();
+                    side(index);
+                    pinned
+                })
+            })
+            .collect();
+        side(0);
+        threads
+            .into_iter()
+            .try_for_each(|thread| thread.join().unwrap())
+    });
+    affinity.apply()?;
+    pinned
+}
+
+// One thread per CPU in `cpus`, each pinned, does `rounds` atomic increments.
+// With SHARED, they all increment the same counter, so every increment has to
+// win ownership of its line and lock it for the whole read-modify-write,
+// which gets slower the more cores compete for it. Otherwise each thread has
+// its own line, and throughput should scale with the number of threads.
+// Unlike bench_false_sharing, the contention here is real, and the locked
+// RMW is what it costs, not just the line transfers.
+#[inline(never)]
+pub fn bench_atomic_contention<const SHARED: bool>(
+    rounds: u64,
+    cpus: &[usize],
+) -> std::io::Result<u64> {
+    let counters: Vec<CacheAligned<AtomicU64>> = (0..cpus.len())
+        .map(|_| CacheAligned(AtomicU64::new(0)))
+        .collect();
+
+    on_cpus(cpus, |index| {
+        let counter = if SHARED {
+            &counters[0].0
+        } else {
+            &counters[index].0
+        };
+        for _ in 0..rounds {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+    })?;
+
+    Ok(counters
+        .iter()
+        .map(|counter| counter.0.load(Ordering::Relaxed))
+        .sum())
+}
+
+/// One node of the chain walked by [`bench_dependent_prefetch`], padded to a
+/// cache line so that every hop touches a new line.
+#[repr(C, align(64))]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Node {
+    pub next: usize,
+    pub value: u64,
+}
+
+// Follows the chain of nodes starting at nodes[0] for `hops` hops, doing some
+// work on each node's value. With PREFETCH, the next node is prefetched as
+// soon as its index is loaded, before the work on the current node, like a
+// hash table or graph traversal would do. This only helps if the work takes
+// long enough to hide part of the miss; compare the L1D misses and cycles per
+// iteration of both variants.
+#[inline(never)]
+pub fn bench_dependent_prefetch<const N: usize, const PREFETCH: bool>(
+    nodes: &[Node; N],
+    hops: usize,
+) -> u64 {
+    const WORK: usize = 32;
+    let mut sum: u64 = 0;
+
+    let mut current = 0;
+    for _ in 0..hops {
+        let node = &nodes[current];
+        if PREFETCH {
+            prefetch_t0(nodes.as_ptr().wrapping_add(node.next).cast());
+        }
+        for _ in 0..WORK {
+            sum += node.value;
+            clobber_rw(&mut sum);
+        }
+        current = node.next;
+    }
+
+    sum
+}
+
+// Follows the chain of nodes starting at nodes[0] for `hops` hops, and does
+// nothing else, so that each hop costs exactly the latency of a load from
+// whichever level of the hierarchy the chain fits in.
+#[inline(never)]
+pub fn bench_pointer_chase<const N: usize>(nodes: &[Node; N], hops: usize) -> usize {
+    let mut current = 0;
+    for _ in 0..hops {
+        current = nodes[current].next;
+    }
+
+    current
+}
+
+// Same as bench_pointer_chase, with a second cursor running P hops ahead of
+// the first one. The second cursor reads each next index speculatively and
+// prefetches the node it points to, so the first cursor should find its
+// nodes in the L1. The second cursor's loads are still dependent on each
+// other, so this only shows how much of the latency prefetching can hide
+// when the addresses can't be computed any sooner than by chasing them.
+#[inline(never)]
+pub fn bench_pointer_chase_lookahead<const N: usize, const P: usize>(
+    nodes: &[Node; N],
+    hops: usize,
+) -> usize {
+    let mut ahead = 0;
+    for _ in 0..P.min(hops) {
+        ahead = nodes[ahead].next;
+    }
+
+    let mut current = 0;
+    for _ in 0..hops {
+        let next_ahead = nodes[ahead].next;
+        prefetch_t0(nodes.as_ptr().wrapping_add(next_ahead).cast());
+        ahead = next_ahead;
+        current = nodes[current].next;
+    }
+
+    current
+}
+
+// Same as bench_pointer_chase, on a chain whose length is only known at
+// runtime, so that it can be sized after the caches the topology reports.
+// Each load's address is the result of the previous load, so the cycles per
+// load are the load-to-use latency of whichever level holds `nodes`.
+#[inline(never)]
+pub fn bench_load_to_use(nodes: &[Node], loads: usize) -> usize {
+    let mut current = 0;
+    for _ in 0..loads {
+        current = nodes[current].next;
+    }
+
+    current
+}
+
+// Walks the chain of nodes in blocks of `distance` hops, and walks each block
+// twice in a row, for `blocks` blocks. Between the two loads of a node, the
+// other distance - 1 nodes of its block are loaded, so that's its reuse
+// distance: the second load hits in any level that holds that many lines.
+// The hops are dependent, so the cycles per load are the average latency of
+// the first loads, which miss, and of the reloads.
+#[inline(never)]
+pub fn bench_reuse_distance(nodes: &[Node], distance: usize, blocks: usize) -> usize {
+    let mut start = 0;
+    for _ in 0..blocks {
+        let mut current = start;
+        for _ in 0..distance {
+            current = nodes[current].next;
+        }
+        black_box(current);
+
+        let mut reused = start;
+        for _ in 0..distance {
+            reused = nodes[reused].next;
+        }
+        start = black_box(reused);
+    }
+
+    start
+}
+
+// Follows K chains through `nodes` at once, starting at `starts`, each for
+// `hops` hops. The chains don't depend on each other, so the core can have
+// up to K misses in flight, until it runs out of line fill buffers: past that
+// point, adding chains stops making each access cheaper. That's the core's
+// memory-level parallelism.
+#[inline(never)]
+pub fn bench_mlp<const N: usize, const K: usize>(
+    nodes: &[Node; N],
+    starts: [usize; K],
+    hops: usize,
+) -> [usize; K] {
+    let mut current = starts;
+    for _ in 0..hops {
+        for cursor in current.iter_mut() {
+            *cursor = nodes[*cursor].next;
+        }
+    }
+
+    current
+}
+
+/// K nodes evenly spaced along the cycle `nodes` form, starting with node 0,
+/// so that [`bench_mlp`]'s chains don't catch up with each other.
+pub fn chain_starts<const N: usize, const K: usize>(nodes: &[Node; N]) -> [usize; K] {
+    let mut order = Vec::with_capacity(N);
+    let mut current = 0;
+    for _ in 0..N {
+        order.push(current);
+        current = nodes[current].next;
+    }
+    std::array::from_fn(|chain| order[chain * N / K])
+}
+
+/// The seed the suite generates its random inputs with, so that every run
+/// benchmarks the same access patterns.
+pub const SEED: u64 = 0x5eed;
+
+/// Shuffle `slice` uniformly at random, in the same order every time for a
+/// given `seed`.
+pub fn shuffle_in_place<T>(slice: &mut [T], seed: u64) {
+    slice.shuffle(&mut StdRng::seed_from_u64(seed));
+}
+
+/// Every index from 0 to `n` excluded, exactly once, in a random order that
+/// only depends on `seed`.
+pub fn random_permutation(n: usize, seed: u64) -> Vec<usize> {
+    let mut permutation: Vec<usize> = (0..n).collect();
+    shuffle_in_place(&mut permutation, seed);
+    permutation
+}
+
+/// Generate `N` nodes, each with a value of 1, that form a single randomly
+/// ordered cycle, for use with [`bench_pointer_chase`] and
+/// [`bench_dependent_prefetch`].
+pub fn random_chain<const N: usize>(seed: u64) -> Box<[Node; N]> {
+    random_chain_of(N, seed)
+        .into_boxed_slice()
+        .try_into()
+        .unwrap()
+}
+
+/// Same as [`random_chain`], with a length only known at runtime, for use
+/// with [`bench_load_to_use`].
+pub fn random_chain_of(len: usize, seed: u64) -> Vec<Node> {
+    // Visiting the nodes in a random order, and linking each to the next one,
+    // gives a cycle through all of them that the hardware prefetcher can't
+    // predict.
+    let order = random_permutation(len, seed);
+
+    let mut nodes = vec![Node { next: 0, value: 1 }; len];
+    for (i, &node) in order.iter().enumerate() {
+        nodes[node].next = order[(i + 1) % len];
+    }
+    nodes
+}
+
+/// Generate `len` random bytes from `seed`, for use with [`bench_branchy`].
+pub fn random_bytes(len: usize, seed: u64) -> Vec<u8> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    (0..len).map(|_| rng.gen()).collect()
+}
+
+/// Generate `M` random indices into an array of length `len` from `seed`, for
+/// use with [`bench_sum_array_indirect`].
+pub fn random_indices<const M: usize>(len: usize, seed: u64) -> Box<[usize; M]> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    (0..M)
+        .map(|_| rng.gen_range(0..len))
+        .collect::<Vec<_>>()
+        .into_boxed_slice()
+        .try_into()
+        .unwrap()
+}
+
+/// Generate `count` indices into an array of length `len`, for use with
+/// [`bench_gather`], with a locality set by `window`: each index is drawn
+/// uniformly from a window of `window` elements, which slides from the start
+/// to the end of the array as the indices go. A window of 1 gives sequential
+/// indices, a window of `len` uniformly random ones, and anything in between
+/// clusters of nearby indices, like the neighbors of a node in a graph with
+/// some locality. An empty array has no indices to give, so `len == 0`
+/// gives none.
+pub fn clustered_indices(len: usize, count: usize, window: usize, seed: u64) -> Vec<usize> {
+    if len == 0 {
+        return Vec::new();
+    }
+    let window = window.clamp(1, len);
+    let mut rng = StdRng::seed_from_u64(seed);
+    (0..count)
+        .map(|i| {
+            let base = i * (len - window) / count.max(1);
+            base + rng.gen_range(0..window)
+        })
+        .collect()
+}
+
+/// Write `indices` to `path`, as a little-endian u64 count followed by one
+/// little-endian u64 per index, so that [`read_indices`] can replay the same
+/// access pattern in a later run, or on another machine.
+pub fn write_indices(path: &Path, indices: &[usize]) -> std::io::Result<()> {
+    let mut bytes = Vec::with_capacity((indices.len() + 1) * 8);
+    bytes.extend_from_slice(&(indices.len() as u64).to_le_bytes());
+    for &index in indices {
+        bytes.extend_from_slice(&(index as u64).to_le_bytes());
+    }
+    std::fs::File::create(path)?.write_all(&bytes)
+}
+
+/// Read `M` indices written by [`write_indices`], checking that they're in
+/// bounds of an array of length `len`.
+pub fn read_indices<const M: usize>(path: &Path, len: usize) -> std::io::Result<Box<[usize; M]>> {
+    let invalid = |message: String| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), message),
+        )
+    };
+
+    let mut bytes = Vec::new();
+    std::fs::File::open(path)?.read_to_end(&mut bytes)?;
+    let mut words = bytes
+        .chunks(8)
+        .map(|chunk| Some(u64::from_le_bytes(chunk.try_into().ok()?) as usize));
+
+    let count = words.next().flatten();
+    if count != Some(M) {
+        return Err(invalid(format!(
+            "expected {} indices, found {:?}",
+            M, count
+        )));
+    }
+    let indices: Vec<usize> = words
+        .map(|index| index.ok_or_else(|| invalid("truncated index".to_string())))
+        .collect::<std::io::Result<_>>()?;
+    if let Some(&index) = indices.iter().find(|&&index| index >= len) {
+        return Err(invalid(format!(
+            "index {} is out of bounds of {}",
+            index, len
+        )));
+    }
+
+    indices
+        .into_boxed_slice()
+        .try_into()
+        .map_err(|indices: Box<[usize]>| {
+            invalid(format!("expected {} indices, found {}", M, indices.len()))
+        })
+}
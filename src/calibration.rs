@@ -0,0 +1,366 @@
+use std::hint::black_box;
+
+#[cfg(target_arch = "x86_64")]
+use crate::benchmarks::{bench_flush_lines, has_clflushopt};
+use crate::benchmarks::{
+    bench_load_lines, bench_prefetch_overhead, bench_ruler_black_box, bench_ruler_clobber_read,
+    bench_ruler_clobber_rw, bench_ruler_empty, bench_sum_of_array_with_stride,
+    bench_sum_of_array_with_stride_prefetch, CacheAligned,
+};
+use crate::runner::{measure, OutputFormat};
+
+/// Measure how many cycles a prefetch instruction costs when the line it
+/// targets is already in L1, by comparing a strided sum over a small,
+/// resident `array` with and without a prefetch per access.
+///
+/// Returns the extra cycles per prefetch, which can be subtracted from the
+/// results of the prefetching benchmarks.
+pub fn prefetch_overhead<const N: usize>(
+    array: &[u8; N],
+    stride: usize,
+    repeats: usize,
+) -> std::io::Result<f64> {
+    // Make sure the array is resident before measuring anything.
+    black_box(bench_sum_of_array_with_stride(array, stride));
+
+    let without_prefetch = measure(|| {
+        for _ in 0..repeats {
+            black_box(bench_sum_of_array_with_stride(array, stride));
+        }
+    })?;
+    let with_prefetch = measure(|| {
+        for _ in 0..repeats {
+            black_box(bench_prefetch_overhead(array, stride));
+        }
+    })?;
+
+    let accesses = (N.div_ceil(stride) * repeats) as f64;
+    let cycles_without = without_prefetch.cycles as f64 / accesses;
+    let cycles_with = with_prefetch.cycles as f64 / accesses;
+    let overhead = cycles_with - cycles_without;
+
+    if OutputFormat::from_args()? != OutputFormat::Table {
+        return Ok(overhead);
+    }
+    println!("====================================================================");
+    println!("Prefetch overhead on a resident {} byte buffer", N);
+    println!("Cycles per access without prefetch: {:.3}", cycles_without);
+    println!("Cycles per access with prefetch: {:.3}", cycles_with);
+    println!(
+        "Instructions per prefetch: {:.3}",
+        (with_prefetch.instructions as f64 - without_prefetch.instructions as f64) / accesses
+    );
+    println!("Cycles per prefetch: {:.3}", overhead);
+    println!();
+
+    Ok(overhead)
+}
+
+/// The per-access counts of a strided sum with and without a software
+/// prefetch per access, measured by [`software_prefetch_benefit`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PrefetchBenefit {
+    pub cycles_without: f64,
+    pub cycles_with: f64,
+    pub instructions_without: f64,
+    pub instructions_with: f64,
+    pub l1_misses_without: f64,
+    pub l1_misses_with: f64,
+}
+
+impl PrefetchBenefit {
+    /// The L1D misses each software prefetch avoided. Near 1 when every
+    /// prefetch brings in a line the loop would have missed on, near 0 when
+    /// the hardware prefetcher already got there, so the prefetches are pure
+    /// overhead.
+    pub fn efficiency(&self) -> f64 {
+        self.l1_misses_without - self.l1_misses_with
+    }
+
+    /// Whether the prefetches made the loop faster at all.
+    pub fn helps(&self) -> bool {
+        self.cycles_with < self.cycles_without
+    }
+}
+
+/// Compare a strided sum over `array` without software prefetches, so that
+/// only the hardware prefetcher helps, with the same sum prefetching `P`
+/// accesses ahead, and print whether the prefetches were worth their
+/// instructions.
+///
+/// With a stride of 1, the hardware prefetcher already hides most misses,
+/// so software prefetches should only add instructions: this is the case
+/// where you shouldn't prefetch.
+pub fn software_prefetch_benefit<const N: usize, const P: usize>(
+    array: &[u8; N],
+    stride: usize,
+    repeats: usize,
+) -> std::io::Result<PrefetchBenefit> {
+    let without_prefetch = measure(|| {
+        for _ in 0..repeats {
+            black_box(bench_sum_of_array_with_stride(array, stride));
+        }
+    })?;
+    let with_prefetch = measure(|| {
+        for _ in 0..repeats {
+            black_box(bench_sum_of_array_with_stride_prefetch::<N, P>(
+                array, stride,
+            ));
+        }
+    })?;
+
+    let accesses = (N.div_ceil(stride) * repeats) as f64;
+    let benefit = PrefetchBenefit {
+        cycles_without: without_prefetch.cycles as f64 / accesses,
+        cycles_with: with_prefetch.cycles as f64 / accesses,
+        instructions_without: without_prefetch.instructions as f64 / accesses,
+        instructions_with: with_prefetch.instructions as f64 / accesses,
+        l1_misses_without: without_prefetch.l1_cache_misses as f64 / accesses,
+        l1_misses_with: with_prefetch.l1_cache_misses as f64 / accesses,
+    };
+
+    if OutputFormat::from_args()? != OutputFormat::Table {
+        return Ok(benefit);
+    }
+    println!("====================================================================");
+    println!(
+        "Software prefetch benefit over {} bytes, stride {}, {} accesses ahead",
+        N, stride, P
+    );
+    println!(
+        "{:<24} {:>16} {:>16} {:>16}",
+        "", "cycles/access", "instr/access", "L1D miss/access"
+    );
+    println!(
+        "{:<24} {:>16.3} {:>16.3} {:>16.4}",
+        "hardware prefetch only",
+        benefit.cycles_without,
+        benefit.instructions_without,
+        benefit.l1_misses_without
+    );
+    println!(
+        "{:<24} {:>16.3} {:>16.3} {:>16.4}",
+        "software prefetch", benefit.cycles_with, benefit.instructions_with, benefit.l1_misses_with
+    );
+    println!(
+        "L1D misses avoided per prefetch: {:.4}",
+        benefit.efficiency()
+    );
+    println!(
+        "Software prefetch {}",
+        if benefit.helps() {
+            "helps"
+        } else {
+            "hurts: the hardware prefetcher already does the job"
+        }
+    );
+    println!();
+
+    Ok(benefit)
+}
+
+/// What one invocation of a benchmarking helper costs, measured by
+/// [`ruler`].
+#[derive(Clone, Debug)]
+pub struct HelperCost {
+    pub name: &'static str,
+    pub cycles: f64,
+    pub instructions: f64,
+}
+
+/// Measure how many cycles and instructions one invocation of `black_box`,
+/// [`clobber_read`](crate::clobber::clobber_read) and
+/// [`clobber_rw`](crate::clobber::clobber_rw) adds to a loop, on this machine,
+/// and print them as a table.
+///
+/// The benchmarks use these helpers to keep the compiler from optimizing
+/// their loops away, so their counts include this overhead.
+pub fn ruler(repeats: usize) -> std::io::Result<Vec<HelperCost>> {
+    const N: usize = 1000;
+
+    let empty = measure(|| {
+        for _ in 0..repeats {
+            black_box(bench_ruler_empty::<N>());
+        }
+    })?;
+    let helpers = [
+        ("black_box", bench_ruler_black_box::<N> as fn() -> u64),
+        ("clobber_read", bench_ruler_clobber_read::<N>),
+        ("clobber_rw", bench_ruler_clobber_rw::<N>),
+    ];
+
+    let invocations = (N * repeats) as f64;
+    let mut costs = Vec::new();
+    for (name, bench) in helpers {
+        let counts = measure(|| {
+            for _ in 0..repeats {
+                black_box(bench());
+            }
+        })?;
+        costs.push(HelperCost {
+            name,
+            cycles: (counts.cycles as f64 - empty.cycles as f64) / invocations,
+            instructions: (counts.instructions as f64 - empty.instructions as f64) / invocations,
+        });
+    }
+
+    if OutputFormat::from_args()? != OutputFormat::Table {
+        return Ok(costs);
+    }
+    println!("====================================================================");
+    println!("Cost of the benchmarking helpers, per invocation");
+    println!("{:<20} {:>12} {:>14}", "helper", "cycles", "instructions");
+    for cost in &costs {
+        println!(
+            "{:<20} {:>12.3} {:>14.3}",
+            cost.name, cost.cycles, cost.instructions
+        );
+    }
+    println!();
+
+    Ok(costs)
+}
+
+/// What flushing a resident line costs, and what reloading it costs
+/// afterwards, measured by [`flush_latency`].
+#[derive(Clone, Debug)]
+pub struct FlushCost {
+    /// The flush instruction.
+    pub name: &'static str,
+    pub flush_cycles: f64,
+    pub reload_cycles: f64,
+}
+
+/// Measure how many cycles flushing a resident cache line takes, with
+/// `clflush` and, if the CPU has it, `clflushopt`, and how many cycles
+/// reloading it then takes, compared with reloading a line that is still
+/// resident. Print them as a table.
+///
+/// Benchmarks that flush their buffer to start from a cold cache pay the
+/// flush on top of the misses, so this tells the two apart.
+#[cfg(target_arch = "x86_64")]
+pub fn flush_latency<const N: usize>(
+    buffer: &CacheAligned<[u8; N]>,
+    repeats: usize,
+) -> std::io::Result<Vec<FlushCost>> {
+    let mut flushes = vec![("clflush", bench_flush_lines::<N, false> as fn(&_))];
+    if has_clflushopt() {
+        flushes.push(("clflushopt", bench_flush_lines::<N, true>));
+    }
+
+    // Every phase is measured on its own, so that the flush and the reload
+    // can be told apart.
+    let mut resident_cycles = 0;
+    let mut costs = Vec::new();
+    for (name, flush) in flushes {
+        let mut flush_cycles = 0;
+        let mut reload_cycles = 0;
+        for _ in 0..repeats {
+            black_box(bench_load_lines(buffer));
+            resident_cycles += measure(|| {
+                black_box(bench_load_lines(buffer));
+            })?
+            .cycles;
+            flush_cycles += measure(|| flush(buffer))?.cycles;
+            reload_cycles += measure(|| {
+                black_box(bench_load_lines(buffer));
+            })?
+            .cycles;
+        }
+
+        let lines = (N.div_ceil(64) * repeats) as f64;
+        costs.push(FlushCost {
+            name,
+            flush_cycles: flush_cycles as f64 / lines,
+            reload_cycles: reload_cycles as f64 / lines,
+        });
+    }
+
+    let resident_lines = (N.div_ceil(64) * repeats * costs.len()) as f64;
+    if OutputFormat::from_args()? != OutputFormat::Table {
+        return Ok(costs);
+    }
+    println!("====================================================================");
+    println!("Cost of flushing a resident {} byte buffer, per line", N);
+    println!(
+        "Cycles per load of a resident line: {:.3}",
+        resident_cycles as f64 / resident_lines
+    );
+    println!("{:<20} {:>12} {:>14}", "flush", "cycles", "reload cycles");
+    for cost in &costs {
+        println!(
+            "{:<20} {:>12.3} {:>14.3}",
+            cost.name, cost.flush_cycles, cost.reload_cycles
+        );
+    }
+    println!();
+
+    Ok(costs)
+}
+
+/// How many instructions a benchmark retires with and without `black_box`
+/// around its result, measured by [`dead_code_elimination`].
+#[derive(Clone, Debug)]
+pub struct GuardEffect {
+    pub guarded_instructions: f64,
+    pub unguarded_instructions: f64,
+}
+
+impl GuardEffect {
+    /// The fraction of the guarded instructions the compiler eliminated
+    /// without the guard, between 0 and 1.
+    pub fn eliminated(&self) -> f64 {
+        1.0 - self.unguarded_instructions / self.guarded_instructions
+    }
+}
+
+/// Measure `bench_sum_of_array_with_stride` over `array`, with its result
+/// passed to `black_box` and with its result discarded, and print how many
+/// instructions per access each retired.
+///
+/// The benchmark isn't inlined, but the compiler can still see that it has
+/// no side effects and drop calls whose result is unused. If the unguarded
+/// version retires (almost) nothing, the guard is what keeps the benchmarks
+/// honest on this compiler.
+pub fn dead_code_elimination<const N: usize>(
+    array: &[u8; N],
+    repeats: usize,
+) -> std::io::Result<GuardEffect> {
+    let guarded = measure(|| {
+        for _ in 0..repeats {
+            black_box(bench_sum_of_array_with_stride(array, 1));
+        }
+    })?;
+    let unguarded = measure(|| {
+        for _ in 0..repeats {
+            bench_sum_of_array_with_stride(array, 1);
+        }
+    })?;
+
+    let accesses = (N * repeats) as f64;
+    let effect = GuardEffect {
+        guarded_instructions: guarded.instructions as f64 / accesses,
+        unguarded_instructions: unguarded.instructions as f64 / accesses,
+    };
+
+    if OutputFormat::from_args()? != OutputFormat::Table {
+        return Ok(effect);
+    }
+    println!("====================================================================");
+    println!("Dead code elimination of a {} byte sum", N);
+    println!(
+        "Instructions per access with black_box: {:.3}",
+        effect.guarded_instructions
+    );
+    println!(
+        "Instructions per access without black_box: {:.3}",
+        effect.unguarded_instructions
+    );
+    println!(
+        "Eliminated without the guard: {:.1}%",
+        effect.eliminated() * 100.0
+    );
+    println!();
+
+    Ok(effect)
+}
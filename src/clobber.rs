@@ -0,0 +1,94 @@
+//! Safe ways to keep the compiler from optimizing a value away, without
+//! changing the generated code around it.
+//!
+//! On x86_64 and aarch64, these are empty `asm!` blocks that claim to use the
+//! value in a register, so they emit no instructions. Elsewhere, they fall
+//! back to [`std::hint::black_box`], which may spill the value to the stack.
+
+/// A type that fits in a general-purpose register.
+pub trait Register: Copy + private::Sealed {}
+
+mod private {
+    pub trait Sealed {
+        fn clobber_read(&self);
+        fn clobber_rw(&mut self);
+    }
+}
+
+macro_rules! impl_register {
+    ($($ty:ty),*) => {
+        $(
+            impl private::Sealed for $ty {
+                #[cfg(target_arch = "x86_64")]
+                #[inline(always)]
+                fn clobber_read(&self) {
+                    // SAFETY: the asm is empty, and only claims to read a
+                    // register.
+                    unsafe {
+                        std::arch::asm!("/* {:r} */", in(reg) *self);
+                    }
+                }
+
+                #[cfg(target_arch = "aarch64")]
+                #[inline(always)]
+                fn clobber_read(&self) {
+                    // SAFETY: the asm is empty, and only claims to read a
+                    // register.
+                    unsafe {
+                        std::arch::asm!("/* {:x} */", in(reg) *self);
+                    }
+                }
+
+                #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+                #[inline(always)]
+                fn clobber_read(&self) {
+                    std::hint::black_box(*self);
+                }
+
+                #[cfg(target_arch = "x86_64")]
+                #[inline(always)]
+                fn clobber_rw(&mut self) {
+                    // SAFETY: the asm is empty, so the register keeps its
+                    // value, which is valid for the type.
+                    unsafe {
+                        std::arch::asm!("/* {:r} */", inout(reg) *self);
+                    }
+                }
+
+                #[cfg(target_arch = "aarch64")]
+                #[inline(always)]
+                fn clobber_rw(&mut self) {
+                    // SAFETY: the asm is empty, so the register keeps its
+                    // value, which is valid for the type.
+                    unsafe {
+                        std::arch::asm!("/* {:x} */", inout(reg) *self);
+                    }
+                }
+
+                #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+                #[inline(always)]
+                fn clobber_rw(&mut self) {
+                    *self = std::hint::black_box(*self);
+                }
+            }
+
+            impl Register for $ty {}
+        )*
+    };
+}
+
+impl_register!(u16, u32, u64, usize, i16, i32, i64, isize);
+
+/// Pretend an instruction reads `value`, so it has to be computed, but
+/// without changing it.
+#[inline(always)]
+pub fn clobber_read<T: Register>(value: &T) {
+    value.clobber_read();
+}
+
+/// Pretend an instruction reads and overwrites `value`, so it has to be
+/// computed, and the compiler can't assume anything about it afterwards.
+#[inline(always)]
+pub fn clobber_rw<T: Register>(value: &mut T) {
+    value.clobber_rw();
+}
@@ -0,0 +1,156 @@
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::affinity::current_cpu;
+use crate::frequency::CPUFREQ_ROOT;
+use crate::runner::flag_passed;
+
+/// Where Linux exposes the temperature and trip points of each thermal zone.
+pub const THERMAL_ROOT: &str = "/sys/class/thermal";
+
+/// How close to its throttle point, in degrees Celsius, a thermal zone can
+/// get by the end of a benchmark before we warn that it may have been
+/// throttled.
+pub const THROTTLE_MARGIN_C: f64 = 5.0;
+
+/// How much the frequency can drop during a benchmark, as a fraction of the
+/// frequency at the start, before we warn that it may have been throttled.
+pub const FREQUENCY_DROP: f64 = 0.1;
+
+/// The thermal state and frequency of the machine at one point in time.
+/// Each field is `None` if sysfs doesn't expose it, eg in most VMs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct Environment {
+    /// The temperature of the hottest thermal zone, in degrees Celsius.
+    pub temperature_c: Option<f64>,
+    /// How far below its lowest passive or critical trip point the zone
+    /// closest to one is, in degrees Celsius: past it, the firmware or the
+    /// kernel starts throttling. Each zone is compared to its own trip
+    /// points, since other zones, like a battery's, may have much lower ones.
+    pub headroom_c: Option<f64>,
+    /// The current frequency of the CPU we run on, in kHz.
+    pub frequency_khz: Option<u64>,
+}
+
+impl Environment {
+    /// Read the thermal zones under `thermal_root`, and the frequency of
+    /// `cpu` under `cpufreq_root`.
+    pub fn read(thermal_root: &Path, cpufreq_root: &Path, cpu: usize) -> Environment {
+        let mut environment = Environment {
+            frequency_khz: read_number(
+                &cpufreq_root.join(format!("cpu{}/cpufreq/scaling_cur_freq", cpu)),
+            )
+            .map(|khz| khz as u64),
+            ..Environment::default()
+        };
+
+        let Ok(entries) = std::fs::read_dir(thermal_root) else {
+            return environment;
+        };
+        for entry in entries.flatten() {
+            let zone = entry.path();
+            if !entry
+                .file_name()
+                .to_string_lossy()
+                .starts_with("thermal_zone")
+            {
+                continue;
+            }
+            // Temperatures are in millidegrees.
+            let temperature = read_number(&zone.join("temp")).map(|temp| temp / 1000.0);
+            if let Some(temp) = temperature {
+                environment.temperature_c =
+                    Some(environment.temperature_c.map_or(temp, |t| t.max(temp)));
+            }
+            let mut throttle: Option<f64> = None;
+            for trip in 0.. {
+                let Ok(kind) =
+                    std::fs::read_to_string(zone.join(format!("trip_point_{}_type", trip)))
+                else {
+                    break;
+                };
+                if !matches!(kind.trim(), "passive" | "critical") {
+                    continue;
+                }
+                if let Some(temp) = read_number(&zone.join(format!("trip_point_{}_temp", trip))) {
+                    let temp = temp / 1000.0;
+                    throttle = Some(throttle.map_or(temp, |t| t.min(temp)));
+                }
+            }
+            if let (Some(temperature), Some(throttle)) = (temperature, throttle) {
+                let headroom = throttle - temperature;
+                environment.headroom_c =
+                    Some(environment.headroom_c.map_or(headroom, |h| h.min(headroom)));
+            }
+        }
+        environment
+    }
+
+    /// Read the environment of the CPU we're running on.
+    pub fn current() -> std::io::Result<Environment> {
+        Ok(Environment::read(
+            Path::new(THERMAL_ROOT),
+            Path::new(CPUFREQ_ROOT),
+            current_cpu()?,
+        ))
+    }
+
+    /// Whether `--environment` was passed, to record the environment around
+    /// each benchmark.
+    pub fn requested() -> bool {
+        flag_passed("--environment")
+    }
+}
+
+/// The environment at the start and at the end of one benchmark.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct EnvironmentChange {
+    pub start: Environment,
+    pub end: Environment,
+}
+
+impl EnvironmentChange {
+    /// Why the benchmark may have been throttled, if a thermal zone ended
+    /// within [`THROTTLE_MARGIN_C`] of its throttle point, or the frequency
+    /// dropped by more than [`FREQUENCY_DROP`].
+    pub fn throttle_warning(&self) -> Option<String> {
+        if let Some(headroom) = self.end.headroom_c {
+            if headroom <= THROTTLE_MARGIN_C {
+                return Some(format!(
+                    "ended {:.1}°C away from a throttle point, results may be throttle-affected",
+                    headroom
+                ));
+            }
+        }
+        if let (Some(start), Some(end)) = (self.start.frequency_khz, self.end.frequency_khz) {
+            if (end as f64) < start as f64 * (1.0 - FREQUENCY_DROP) {
+                return Some(format!(
+                    "frequency dropped from {} MHz to {} MHz, results may be throttle-affected",
+                    start / 1000,
+                    end / 1000
+                ));
+            }
+        }
+        None
+    }
+
+    /// Print the temperature and frequency at the start and at the end, for
+    /// whichever sysfs exposes.
+    pub fn print(&self) {
+        if let (Some(start), Some(end)) = (self.start.temperature_c, self.end.temperature_c) {
+            print!("Temperature: {:.1}°C -> {:.1}°C", start, end);
+            if let Some(headroom) = self.end.headroom_c {
+                print!(" ({:.1}°C from throttling)", headroom);
+            }
+            println!();
+        }
+        if let (Some(start), Some(end)) = (self.start.frequency_khz, self.end.frequency_khz) {
+            println!("Frequency: {} MHz -> {} MHz", start / 1000, end / 1000);
+        }
+    }
+}
+
+fn read_number(path: &Path) -> Option<f64> {
+    std::fs::read_to_string(path).ok()?.trim().parse().ok()
+}
@@ -0,0 +1,138 @@
+use std::fmt;
+
+/// Why a benchmark couldn't be measured.
+///
+/// It converts to and from [`std::io::Error`] without losing the variant, so
+/// that `?` works both ways: the `io::Error` made from a `BenchError` carries
+/// it as its inner error, and converting back unwraps it.
+#[derive(Debug)]
+pub enum BenchError {
+    /// The kernel won't let us count `event`, usually because of
+    /// `/proc/sys/kernel/perf_event_paranoid`.
+    PermissionDenied {
+        event: String,
+        source: std::io::Error,
+    },
+    /// The CPU, or the kernel, can't count `event`.
+    UnsupportedEvent {
+        event: String,
+        source: std::io::Error,
+    },
+    /// With `--strict`, the counter keyed `event` only counted for
+    /// `running_fraction` of the run, because the PMU was multiplexed.
+    MultiplexingUnreliable {
+        event: String,
+        running_fraction: f64,
+    },
+    /// A buffer of `bytes` bytes couldn't be allocated.
+    AllocationFailed { bytes: usize },
+    /// Ctrl-C was pressed, see [`crate::runner::install_interrupt_handler`].
+    Interrupted,
+    /// The benchmark `name` ran for longer than `--timeout`.
+    TimedOut { name: String },
+    /// Anything else, like a bad command line or an unreadable sysfs file.
+    Io(std::io::Error),
+}
+
+/// Whether `err`, returned by perf_event when opening a counter, means the
+/// CPU or the kernel can't count the event. Many PMUs return EINVAL for a raw
+/// event code they don't know.
+pub fn is_unsupported_event(err: &std::io::Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(libc::ENOENT | libc::EOPNOTSUPP | libc::ENODEV | libc::EINVAL)
+    )
+}
+
+impl BenchError {
+    /// Classify the error perf_event returned when opening the counter keyed
+    /// `event`.
+    pub fn opening(event: &str, err: std::io::Error) -> BenchError {
+        match err.raw_os_error() {
+            Some(libc::EACCES | libc::EPERM) => BenchError::PermissionDenied {
+                event: event.to_string(),
+                source: err,
+            },
+            _ if is_unsupported_event(&err) => BenchError::UnsupportedEvent {
+                event: event.to_string(),
+                source: err,
+            },
+            _ => BenchError::Io(err),
+        }
+    }
+
+    /// The closest [`std::io::ErrorKind`], which is also the kind of the
+    /// `io::Error` this converts to.
+    pub fn kind(&self) -> std::io::ErrorKind {
+        match self {
+            BenchError::PermissionDenied { .. } => std::io::ErrorKind::PermissionDenied,
+            BenchError::UnsupportedEvent { .. } => std::io::ErrorKind::Unsupported,
+            BenchError::MultiplexingUnreliable { .. } => std::io::ErrorKind::InvalidData,
+            BenchError::AllocationFailed { .. } => std::io::ErrorKind::OutOfMemory,
+            BenchError::Interrupted => std::io::ErrorKind::Interrupted,
+            BenchError::TimedOut { .. } => std::io::ErrorKind::TimedOut,
+            BenchError::Io(err) => err.kind(),
+        }
+    }
+}
+
+impl fmt::Display for BenchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BenchError::PermissionDenied { event, source } => write!(
+                f,
+                "not allowed to count {}, try lowering /proc/sys/kernel/perf_event_paranoid: {}",
+                event, source
+            ),
+            BenchError::UnsupportedEvent { event, source } => {
+                write!(f, "can't count {} on this CPU: {}", event, source)
+            }
+            BenchError::MultiplexingUnreliable {
+                event,
+                running_fraction,
+            } => write!(
+                f,
+                "{} only counted for {:.0}% of the run",
+                event,
+                running_fraction * 100.0
+            ),
+            BenchError::AllocationFailed { bytes } => {
+                write!(f, "can't allocate {} bytes", bytes)
+            }
+            BenchError::Interrupted => write!(f, "interrupted by Ctrl-C"),
+            BenchError::TimedOut { name } => {
+                write!(f, "{} timed out, discarding its results", name)
+            }
+            BenchError::Io(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for BenchError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BenchError::PermissionDenied { source, .. }
+            | BenchError::UnsupportedEvent { source, .. } => Some(source),
+            BenchError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for BenchError {
+    fn from(err: std::io::Error) -> BenchError {
+        if err.get_ref().is_some_and(|inner| inner.is::<BenchError>()) {
+            return *err.into_inner().unwrap().downcast().unwrap();
+        }
+        BenchError::Io(err)
+    }
+}
+
+impl From<BenchError> for std::io::Error {
+    fn from(err: BenchError) -> std::io::Error {
+        match err {
+            BenchError::Io(err) => err,
+            err => std::io::Error::new(err.kind(), err),
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use std::hint::black_box;
+use std::path::{Path, PathBuf};
+
+use crate::affinity::Affinity;
+use crate::runner::{add_exit_hook, measure, option_value, remove_exit_hook};
+
+/// Where Linux exposes the cpufreq settings of each CPU.
+pub const CPUFREQ_ROOT: &str = "/sys/devices/system/cpu";
+
+/// The CPUs we run on, held at a fixed frequency with the `userspace`
+/// governor. Dropping it puts back the governors they had before.
+///
+/// The previous governors are also restored if the watchdog exits the
+/// process, but not if it's killed, eg by a second Ctrl-C.
+pub struct PinnedFrequency {
+    /// The frequency we asked for, in kHz.
+    pub khz: u64,
+    /// The `scaling_governor` file of each pinned CPU, and what it said
+    /// before we changed it.
+    previous_governors: Vec<(PathBuf, String)>,
+    /// The [`add_exit_hook`] restoring them, once every CPU is pinned.
+    exit_hook: Option<u64>,
+}
+
+impl PinnedFrequency {
+    /// Set every CPU of `cpus`, as found under `root`, to the `userspace`
+    /// governor at `khz`.
+    ///
+    /// Fails with [`std::io::ErrorKind::Unsupported`] if a CPU doesn't offer
+    /// the `userspace` governor. CPUs pinned before the failure are restored.
+    pub fn pin(root: &Path, cpus: &[usize], khz: u64) -> std::io::Result<PinnedFrequency> {
+        let mut pinned = PinnedFrequency {
+            khz,
+            previous_governors: Vec::new(),
+            exit_hook: None,
+        };
+        for cpu in cpus {
+            let cpufreq = root.join(format!("cpu{}/cpufreq", cpu));
+            let available = std::fs::read_to_string(cpufreq.join("scaling_available_governors"))
+                .map_err(|err| {
+                    std::io::Error::new(
+                        err.kind(),
+                        format!("can't read the governors of CPU {}: {}", cpu, err),
+                    )
+                })?;
+            if !available
+                .split_whitespace()
+                .any(|governor| governor == "userspace")
+            {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    format!(
+                        "CPU {} doesn't offer the userspace governor, only {}",
+                        cpu,
+                        available.trim()
+                    ),
+                ));
+            }
+
+            let governor = cpufreq.join("scaling_governor");
+            let previous = std::fs::read_to_string(&governor)?.trim().to_string();
+            std::fs::write(&governor, "userspace")?;
+            pinned.previous_governors.push((governor, previous));
+            std::fs::write(cpufreq.join("scaling_setspeed"), khz.to_string())?;
+        }
+        let previous_governors = pinned.previous_governors.clone();
+        pinned.exit_hook = Some(add_exit_hook(move || {
+            restore_governors(&previous_governors)
+        }));
+        Ok(pinned)
+    }
+
+    /// Pin the CPUs we may run on to the frequency passed with `--pin-freq`,
+    /// in MHz, if any.
+    pub fn from_args() -> std::io::Result<Option<PinnedFrequency>> {
+        let Some(value) = option_value("--pin-freq") else {
+            return Ok(None);
+        };
+        let mhz: u64 = match value.parse() {
+            Ok(mhz) if mhz > 0 => mhz,
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("invalid frequency {:?}", value),
+                ))
+            }
+        };
+        let cpus = Affinity::current()?.cpus();
+        PinnedFrequency::pin(Path::new(CPUFREQ_ROOT), &cpus, mhz * 1000).map(Some)
+    }
+
+    /// Print the frequency we asked for, next to the one a short busy loop
+    /// actually ran at, and how that compares to the reference frequency.
+    /// The CPU may ignore the request, eg when it's thermally throttled, or
+    /// when the firmware manages frequencies itself.
+    pub fn report(&self) -> std::io::Result<()> {
+        let counts = measure(|| {
+            let mut x = 0u64;
+            for i in 0..10_000_000u64 {
+                x = black_box(x.wrapping_add(i));
+            }
+        })?;
+        // Cycles per nanosecond are GHz, so cycles per microsecond are MHz.
+        let achieved_mhz = counts.cycles as f64 / counts.nanoseconds() as f64 * 1000.0;
+        print!(
+            "Pinned the CPU frequency to {} MHz, measured {:.0} MHz",
+            self.khz / 1000,
+            achieved_mhz
+        );
+        if !counts.looks_inactive(counts.ref_cycles) {
+            print!(
+                " ({:.3} x reference frequency)",
+                counts.cycles as f64 / counts.ref_cycles as f64
+            );
+        }
+        println!();
+        Ok(())
+    }
+}
+
+impl Drop for PinnedFrequency {
+    fn drop(&mut self) {
+        if let Some(exit_hook) = self.exit_hook {
+            remove_exit_hook(exit_hook);
+        }
+        restore_governors(&self.previous_governors);
+    }
+}
+
+/// Write back each `scaling_governor` file's previous governor, the last one
+/// changed first.
+fn restore_governors(previous_governors: &[(PathBuf, String)]) {
+    for (governor, previous) in previous_governors.iter().rev() {
+        if let Err(err) = std::fs::write(governor, previous) {
+            eprintln!(
+                "Warning: can't restore the {} governor in {}: {}",
+                previous,
+                governor.display(),
+                err
+            );
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+use crate::runner::path_from_args;
+
+/// One line of a [`gnuplot_script`] chart.
+pub struct Series<'a> {
+    pub label: &'a str,
+    pub values: Vec<f64>,
+}
+
+/// A gnuplot script that plots every series in `series` against `x`, with
+/// the data inline, so the script renders on its own. The first series goes
+/// on the left axis, and the others on the right one.
+pub fn gnuplot_script(title: &str, x: &Series, series: &[Series], log_x: bool) -> String {
+    let mut script = String::new();
+
+    writeln!(script, "$data << EOD").unwrap();
+    let labels: Vec<&str> = [x.label]
+        .into_iter()
+        .chain(series.iter().map(|series| series.label))
+        .collect();
+    writeln!(script, "# {}", labels.join(", ")).unwrap();
+    for (row, x_value) in x.values.iter().enumerate() {
+        let mut line = x_value.to_string();
+        for series in series {
+            write!(line, " {}", series.values[row]).unwrap();
+        }
+        writeln!(script, "{}", line).unwrap();
+    }
+    writeln!(script, "EOD").unwrap();
+    writeln!(script).unwrap();
+
+    writeln!(script, "set title {:?}", title).unwrap();
+    writeln!(script, "set xlabel {:?}", x.label).unwrap();
+    if log_x {
+        writeln!(script, "set logscale x 2").unwrap();
+    }
+    if let [first, rest @ ..] = series {
+        writeln!(script, "set ylabel {:?}", first.label).unwrap();
+        if !rest.is_empty() {
+            let labels: Vec<&str> = rest.iter().map(|series| series.label).collect();
+            writeln!(script, "set y2label {:?}", labels.join(", ")).unwrap();
+            writeln!(script, "set ytics nomirror").unwrap();
+            writeln!(script, "set y2tics").unwrap();
+        }
+    }
+    writeln!(script, "set key top left").unwrap();
+
+    let plots: Vec<String> = series
+        .iter()
+        .enumerate()
+        .map(|(index, series)| {
+            let axes = if index == 0 { "x1y1" } else { "x1y2" };
+            format!(
+                "$data using 1:{} axes {} with linespoints title {:?}",
+                index + 2,
+                axes,
+                series.label
+            )
+        })
+        .collect();
+    writeln!(script, "plot {}", plots.join(", \\\n     ")).unwrap();
+
+    script
+}
+
+/// Where `--gnuplot` says to write the script for `sweep`: the path it was
+/// given, with the sweep's name appended to the file stem, so that several
+/// sweeps in one run don't overwrite each other.
+pub fn gnuplot_path(path: &Path, sweep: &str) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let mut file_name = format!("{}_{}", stem, sweep);
+    if let Some(extension) = path.extension() {
+        file_name = format!("{}.{}", file_name, extension.to_string_lossy());
+    }
+    path.with_file_name(file_name)
+}
+
+/// If `--gnuplot` was passed, write `script` for `sweep` next to the path it
+/// was given.
+pub fn write_gnuplot(sweep: &str, script: &str) -> std::io::Result<()> {
+    let Some(path) = path_from_args("--gnuplot") else {
+        return Ok(());
+    };
+    let path = gnuplot_path(&path, sweep);
+    std::fs::write(&path, script)?;
+    eprintln!("Wrote gnuplot script to {}", path.display());
+    Ok(())
+}
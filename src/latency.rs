@@ -0,0 +1,206 @@
+use std::hint::black_box;
+use std::ops::RangeInclusive;
+
+use crate::benchmarks::{bench_load_to_use, bench_pointer_chase, random_chain_of, Node, SEED};
+use crate::runner::{measure, OutputFormat};
+use crate::topology::{format_size, Topology};
+
+/// Where a working set fits in the memory hierarchy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemoryLevel {
+    L1,
+    L2,
+    L3,
+    Dram,
+}
+
+impl MemoryLevel {
+    /// The smallest level of `topology`'s data caches that holds
+    /// `working_set` bytes.
+    pub fn for_working_set(topology: &Topology, working_set: usize) -> MemoryLevel {
+        topology
+            .caches
+            .iter()
+            .filter(|cache| cache.kind != "Instruction" && cache.size >= working_set)
+            .map(|cache| match cache.level {
+                1 => MemoryLevel::L1,
+                2 => MemoryLevel::L2,
+                3 => MemoryLevel::L3,
+                _ => MemoryLevel::Dram,
+            })
+            .next()
+            .unwrap_or(MemoryLevel::Dram)
+    }
+
+    /// The load-to-use latency we expect from this level, in cycles. These
+    /// are ballpark figures for recent Intel and AMD cores; anything far off
+    /// means the access pattern isn't what we think it is.
+    pub fn expected_latency(self) -> RangeInclusive<f64> {
+        match self {
+            MemoryLevel::L1 => 3.0..=6.0,
+            MemoryLevel::L2 => 10.0..=20.0,
+            MemoryLevel::L3 => 30.0..=80.0,
+            MemoryLevel::Dram => 150.0..=1000.0,
+        }
+    }
+
+    /// How many bytes of nodes [`load_to_use_latencies`] chases to hit this
+    /// level: half of the level's data cache, so that the chain stays
+    /// resident next to the rest of the process, but at least twice the
+    /// level above it, so that the chain doesn't fit there instead. `None`
+    /// for DRAM, or if no size fits both.
+    pub fn working_set(self, topology: &Topology) -> Option<usize> {
+        let level = match self {
+            MemoryLevel::L1 => 1,
+            MemoryLevel::L2 => 2,
+            MemoryLevel::L3 => 3,
+            MemoryLevel::Dram => return None,
+        };
+        let data_cache = |level| {
+            topology
+                .caches
+                .iter()
+                .find(|cache| cache.level == level && cache.kind != "Instruction")
+                .map(|cache| cache.size)
+        };
+        let size = data_cache(level)?;
+        let above = if level > 1 { data_cache(level - 1)? } else { 0 };
+        let working_set = (size / 2).max(above * 2);
+        (working_set <= size).then_some(working_set)
+    }
+}
+
+/// The latency of a single dependent load from one level, measured by
+/// [`load_to_use_latencies`].
+#[derive(Clone, Debug)]
+pub struct LoadToUse {
+    pub level: MemoryLevel,
+    pub working_set: usize,
+    pub cycles: f64,
+    pub nanoseconds: f64,
+}
+
+impl LoadToUse {
+    /// Chain `loads` dependent loads through a pre-warmed random chain of
+    /// `working_set` bytes, which should only fit in `level`.
+    pub fn measure(
+        level: MemoryLevel,
+        working_set: usize,
+        loads: usize,
+    ) -> std::io::Result<LoadToUse> {
+        let nodes = random_chain_of(working_set / std::mem::size_of::<Node>(), SEED);
+        // Pull the whole chain into the level before measuring.
+        black_box(bench_load_to_use(&nodes, nodes.len()));
+
+        let counts = measure(|| {
+            black_box(bench_load_to_use(&nodes, loads));
+        })?;
+        Ok(LoadToUse {
+            level,
+            working_set,
+            cycles: counts.cycles as f64 / loads as f64,
+            nanoseconds: counts.nanoseconds() as f64 / loads as f64,
+        })
+    }
+}
+
+/// Chain `loads` dependent loads through a pre-warmed buffer sized for each
+/// cache level of `topology`, with [`MemoryLevel::working_set`], and print
+/// the latency of a single load from each level as a table.
+///
+/// Unlike [`chase_latency`], which checks whole chains against the levels
+/// they happen to fit in, this picks the buffer sizes to hit each level, to
+/// give one reference latency per level. The L3 figure includes some DTLB
+/// misses, unless the buffer is backed by huge pages.
+pub fn load_to_use_latencies(topology: &Topology, loads: usize) -> std::io::Result<Vec<LoadToUse>> {
+    let mut latencies = Vec::new();
+    for level in [MemoryLevel::L1, MemoryLevel::L2, MemoryLevel::L3] {
+        if let Some(working_set) = level.working_set(topology) {
+            latencies.push(LoadToUse::measure(level, working_set, loads)?);
+        }
+    }
+
+    if OutputFormat::from_args()? != OutputFormat::Table {
+        return Ok(latencies);
+    }
+    println!("====================================================================");
+    println!("Load-to-use latency, over {} dependent loads", loads);
+    for latency in &latencies {
+        println!(
+            "{:<6} {:>8} {:>10.2} cycles {:>8.2} ns",
+            format!("{:?}", latency.level),
+            format_size(latency.working_set),
+            latency.cycles,
+            latency.nanoseconds
+        );
+    }
+    println!();
+
+    Ok(latencies)
+}
+
+/// How long each hop of [`chase_latency`] took, and how long we expected.
+#[derive(Clone, Debug)]
+pub struct ChaseLatency {
+    pub working_set: usize,
+    pub cycles_per_hop: f64,
+    /// `None` if the topology couldn't be read.
+    pub level: Option<MemoryLevel>,
+}
+
+impl ChaseLatency {
+    /// Whether the measured latency is within the expected range for the
+    /// level the chain fits in, or `None` if we don't know the level.
+    pub fn as_expected(&self) -> Option<bool> {
+        let level = self.level?;
+        Some(level.expected_latency().contains(&self.cycles_per_hop))
+    }
+}
+
+/// Follow `nodes` for `hops` hops, and compare the cycles per hop with the
+/// latency of the cache level `topology` says the chain fits in. Print both,
+/// and flag the result if they're far apart: usually, that means the chain
+/// became prefetchable, or didn't fit where we thought.
+pub fn chase_latency<const N: usize>(
+    nodes: &[Node; N],
+    hops: usize,
+    topology: Option<&Topology>,
+) -> std::io::Result<ChaseLatency> {
+    // Make sure the chain is as resident as it can be before measuring.
+    black_box(bench_pointer_chase(nodes, N));
+
+    let counts = measure(|| {
+        black_box(bench_pointer_chase(nodes, hops));
+    })?;
+
+    let working_set = std::mem::size_of_val(nodes);
+    let latency = ChaseLatency {
+        working_set,
+        cycles_per_hop: counts.cycles as f64 / hops as f64,
+        level: topology.map(|topology| MemoryLevel::for_working_set(topology, working_set)),
+    };
+
+    if OutputFormat::from_args()? != OutputFormat::Table {
+        return Ok(latency);
+    }
+    println!("====================================================================");
+    println!("Pointer chase over {} KB", working_set / 1024);
+    println!("Cycles per hop: {:.3}", latency.cycles_per_hop);
+    if let Some(level) = latency.level {
+        let expected = level.expected_latency();
+        println!(
+            "Fits in {:?}, expected {:.0} to {:.0} cycles: {}",
+            level,
+            expected.start(),
+            expected.end(),
+            if latency.as_expected() == Some(true) {
+                "OK"
+            } else {
+                "UNEXPECTED"
+            }
+        );
+    }
+    println!();
+
+    Ok(latency)
+}
@@ -0,0 +1,20 @@
+pub mod affinity;
+pub mod aliasing;
+pub mod associativity;
+pub mod benchmarks;
+pub mod calibration;
+pub mod clobber;
+pub mod environment;
+pub mod error;
+pub mod frequency;
+pub mod gnuplot;
+pub mod latency;
+pub mod page_walk;
+pub mod prefetchers;
+pub mod profile;
+pub mod runner;
+pub mod speculation;
+pub mod sweeps;
+pub mod topology;
+#[cfg(target_arch = "x86_64")]
+pub mod transition;
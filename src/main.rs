@@ -1,359 +1,357 @@
 #![allow(unused)]
 
-mod runner;
-
-use std::arch::asm;
-use std::arch::x86_64::{_mm_prefetch, _MM_HINT_T0};
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::{_MM_HINT_NTA, _MM_HINT_T0, _MM_HINT_T1, _MM_HINT_T2};
 use std::hint::black_box;
 
-use rand::Rng;
-use runner::run_benchmarks;
+use test_prefetch::affinity::Affinity;
+#[cfg(target_arch = "x86_64")]
+use test_prefetch::aliasing::aliasing_penalty;
+use test_prefetch::associativity::assoc_conflict;
+use test_prefetch::benchmarks::*;
+#[cfg(target_arch = "x86_64")]
+use test_prefetch::calibration::flush_latency;
+use test_prefetch::calibration::{
+    dead_code_elimination, prefetch_overhead, ruler, software_prefetch_benefit,
+};
+use test_prefetch::frequency::PinnedFrequency;
+use test_prefetch::latency::{chase_latency, load_to_use_latencies};
+use test_prefetch::page_walk::{page_walk_cycles, SmallPages};
+use test_prefetch::prefetchers::EnabledPrefetchers;
+use test_prefetch::profile::MachineProfile;
+use test_prefetch::runner::{
+    any_selected, flag_passed, install_interrupt_handler, is_selected, iterations_from_env,
+    loop_from_args, measure, path_from_args, print_reproducibility, print_schema, print_summary,
+    run_benchmarks, suite_repeats_from_args, AbComparison, BenchConfig, CGroup, Category,
+    CsvReporter, Fence, OutputFormat,
+};
+use test_prefetch::speculation::speculation_ratio;
+#[cfg(target_arch = "x86_64")]
+use test_prefetch::sweeps::sweep_store_buffer;
+use test_prefetch::sweeps::{
+    sweep_atomic_contention, sweep_gather_locality, sweep_mlp, sweep_prefetch_distance,
+    sweep_prefetches, sweep_reuse_distance, sweep_stride, sweep_work,
+};
+use test_prefetch::topology::{print_topology, Topology};
+#[cfg(target_arch = "x86_64")]
+use test_prefetch::transition::avx_sse_transition_penalty;
 
-macro_rules! asm_comment {
-    ($tt:tt) => {
-        "// {}"
-    };
-}
+// ----------------
 
-macro_rules! side_effect_read {
-    ($( $x:expr ),*) => {
-        unsafe {
-            asm!(
-                $(asm_comment!($x),)*
-                $(in(reg) $x,)*
-            );
-        }
-    };
-}
+pub fn main() -> std::io::Result<()> {
+    if flag_passed("--print-schema") {
+        print_schema();
+        return Ok(());
+    }
 
-macro_rules! side_effect_rw {
-    ($( $x:expr ),*) => {
-        unsafe {
-            asm!(
-                $(asm_comment!($x),)*
-                $(inout(reg) $x,)*
+    // Counting on a cgroup fails in a lot of containers, for reasons outside
+    // of our control. Explain why and skip the run, rather than error out.
+    if CGroup::requested() {
+        if let Err(err) = measure(|| {}) {
+            eprintln!(
+                "Skipping benchmarks, can't count events on the cgroup: {}",
+                err
             );
-        }
-    };
-}
-
-#[inline(never)]
-pub fn bench_noops<T, const N: usize>(_array: &[T; N]) -> u64 {
-    let x = black_box(3);
-    let mut sum = black_box(0);
-
-    // This loop's instr/cycle throughput should peak at whatever
-    // the CPU's pipeline width is. For Intel CPUs this is usually 4.
-    // It might be a little higher because of µops fusion.
-    for _ in 0..N {
-        unsafe {
-            asm!("nop", "nop", "nop", "nop", "nop", "nop");
+            return Ok(());
         }
     }
 
-    sum
-}
-
-#[inline(never)]
-pub fn bench_alu_ops<T, const N: usize>(_array: &[T; N]) -> u64 {
-    let mut sum = 0;
-
-    // This loop's throughput should peak at 3 instr/cycle.
-    // This is because the loop to a 3-instructions body, and can only
-    // run one iteration per cycle, because of the dependency chain
-    // between the `sum +=` instructions.
-    for _ in 0..N {
-        sum += 3;
-        side_effect_read!(sum);
+    install_interrupt_handler()?;
+    // Every benchmark writes its row to the same reporter, so that the header
+    // is only written once.
+    let mut csv = (OutputFormat::from_args()? == OutputFormat::Csv)
+        .then(|| CsvReporter::new(std::io::stdout()));
+    let listing = flag_passed("--list");
+    if !listing {
+        print_topology()?;
     }
 
-    sum
-}
-
-#[inline(never)]
-pub fn bench_alu_ops_unrolled<T, const N: usize>(_array: &[T; N]) -> u64 {
-    let x = black_box(3);
-
-    let mut sum_1 = 0;
-    let mut sum_2 = 0;
-    let mut sum_3 = 0;
-    let mut sum_4 = 0;
-
-    // For some reason, the throughput of this loop peaks on a Zen CPU at
-    // 3.3 instr/cycle, against an expected 4 instr/cycle.
-    // By contrast, an Intel i7-5600u (Broadwell archictecture) peaks at 4.0,
-    // as expected.
-    // As far as I could figure out, the bottleneck is somewhere in register
-    // renaming: bench_alu_ops_super_unrolled, which uses more virtual GPUs,
-    // peaks at 3.9 instr/cycle on Zen; and this loop's throughput can also be
-    // increased to 3.9 instr/cycle by setting every variable to zero every
-    // loop iteration, which breaks the dependency chain and "resets" register
-    // renaming.
-    for _ in 0..N {
-        sum_1 += x;
-        sum_2 += x;
-        sum_3 += x;
-        sum_4 += x;
-        side_effect_rw!(sum_1, sum_2, sum_3, sum_4);
-    }
-
-    sum_1 + sum_2 + sum_3 + sum_4
-}
-
-#[inline(never)]
-pub fn bench_alu_ops_super_unrolled<T, const N: usize>(_array: &[T; N]) -> u64 {
-    let x = black_box(3);
-
-    let mut sum_1 = 0;
-    let mut sum_2 = 0;
-    let mut sum_3 = 0;
-    let mut sum_4 = 0;
-    let mut sum_5 = 0;
-    let mut sum_6 = 0;
-    let mut sum_7 = 0;
-    let mut sum_8 = 0;
-
-    // This loop's throughput should peak at the CPU's number of ALU slots.
-    // Most CPUs have 4 ALU slots and will thus peak at 4 instr/cycle.
-    for _ in 0..N {
-        unsafe {
-            asm!(
-                "add {sum_1}, {x}",
-                "add {sum_2}, {x}",
-                "add {sum_3}, {x}",
-                "add {sum_4}, {x}",
-                "add {sum_5}, {x}",
-                "add {sum_6}, {x}",
-                "add {sum_7}, {x}",
-                "add {sum_8}, {x}",
-                sum_1 = inout(reg) sum_1,
-                sum_2 = inout(reg) sum_2,
-                sum_3 = inout(reg) sum_3,
-                sum_4 = inout(reg) sum_4,
-                sum_5 = inout(reg) sum_5,
-                sum_6 = inout(reg) sum_6,
-                sum_7 = inout(reg) sum_7,
-                sum_8 = inout(reg) sum_8,
-                x = in(reg) x as u64,
-            );
+    // Frequency scaling is the biggest source of noise between runs, so pin
+    // it if asked to. The guard restores the governors when main returns.
+    let pinned_frequency = if listing {
+        None
+    } else {
+        match PinnedFrequency::from_args() {
+            Ok(pinned) => pinned,
+            Err(err) if err.kind() == std::io::ErrorKind::InvalidInput => return Err(err),
+            Err(err) => {
+                eprintln!("Warning: can't pin the CPU frequency: {}", err);
+                eprintln!("Try `sudo cpupower frequency-set -d <MHz>MHz -u <MHz>MHz` instead");
+                None
+            }
+        }
+    };
+    if let Some(pinned) = &pinned_frequency {
+        if OutputFormat::from_args()? == OutputFormat::Table {
+            if let Err(err) = pinned.report() {
+                eprintln!("Warning: can't measure the achieved frequency: {}", err);
+            }
         }
     }
 
-    sum_1 + sum_2 + sum_3 + sum_4 + sum_5 + sum_6 + sum_7 + sum_8
-}
-
-#[inline(never)]
-pub fn bench_mul_ops<T, const N: usize>(_array: &[T; N]) -> u64 {
-    let x = black_box(3);
-
-    // This loop's throughput will peak at one iteration per mul latency.
-    // Most CPUs have a mul latency of 3, so the loop body will run 0.33 times
-    // per cycle.
-    // With the loop counter, this translates to roughly 0.4 instr/cycle.
-    let mut product = black_box(0);
-    for _ in 0..N {
-        product *= x;
-    }
-
-    product
-}
-
-#[inline(never)]
-pub fn bench_sum_of_array(array: &[u8]) -> u8 {
-    let x = black_box(3);
-    let mut sum = 0;
-
-    // Like bench_alu_ops, this loop should peak at 1 iteration per cycle.
-    // Given the loop has a 5-instuction body, this translates to 5 instr/cycle.
-    // On Zen, it instead peaks at 0.6 iteration per cycle, eg 3 instr/cycle.
-    for i in 0..array.len() {
-        sum += array[i];
-        side_effect_read!(sum as u64);
+    // Same for the hardware prefetchers, to attribute the results of a sweep
+    // to one of them. Unlike frequency pinning, there's no point running
+    // without the settings that were asked for.
+    let enabled_prefetchers = if listing {
+        None
+    } else {
+        EnabledPrefetchers::from_args().map_err(|err| {
+            if err.kind() == std::io::ErrorKind::InvalidInput {
+                return err;
+            }
+            std::io::Error::new(
+                err.kind(),
+                format!(
+                    "can't set the hardware prefetchers: {} (try `sudo modprobe msr`, and running as root)",
+                    err
+                ),
+            )
+        })?
+    };
+    if let Some(enabled) = &enabled_prefetchers {
+        enabled.report()?;
     }
 
-    sum
-}
-
-#[inline(never)]
-pub fn bench_sum_of_array_unrolled(array: &[u8]) -> u8 {
-    let x = black_box(3);
-    let mut sum_1 = 0;
-    let mut sum_2 = 0;
-
-    let mut i = 0;
-    while i < array.len() {
-        sum_1 += array[i] & x;
-        sum_2 += array[i + 1] & x;
-        i += 2;
+    let mut result = run_all(&mut csv);
+    if let (Ok(()), Some(name)) = (&result, loop_from_args()) {
+        // Looping only ends with Ctrl-C, so we never found the benchmark.
+        result = Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("no benchmark called {:?} to loop", name),
+        ));
     }
-
-    sum_1 + sum_2
-}
-
-#[inline(never)]
-pub fn bench_sum_of_array_with_stride<const N: usize>(array: &[u8; N], stride: usize) -> u8 {
-    let x = black_box(3);
-    let mut sum = 0;
-
-    let mut i = 0;
-    while i < N {
-        sum += array[i] & x;
-        i += stride;
+    if let Err(err) = &result {
+        if err.kind() != std::io::ErrorKind::Interrupted {
+            return result;
+        }
+        eprintln!("Interrupted, printing the results so far");
     }
 
-    sum
-}
-
-#[inline(never)]
-pub fn bench_sum_of_array_with_stride_prefetch<const N: usize, const P: usize>(
-    array: &[u8; N],
-    stride: usize,
-) -> u8 {
-    let x = black_box(3);
-    let mut sum = 0;
+    print_summary();
+    print_reproducibility();
 
-    let mut i = 0;
-    while i < N {
-        sum += array[i] & x;
-        unsafe {
-            _mm_prefetch(array.as_ptr().add(i + P * stride) as *const i8, _MM_HINT_T0);
-        };
-        i += stride;
-    }
-
-    sum
+    Ok(())
 }
 
-#[inline(never)]
-pub fn bench_sum_array_stride_and_pad<const N: usize>(array: &[u8; N], stride: usize) -> u8 {
-    let x = black_box(3);
-    let mut sum: u64 = 0;
-
-    let mut i = 0;
-    while i < N {
-        sum += array[i] as u64;
-        unsafe {
-            asm!(
-                "xor {sum}, {sum}",
-                "add {sum}, {x}",
-                "add {sum}, {x}",
-                "add {i}, {stride}",
-                sum = inout(reg) sum,
-                i = inout(reg) i,
-                x = in(reg) x as u64,
-                stride = in(reg) stride,
-            );
-        }
+fn run_all(csv: &mut Option<CsvReporter<std::io::Stdout>>) -> std::io::Result<()> {
+    // A curated subset of the suite, summed up on a single page.
+    if flag_passed("--profile") {
+        MachineProfile::measure(&Topology::detect()?)?.print();
+        return Ok(());
     }
 
-    sum as u8
-}
-
-#[inline(never)]
-pub fn bench_sum_array_changing_stride<const N: usize>(array: &[u8; N]) -> u8 {
-    let x = black_box(3);
-    let mut sum: u64 = 0;
-    let mut stride: u64 = 0;
-
-    let mut i = 0;
-    while i < N {
-        sum += array[i] as u64;
-        unsafe {
-            asm!(
-                "xor {sum}, {sum}",
-                "add {stride}, 9",
-                "and {stride}, 31",
-                "add {i}, 110",
-                "add {i}, {stride}",
-                sum = inout(reg) sum,
-                i = inout(reg) i,
-                stride = in(reg) stride,
-            );
-        }
+    if flag_passed("--calibrate") {
+        ruler(10_000)?;
     }
 
-    sum as u8
-}
-
-#[inline(never)]
-pub fn bench_sum_array_indirect<const N: usize, const M: usize>(
-    array: &[u8; N],
-    indices: &[usize; M],
-) -> u8 {
-    let x = black_box(3);
-    let mut sum: u64 = 0;
-    let mut stride: u64 = 0;
-
-    let mut i = 0;
-    while i < M {
-        // TODO - check that indices are in bounds
-        sum += array[indices[i]] as u64;
-        i += 64;
+    for _ in 0..suite_repeats_from_args()? {
+        run_suite(csv)?;
     }
 
-    sum as u8
+    Ok(())
 }
 
-// ----------------
-
-pub fn main() -> std::io::Result<()> {
-    const ITER_COUNT: usize = 10_000;
+fn run_suite(csv: &mut Option<CsvReporter<std::io::Stdout>>) -> std::io::Result<()> {
+    // Scale these to the machine: up for stable figures on a quiet server,
+    // down for a quick smoke test.
+    let iter_count = iterations_from_env("PREFETCH_ITERS", 10_000)?;
+    let small_iter_count = iterations_from_env("PREFETCH_SMALL_ITERS", 1_000)?;
 
     let small_array_empty = [(); 1000];
 
     run_benchmarks(
         "bench_noops",
         || {
-            for _ in 0..ITER_COUNT {
+            for _ in 0..iter_count {
                 black_box(bench_noops(&small_array_empty));
             }
         },
-        small_array_empty.len() * ITER_COUNT,
-        None,
+        BenchConfig::new(small_array_empty.len() * iter_count)
+            .peak_ipc(4.0)
+            .category(Category::FrontEnd),
+        csv.as_mut(),
     )?;
 
     run_benchmarks(
         "bench_alu_ops",
         || {
-            for _ in 0..ITER_COUNT {
+            for _ in 0..iter_count {
                 black_box(bench_alu_ops(&small_array_empty));
             }
         },
-        small_array_empty.len() * ITER_COUNT,
-        None,
+        BenchConfig::new(small_array_empty.len() * iter_count)
+            .peak_ipc(3.0)
+            .category(Category::Alu),
+        csv.as_mut(),
     )?;
 
     run_benchmarks(
         "bench_alu_ops_unrolled",
         || {
-            for _ in 0..ITER_COUNT {
+            for _ in 0..iter_count {
                 black_box(bench_alu_ops_unrolled(&small_array_empty));
             }
         },
-        small_array_empty.len() * ITER_COUNT,
-        None,
+        BenchConfig::new(small_array_empty.len() * iter_count)
+            .peak_ipc(4.0)
+            .category(Category::Alu),
+        csv.as_mut(),
     )?;
 
+    #[cfg(target_arch = "x86_64")]
     run_benchmarks(
         "bench_alu_ops_super_unrolled",
         || {
-            for _ in 0..ITER_COUNT {
+            for _ in 0..iter_count {
                 black_box(bench_alu_ops_super_unrolled(&small_array_empty));
             }
         },
-        small_array_empty.len() * ITER_COUNT,
-        None,
+        BenchConfig::new(small_array_empty.len() * iter_count)
+            .peak_ipc(4.0)
+            .category(Category::Alu),
+        csv.as_mut(),
+    )?;
+
+    // The same eight independent adds per block, in a counted loop, in a
+    // pointer-bump loop, and unrolled with no loop at all. An iteration is one
+    // add, so instructions per iteration above 1 are loop overhead.
+    const LOOP_BLOCKS: usize = 1024;
+    let loop_elements = black_box(vec![0u64; LOOP_BLOCKS]);
+
+    #[cfg(target_arch = "x86_64")]
+    run_benchmarks(
+        "bench_loop_overhead_counted",
+        || {
+            for _ in 0..iter_count {
+                black_box(bench_loop_overhead_counted(LOOP_BLOCKS));
+            }
+        },
+        BenchConfig::new(LOOP_BLOCKS * LOOP_OVERHEAD_OPS * iter_count).category(Category::Alu),
+        csv.as_mut(),
+    )?;
+
+    #[cfg(target_arch = "x86_64")]
+    run_benchmarks(
+        "bench_loop_overhead_pointer",
+        || {
+            for _ in 0..iter_count {
+                black_box(bench_loop_overhead_pointer(&loop_elements));
+            }
+        },
+        BenchConfig::new(LOOP_BLOCKS * LOOP_OVERHEAD_OPS * iter_count).category(Category::Alu),
+        csv.as_mut(),
+    )?;
+
+    run_benchmarks(
+        "bench_loop_overhead_unrolled",
+        || {
+            for _ in 0..iter_count {
+                black_box(bench_loop_overhead_unrolled());
+            }
+        },
+        BenchConfig::new(UNROLLED_BLOCKS * LOOP_OVERHEAD_OPS * iter_count).category(Category::Alu),
+        csv.as_mut(),
+    )?;
+
+    // The same, with lfence around the measured region, so that the code
+    // around it can't overlap with the benchmark. The difference in cycles
+    // with the unfenced version is what that overlap hid.
+    run_benchmarks(
+        "bench_loop_overhead_unrolled_lfence",
+        || {
+            for _ in 0..iter_count {
+                black_box(bench_loop_overhead_unrolled());
+            }
+        },
+        BenchConfig::new(UNROLLED_BLOCKS * LOOP_OVERHEAD_OPS * iter_count)
+            .fence(Fence::Lfence)
+            .category(Category::Alu),
+        csv.as_mut(),
+    )?;
+
+    // The same loads, over an array that fits in the L1D, addressed as base +
+    // index * scale or as a bumped pointer. Compare the IPC: the instructions
+    // are the same, but the indexed loads may cost more uops.
+    let addressed_elements = black_box(vec![1u64; 1024]);
+
+    #[cfg(target_arch = "x86_64")]
+    run_benchmarks(
+        "bench_addressing_indexed",
+        || {
+            for _ in 0..iter_count {
+                black_box(bench_addressing_indexed(&addressed_elements));
+            }
+        },
+        BenchConfig::new(addressed_elements.len() * iter_count)
+            .data_loads(addressed_elements.len() * iter_count)
+            .peak_ipc(4.0)
+            .category(Category::Alu),
+        csv.as_mut(),
+    )?;
+
+    #[cfg(target_arch = "x86_64")]
+    run_benchmarks(
+        "bench_addressing_pointer",
+        || {
+            for _ in 0..iter_count {
+                black_box(bench_addressing_pointer(&addressed_elements));
+            }
+        },
+        BenchConfig::new(addressed_elements.len() * iter_count)
+            .data_loads(addressed_elements.len() * iter_count)
+            .peak_ipc(4.0)
+            .category(Category::Alu),
+        csv.as_mut(),
+    )?;
+
+    // Longer and longer bursts of stores that can't retire, to find how many
+    // the store buffer holds.
+    #[cfg(target_arch = "x86_64")]
+    if is_selected("sweep_store_buffer", Some(Category::Alu))? {
+        sweep_store_buffer(iter_count)?;
+    }
+
+    // Both execute the same number of instructions, but the second one's code
+    // doesn't fit in the L1 instruction cache.
+    #[cfg(target_arch = "x86_64")]
+    run_benchmarks(
+        "bench_code_footprint_1KB",
+        || {
+            for _ in 0..iter_count {
+                black_box(bench_code_footprint::<_, 1000, 64>(&small_array_empty));
+            }
+        },
+        BenchConfig::new(small_array_empty.len() * iter_count * 64)
+            .peak_ipc(4.0)
+            .category(Category::FrontEnd),
+        csv.as_mut(),
+    )?;
+
+    #[cfg(target_arch = "x86_64")]
+    run_benchmarks(
+        "bench_code_footprint_64KB",
+        || {
+            for _ in 0..iter_count / 64 {
+                black_box(bench_code_footprint::<_, 1000, 4096>(&small_array_empty));
+            }
+        },
+        BenchConfig::new(small_array_empty.len() * iter_count / 64 * 4096)
+            .peak_ipc(4.0)
+            .category(Category::FrontEnd),
+        csv.as_mut(),
     )?;
 
     run_benchmarks(
         "bench_mul_ops",
         || {
-            for _ in 0..ITER_COUNT {
+            for _ in 0..iter_count {
                 black_box(bench_mul_ops(&small_array_empty));
             }
         },
-        small_array_empty.len() * ITER_COUNT,
-        None,
+        BenchConfig::new(small_array_empty.len() * iter_count)
+            .peak_ipc(0.4)
+            .category(Category::Alu),
+        csv.as_mut(),
     )?;
 
     let small_array = black_box([0; 1000]);
@@ -361,139 +359,1109 @@ pub fn main() -> std::io::Result<()> {
     run_benchmarks(
         "bench_sum_of_array",
         || {
-            for _ in 0..ITER_COUNT {
+            for _ in 0..iter_count {
                 black_box(bench_sum_of_array(&small_array));
             }
         },
-        small_array.len() * ITER_COUNT,
-        Some(small_array.len() * ITER_COUNT),
+        BenchConfig::new(small_array.len() * iter_count)
+            .data_loads(small_array.len() * iter_count)
+            .elements(small_array.len() * iter_count, 1)
+            .peak_ipc(5.0)
+            .category(Category::L1),
+        csv.as_mut(),
+    )?;
+
+    if is_selected("calibrate_prefetch_overhead", Some(Category::Prefetch))? {
+        prefetch_overhead(&small_array, 1, iter_count)?;
+    }
+
+    if is_selected("calibrate_dead_code_elimination", None)? {
+        dead_code_elimination(&small_array, iter_count)?;
+    }
+
+    // Small enough to be resident in L1 before each flush.
+    let flushed_buffer = black_box(CacheAligned([1; 16 * 1024]));
+    #[cfg(target_arch = "x86_64")]
+    if is_selected("calibrate_flush_latency", Some(Category::Dram))? {
+        flush_latency(&flushed_buffer, 100)?;
+    }
+
+    // Big enough for a 4KB window at either offset.
+    let mut aliasing_buffer = black_box([0; 3 * 4096]);
+
+    #[cfg(target_arch = "x86_64")]
+    run_benchmarks(
+        "bench_4k_aliasing",
+        || {
+            for _ in 0..iter_count {
+                black_box(bench_4k_aliasing(&mut aliasing_buffer, 4096));
+            }
+        },
+        BenchConfig::new(4096 / 8 * iter_count)
+            .data_loads(4096 / 8 * iter_count)
+            .category(Category::L1),
+        csv.as_mut(),
     )?;
 
+    #[cfg(target_arch = "x86_64")]
+    run_benchmarks(
+        "bench_4k_aliasing_offset_4160",
+        || {
+            for _ in 0..iter_count {
+                black_box(bench_4k_aliasing(&mut aliasing_buffer, 4096 + 64));
+            }
+        },
+        BenchConfig::new(4096 / 8 * iter_count)
+            .data_loads(4096 / 8 * iter_count)
+            .category(Category::L1),
+        csv.as_mut(),
+    )?;
+
+    #[cfg(target_arch = "x86_64")]
+    if is_selected("aliasing_penalty", Some(Category::L1))? {
+        aliasing_penalty(&mut aliasing_buffer, 4096, 4096 + 64, iter_count)?;
+    }
+
+    // A few dozen lines that all map to the same L1D set, against the same
+    // lines spread over as many sets.
+    if is_selected("assoc_conflict", Some(Category::L1))? {
+        let l1d = Topology::detect().ok().and_then(|topology| {
+            topology
+                .caches
+                .into_iter()
+                .find(|cache| cache.level == 1 && cache.kind == "Data")
+        });
+        match l1d {
+            Some(l1d) => {
+                assoc_conflict(&l1d, iter_count)?;
+            }
+            None => eprintln!("Warning: can't find the L1D cache, skipping assoc_conflict"),
+        }
+    }
+
+    // The same branch on the same bytes, unpredictable when they're random
+    // and predictable once they're sorted. Compare cycles per iteration, and
+    // how many issued uops are thrown away to mispredicts.
+    let random_bytes = random_bytes(4096, SEED);
+    let mut sorted_bytes = random_bytes.clone();
+    sorted_bytes.sort_unstable();
+
+    run_benchmarks(
+        "bench_branchy_random",
+        || {
+            for _ in 0..iter_count {
+                black_box(bench_branchy(&random_bytes));
+            }
+        },
+        BenchConfig::new(random_bytes.len() * iter_count).category(Category::Branch),
+        csv.as_mut(),
+    )?;
+
+    run_benchmarks(
+        "bench_branchy_sorted",
+        || {
+            for _ in 0..iter_count {
+                black_box(bench_branchy(&sorted_bytes));
+            }
+        },
+        BenchConfig::new(sorted_bytes.len() * iter_count).category(Category::Branch),
+        csv.as_mut(),
+    )?;
+
+    if is_selected("speculation_ratio", Some(Category::Branch))? {
+        speculation_ratio(&random_bytes, &sorted_bytes, iter_count)?;
+    }
+
+    // One AVX add and one SSE add per iteration, with and without clearing
+    // the upper halves of the ymm registers in between.
+    const TRANSITIONS: u64 = 1_000_000;
+    #[cfg(target_arch = "x86_64")]
+    if is_x86_feature_detected!("avx") {
+        run_benchmarks(
+            "bench_avx_sse_transition_dirty",
+            || {
+                black_box(bench_avx_sse_transition::<false>(TRANSITIONS));
+            },
+            BenchConfig::new(TRANSITIONS as usize).category(Category::Alu),
+            csv.as_mut(),
+        )?;
+
+        run_benchmarks(
+            "bench_avx_sse_transition_vzeroupper",
+            || {
+                black_box(bench_avx_sse_transition::<true>(TRANSITIONS));
+            },
+            BenchConfig::new(TRANSITIONS as usize).category(Category::Alu),
+            csv.as_mut(),
+        )?;
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    if is_selected("avx_sse_transition_penalty", Some(Category::Alu))? {
+        avx_sse_transition_penalty(TRANSITIONS)?;
+    }
+
     run_benchmarks(
         "bench_sum_of_array_unrolled",
         || {
-            for _ in 0..ITER_COUNT {
+            for _ in 0..iter_count {
                 black_box(bench_sum_of_array_unrolled(&small_array));
             }
         },
-        small_array.len() * ITER_COUNT / 2,
-        Some(small_array.len() * ITER_COUNT),
+        BenchConfig::new(small_array.len() * iter_count / 2)
+            .data_loads(small_array.len() * iter_count)
+            .elements(small_array.len() * iter_count, 1)
+            .category(Category::L1),
+        csv.as_mut(),
+    )?;
+
+    // Same values, but the denormal ones make every multiplication take a
+    // microcode assist, unless flush-to-zero is on.
+    let normal_floats = black_box([1.0_f32; 1000]);
+    let denormal_floats = black_box([f32::MIN_POSITIVE / 8.0; 1000]);
+
+    run_benchmarks(
+        "bench_sum_floats_normal",
+        || {
+            for _ in 0..iter_count {
+                black_box(bench_sum_floats(&normal_floats));
+            }
+        },
+        BenchConfig::new(normal_floats.len() * iter_count)
+            .data_loads(normal_floats.len() * iter_count)
+            .category(Category::Alu),
+        csv.as_mut(),
     )?;
 
-    const SMALL_ITER_COUNT: usize = 1_000;
-    let array_1_mb = black_box([0; 1_000_000]);
+    run_benchmarks(
+        "bench_sum_floats_denormal",
+        || {
+            for _ in 0..iter_count {
+                black_box(bench_sum_floats(&denormal_floats));
+            }
+        },
+        BenchConfig::new(denormal_floats.len() * iter_count)
+            .data_loads(denormal_floats.len() * iter_count)
+            .category(Category::Alu),
+        csv.as_mut(),
+    )?;
+
+    #[cfg(target_arch = "x86_64")]
+    run_benchmarks(
+        "bench_sum_floats_denormal_ftz",
+        || {
+            for _ in 0..iter_count {
+                black_box(bench_sum_floats_ftz(&denormal_floats));
+            }
+        },
+        BenchConfig::new(denormal_floats.len() * iter_count)
+            .data_loads(denormal_floats.len() * iter_count)
+            .category(Category::Alu),
+        csv.as_mut(),
+    )?;
+
+    // One load per page, either aligned, split across two cache lines, or
+    // split across two pages. 64 pages is about as many as the L1 DTLB
+    // holds, so the split loads shouldn't miss it any more than the others.
+    const PAGES: usize = 64;
+    let pages = black_box(PageAligned([1; PAGES * 4096]));
+
+    #[cfg(target_arch = "x86_64")]
+    for (name, offset) in [
+        ("bench_page_split_loads_aligned", 0),
+        ("bench_page_split_loads_line_split", 56),
+        ("bench_page_split_loads_page_split", 4088),
+    ] {
+        run_benchmarks(
+            name,
+            || {
+                for _ in 0..iter_count {
+                    black_box(bench_page_split_loads(&pages, offset));
+                }
+            },
+            BenchConfig::new(PAGES * iter_count)
+                .data_loads(PAGES * iter_count)
+                .line_offset(offset % 64)
+                .category(Category::Tlb),
+            csv.as_mut(),
+        )?;
+    }
+
+    // On the heap, since a thread with a small stack may not have room for
+    // it, and page-aligned, so that the strided accesses land at the same
+    // offsets within lines and pages on every run.
+    let aligned_buffer_1_mb = AlignedBuffer::page_aligned(1_000_000)?;
+    let array_1_mb: &[u8; 1_000_000] = black_box(aligned_buffer_1_mb[..].try_into().unwrap());
 
     run_benchmarks(
         "bench_sum_array_1MB",
         || {
-            for _ in 0..SMALL_ITER_COUNT {
-                black_box(bench_sum_of_array_with_stride(&array_1_mb, 1));
+            for _ in 0..small_iter_count {
+                black_box(bench_sum_of_array_with_stride(array_1_mb, 1));
             }
         },
-        array_1_mb.len() * SMALL_ITER_COUNT,
-        Some(array_1_mb.len() * ITER_COUNT),
+        BenchConfig::new(array_1_mb.len() * small_iter_count)
+            .data_loads(array_1_mb.len() * iter_count)
+            .strided(array_1_mb.len(), 1, small_iter_count)
+            .expected_l1_miss_rate(0.0..=5.0)
+            .category(Category::L2),
+        csv.as_mut(),
     )?;
 
+    // Same as bench_sum_array_1MB, with a software prefetch one line ahead of
+    // every access. The hardware prefetcher already handles a sequential
+    // scan, so this should only add instructions, and no fewer misses.
     run_benchmarks(
-        "bench_sum_array_1MB_stride_64",
+        "bench_sum_array_1MB_software_prefetch",
         || {
-            for _ in 0..SMALL_ITER_COUNT {
-                black_box(bench_sum_of_array_with_stride(&array_1_mb, 64));
+            for _ in 0..small_iter_count {
+                black_box(bench_sum_of_array_with_stride_prefetch::<1_000_000, 64>(
+                    array_1_mb, 1,
+                ));
             }
         },
-        array_1_mb.len() * SMALL_ITER_COUNT / 64,
-        Some(array_1_mb.len() * ITER_COUNT / 64),
+        BenchConfig::new(array_1_mb.len() * small_iter_count)
+            .data_loads(array_1_mb.len() * small_iter_count)
+            .strided(array_1_mb.len(), 1, small_iter_count)
+            .category(Category::Prefetch),
+        csv.as_mut(),
     )?;
 
+    if is_selected("software_prefetch_benefit", Some(Category::Prefetch))? {
+        software_prefetch_benefit::<1_000_000, 64>(array_1_mb, 1, small_iter_count)?;
+    }
+
     run_benchmarks(
+        "bench_sum_array_1MB_stride_64",
+        || {
+            for _ in 0..small_iter_count {
+                black_box(bench_sum_of_array_with_stride(array_1_mb, 64));
+            }
+        },
+        BenchConfig::new(array_1_mb.len() * small_iter_count / 64)
+            .data_loads(array_1_mb.len() * iter_count / 64)
+            .strided(array_1_mb.len(), 64, small_iter_count)
+            .expected_l1_miss_rate(75.0..=100.0)
+            .category(Category::L2),
+        csv.as_mut(),
+    )?;
+
+    let stride_16 = run_benchmarks(
         "bench_sum_array_1MB_stride_16",
         || {
-            for _ in 0..SMALL_ITER_COUNT {
-                black_box(bench_sum_of_array_with_stride(&array_1_mb, 16));
+            for _ in 0..small_iter_count {
+                black_box(bench_sum_of_array_with_stride(array_1_mb, 16));
+            }
+        },
+        BenchConfig::new(array_1_mb.len() * small_iter_count / 16)
+            .data_loads(array_1_mb.len() * small_iter_count / 16)
+            .strided(array_1_mb.len(), 16, small_iter_count)
+            .expected_l1_miss_rate(15.0..=35.0)
+            .category(Category::L2),
+        csv.as_mut(),
+    )?;
+
+    // Same as above, but writing back every byte read. Compare the load and
+    // store misses with the read-only versions. Page-aligned like the array
+    // above, so that both see the same offsets within lines and pages.
+    let mut array_1_mb_rmw = black_box(AlignedBuffer::page_aligned(1_000_000)?);
+
+    run_benchmarks(
+        "bench_rmw_array_1MB_stride_16",
+        || {
+            for _ in 0..small_iter_count {
+                black_box(bench_rmw_array_with_stride(&mut array_1_mb_rmw, 16));
             }
         },
-        array_1_mb.len() * SMALL_ITER_COUNT / 16,
-        Some(array_1_mb.len() * SMALL_ITER_COUNT / 16),
+        BenchConfig::new(array_1_mb.len() * small_iter_count / 16)
+            .data_loads(array_1_mb.len() * small_iter_count / 16)
+            .strided(array_1_mb.len(), 16, small_iter_count)
+            .category(Category::L2),
+        csv.as_mut(),
     )?;
 
     run_benchmarks(
+        "bench_rmw_array_1MB_stride_64",
+        || {
+            for _ in 0..small_iter_count {
+                black_box(bench_rmw_array_with_stride(&mut array_1_mb_rmw, 64));
+            }
+        },
+        BenchConfig::new(array_1_mb.len() * small_iter_count / 64)
+            .data_loads(array_1_mb.len() * small_iter_count / 64)
+            .strided(array_1_mb.len(), 64, small_iter_count)
+            .category(Category::L2),
+        csv.as_mut(),
+    )?;
+
+    #[cfg(target_arch = "x86_64")]
+    if has_prefetchw() {
+        run_benchmarks(
+            "bench_rmw_array_1MB_stride_16_prefetchw_4",
+            || {
+                for _ in 0..small_iter_count {
+                    black_box(bench_rmw_array_with_stride_prefetchw::<4>(
+                        &mut array_1_mb_rmw,
+                        16,
+                    ));
+                }
+            },
+            BenchConfig::new(array_1_mb.len() * small_iter_count / 16)
+                .data_loads(array_1_mb.len() * small_iter_count / 16)
+                .strided(array_1_mb.len(), 16, small_iter_count)
+                .category(Category::Prefetch),
+            csv.as_mut(),
+        )?;
+    }
+
+    if is_selected("sweep_stride", Some(Category::Prefetch))? {
+        sweep_stride(
+            array_1_mb,
+            &[1, 2, 4, 8, 16, 32, 48, 64, 96, 128, 192, 256],
+            small_iter_count / 10,
+        )?;
+    }
+
+    // One access per line, with more and more prefetches each, to find how
+    // many the core can issue before they get in the way of the loads.
+    if is_selected("sweep_prefetches", Some(Category::Prefetch))? {
+        sweep_prefetches(array_1_mb, 64, small_iter_count)?;
+    }
+
+    // Every prefetch distance up to 32 accesses at the stride of the
+    // prefetch_4 and prefetch_1 benchmarks below, to find the best one.
+    if is_selected("sweep_prefetch_distance", Some(Category::Prefetch))? {
+        sweep_prefetch_distance(array_1_mb, 16, small_iter_count)?;
+    }
+
+    let stride_16_prefetch_4 = run_benchmarks(
         "bench_sum_array_1MB_stride_16_prefetch_4",
         || {
-            for _ in 0..SMALL_ITER_COUNT {
+            for _ in 0..small_iter_count {
                 black_box(bench_sum_of_array_with_stride_prefetch::<1_000_000, 4>(
-                    &array_1_mb,
-                    16,
+                    array_1_mb, 16,
                 ));
             }
         },
-        array_1_mb.len() * SMALL_ITER_COUNT / 16,
-        Some(array_1_mb.len() * SMALL_ITER_COUNT / 16),
+        BenchConfig::new(array_1_mb.len() * small_iter_count / 16)
+            .data_loads(array_1_mb.len() * small_iter_count / 16)
+            .strided(array_1_mb.len(), 16, small_iter_count)
+            .category(Category::Prefetch),
+        csv.as_mut(),
+    )?;
+
+    // bench_sum_array_1MB_stride_16 and the benchmark above, side by side,
+    // to tell how much the prefetch helped.
+    if let (Some(baseline), Some(variant)) = (stride_16, stride_16_prefetch_4) {
+        AbComparison {
+            name: "compare_sum_array_1MB_stride_16_prefetch_4".to_string(),
+            baseline,
+            variant,
+        }
+        .report()?;
+    }
+
+    // The same distance in cache lines at two strides. In elements, it's 16
+    // accesses ahead at stride 16, but only 4 at stride 64.
+    run_benchmarks(
+        "bench_sum_array_1MB_stride_16_prefetch_4_lines",
+        || {
+            for _ in 0..small_iter_count {
+                black_box(
+                    bench_sum_of_array_with_stride_prefetch_lines::<1_000_000, 4>(array_1_mb, 16),
+                );
+            }
+        },
+        BenchConfig::new(array_1_mb.len() * small_iter_count / 16)
+            .data_loads(array_1_mb.len() * small_iter_count / 16)
+            .strided(array_1_mb.len(), 16, small_iter_count)
+            .category(Category::Prefetch),
+        csv.as_mut(),
+    )?;
+
+    run_benchmarks(
+        "bench_sum_array_1MB_stride_64_prefetch_4_lines",
+        || {
+            for _ in 0..small_iter_count {
+                black_box(
+                    bench_sum_of_array_with_stride_prefetch_lines::<1_000_000, 4>(array_1_mb, 64),
+                );
+            }
+        },
+        BenchConfig::new(array_1_mb.len() * small_iter_count / 64)
+            .data_loads(array_1_mb.len() * small_iter_count / 64)
+            .strided(array_1_mb.len(), 64, small_iter_count)
+            .category(Category::Prefetch),
+        csv.as_mut(),
     )?;
 
     run_benchmarks(
         "bench_sum_array_1MB_stride_16_prefetch_1",
         || {
-            for _ in 0..SMALL_ITER_COUNT {
+            for _ in 0..small_iter_count {
                 black_box(bench_sum_of_array_with_stride_prefetch::<1_000_000, 1>(
-                    &array_1_mb,
-                    16,
+                    array_1_mb, 16,
+                ));
+            }
+        },
+        BenchConfig::new(array_1_mb.len() * small_iter_count / 16)
+            .data_loads(array_1_mb.len() * small_iter_count / 16)
+            .strided(array_1_mb.len(), 16, small_iter_count)
+            .category(Category::Prefetch),
+        csv.as_mut(),
+    )?;
+
+    // One prefetch per 64-byte cache line instead of one per access.
+    run_benchmarks(
+        "bench_sum_array_1MB_stride_16_prefetch_4_per_line",
+        || {
+            for _ in 0..small_iter_count {
+                black_box(bench_sum_of_array_with_stride_prefetch_every::<
+                    1_000_000,
+                    4,
+                    4,
+                >(array_1_mb, 16));
+            }
+        },
+        BenchConfig::new(array_1_mb.len() * small_iter_count / 16)
+            .data_loads(array_1_mb.len() * small_iter_count / 16)
+            .strided(array_1_mb.len(), 16, small_iter_count)
+            .category(Category::Prefetch),
+        csv.as_mut(),
+    )?;
+
+    // One access per line, prefetching the next line, at several positions
+    // within the line. Only the position changes from one to the other.
+    // Allocated straight on the heap: Box::new would build the megabyte on
+    // the stack first.
+    let line_aligned_buffer_1_mb = AlignedBuffer::new(1_000_000, 64)?;
+    let aligned_array_1_mb: &[u8; 1_000_000] =
+        black_box(line_aligned_buffer_1_mb[..].try_into().unwrap());
+
+    for offset in [0, 32, 56, 60] {
+        run_benchmarks(
+            &format!("bench_sum_array_1MB_stride_64_prefetch_1_offset_{}", offset),
+            || {
+                for _ in 0..small_iter_count {
+                    black_box(
+                        bench_sum_of_array_with_stride_prefetch_offset::<1_000_000, 1>(
+                            aligned_array_1_mb,
+                            64,
+                            offset,
+                        ),
+                    );
+                }
+            },
+            BenchConfig::new(array_1_mb.len() * small_iter_count / 64)
+                .data_loads(array_1_mb.len() * small_iter_count / 64)
+                .strided(array_1_mb.len(), 64, small_iter_count)
+                .line_offset(offset)
+                .category(Category::Prefetch),
+            csv.as_mut(),
+        )?;
+    }
+
+    run_benchmarks(
+        "bench_sum_array_1MB_stride_4_prefetch_64",
+        || {
+            for _ in 0..small_iter_count {
+                black_box(bench_sum_of_array_with_stride_prefetch::<1_000_000, 64>(
+                    array_1_mb, 4,
                 ));
             }
         },
-        array_1_mb.len() * SMALL_ITER_COUNT / 16,
-        Some(array_1_mb.len() * SMALL_ITER_COUNT / 16),
+        BenchConfig::new(array_1_mb.len() * small_iter_count / 4)
+            .data_loads(array_1_mb.len() * small_iter_count / 4)
+            .strided(array_1_mb.len(), 4, small_iter_count)
+            .category(Category::Prefetch),
+        csv.as_mut(),
+    )?;
+
+    run_benchmarks(
+        "bench_sum_array_1MB_stride_4_prefetch_64_per_line",
+        || {
+            for _ in 0..small_iter_count {
+                black_box(bench_sum_of_array_with_stride_prefetch_every::<
+                    1_000_000,
+                    64,
+                    16,
+                >(array_1_mb, 4));
+            }
+        },
+        BenchConfig::new(array_1_mb.len() * small_iter_count / 4)
+            .data_loads(array_1_mb.len() * small_iter_count / 4)
+            .strided(array_1_mb.len(), 4, small_iter_count)
+            .category(Category::Prefetch),
+        csv.as_mut(),
+    )?;
+
+    // Same prefetch distance as above, but with the prefetch issued at
+    // different points of the loop body. Compare the cycles per iteration.
+    run_benchmarks(
+        "bench_sum_array_1MB_stride_16_prefetch_4_top",
+        || {
+            for _ in 0..small_iter_count {
+                black_box(bench_sum_of_array_with_prefetch_placement::<
+                    1_000_000,
+                    4,
+                    PREFETCH_AT_TOP,
+                >(array_1_mb, 16));
+            }
+        },
+        BenchConfig::new(array_1_mb.len() * small_iter_count / 16)
+            .data_loads(array_1_mb.len() * small_iter_count / 16)
+            .strided(array_1_mb.len(), 16, small_iter_count)
+            .category(Category::Prefetch),
+        csv.as_mut(),
+    )?;
+
+    run_benchmarks(
+        "bench_sum_array_1MB_stride_16_prefetch_4_after_load",
+        || {
+            for _ in 0..small_iter_count {
+                black_box(bench_sum_of_array_with_prefetch_placement::<
+                    1_000_000,
+                    4,
+                    PREFETCH_AFTER_LOAD,
+                >(array_1_mb, 16));
+            }
+        },
+        BenchConfig::new(array_1_mb.len() * small_iter_count / 16)
+            .data_loads(array_1_mb.len() * small_iter_count / 16)
+            .strided(array_1_mb.len(), 16, small_iter_count)
+            .category(Category::Prefetch),
+        csv.as_mut(),
+    )?;
+
+    run_benchmarks(
+        "bench_sum_array_1MB_stride_16_prefetch_4_after_compute",
+        || {
+            for _ in 0..small_iter_count {
+                black_box(bench_sum_of_array_with_prefetch_placement::<
+                    1_000_000,
+                    4,
+                    PREFETCH_AFTER_COMPUTE,
+                >(array_1_mb, 16));
+            }
+        },
+        BenchConfig::new(array_1_mb.len() * small_iter_count / 16)
+            .data_loads(array_1_mb.len() * small_iter_count / 16)
+            .strided(array_1_mb.len(), 16, small_iter_count)
+            .category(Category::Prefetch),
+        csv.as_mut(),
+    )?;
+
+    run_benchmarks(
+        "bench_sum_array_1MB_stride_16_prefetch_4_split",
+        || {
+            for _ in 0..small_iter_count {
+                black_box(bench_sum_of_array_with_prefetch_placement::<
+                    1_000_000,
+                    4,
+                    PREFETCH_SPLIT,
+                >(array_1_mb, 16));
+            }
+        },
+        BenchConfig::new(array_1_mb.len() * small_iter_count / 16)
+            .data_loads(array_1_mb.len() * small_iter_count / 16)
+            .strided(array_1_mb.len(), 16, small_iter_count)
+            .category(Category::Prefetch),
+        csv.as_mut(),
+    )?;
+
+    #[cfg(target_arch = "x86_64")]
+    run_benchmarks(
+        "bench_sum_array_1MB_stride_16_prefetch_4_nta",
+        || {
+            for _ in 0..small_iter_count {
+                black_box(bench_sum_of_array_with_stride_prefetch_hint::<
+                    1_000_000,
+                    4,
+                    _MM_HINT_NTA,
+                >(array_1_mb, 16));
+            }
+        },
+        BenchConfig::new(array_1_mb.len() * small_iter_count / 16)
+            .data_loads(array_1_mb.len() * small_iter_count / 16)
+            .strided(array_1_mb.len(), 16, small_iter_count)
+            .category(Category::Prefetch),
+        csv.as_mut(),
     )?;
 
+    // This needs to be bigger than the last-level cache, so that we can
+    // compare how much T0 and NTA prefetches pollute the caches when streaming
+    // through memory. Compare the "L2 hits from L1 misses" lines.
+    // The array is filled with ones so that every page is actually backed by
+    // memory, instead of all mapping to the zero page.
+    const LARGE_ITER_COUNT: usize = 20;
+    const LARGE_ARRAY_SIZE: usize = 64 * 1024 * 1024;
+    // Filling it takes a while, so only do it for the benchmarks that need it.
+    if any_selected(&[
+        #[cfg(target_arch = "x86_64")]
+        (
+            "bench_sum_array_64MB_stride_16_prefetch_4_t0",
+            Some(Category::Prefetch),
+        ),
+        #[cfg(target_arch = "x86_64")]
+        (
+            "bench_sum_array_64MB_stride_16_prefetch_4_t1",
+            Some(Category::Prefetch),
+        ),
+        #[cfg(target_arch = "x86_64")]
+        (
+            "bench_sum_array_64MB_stride_16_prefetch_4_t2",
+            Some(Category::Prefetch),
+        ),
+        #[cfg(target_arch = "x86_64")]
+        (
+            "bench_sum_array_64MB_stride_16_prefetch_4_nta",
+            Some(Category::Prefetch),
+        ),
+        ("sweep_work", Some(Category::Dram)),
+    ])? {
+        let array_64_mb: Box<[u8; LARGE_ARRAY_SIZE]> = black_box(
+            vec![1; LARGE_ARRAY_SIZE]
+                .into_boxed_slice()
+                .try_into()
+                .unwrap(),
+        );
+        // None of it stays cached from one pass to the next, so every line of
+        // every pass should be one cache access.
+        let large_array_lines = array_64_mb.len() * LARGE_ITER_COUNT / 64;
+
+        #[cfg(target_arch = "x86_64")]
+        run_benchmarks(
+            "bench_sum_array_64MB_stride_16_prefetch_4_t0",
+            || {
+                for _ in 0..LARGE_ITER_COUNT {
+                    black_box(bench_sum_of_array_with_stride_prefetch_hint::<
+                        LARGE_ARRAY_SIZE,
+                        4,
+                        _MM_HINT_T0,
+                    >(&array_64_mb, 16));
+                }
+            },
+            BenchConfig::new(array_64_mb.len() * LARGE_ITER_COUNT / 16)
+                .data_loads(array_64_mb.len() * LARGE_ITER_COUNT / 16)
+                .strided(array_64_mb.len(), 16, LARGE_ITER_COUNT)
+                .expected_accesses(large_array_lines)
+                .category(Category::Prefetch),
+            csv.as_mut(),
+        )?;
+
+        #[cfg(target_arch = "x86_64")]
+        run_benchmarks(
+            "bench_sum_array_64MB_stride_16_prefetch_4_t1",
+            || {
+                for _ in 0..LARGE_ITER_COUNT {
+                    black_box(bench_sum_of_array_with_stride_prefetch_hint::<
+                        LARGE_ARRAY_SIZE,
+                        4,
+                        _MM_HINT_T1,
+                    >(&array_64_mb, 16));
+                }
+            },
+            BenchConfig::new(array_64_mb.len() * LARGE_ITER_COUNT / 16)
+                .data_loads(array_64_mb.len() * LARGE_ITER_COUNT / 16)
+                .strided(array_64_mb.len(), 16, LARGE_ITER_COUNT)
+                .expected_accesses(large_array_lines)
+                .category(Category::Prefetch),
+            csv.as_mut(),
+        )?;
+
+        #[cfg(target_arch = "x86_64")]
+        run_benchmarks(
+            "bench_sum_array_64MB_stride_16_prefetch_4_t2",
+            || {
+                for _ in 0..LARGE_ITER_COUNT {
+                    black_box(bench_sum_of_array_with_stride_prefetch_hint::<
+                        LARGE_ARRAY_SIZE,
+                        4,
+                        _MM_HINT_T2,
+                    >(&array_64_mb, 16));
+                }
+            },
+            BenchConfig::new(array_64_mb.len() * LARGE_ITER_COUNT / 16)
+                .data_loads(array_64_mb.len() * LARGE_ITER_COUNT / 16)
+                .strided(array_64_mb.len(), 16, LARGE_ITER_COUNT)
+                .expected_accesses(large_array_lines)
+                .category(Category::Prefetch),
+            csv.as_mut(),
+        )?;
+
+        #[cfg(target_arch = "x86_64")]
+        run_benchmarks(
+            "bench_sum_array_64MB_stride_16_prefetch_4_nta",
+            || {
+                for _ in 0..LARGE_ITER_COUNT {
+                    black_box(bench_sum_of_array_with_stride_prefetch_hint::<
+                        LARGE_ARRAY_SIZE,
+                        4,
+                        _MM_HINT_NTA,
+                    >(&array_64_mb, 16));
+                }
+            },
+            BenchConfig::new(array_64_mb.len() * LARGE_ITER_COUNT / 16)
+                .data_loads(array_64_mb.len() * LARGE_ITER_COUNT / 16)
+                .strided(array_64_mb.len(), 16, LARGE_ITER_COUNT)
+                .expected_accesses(large_array_lines)
+                .category(Category::Prefetch),
+            csv.as_mut(),
+        )?;
+
+        // Find the amount of work per byte at which streaming from memory stops
+        // being the bottleneck.
+        if is_selected("sweep_work", Some(Category::Dram))? {
+            sweep_work(&*array_64_mb, &[0, 1, 2, 4, 8, 16, 32, 64], 1)?;
+        }
+    }
+
+    // These need two CPUs to bounce cache lines between. Cycles per iteration
+    // are cycles per ownership transfer for the ping-pong, and cycles per
+    // increment for the others.
+    const PINGPONG_ROUNDS: u64 = 100_000;
+    if let [first, second, ..] = Affinity::current()?.cpus()[..] {
+        let cpus = [first, second];
+        // The callbacks can't return errors, so they keep the last one here.
+        let mut pinning = Ok(());
+
+        run_benchmarks(
+            "bench_cache_pingpong",
+            || {
+                if let Err(err) = bench_cache_pingpong(PINGPONG_ROUNDS, cpus).map(black_box) {
+                    pinning = Err(err);
+                }
+            },
+            BenchConfig::new(2 * PINGPONG_ROUNDS as usize).category(Category::L2),
+            csv.as_mut(),
+        )?;
+
+        run_benchmarks(
+            "bench_false_sharing_same_line",
+            || {
+                if let Err(err) = bench_false_sharing::<true>(PINGPONG_ROUNDS, cpus).map(black_box)
+                {
+                    pinning = Err(err);
+                }
+            },
+            BenchConfig::new(PINGPONG_ROUNDS as usize).category(Category::L2),
+            csv.as_mut(),
+        )?;
+
+        run_benchmarks(
+            "bench_false_sharing_separate_lines",
+            || {
+                if let Err(err) = bench_false_sharing::<false>(PINGPONG_ROUNDS, cpus).map(black_box)
+                {
+                    pinning = Err(err);
+                }
+            },
+            BenchConfig::new(PINGPONG_ROUNDS as usize).category(Category::L1),
+            csv.as_mut(),
+        )?;
+        pinning?;
+    }
+
+    // Throughput of atomic increments as more cores contend for the same
+    // counter, against the same number of cores with a counter each.
+    let cpus = Affinity::current()?.cpus();
+    if cpus.len() >= 2 && is_selected("sweep_atomic_contention", Some(Category::L2))? {
+        sweep_atomic_contention(&cpus, PINGPONG_ROUNDS)?;
+    }
+
+    // 16MB of nodes, visited in a random order, so that most hops miss the
+    // L2 unless the prefetch gets there first.
+    const CHAIN_LENGTH: usize = 1 << 18;
+    if any_selected(&[
+        ("bench_dependent_prefetch_off", Some(Category::Prefetch)),
+        ("bench_dependent_prefetch_on", Some(Category::Prefetch)),
+        ("bench_pointer_chase", Some(Category::Prefetch)),
+        ("bench_pointer_chase_lookahead_8", Some(Category::Prefetch)),
+        ("chase_latency", Some(Category::Dram)),
+    ])? {
+        let chain: Box<[Node; CHAIN_LENGTH]> = black_box(random_chain(SEED));
+
+        run_benchmarks(
+            "bench_dependent_prefetch_off",
+            || {
+                for _ in 0..LARGE_ITER_COUNT {
+                    black_box(bench_dependent_prefetch::<CHAIN_LENGTH, false>(
+                        &chain,
+                        CHAIN_LENGTH,
+                    ));
+                }
+            },
+            BenchConfig::new(CHAIN_LENGTH * LARGE_ITER_COUNT)
+                .data_loads(CHAIN_LENGTH * LARGE_ITER_COUNT)
+                .category(Category::Prefetch),
+            csv.as_mut(),
+        )?;
+
+        run_benchmarks(
+            "bench_dependent_prefetch_on",
+            || {
+                for _ in 0..LARGE_ITER_COUNT {
+                    black_box(bench_dependent_prefetch::<CHAIN_LENGTH, true>(
+                        &chain,
+                        CHAIN_LENGTH,
+                    ));
+                }
+            },
+            BenchConfig::new(CHAIN_LENGTH * LARGE_ITER_COUNT)
+                .data_loads(CHAIN_LENGTH * LARGE_ITER_COUNT)
+                .category(Category::Prefetch),
+            csv.as_mut(),
+        )?;
+
+        // The same chain, with no work to hide the misses behind, walked plainly
+        // and with a cursor running 8 hops ahead to prefetch for the walk.
+        run_benchmarks(
+            "bench_pointer_chase",
+            || {
+                for _ in 0..LARGE_ITER_COUNT {
+                    black_box(bench_pointer_chase(&chain, CHAIN_LENGTH));
+                }
+            },
+            BenchConfig::new(CHAIN_LENGTH * LARGE_ITER_COUNT)
+                .data_loads(CHAIN_LENGTH * LARGE_ITER_COUNT)
+                .category(Category::Prefetch),
+            csv.as_mut(),
+        )?;
+
+        run_benchmarks(
+            "bench_pointer_chase_lookahead_8",
+            || {
+                for _ in 0..LARGE_ITER_COUNT {
+                    black_box(bench_pointer_chase_lookahead::<CHAIN_LENGTH, 8>(
+                        &chain,
+                        CHAIN_LENGTH,
+                    ));
+                }
+            },
+            BenchConfig::new(CHAIN_LENGTH * LARGE_ITER_COUNT)
+                .data_loads(2 * CHAIN_LENGTH * LARGE_ITER_COUNT)
+                .category(Category::Prefetch),
+            csv.as_mut(),
+        )?;
+
+        // One chain per level of the hierarchy, on most CPUs: 16KB, 256KB, 16MB
+        // and 128MB. Each hop's latency should match the level the chain fits in.
+        if is_selected("chase_latency", Some(Category::Dram))? {
+            let topology = Topology::detect().ok();
+            const HOPS: usize = 1 << 20;
+            chase_latency(&random_chain::<{ 1 << 8 }>(SEED), HOPS, topology.as_ref())?;
+            chase_latency(&random_chain::<{ 1 << 12 }>(SEED), HOPS, topology.as_ref())?;
+            chase_latency(&chain, HOPS, topology.as_ref())?;
+            chase_latency(&random_chain::<{ 1 << 21 }>(SEED), HOPS, topology.as_ref())?;
+        }
+    }
+
+    // A chain sized for each cache level the topology reports, to get one
+    // reference load-to-use latency per level.
+    if is_selected("load_to_use_latencies", Some(Category::Dram))? {
+        if let Ok(topology) = Topology::detect() {
+            load_to_use_latencies(&topology, 1 << 22)?;
+        }
+    }
+
+    // 128MB of nodes, walked by more and more independent chains at once, to
+    // find how many misses the core can keep in flight.
+    if is_selected("sweep_mlp", Some(Category::Dram))? {
+        sweep_mlp(&random_chain::<{ 1 << 21 }>(SEED), 1 << 18)?;
+    }
+
+    // The same 128MB, reloading each line after more and more other lines,
+    // from 4KB to 32MB, to find how many lines each level holds on to.
+    if is_selected("sweep_reuse_distance", Some(Category::Dram))? {
+        let distances: Vec<usize> = (6..=19).map(|shift| 1 << shift).collect();
+        sweep_reuse_distance(&*random_chain::<{ 1 << 21 }>(SEED), &distances, 1 << 21)?;
+    }
+
+    // 1GB of 4KB pages, only ever read, so it's all backed by the zero page.
+    // With a 4KB stride, consecutive loads share a page table, whose entries
+    // the page walker finds in cache; with a 2MB stride, each load needs a
+    // page table of its own.
+    const MAPPING_SIZE: usize = 1 << 30;
+    if any_selected(&[
+        ("bench_page_walk_stress_4KB", Some(Category::Tlb)),
+        (
+            "bench_page_walk_stress_4KB_walk_cycles",
+            Some(Category::Tlb),
+        ),
+        ("bench_page_walk_stress_2MB", Some(Category::Tlb)),
+        (
+            "bench_page_walk_stress_2MB_walk_cycles",
+            Some(Category::Tlb),
+        ),
+    ])? {
+        let mapping = SmallPages::new(MAPPING_SIZE)?;
+
+        for (name, page_stride) in [
+            ("bench_page_walk_stress_4KB", 4096),
+            ("bench_page_walk_stress_2MB", 2 * 1024 * 1024),
+        ] {
+            let loads = MAPPING_SIZE.div_ceil(page_stride);
+            let repeats = (1 << 20) / loads;
+            run_benchmarks(
+                name,
+                || {
+                    for _ in 0..repeats {
+                        black_box(bench_page_walk_stress(&mapping, page_stride));
+                    }
+                },
+                BenchConfig::new(loads * repeats)
+                    .data_loads(loads * repeats)
+                    .category(Category::Tlb),
+                csv.as_mut(),
+            )?;
+
+            if is_selected(&format!("{}_walk_cycles", name), Some(Category::Tlb))? {
+                page_walk_cycles(&mapping, page_stride, repeats)?;
+            }
+        }
+    }
+
+    if any_selected(&[
+        ("bench_sequential_store_64MB", Some(Category::Dram)),
+        #[cfg(target_arch = "x86_64")]
+        ("bench_sequential_store_nt_64MB", Some(Category::Dram)),
+    ])? {
+        let mut array_64_mb_of_u64: Box<[u64; LARGE_ARRAY_SIZE / 8]> =
+            vec![1; LARGE_ARRAY_SIZE / 8]
+                .into_boxed_slice()
+                .try_into()
+                .unwrap();
+
+        run_benchmarks(
+            "bench_sequential_store_64MB",
+            || {
+                for _ in 0..LARGE_ITER_COUNT {
+                    black_box(bench_sequential_store(&mut array_64_mb_of_u64));
+                }
+            },
+            BenchConfig::new(LARGE_ARRAY_SIZE / 8 * LARGE_ITER_COUNT)
+                .bytes(LARGE_ARRAY_SIZE * LARGE_ITER_COUNT)
+                .category(Category::Dram),
+            csv.as_mut(),
+        )?;
+
+        #[cfg(target_arch = "x86_64")]
+        run_benchmarks(
+            "bench_sequential_store_nt_64MB",
+            || {
+                for _ in 0..LARGE_ITER_COUNT {
+                    black_box(bench_sequential_store_nt(&mut array_64_mb_of_u64));
+                }
+            },
+            BenchConfig::new(LARGE_ARRAY_SIZE / 8 * LARGE_ITER_COUNT)
+                .bytes(LARGE_ARRAY_SIZE * LARGE_ITER_COUNT)
+                .category(Category::Dram),
+            csv.as_mut(),
+        )?;
+    }
+
+    #[cfg(target_arch = "x86_64")]
     run_benchmarks(
         "bench_sum_array_stride_16_and_pad",
         || {
-            for _ in 0..SMALL_ITER_COUNT {
-                black_box(bench_sum_array_stride_and_pad(&array_1_mb, 16));
+            for _ in 0..small_iter_count {
+                black_box(bench_sum_array_stride_and_pad(array_1_mb, 16));
             }
         },
-        array_1_mb.len() * SMALL_ITER_COUNT / 16,
-        Some(array_1_mb.len() * SMALL_ITER_COUNT / 16),
+        BenchConfig::new(array_1_mb.len() * small_iter_count / 16)
+            .data_loads(array_1_mb.len() * small_iter_count / 16)
+            .strided(array_1_mb.len(), 16, small_iter_count)
+            .category(Category::L2),
+        csv.as_mut(),
     )?;
 
+    #[cfg(target_arch = "x86_64")]
     run_benchmarks(
         "bench_sum_array_stride_128_and_pad",
         || {
-            for _ in 0..SMALL_ITER_COUNT {
-                black_box(bench_sum_array_stride_and_pad(&array_1_mb, 128));
+            for _ in 0..small_iter_count {
+                black_box(bench_sum_array_stride_and_pad(array_1_mb, 128));
             }
         },
-        array_1_mb.len() * SMALL_ITER_COUNT / 128,
-        Some(array_1_mb.len() * SMALL_ITER_COUNT / 128),
+        BenchConfig::new(array_1_mb.len() * small_iter_count / 128)
+            .data_loads(array_1_mb.len() * small_iter_count / 128)
+            .strided(array_1_mb.len(), 128, small_iter_count)
+            .category(Category::L2),
+        csv.as_mut(),
     )?;
 
+    #[cfg(target_arch = "x86_64")]
     run_benchmarks(
         "bench_sum_array_changing_stride",
         || {
-            for _ in 0..SMALL_ITER_COUNT {
-                black_box(bench_sum_array_changing_stride(&array_1_mb));
+            for _ in 0..small_iter_count {
+                black_box(bench_sum_array_changing_stride(array_1_mb));
             }
         },
-        array_1_mb.len() * SMALL_ITER_COUNT / 128,
-        Some(array_1_mb.len() * SMALL_ITER_COUNT / 128),
+        BenchConfig::new(array_1_mb.len() * small_iter_count / 128)
+            .data_loads(array_1_mb.len() * small_iter_count / 128)
+            .category(Category::L2),
+        csv.as_mut(),
     )?;
 
-    // generate random indices
-    let array_indices: [usize; 100_000] = (0..100_000)
-        .map(|_| rand::thread_rng().gen_range(0..array_1_mb.len()))
-        .collect::<Vec<_>>()
-        .try_into()
-        .unwrap();
+    // generate random indices, or replay the ones dumped by an earlier run,
+    // to compare variants of the benchmark on the same access pattern
+    let array_indices: Box<[usize; 100_000]> = match path_from_args("--load-indices") {
+        Some(path) => read_indices(&path, array_1_mb.len())?,
+        None => random_indices(array_1_mb.len(), SEED),
+    };
+    if let Some(path) = path_from_args("--dump-indices") {
+        write_indices(&path, &*array_indices)?;
+    }
+    let checked_indices = CheckedIndices::new(&array_indices)?;
 
+    // Each load lands on a random line of an array far bigger than the L1D,
+    // so each should be one cache access.
     run_benchmarks(
         "bench_sum_array_indirect",
         || {
-            for _ in 0..SMALL_ITER_COUNT {
-                black_box(bench_sum_array_indirect(&array_1_mb, &array_indices));
+            for _ in 0..small_iter_count {
+                black_box(bench_sum_array_indirect(array_1_mb, checked_indices));
             }
         },
-        array_indices.len() * SMALL_ITER_COUNT / 64,
-        Some(array_indices.len() * SMALL_ITER_COUNT / 64),
+        BenchConfig::new(array_indices.len() * small_iter_count / 64)
+            .data_loads(array_indices.len() * small_iter_count / 64)
+            .elements(array_indices.len() * small_iter_count / 64, 1)
+            .expected_accesses(array_indices.len() * small_iter_count / 64)
+            .category(Category::L2),
+        csv.as_mut(),
     )?;
 
+    run_benchmarks(
+        "bench_sum_array_indirect_prefetch_8",
+        || {
+            for _ in 0..small_iter_count {
+                black_box(bench_sum_array_indirect_prefetch::<_, _, 8>(
+                    array_1_mb,
+                    checked_indices,
+                ));
+            }
+        },
+        BenchConfig::new(array_indices.len() * small_iter_count / 64)
+            .data_loads(array_indices.len() * small_iter_count / 64)
+            .elements(array_indices.len() * small_iter_count / 64, 1)
+            .expected_accesses(array_indices.len() * small_iter_count / 64)
+            .category(Category::L2),
+        csv.as_mut(),
+    )?;
+
+    // Unlike bench_sum_array_indirect's uniformly random indices, these go
+    // from sequential, through clusters of 64 bytes to 16MB, to random, over
+    // an array much larger than the L2.
+    if is_selected("sweep_gather_locality", Some(Category::Dram))? {
+        let array = black_box(vec![1u8; 64 << 20]);
+        sweep_gather_locality(
+            &array,
+            1 << 20,
+            &[1, 64, 4 << 10, 64 << 10, 1 << 20, 16 << 20, array.len()],
+            10,
+        )?;
+    }
+
     Ok(())
 }
@@ -0,0 +1,169 @@
+use std::hint::black_box;
+use std::ops::{Deref, DerefMut};
+
+#[cfg(target_os = "linux")]
+use perf_event::Builder;
+
+use crate::aliasing::is_intel;
+use crate::benchmarks::bench_page_walk_stress;
+#[cfg(not(unix))]
+use crate::benchmarks::AlignedBuffer;
+use crate::runner::OutputFormat;
+
+/// Raw event code for Intel's `DTLB_LOAD_MISSES.WALK_ACTIVE`, which counts
+/// the cycles during which the page walker is busy with a load's miss.
+/// Counter mask 1 makes it count cycles with at least one walk, rather than
+/// the number of walks in flight.
+const DTLB_LOAD_MISSES_WALK_ACTIVE: u64 = 0x0100_1008;
+
+/// An anonymous mapping backed by 4KB pages, even if transparent huge pages
+/// are enabled, so that striding through it exercises the page walker.
+///
+/// It's zeroed, and pages are only backed by memory once written to, so a
+/// large mapping that is only read costs page tables and not much else.
+/// Outside of Unix, it's a page-aligned [`AlignedBuffer`], which is usually
+/// lazily backed too, but may get huge pages.
+pub struct SmallPages {
+    #[cfg(unix)]
+    ptr: *mut u8,
+    #[cfg(unix)]
+    len: usize,
+    #[cfg(not(unix))]
+    buffer: AlignedBuffer,
+}
+
+impl SmallPages {
+    #[cfg(unix)]
+    pub fn new(len: usize) -> std::io::Result<SmallPages> {
+        // SAFETY: we ask for a fresh mapping, and only hand out slices of it
+        // while it's alive.
+        unsafe {
+            let ptr = libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            if ptr == libc::MAP_FAILED {
+                return Err(std::io::Error::last_os_error());
+            }
+            let mapping = SmallPages {
+                ptr: ptr as *mut u8,
+                len,
+            };
+            // Only Linux has transparent huge pages to opt out of.
+            #[cfg(target_os = "linux")]
+            if libc::madvise(ptr, len, libc::MADV_NOHUGEPAGE) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(mapping)
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn new(len: usize) -> std::io::Result<SmallPages> {
+        Ok(SmallPages {
+            buffer: AlignedBuffer::page_aligned(len)?,
+        })
+    }
+}
+
+impl Deref for SmallPages {
+    type Target = [u8];
+
+    #[cfg(unix)]
+    fn deref(&self) -> &[u8] {
+        // SAFETY: the mapping is `len` bytes long and lives as long as self.
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+
+    #[cfg(not(unix))]
+    fn deref(&self) -> &[u8] {
+        &self.buffer
+    }
+}
+
+impl DerefMut for SmallPages {
+    #[cfg(unix)]
+    fn deref_mut(&mut self) -> &mut [u8] {
+        // SAFETY: same as deref, and we have unique access to self.
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+
+    #[cfg(not(unix))]
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buffer
+    }
+}
+
+#[cfg(unix)]
+impl Drop for SmallPages {
+    fn drop(&mut self) {
+        // SAFETY: no slice of the mapping outlives self.
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.len);
+        }
+    }
+}
+
+/// Count the cycles during which the page walker is busy while running
+/// `callback`, or return `None` if the CPU doesn't have a counter for it.
+#[cfg(target_os = "linux")]
+fn count_walk_cycles(mut callback: impl FnMut()) -> Option<u64> {
+    if !is_intel() {
+        return None;
+    }
+    let mut counter = Builder::new()
+        .raw_config(DTLB_LOAD_MISSES_WALK_ACTIVE)
+        .build()
+        .ok()?;
+    counter.enable().ok()?;
+    callback();
+    counter.disable().ok()?;
+    counter.read().ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn count_walk_cycles(_callback: impl FnMut()) -> Option<u64> {
+    None
+}
+
+/// Count the cycles the page walker spends on [`bench_page_walk_stress`]
+/// over `buffer` with `page_stride`, per load, and print them.
+///
+/// Returns `None` if the CPU doesn't have a counter for it.
+pub fn page_walk_cycles(
+    buffer: &[u8],
+    page_stride: usize,
+    repeats: usize,
+) -> std::io::Result<Option<f64>> {
+    let run = || {
+        for _ in 0..repeats {
+            black_box(bench_page_walk_stress(buffer, page_stride));
+        }
+    };
+
+    // Fault every page in before counting anything.
+    run();
+
+    let walk_cycles = count_walk_cycles(run);
+
+    let loads = (buffer.len().div_ceil(page_stride) * repeats) as f64;
+    let per_load = walk_cycles.map(|cycles| cycles as f64 / loads);
+    if OutputFormat::from_args()? != OutputFormat::Table {
+        return Ok(per_load);
+    }
+    println!("====================================================================");
+    match per_load {
+        Some(per_load) => println!(
+            "Page walk cycles per load with a {} byte stride: {:.3}",
+            page_stride, per_load
+        ),
+        None => println!("Page walk cycles per load: (no counter on this CPU)"),
+    }
+    println!();
+
+    Ok(per_load)
+}
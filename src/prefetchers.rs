@@ -0,0 +1,259 @@
+use std::fs::{File, OpenOptions};
+#[cfg(unix)]
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+use serde_json::json;
+
+use crate::affinity::Affinity;
+use crate::aliasing::is_intel;
+use crate::runner::{add_exit_hook, option_value, remove_exit_hook, OutputFormat, SCHEMA_VERSION};
+
+/// Where Linux exposes the MSRs of each CPU, with the `msr` module loaded.
+pub const MSR_ROOT: &str = "/dev/cpu";
+
+/// The MSR whose low four bits each disable one of the hardware prefetchers
+/// of Intel cores since Nehalem, `MISC_FEATURE_CONTROL`.
+pub const MSR_PREFETCH_CONTROL: u64 = 0x1a4;
+
+/// One of the hardware prefetchers `MSR_PREFETCH_CONTROL` can disable.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Prefetcher {
+    /// The L2 streamer, which follows ascending and descending streams of
+    /// accesses within a page.
+    Streamer,
+    /// The L2 adjacent line prefetcher, which fetches the other half of each
+    /// 128-byte aligned pair of lines.
+    Adjacent,
+    /// The L1D next-line prefetcher.
+    Dcu,
+    /// The L1D prefetcher that follows the stride of each load instruction.
+    DcuIp,
+}
+
+impl Prefetcher {
+    pub const ALL: [Prefetcher; 4] = [
+        Prefetcher::Streamer,
+        Prefetcher::Adjacent,
+        Prefetcher::Dcu,
+        Prefetcher::DcuIp,
+    ];
+
+    /// The bit of `MSR_PREFETCH_CONTROL` that disables this prefetcher.
+    pub fn disable_bit(self) -> u64 {
+        match self {
+            Prefetcher::Streamer => 1 << 0,
+            Prefetcher::Adjacent => 1 << 1,
+            Prefetcher::Dcu => 1 << 2,
+            Prefetcher::DcuIp => 1 << 3,
+        }
+    }
+
+    /// The name `--prefetchers` knows this prefetcher by.
+    pub fn name(self) -> &'static str {
+        match self {
+            Prefetcher::Streamer => "streamer",
+            Prefetcher::Adjacent => "adjacent",
+            Prefetcher::Dcu => "dcu",
+            Prefetcher::DcuIp => "dcu-ip",
+        }
+    }
+
+    /// Parse a comma-separated list of prefetcher names, eg
+    /// "streamer,adjacent". "none" enables none of them, "all" all of them.
+    pub fn parse_list(list: &str) -> Option<Vec<Prefetcher>> {
+        match list {
+            "none" => return Some(Vec::new()),
+            "all" => return Some(Prefetcher::ALL.to_vec()),
+            _ => {}
+        }
+        list.split(',')
+            .map(|name| {
+                Prefetcher::ALL
+                    .into_iter()
+                    .find(|prefetcher| prefetcher.name() == name)
+            })
+            .collect()
+    }
+}
+
+/// The value to write to `MSR_PREFETCH_CONTROL` to enable exactly `enabled`,
+/// keeping the bits of `previous` that don't control prefetchers.
+pub fn prefetch_control(previous: u64, enabled: &[Prefetcher]) -> u64 {
+    Prefetcher::ALL
+        .into_iter()
+        .filter(|prefetcher| !enabled.contains(prefetcher))
+        .fold(previous & !0xf, |value, prefetcher| {
+            value | prefetcher.disable_bit()
+        })
+}
+
+/// Read the MSR `register` from `msr`, an MSR file.
+#[cfg(unix)]
+fn read_msr(msr: &File, register: u64) -> std::io::Result<u64> {
+    let mut bytes = [0; 8];
+    msr.read_exact_at(&mut bytes, register)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// Write `value` to the MSR `register` of `msr`, an MSR file.
+#[cfg(unix)]
+fn write_msr(msr: &File, register: u64, value: u64) -> std::io::Result<()> {
+    msr.write_all_at(&value.to_le_bytes(), register)
+}
+
+// There are no MSR files outside of Linux, so opening one already failed.
+#[cfg(not(unix))]
+fn read_msr(_msr: &File, _register: u64) -> std::io::Result<u64> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(not(unix))]
+fn write_msr(_msr: &File, _register: u64, _value: u64) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+/// The CPUs we run on, with only some of their hardware prefetchers enabled.
+/// Dropping it puts back the settings they had before.
+///
+/// Like [`crate::frequency::PinnedFrequency`], the previous settings are
+/// also restored if the watchdog exits the process, but not if it's killed.
+pub struct EnabledPrefetchers {
+    pub enabled: Vec<Prefetcher>,
+    /// The MSR file of each CPU, and what its `MSR_PREFETCH_CONTROL` was
+    /// before we changed it.
+    previous: Vec<(PathBuf, u64)>,
+    /// The [`add_exit_hook`] restoring them, once every CPU is set.
+    exit_hook: Option<u64>,
+}
+
+impl EnabledPrefetchers {
+    /// Enable only `enabled` on every CPU of `cpus`, through the MSR files
+    /// under `root`.
+    pub fn set(
+        root: &Path,
+        cpus: &[usize],
+        enabled: &[Prefetcher],
+    ) -> std::io::Result<EnabledPrefetchers> {
+        let mut set = EnabledPrefetchers {
+            enabled: enabled.to_vec(),
+            previous: Vec::new(),
+            exit_hook: None,
+        };
+        for cpu in cpus {
+            let path = root.join(format!("{}/msr", cpu));
+            let msr = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&path)
+                .map_err(|err| {
+                    std::io::Error::new(
+                        err.kind(),
+                        format!("can't open the MSRs of CPU {}: {}", cpu, err),
+                    )
+                })?;
+            let previous = read_msr(&msr, MSR_PREFETCH_CONTROL)?;
+            write_msr(
+                &msr,
+                MSR_PREFETCH_CONTROL,
+                prefetch_control(previous, enabled),
+            )?;
+            set.previous.push((path, previous));
+        }
+        let previous = set.previous.clone();
+        set.exit_hook = Some(add_exit_hook(move || restore_prefetchers(&previous)));
+        Ok(set)
+    }
+
+    /// Enable only the prefetchers passed with `--prefetchers`, if any, on
+    /// the CPUs we may run on.
+    pub fn from_args() -> std::io::Result<Option<EnabledPrefetchers>> {
+        let Some(value) = option_value("--prefetchers") else {
+            return Ok(None);
+        };
+        let Some(enabled) = Prefetcher::parse_list(&value) else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "invalid prefetchers {:?}, expected a list of {}, or none or all",
+                    value,
+                    Prefetcher::ALL.map(Prefetcher::name).join(", ")
+                ),
+            ));
+        };
+        if !is_intel() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "only Intel CPUs have MSR 0x1a4",
+            ));
+        }
+        let cpus = Affinity::current()?.cpus();
+        EnabledPrefetchers::set(Path::new(MSR_ROOT), &cpus, &enabled).map(Some)
+    }
+
+    /// Print which prefetchers are enabled, as a line or, with
+    /// `--format jsonl`, as a line of metadata.
+    pub fn report(&self) -> std::io::Result<()> {
+        let names: Vec<&str> = self.enabled.iter().map(|p| p.name()).collect();
+        match OutputFormat::from_args()? {
+            OutputFormat::Table => {
+                let disabled: Vec<&str> = Prefetcher::ALL
+                    .into_iter()
+                    .filter(|prefetcher| !self.enabled.contains(prefetcher))
+                    .map(Prefetcher::name)
+                    .collect();
+                println!(
+                    "Hardware prefetchers enabled: {}, disabled: {}",
+                    if names.is_empty() {
+                        "none".to_string()
+                    } else {
+                        names.join(", ")
+                    },
+                    if disabled.is_empty() {
+                        "none".to_string()
+                    } else {
+                        disabled.join(", ")
+                    },
+                );
+            }
+            OutputFormat::JsonLines => {
+                println!(
+                    "{}",
+                    json!({
+                        "schema_version": SCHEMA_VERSION,
+                        "metadata": { "prefetchers": names },
+                    })
+                );
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+impl Drop for EnabledPrefetchers {
+    fn drop(&mut self) {
+        if let Some(exit_hook) = self.exit_hook {
+            remove_exit_hook(exit_hook);
+        }
+        restore_prefetchers(&self.previous);
+    }
+}
+
+/// Write back each MSR file's previous `MSR_PREFETCH_CONTROL`, the last one
+/// changed first.
+fn restore_prefetchers(previous: &[(PathBuf, u64)]) {
+    for (path, previous) in previous.iter().rev() {
+        let restored = OpenOptions::new()
+            .write(true)
+            .open(path)
+            .and_then(|msr| write_msr(&msr, MSR_PREFETCH_CONTROL, *previous));
+        if let Err(err) = restored {
+            eprintln!(
+                "Warning: can't restore the prefetchers in {}: {}",
+                path.display(),
+                err
+            );
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use std::hint::black_box;
+
+use crate::benchmarks::{
+    bench_branchy, bench_loop_overhead_unrolled, bench_sum_of_array_with_stride_slice,
+    random_bytes, LOOP_OVERHEAD_OPS, SEED, UNROLLED_BLOCKS,
+};
+use crate::error::BenchError;
+use crate::latency::{LoadToUse, MemoryLevel};
+use crate::runner::measure;
+use crate::topology::{format_size, Topology};
+
+/// The smallest and largest buffer [`MachineProfile::measure`] uses to hit
+/// DRAM, for the latency and bandwidth.
+const DRAM_WORKING_SET: (usize, usize) = (256 << 20, 1 << 30);
+
+/// The key parameters of a machine, each measured by one of the benchmarks
+/// of the suite, for `--profile`.
+#[derive(Clone, Debug)]
+pub struct MachineProfile {
+    pub topology: Topology,
+    /// Instructions per cycle on independent adds, with no loop overhead.
+    pub peak_ipc: f64,
+    /// The load-to-use latency of each level, DRAM included.
+    pub latencies: Vec<LoadToUse>,
+    /// Single-threaded read bandwidth from DRAM, in GB/s.
+    pub bandwidth: f64,
+    /// Cycles per line of a sequential read from DRAM, with the hardware
+    /// prefetcher's help.
+    pub cycles_per_streamed_line: f64,
+    /// Cycles lost to each mispredicted branch.
+    pub mispredict_penalty: f64,
+}
+
+impl MachineProfile {
+    /// Run the curated subset of benchmarks behind each field.
+    pub fn measure(topology: &Topology) -> Result<MachineProfile, BenchError> {
+        let counts = measure(|| {
+            for _ in 0..10_000 {
+                black_box(bench_loop_overhead_unrolled());
+            }
+        })?;
+        let peak_ipc = counts.instructions as f64 / counts.cycles as f64;
+
+        let mut latencies = Vec::new();
+        for level in [MemoryLevel::L1, MemoryLevel::L2, MemoryLevel::L3] {
+            if let Some(working_set) = level.working_set(topology) {
+                latencies.push(LoadToUse::measure(level, working_set, 1 << 22)?);
+            }
+        }
+        // Well past the last level cache, so that almost every load misses.
+        let largest_cache = topology
+            .caches
+            .iter()
+            .map(|cache| cache.size)
+            .max()
+            .unwrap_or(0);
+        let dram_working_set = (largest_cache * 4).clamp(DRAM_WORKING_SET.0, DRAM_WORKING_SET.1);
+        latencies.push(LoadToUse::measure(
+            MemoryLevel::Dram,
+            dram_working_set,
+            1 << 20,
+        )?);
+
+        // One load per line, so that the loop is bound by the memory, not by
+        // the sum.
+        let mut array = Vec::new();
+        array
+            .try_reserve_exact(dram_working_set)
+            .map_err(|_| BenchError::AllocationFailed {
+                bytes: dram_working_set,
+            })?;
+        array.resize(dram_working_set, 1u8);
+        let counts = measure(|| {
+            black_box(bench_sum_of_array_with_stride_slice(&array, 64));
+        })?;
+        let bandwidth = array.len() as f64 / counts.nanoseconds() as f64;
+        let cycles_per_streamed_line = counts.cycles as f64 / (array.len() / 64) as f64;
+        drop(array);
+
+        // Random bytes mispredict half of their branches, sorted bytes almost
+        // none, for the same work.
+        let random = random_bytes(1 << 16, SEED);
+        let mut sorted = random.clone();
+        sorted.sort_unstable();
+        let random_counts = measure(|| {
+            black_box(bench_branchy(&random));
+        })?;
+        let sorted_counts = measure(|| {
+            black_box(bench_branchy(&sorted));
+        })?;
+        let mispredict_penalty =
+            (random_counts.cycles as f64 - sorted_counts.cycles as f64) / (random.len() / 2) as f64;
+
+        Ok(MachineProfile {
+            topology: topology.clone(),
+            peak_ipc,
+            latencies,
+            bandwidth,
+            cycles_per_streamed_line,
+            mispredict_penalty,
+        })
+    }
+
+    /// How many times faster the hardware prefetcher streams a line from
+    /// DRAM than a dependent load fetches one, or `None` without a DRAM
+    /// latency.
+    pub fn prefetch_speedup(&self) -> Option<f64> {
+        let dram = self
+            .latencies
+            .iter()
+            .find(|latency| latency.level == MemoryLevel::Dram)?;
+        Some(dram.cycles / self.cycles_per_streamed_line)
+    }
+
+    /// Print the profile as a single page.
+    pub fn print(&self) {
+        println!("====================================================================");
+        println!("Machine profile: {}", self.topology.model);
+        println!(
+            "{} cores, {} threads",
+            self.topology.cores, self.topology.threads
+        );
+        println!();
+        println!(
+            "Peak IPC:                  {:.2} ({} independent adds per block, {} blocks)",
+            self.peak_ipc, LOOP_OVERHEAD_OPS, UNROLLED_BLOCKS
+        );
+        println!("Load-to-use latency:");
+        for latency in &self.latencies {
+            println!(
+                "  {:<6} {:>8} {:>10.2} cycles {:>8.2} ns",
+                format!("{:?}", latency.level),
+                format_size(latency.working_set),
+                latency.cycles,
+                latency.nanoseconds
+            );
+        }
+        println!(
+            "Memory bandwidth:          {:.2} GB/s (one thread, reads)",
+            self.bandwidth
+        );
+        println!(
+            "Branch mispredict penalty: {:.1} cycles",
+            self.mispredict_penalty
+        );
+        if let Some(speedup) = self.prefetch_speedup() {
+            println!(
+                "Prefetch effectiveness:    {:.1}x ({:.2} cycles per streamed line, vs one DRAM latency)",
+                speedup, self.cycles_per_streamed_line
+            );
+        }
+        println!();
+    }
+}
@@ -1,7 +1,27 @@
+use std::arch::asm;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+#[cfg(target_os = "linux")]
 use perf_event::events::{Cache, CacheOp, CacheResult, Hardware, Software, WhichCache};
-use perf_event::{Builder, Group};
+#[cfg(target_os = "linux")]
+use perf_event::{Builder, Counter, Group};
+use serde::Serialize;
+use serde_json::json;
 use thousands::Separable;
 
+use crate::affinity::{current_cpu, Affinity};
+use crate::aliasing::{cpu_family_model, cpu_vendor};
+use crate::environment::{Environment, EnvironmentChange};
+use crate::error::{is_unsupported_event, BenchError};
+use crate::topology::Topology;
+
 /*
 #[repr(u32)]
 pub enum Hardware {
@@ -62,264 +82,3609 @@ pub enum CacheResult {
 }
 */
 
-pub fn run_benchmarks(
+/// The raw value of every counter we collect, over one benchmark run.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Counts {
+    pub task_clock: u64,
+    /// Nanoseconds elapsed around the measured region, according to
+    /// [`Instant`]. Unlike `task_clock`, this keeps running while the thread
+    /// is descheduled.
+    pub wall_clock: u64,
+    pub context_switches: u64,
+    pub cpu_migrations: u64,
+    pub page_faults: u64,
+    pub cycles: u64,
+    /// Cycles at the CPU's fixed reference frequency, unaffected by turbo
+    /// and throttling. Zero if the CPU can't count them.
+    pub ref_cycles: u64,
+    pub instructions: u64,
+    pub cache_accesses: u64,
+    pub l1_cache_loads: u64,
+    pub l1_cache_misses: u64,
+    pub l1_cache_prefetches: u64,
+    /// Zero if the CPU can't count L1D store misses, which most can't.
+    pub l1_cache_store_misses: u64,
+    pub l1i_cache_misses: u64,
+    /// Zero if the CPU can't count DTLB load misses.
+    pub dtlb_load_misses: u64,
+    pub l2_cache_accesses_from_dc_misses: u64,
+    pub l2_cache_hits_from_dc_misses: u64,
+    /// The L2 requests for ownership that L1D store misses sent. Zero if we
+    /// don't know the event on this CPU.
+    pub l2_cache_accesses_from_store_misses: u64,
+    /// How long each event was enabled, and actually counting, in
+    /// nanoseconds, keyed like [`Counts::values`]. The second is less than
+    /// the first if the PMU was multiplexed.
+    #[serde(skip)]
+    pub times: BTreeMap<String, (u64, u64)>,
+    /// Which group counted each event, keyed like [`Counts::values`]. Each
+    /// group counts a separate run of the benchmark.
+    #[serde(skip)]
+    pub groups: BTreeMap<String, usize>,
+    /// The temperature and frequency around the run, with `--environment`.
+    #[serde(skip)]
+    pub environment: Option<EnvironmentChange>,
+    /// Whether every group counted the same run, with `--single-run`.
+    #[serde(skip)]
+    pub single_run: bool,
+    /// Why the events that weren't counted couldn't be, keyed like
+    /// [`Counts::values`].
+    #[serde(skip)]
+    pub unavailable: BTreeMap<String, String>,
+}
+
+/// One event's value, and how long it was enabled and actually counting, in
+/// nanoseconds.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Reading {
+    /// What the counter read, only counting while the event was running.
+    pub value: u64,
+    pub time_enabled: u64,
+    pub time_running: u64,
+    /// The index of the group that counted the event.
+    pub group: usize,
+}
+
+impl Reading {
+    /// The value extrapolated to the whole time the event was enabled, like
+    /// `perf stat` does, assuming the event occurred at the same rate while
+    /// the PMU was counting others. Zero if it never got to count.
+    pub fn scaled_value(&self) -> u64 {
+        if self.time_running == 0 || self.time_running >= self.time_enabled {
+            return self.value;
+        }
+        (self.value as u128 * self.time_enabled as u128 / self.time_running as u128) as u64
+    }
+}
+
+/// Every event's reading over one benchmark run, keyed like
+/// [`Counts::values`], merged from however many groups the PMU needed to
+/// count them all.
+#[derive(Clone, Debug, Default)]
+pub struct BenchResult {
+    pub wall_clock: u64,
+    pub readings: BTreeMap<String, Reading>,
+    /// Whether every group counted the same run, see
+    /// [`CounterSet::measure_once`].
+    pub single_run: bool,
+    /// Why the events that couldn't be opened couldn't be, keyed like
+    /// [`Counts::values`].
+    pub unavailable: BTreeMap<String, String>,
+}
+
+impl BenchResult {
+    /// The value of the event called `name`, scaled for multiplexing, or
+    /// zero if it wasn't counted.
+    pub fn value(&self, name: &str) -> u64 {
+        self.readings.get(name).map_or(0, Reading::scaled_value)
+    }
+}
+
+/// The raw event codes that count the L2 accesses and the L2 hits caused by
+/// L1D misses, on a CPU of `vendor`, family and model, as cpuid reports them,
+/// or `None` if we don't know them for that CPU.
+pub fn l2_miss_events_for(vendor: &[u8], family: u32, model: u32) -> Option<(u64, u64)> {
+    match (vendor, family, model) {
+        // Zen's L2RequestG1 with every data cache request, and
+        // L2CacheReqStat with the hits among those.
+        (b"AuthenticAMD", 0x17.., _) => Some((0xc860, 0x7064)),
+        // L2_RQSTS.ALL_DEMAND_DATA_RD and L2_RQSTS.DEMAND_DATA_RD_HIT, whose
+        // unit mask changed with Ice Lake.
+        (b"GenuineIntel", 6, 0x4e | 0x5e | 0x55 | 0x8e | 0x9e | 0xa5 | 0xa6) => {
+            Some((0xe124, 0x4124))
+        }
+        (
+            b"GenuineIntel",
+            6,
+            0x6a | 0x6c | 0x7d | 0x7e | 0x8c | 0x8d | 0x8f | 0x97 | 0x9a | 0xa7 | 0xb7 | 0xba
+            | 0xbf | 0xcf,
+        ) => Some((0xe124, 0xc124)),
+        _ => None,
+    }
+}
+
+/// [`l2_miss_events_for`] the CPU we're running on.
+pub fn l2_miss_events() -> Option<(u64, u64)> {
+    let (family, model) = cpu_family_model();
+    l2_miss_events_for(&cpu_vendor(), family, model)
+}
+
+/// The raw event code that counts the L2 requests for ownership caused by
+/// L1D store misses, on a CPU of `vendor`, family and model, or `None` if we
+/// don't know it for that CPU.
+pub fn l2_store_miss_event_for(vendor: &[u8], family: u32, model: u32) -> Option<u64> {
+    match (vendor, family, model) {
+        // Zen's L2RequestG1 with only the RdBlkX requests, which stores send.
+        (b"AuthenticAMD", 0x17.., _) => Some(0x4060),
+        // L2_RQSTS.ALL_RFO, the same from Skylake on.
+        (
+            b"GenuineIntel",
+            6,
+            0x4e | 0x5e | 0x55 | 0x8e | 0x9e | 0xa5 | 0xa6 | 0x6a | 0x6c | 0x7d | 0x7e | 0x8c
+            | 0x8d | 0x8f | 0x97 | 0x9a | 0xa7 | 0xb7 | 0xba | 0xbf | 0xcf,
+        ) => Some(0xe224),
+        _ => None,
+    }
+}
+
+/// [`l2_store_miss_event_for`] the CPU we're running on.
+pub fn l2_store_miss_event() -> Option<u64> {
+    let (family, model) = cpu_family_model();
+    l2_store_miss_event_for(&cpu_vendor(), family, model)
+}
+
+/// How much longer than the task clock the wall clock can run before we
+/// consider a benchmark preempted, as a fraction of the task clock.
+pub const PREEMPTION_THRESHOLD: f64 = 0.05;
+
+/// The fraction of the run two events must have counted together for, for
+/// the table to print a ratio between them.
+pub const MIN_OVERLAP: f64 = 0.5;
+
+/// How far the measured cache accesses can be from
+/// [`BenchConfig::expected_accesses`] before we warn, as a fraction of the
+/// expected count.
+pub const ACCESS_TOLERANCE: f64 = 0.2;
+
+/// How much of the run an event can miss to multiplexing before we warn that
+/// its count was extrapolated, as a fraction of the run.
+pub const MULTIPLEXING_TOLERANCE: f64 = 0.03;
+
+impl Counts {
+    /// Every counter's value, keyed by its field name, which is stable
+    /// across runs and CPUs even for raw events.
+    pub fn values(&self) -> Vec<(String, u64)> {
+        vec![
+            ("task_clock".to_string(), self.task_clock),
+            ("wall_clock".to_string(), self.wall_clock),
+            ("context_switches".to_string(), self.context_switches),
+            ("cpu_migrations".to_string(), self.cpu_migrations),
+            ("page_faults".to_string(), self.page_faults),
+            ("cycles".to_string(), self.cycles),
+            ("ref_cycles".to_string(), self.ref_cycles),
+            ("instructions".to_string(), self.instructions),
+            ("cache_accesses".to_string(), self.cache_accesses),
+            ("l1_cache_loads".to_string(), self.l1_cache_loads),
+            ("l1_cache_misses".to_string(), self.l1_cache_misses),
+            ("l1_cache_prefetches".to_string(), self.l1_cache_prefetches),
+            (
+                "l1_cache_store_misses".to_string(),
+                self.l1_cache_store_misses,
+            ),
+            ("l1i_cache_misses".to_string(), self.l1i_cache_misses),
+            ("dtlb_load_misses".to_string(), self.dtlb_load_misses),
+            (
+                "l2_cache_accesses_from_dc_misses".to_string(),
+                self.l2_cache_accesses_from_dc_misses,
+            ),
+            (
+                "l2_cache_hits_from_dc_misses".to_string(),
+                self.l2_cache_hits_from_dc_misses,
+            ),
+            (
+                "l2_cache_accesses_from_store_misses".to_string(),
+                self.l2_cache_accesses_from_store_misses,
+            ),
+        ]
+    }
+
+    /// The typed view of `result`. Events it doesn't have read zero.
+    pub fn from_result(result: &BenchResult) -> Counts {
+        Counts {
+            task_clock: result.value("task_clock"),
+            wall_clock: result.wall_clock,
+            context_switches: result.value("context_switches"),
+            cpu_migrations: result.value("cpu_migrations"),
+            page_faults: result.value("page_faults"),
+            cycles: result.value("cycles"),
+            ref_cycles: result.value("ref_cycles"),
+            instructions: result.value("instructions"),
+            cache_accesses: result.value("cache_accesses"),
+            l1_cache_loads: result.value("l1_cache_loads"),
+            l1_cache_misses: result.value("l1_cache_misses"),
+            l1_cache_prefetches: result.value("l1_cache_prefetches"),
+            l1_cache_store_misses: result.value("l1_cache_store_misses"),
+            l1i_cache_misses: result.value("l1i_cache_misses"),
+            dtlb_load_misses: result.value("dtlb_load_misses"),
+            l2_cache_accesses_from_dc_misses: result.value("l2_cache_accesses_from_dc_misses"),
+            l2_cache_hits_from_dc_misses: result.value("l2_cache_hits_from_dc_misses"),
+            l2_cache_accesses_from_store_misses: result
+                .value("l2_cache_accesses_from_store_misses"),
+            times: result
+                .readings
+                .iter()
+                .map(|(name, reading)| (name.clone(), (reading.time_enabled, reading.time_running)))
+                .collect(),
+            groups: result
+                .readings
+                .iter()
+                .map(|(name, reading)| (name.clone(), reading.group))
+                .collect(),
+            environment: None,
+            single_run: result.single_run,
+            unavailable: result.unavailable.clone(),
+        }
+    }
+
+    /// Whether the event keyed `key` was counted, as opposed to reading zero
+    /// because its counter couldn't be opened. The wall clock is always
+    /// measured, and counts we don't have times for, like ones built by
+    /// hand, are assumed to have been counted.
+    pub fn counted(&self, key: &str) -> bool {
+        key == "wall_clock" || self.times.is_empty() || self.times.contains_key(key)
+    }
+
+    /// Whether the events keyed `a` and `b` were counted on the same run of
+    /// the benchmark. If not, ratios between them combine two runs, which may
+    /// have started from different cache or frequency states. Events we
+    /// don't know the group of are assumed to be on the same run.
+    pub fn same_run(&self, a: &str, b: &str) -> bool {
+        if self.single_run {
+            return true;
+        }
+        match (self.groups.get(a), self.groups.get(b)) {
+            (Some(a), Some(b)) => a == b,
+            _ => true,
+        }
+    }
+
+    /// The fraction of the time the event keyed `key` was enabled during
+    /// which it was actually counting, between 0 and 1. Below 1, the kernel
+    /// multiplexed it with other events. Events we don't have times for are
+    /// assumed to have counted the whole time.
+    pub fn running_fraction(&self, key: &str) -> f64 {
+        match self.times.get(key) {
+            Some(&(enabled, running)) if enabled > 0 => running as f64 / enabled as f64,
+            _ => 1.0,
+        }
+    }
+
+    /// The fraction of the run during which the events keyed `a` and `b`
+    /// were both counting, between 0 and 1. The kernel schedules the events
+    /// of a group together, so those overlap for as long as the least
+    /// running one counted. Events counted on different runs never overlap.
+    /// For others, this is the least they could have overlapped.
+    pub fn overlap(&self, a: &str, b: &str) -> f64 {
+        let (running_a, running_b) = (self.running_fraction(a), self.running_fraction(b));
+        match (self.groups.get(a), self.groups.get(b)) {
+            (Some(group_a), Some(group_b)) if group_a == group_b => running_a.min(running_b),
+            _ if !self.same_run(a, b) => 0.0,
+            _ => (running_a + running_b - 1.0).max(0.0),
+        }
+    }
+
+    /// Whether the events keyed `a` and `b` counted together for at least
+    /// [`MIN_OVERLAP`] of the run, so that a ratio between them means
+    /// something.
+    pub fn comparable(&self, a: &str, b: &str) -> bool {
+        self.overlap(a, b) >= MIN_OVERLAP
+    }
+
+    /// Whether `value`, which should be nonzero for any run that executed
+    /// instructions, reads zero anyway. This happens on some VMs, where
+    /// hardware counters can be opened but never count anything.
+    pub fn looks_inactive(&self, value: u64) -> bool {
+        value == 0 && (self.cycles > 0 || self.instructions > 0)
+    }
+
+    /// How long the run took, in nanoseconds. Without counters, the wall
+    /// clock is the only time we have.
+    pub fn nanoseconds(&self) -> u64 {
+        if self.counted("task_clock") {
+            self.task_clock
+        } else {
+            self.wall_clock
+        }
+    }
+
+    /// L1D misses, in percent of L1D loads.
+    pub fn l1_miss_rate(&self) -> f64 {
+        self.l1_cache_misses as f64 / self.l1_cache_loads as f64 * 100.0
+    }
+
+    /// Whether the L1D miss rate is within `expected`, in percent, or `None`
+    /// if the L1D counters look inactive or didn't count together for long
+    /// enough, and we can't tell.
+    pub fn l1_miss_rate_within(&self, expected: &RangeInclusive<f64>) -> Option<bool> {
+        if self.looks_inactive(self.l1_cache_loads)
+            || !self.comparable("l1_cache_misses", "l1_cache_loads")
+        {
+            return None;
+        }
+        Some(expected.contains(&self.l1_miss_rate()))
+    }
+
+    /// Which events the PMU multiplexed for more than
+    /// [`MULTIPLEXING_TOLERANCE`] of the run, with the fraction of the run
+    /// each one was counting, least counted first.
+    pub fn multiplexed(&self) -> Vec<(String, f64)> {
+        let mut multiplexed: Vec<(String, f64)> = self
+            .times
+            .keys()
+            .map(|key| (key.clone(), self.running_fraction(key)))
+            .filter(|&(_, running_fraction)| running_fraction < 1.0 - MULTIPLEXING_TOLERANCE)
+            .collect();
+        multiplexed.sort_by(|(_, a), (_, b)| a.total_cmp(b));
+        multiplexed
+    }
+
+    /// The measured cache accesses, as a multiple of `expected`, or `None` if
+    /// the counter couldn't be opened or looks inactive.
+    ///
+    /// `cache_accesses` is perf's generic "cache references" event, which
+    /// most CPUs map to last level cache references rather than L1D loads.
+    pub fn accesses_ratio(&self, expected: usize) -> Option<f64> {
+        if !self.counted("cache_accesses") || self.looks_inactive(self.cache_accesses) {
+            return None;
+        }
+        Some(self.cache_accesses as f64 / expected as f64)
+    }
+
+    /// Whether wall-clock time exceeds task-clock time by more than
+    /// [`PREEMPTION_THRESHOLD`], meaning the thread spent part of the run
+    /// descheduled and the counts may be unreliable.
+    pub fn looks_preempted(&self) -> bool {
+        self.wall_clock as f64 > self.task_clock as f64 * (1.0 + PREEMPTION_THRESHOLD)
+    }
+}
+
+/// The header line for the CSV output format.
+pub fn csv_header() -> String {
+    "benchmark,task_clock_ms,cycles,instructions,ipc,l1_loads,l1_misses,l1_miss_pct,\
+     l1_prefetches,l2_accesses,l2_hits"
+        .to_string()
+}
+
+/// One line of the CSV output format. Counts are written as plain integers,
+/// without the separators used in the table. Events that couldn't be counted
+/// are left empty, and so are ratios that are undefined, because a count is
+/// zero or wasn't counted. Without counters, the task clock column has the
+/// wall clock, see [`Counts::nanoseconds`].
+pub fn csv_row(name: &str, counts: &Counts) -> String {
+    let count = |key: &str, value: u64| {
+        if counts.counted(key) {
+            value.to_string()
+        } else {
+            String::new()
+        }
+    };
+    let ratio = |keys: [&str; 2], ratio: f64| {
+        if ratio.is_finite() && keys.iter().all(|key| counts.counted(key)) {
+            format!("{:.3}", ratio)
+        } else {
+            String::new()
+        }
+    };
+    [
+        name.to_string(),
+        format!("{:.3}", counts.nanoseconds() as f64 / 1e6),
+        count("cycles", counts.cycles),
+        count("instructions", counts.instructions),
+        ratio(
+            ["instructions", "cycles"],
+            counts.instructions as f64 / counts.cycles as f64,
+        ),
+        count("l1_cache_loads", counts.l1_cache_loads),
+        count("l1_cache_misses", counts.l1_cache_misses),
+        ratio(["l1_cache_misses", "l1_cache_loads"], counts.l1_miss_rate()),
+        count("l1_cache_prefetches", counts.l1_cache_prefetches),
+        count(
+            "l2_cache_accesses_from_dc_misses",
+            counts.l2_cache_accesses_from_dc_misses,
+        ),
+        count(
+            "l2_cache_hits_from_dc_misses",
+            counts.l2_cache_hits_from_dc_misses,
+        ),
+    ]
+    .join(",")
+}
+
+/// Writes the CSV output format to `writer`: [`csv_header`] before the first
+/// row, then one [`csv_row`] per benchmark.
+pub struct CsvReporter<W> {
+    writer: W,
+    header_written: bool,
+}
+
+impl<W: std::io::Write> CsvReporter<W> {
+    pub fn new(writer: W) -> CsvReporter<W> {
+        CsvReporter {
+            writer,
+            header_written: false,
+        }
+    }
+
+    pub fn write_row(&mut self, name: &str, counts: &Counts) -> std::io::Result<()> {
+        if !self.header_written {
+            writeln!(self.writer, "{}", csv_header())?;
+            self.header_written = true;
+        }
+        writeln!(self.writer, "{}", csv_row(name, counts))
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// A cgroup to count events for, selected with `--cgroup`, instead of this
+/// process.
+///
+/// The kernel only counts cgroup events on one CPU at a time, so opening a
+/// `CGroup` pins the current thread to the CPU it's running on, and the
+/// counters only observe that CPU.
+pub struct CGroup {
+    file: File,
+    cpu: usize,
+}
+
+impl CGroup {
+    /// Open the cgroup at `path`, either a full path under the cgroup v2 mount
+    /// point, or one relative to it.
+    pub fn open(path: &Path) -> std::io::Result<CGroup> {
+        let Some(root) = cgroup2_mount_point()? else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "cgroup v2 isn't mounted, so we can't count events per cgroup",
+            ));
+        };
+        let path = if path.starts_with(&root) {
+            path.to_path_buf()
+        } else {
+            root.join(path.strip_prefix("/").unwrap_or(path))
+        };
+        let file = File::open(&path).map_err(|err| {
+            std::io::Error::new(
+                err.kind(),
+                format!("can't open cgroup {}: {}", path.display(), err),
+            )
+        })?;
+
+        let cpu = current_cpu()?;
+        Affinity::only(cpu).apply()?;
+
+        Ok(CGroup { file, cpu })
+    }
+
+    /// Whether `--cgroup` was passed on the command line.
+    pub fn requested() -> bool {
+        flag_passed("--cgroup")
+    }
+
+    /// Open the cgroup passed with `--cgroup`, if any.
+    pub fn from_args() -> std::io::Result<Option<CGroup>> {
+        let Some(path) = option_value("--cgroup") else {
+            return Ok(None);
+        };
+        match path.as_str() {
+            "" => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "--cgroup needs a path",
+            )),
+            path => CGroup::open(Path::new(path)).map(Some),
+        }
+    }
+}
+
+/// Where the cgroup v2 hierarchy is mounted, if it is.
+fn cgroup2_mount_point() -> std::io::Result<Option<PathBuf>> {
+    let mounts = std::fs::read_to_string("/proc/mounts")?;
+    Ok(mounts.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        let mount_point = fields.nth(1)?;
+        (fields.next()? == "cgroup2").then(|| PathBuf::from(mount_point))
+    }))
+}
+
+/// A counter builder that joins `group`, and observes `cgroup` if there is one.
+#[cfg(target_os = "linux")]
+fn builder<'a>(group: &'a mut Group, cgroup: Option<&'a CGroup>) -> Builder<'a> {
+    let builder = Builder::new().group(group);
+    match cgroup {
+        Some(cgroup) => builder.observe_cgroup(&cgroup.file).one_cpu(cgroup.cpu),
+        None => builder,
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn new_group(cgroup: Option<&CGroup>) -> std::io::Result<Group> {
+    match cgroup {
+        Some(cgroup) => Group::for_cgroup(&cgroup.file, cgroup.cpu),
+        None => Group::new(),
+    }
+    .map_err(|err| match (cgroup, err.kind()) {
+        (Some(_), std::io::ErrorKind::PermissionDenied) => std::io::Error::new(
+            err.kind(),
+            format!(
+                "counting on a cgroup needs CAP_PERFMON or a perf_event_paranoid of 0 or less: {}",
+                err
+            ),
+        ),
+        _ => err,
+    })
+}
+
+/// The raw event code of each counter that counts one, on the CPU we're
+/// running on, keyed like [`Counts::values`].
+pub fn raw_event_codes() -> Vec<(&'static str, u64)> {
+    let mut codes = Vec::new();
+    if let Some((accesses, hits)) = l2_miss_events() {
+        codes.push(("l2_cache_accesses_from_dc_misses", accesses));
+        codes.push(("l2_cache_hits_from_dc_misses", hits));
+    }
+    if let Some(event) = l2_store_miss_event() {
+        codes.push(("l2_cache_accesses_from_store_misses", event));
+    }
+    codes
+}
+
+/// The lines of the `raw` output format for the benchmark called `name`: one
+/// `key=value` line per counter that was counted, then one
+/// `raw_0x<code>=value` line per raw event of `codes` that was counted, see
+/// [`raw_event_codes`], so that the same figure can be looked up by the code
+/// the CPU's manual gives it.
+pub fn raw_lines(name: &str, counts: &Counts, codes: &[(&str, u64)]) -> Vec<String> {
+    let values = counts.values();
+    let mut lines = vec![format!("benchmark={}", name)];
+    for (key, value) in &values {
+        if counts.counted(key) {
+            lines.push(format!("{}={}", key, value));
+        }
+    }
+    for &(key, code) in codes {
+        if !counts.counted(key) {
+            continue;
+        }
+        if let Some((_, value)) = values.iter().find(|(k, _)| k == key) {
+            lines.push(format!("raw_{:#x}={}", code, value));
+        }
+    }
+    lines
+}
+
+/// The lines of the `perf-csv` output format, in the layout of
+/// `perf stat -x,`: value, unit, event name, run time, percentage of the time
+/// the event was counting, and two empty metric columns.
+///
+/// Events are named like `perf stat` names them, so that tools written for its
+/// output can parse ours. Like in its output, the events that couldn't be
+/// opened read `<not supported>`, and the ones the PMU never scheduled
+/// `<not counted>`.
+pub fn perf_csv_lines(counts: &Counts) -> Vec<String> {
+    // perf's name for each counter, and its key in `Counts::values`.
+    let events = [
+        ("context-switches", "context_switches"),
+        ("cpu-migrations", "cpu_migrations"),
+        ("page-faults", "page_faults"),
+        ("cycles", "cycles"),
+        ("ref-cycles", "ref_cycles"),
+        ("instructions", "instructions"),
+        ("cache-references", "cache_accesses"),
+        ("L1-dcache-loads", "l1_cache_loads"),
+        ("L1-dcache-load-misses", "l1_cache_misses"),
+        ("L1-dcache-prefetches", "l1_cache_prefetches"),
+        ("L1-dcache-store-misses", "l1_cache_store_misses"),
+        ("L1-icache-load-misses", "l1i_cache_misses"),
+        ("dTLB-load-misses", "dtlb_load_misses"),
+        (
+            "l2_cache_accesses_from_dc_misses",
+            "l2_cache_accesses_from_dc_misses",
+        ),
+        (
+            "l2_cache_hits_from_dc_misses",
+            "l2_cache_hits_from_dc_misses",
+        ),
+        (
+            "l2_cache_accesses_from_store_misses",
+            "l2_cache_accesses_from_store_misses",
+        ),
+    ];
+    let values: BTreeMap<String, u64> = counts.values().into_iter().collect();
+    let run_time = |key: &str| {
+        let (time_enabled, time_running) = counts.times.get(key).copied().unwrap_or_default();
+        let percent = if time_enabled == 0 {
+            100.0
+        } else {
+            time_running as f64 / time_enabled as f64 * 100.0
+        };
+        format!("{},{:.2}", time_running, percent)
+    };
+
+    let mut lines = vec![
+        format!(
+            "{:.2},msec,task-clock,{},,",
+            counts.nanoseconds() as f64 / 1_000_000.0,
+            run_time("task_clock")
+        ),
+        format!(
+            "{},ns,duration_time,{},100.00,,",
+            counts.wall_clock, counts.wall_clock
+        ),
+    ];
+    for (name, key) in events {
+        let never_ran = matches!(counts.times.get(key), Some(&(_, 0)));
+        let value = if !counts.counted(key) {
+            "<not supported>".to_string()
+        } else if never_ran {
+            "<not counted>".to_string()
+        } else {
+            values[key].to_string()
+        };
+        lines.push(format!("{},,{},{},,", value, name, run_time(key)));
+    }
+    lines
+}
+
+/// How many times [`retry_transient`] retries before giving up.
+const TRANSIENT_RETRIES: u32 = 5;
+
+/// Whether `err` is one that perf_event returns while the counters are busy,
+/// eg because another profiler holds them, and which may go away on its own.
+/// Errors like ENOENT (unsupported event) or EACCES (no permission) won't.
+fn is_transient(err: &std::io::Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(libc::EAGAIN | libc::EBUSY | libc::ENOSPC)
+    )
+}
+
+/// Run `operation`, retrying with exponential backoff for as long as it fails
+/// with a transient error, up to [`TRANSIENT_RETRIES`] times. `what` describes
+/// the operation in the error message if it still fails after that.
+pub fn retry_transient<T>(
+    what: &str,
+    mut operation: impl FnMut() -> std::io::Result<T>,
+) -> std::io::Result<T> {
+    let mut delay = Duration::from_millis(1);
+    let mut retries = 0;
+    loop {
+        match operation() {
+            Err(err) if is_transient(&err) && retries < TRANSIENT_RETRIES => {
+                std::thread::sleep(delay);
+                delay *= 2;
+                retries += 1;
+            }
+            Err(err) if is_transient(&err) => {
+                return Err(std::io::Error::new(
+                    err.kind(),
+                    format!(
+                        "can't {} after {} retries, another profiler may be using the counters: {}",
+                        what, retries, err
+                    ),
+                ))
+            }
+            result => return result,
+        }
+    }
+}
+
+/// All the counters we collect, opened once and reused across benchmarks.
+///
+/// Opening a dozen counters takes a while, long enough to dominate the run
+/// time of short benchmarks, so this resets the existing counters between
+/// measurements instead of rebuilding them.
+#[cfg(target_os = "linux")]
+pub struct CounterSet {
+    /// Each group, with its counters keyed like [`Counts::values`]. The PMU
+    /// can't count every event at once, so the benchmark runs once per group.
+    groups: Vec<(Group, Vec<(String, Counter)>)>,
+    /// Why each optional event couldn't be opened, see [`optional_counter`].
+    unavailable: BTreeMap<String, String>,
+}
+
+/// Keep the counter for the optional event keyed `name` if it could be
+/// opened, or record why it couldn't in `unavailable` if the CPU or the
+/// kernel can't count it. Any other error, like a permission error or
+/// another profiler holding the PMU, fails like a required counter would.
+#[cfg(target_os = "linux")]
+fn optional_counter(
     name: &str,
-    callback: impl Fn(),
-    iterations: usize,
-    data_loads: Option<usize>,
+    counter: std::io::Result<Counter>,
+    counters: &mut Vec<(String, Counter)>,
+    unavailable: &mut BTreeMap<String, String>,
 ) -> std::io::Result<()> {
-    // if "--list" is passed, we just print the name of the benchmark
-    if std::env::args().any(|arg| arg == "--list") {
-        println!("{}", name);
-        return Ok(());
+    match counter {
+        Ok(counter) => counters.push((name.to_string(), counter)),
+        Err(err) if is_unsupported_event(&err) => {
+            unavailable.insert(name.to_string(), err.to_string());
+        }
+        Err(err) => return Err(BenchError::opening(name, err).into()),
     }
+    Ok(())
+}
 
-    // check that the current benchmark is among those passed to argv
-    if !std::env::args().any(|arg| name.contains(&arg)) {
-        return Ok(());
+#[cfg(target_os = "linux")]
+impl CounterSet {
+    /// Open the counters, observing this thread, or the tasks of `cgroup` if
+    /// there is one.
+    pub fn new(cgroup: Option<&CGroup>) -> std::io::Result<CounterSet> {
+        // A `Group` lets us enable and disable several counters atomically.
+        let mut group = new_group(cgroup)?;
+        let mut counters = Vec::new();
+        let mut unavailable = BTreeMap::new();
+
+        let software_events = [
+            ("task_clock", Software::TASK_CLOCK),
+            ("context_switches", Software::CONTEXT_SWITCHES),
+            ("cpu_migrations", Software::CPU_MIGRATIONS),
+            ("page_faults", Software::PAGE_FAULTS),
+        ];
+        for (name, event) in software_events {
+            let counter = builder(&mut group, cgroup)
+                .kind(event)
+                .build()
+                .map_err(|err| BenchError::opening(name, err))?;
+            counters.push((name.to_string(), counter));
+        }
+
+        // Every figure per iteration needs these two, so there's no point
+        // going on without them. The other events are optional: each CPU
+        // supports a different subset, and the table prints "n/a" for the
+        // ones it doesn't, see `Counts::counted`.
+        let hardware_events = [
+            ("cycles", Hardware::CPU_CYCLES),
+            ("instructions", Hardware::INSTRUCTIONS),
+        ];
+        for (name, event) in hardware_events {
+            let counter = builder(&mut group, cgroup)
+                .kind(event)
+                .build()
+                .map_err(|err| BenchError::opening(name, err))?;
+            counters.push((name.to_string(), counter));
+        }
+
+        // In the same group as the cycles, so that their ratio, the
+        // effective frequency multiplier, compares counts of the same run.
+        // On Intel, it takes a fixed counter rather than a programmable one.
+        let ref_cycles = builder(&mut group, cgroup)
+            .kind(Hardware::REF_CPU_CYCLES)
+            .build();
+        optional_counter("ref_cycles", ref_cycles, &mut counters, &mut unavailable)?;
+
+        let cache_accesses = builder(&mut group, cgroup)
+            .kind(Hardware::CACHE_REFERENCES)
+            .build();
+        optional_counter(
+            "cache_accesses",
+            cache_accesses,
+            &mut counters,
+            &mut unavailable,
+        )?;
+
+        let l1_events = [
+            ("l1_cache_loads", CacheOp::READ, CacheResult::ACCESS),
+            ("l1_cache_misses", CacheOp::READ, CacheResult::MISS),
+            (
+                "l1_cache_prefetches",
+                CacheOp::PREFETCH,
+                CacheResult::ACCESS,
+            ),
+        ];
+        for (name, operation, result) in l1_events {
+            let counter = builder(&mut group, cgroup)
+                .kind(Cache {
+                    which: WhichCache::L1D,
+                    operation,
+                    result,
+                })
+                .build();
+            optional_counter(name, counter, &mut counters, &mut unavailable)?;
+        }
+
+        let mut groups = vec![(group, counters)];
+
+        // We need to separate L2 cache events into their own group,
+        // because they're incompatible with some of the events of
+        // the first group.
+        // The L1I event goes here too, because the first group already uses as
+        // many hardware counters as most PMUs have.
+        let mut group = new_group(cgroup)?;
+        let mut counters = Vec::new();
+
+        let l1i_cache_misses = builder(&mut group, cgroup)
+            .kind(Cache {
+                which: WhichCache::L1I,
+                operation: CacheOp::READ,
+                result: CacheResult::MISS,
+            })
+            .build();
+        optional_counter(
+            "l1i_cache_misses",
+            l1i_cache_misses,
+            &mut counters,
+            &mut unavailable,
+        )?;
+
+        // Most CPUs can't count L1D store misses.
+        let l1_cache_store_misses = builder(&mut group, cgroup)
+            .kind(Cache {
+                which: WhichCache::L1D,
+                operation: CacheOp::WRITE,
+                result: CacheResult::MISS,
+            })
+            .build();
+        optional_counter(
+            "l1_cache_store_misses",
+            l1_cache_store_misses,
+            &mut counters,
+            &mut unavailable,
+        )?;
+
+        // Raw events mean something different on every microarchitecture,
+        // so only count these where we know their codes.
+        if let Some((accesses, hits)) = l2_miss_events() {
+            for (name, event) in [
+                ("l2_cache_accesses_from_dc_misses", accesses),
+                ("l2_cache_hits_from_dc_misses", hits),
+            ] {
+                let counter = builder(&mut group, cgroup).raw_config(event).build();
+                optional_counter(name, counter, &mut counters, &mut unavailable)?;
+            }
+        }
+
+        if !counters.is_empty() {
+            groups.push((group, counters));
+        }
+
+        // The second group is full too. These get a group of their own so
+        // that they don't get multiplexed with the others.
+        let mut group = new_group(cgroup)?;
+        let mut counters = Vec::new();
+
+        let dtlb_load_misses = builder(&mut group, cgroup)
+            .kind(Cache {
+                which: WhichCache::DTLB,
+                operation: CacheOp::READ,
+                result: CacheResult::MISS,
+            })
+            .build();
+        optional_counter(
+            "dtlb_load_misses",
+            dtlb_load_misses,
+            &mut counters,
+            &mut unavailable,
+        )?;
+
+        if let Some(event) = l2_store_miss_event() {
+            let counter = builder(&mut group, cgroup).raw_config(event).build();
+            optional_counter(
+                "l2_cache_accesses_from_store_misses",
+                counter,
+                &mut counters,
+                &mut unavailable,
+            )?;
+        }
+
+        if !counters.is_empty() {
+            groups.push((group, counters));
+        }
+
+        Ok(CounterSet {
+            groups,
+            unavailable,
+        })
     }
 
-    let skip_all_this = false;
-    if skip_all_this {
+    /// Run `callback` once per group with that group's counters enabled, and
+    /// merge all their readings.
+    ///
+    /// `fence` runs right before the counters are enabled and right after
+    /// they're disabled. The wall clock time comes from the first run.
+    pub fn measure(
+        &mut self,
+        fence: Fence,
+        mut callback: impl FnMut(),
+    ) -> std::io::Result<BenchResult> {
+        let mut result = BenchResult {
+            unavailable: self.unavailable.clone(),
+            ..BenchResult::default()
+        };
+
+        for (index, (group, counters)) in self.groups.iter_mut().enumerate() {
+            group.reset()?;
+            fence.run();
+            group.enable()?;
+            let start = Instant::now();
+            callback();
+            let wall_clock = start.elapsed();
+            group.disable()?;
+            fence.run();
+
+            if index == 0 {
+                result.wall_clock = wall_clock.as_nanos() as u64;
+            }
+            read_group(index, group, counters, &mut result)?;
+        }
+
+        Ok(result)
+    }
+
+    /// Same as [`CounterSet::measure`], but runs `callback` only once, with
+    /// every group enabled, for `--single-run`.
+    ///
+    /// The PMU can't count every group at once, so the kernel multiplexes
+    /// them, and each group only counts part of the run. In exchange, slow
+    /// benchmarks take a third of the time, and every counter observes the
+    /// same run.
+    pub fn measure_once(
+        &mut self,
+        fence: Fence,
+        callback: impl FnOnce(),
+    ) -> std::io::Result<BenchResult> {
+        let mut result = BenchResult {
+            single_run: true,
+            unavailable: self.unavailable.clone(),
+            ..BenchResult::default()
+        };
+
+        for (group, _) in &mut self.groups {
+            group.reset()?;
+        }
+        fence.run();
+        for (group, _) in &mut self.groups {
+            group.enable()?;
+        }
+        let start = Instant::now();
         callback();
-        return Ok(());
+        let wall_clock = start.elapsed();
+        for (group, _) in self.groups.iter_mut().rev() {
+            group.disable()?;
+        }
+        fence.run();
+
+        result.wall_clock = wall_clock.as_nanos() as u64;
+        for (index, (group, counters)) in self.groups.iter_mut().enumerate() {
+            read_group(index, group, counters, &mut result)?;
+        }
+
+        Ok(result)
     }
+}
 
-    println!("====================================================================");
-    println!("Benchmarking {}... ", name);
+/// Add the readings of `group`, the group at `index`, to `result`.
+#[cfg(target_os = "linux")]
+fn read_group(
+    index: usize,
+    group: &mut Group,
+    counters: &[(String, Counter)],
+    result: &mut BenchResult,
+) -> std::io::Result<()> {
+    let counts = retry_transient("read the counters", || group.read())?;
+    for (name, counter) in counters {
+        let reading = Reading {
+            value: counts[counter],
+            time_enabled: counts.time_enabled(),
+            time_running: counts.time_running(),
+            group: index,
+        };
+        result.readings.insert(name.clone(), reading);
+    }
+    Ok(())
+}
 
-    // A `Group` lets us enable and disable several counters atomically.
-    let mut group = Group::new()?;
-
-    let task_clock = Builder::new()
-        .group(&mut group)
-        .kind(Software::TASK_CLOCK)
-        .build()?;
-    let context_switches = Builder::new()
-        .group(&mut group)
-        .kind(Software::CONTEXT_SWITCHES)
-        .build()?;
-    let cpu_migrations = Builder::new()
-        .group(&mut group)
-        .kind(Software::CPU_MIGRATIONS)
-        .build()?;
-    let page_faults = Builder::new()
-        .group(&mut group)
-        .kind(Software::PAGE_FAULTS)
-        .build()?;
-
-    let cycles = Builder::new()
-        .group(&mut group)
-        .kind(Hardware::CPU_CYCLES)
-        .build()?;
-    let instructions = Builder::new()
-        .group(&mut group)
-        .kind(Hardware::INSTRUCTIONS)
-        .build()?;
-
-    let cache_accesses = Builder::new()
-        .group(&mut group)
-        .kind(Hardware::CACHE_REFERENCES)
-        .build()?;
-    let l1_cache_loads = Builder::new()
-        .group(&mut group)
-        .kind(Cache {
-            which: WhichCache::L1D,
-            operation: CacheOp::READ,
-            result: CacheResult::ACCESS,
-        })
-        .build()?;
-    let l1_cache_misses = Builder::new()
-        .group(&mut group)
-        .kind(Cache {
-            which: WhichCache::L1D,
-            operation: CacheOp::READ,
-            result: CacheResult::MISS,
-        })
-        .build()?;
-    let l1_cache_prefetches = Builder::new()
-        .group(&mut group)
-        .kind(Cache {
-            which: WhichCache::L1D,
-            operation: CacheOp::PREFETCH,
-            result: CacheResult::ACCESS,
-        })
-        .build()?;
-
-    // We need to separate L2 cache events into their own group,
-    // because they're incompatible with some of the events of
-    // the first group.
-    let mut group_2 = Group::new()?;
-
-    let l2_cache_accesses_from_dc_misses = Builder::new()
-        .group(&mut group_2)
-        .raw_config(0xc860)
-        .build()?;
-    let l2_cache_hits_from_dc_misses = Builder::new()
-        .group(&mut group_2)
-        .raw_config(0x7064)
-        .build()?;
-
-    group.enable()?;
-    callback();
-    group.disable()?;
+/// Without perf_event, there are no counters to open, and the only reading
+/// is the wall clock. Every other event reads as not counted, see
+/// [`Counts::counted`], so the results still show relative timings.
+#[cfg(not(target_os = "linux"))]
+pub struct CounterSet;
+
+#[cfg(not(target_os = "linux"))]
+impl CounterSet {
+    pub fn new(cgroup: Option<&CGroup>) -> std::io::Result<CounterSet> {
+        if cgroup.is_some() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "counting on a cgroup is only supported on Linux",
+            ));
+        }
+        eprintln!("Warning: {}, only measuring the wall clock", NO_COUNTERS);
+        Ok(CounterSet)
+    }
+
+    pub fn measure(
+        &mut self,
+        fence: Fence,
+        callback: impl FnMut(),
+    ) -> std::io::Result<BenchResult> {
+        Ok(measure_wall_clock(fence, callback, false))
+    }
+
+    pub fn measure_once(
+        &mut self,
+        fence: Fence,
+        callback: impl FnOnce(),
+    ) -> std::io::Result<BenchResult> {
+        Ok(measure_wall_clock(fence, callback, true))
+    }
+}
 
-    group_2.enable()?;
+/// Run `callback` once, and record the wall clock as its only reading.
+#[cfg(not(target_os = "linux"))]
+fn measure_wall_clock(fence: Fence, callback: impl FnOnce(), single_run: bool) -> BenchResult {
+    fence.run();
+    let start = Instant::now();
     callback();
-    group_2.disable()?;
+    let wall_clock = start.elapsed().as_nanos() as u64;
+    fence.run();
 
-    /*
-    We want to display something like this:
+    let reading = Reading {
+        value: wall_clock,
+        time_enabled: wall_clock,
+        time_running: wall_clock,
+        group: 0,
+    };
+    BenchResult {
+        wall_clock,
+        readings: BTreeMap::from([("wall_clock".to_string(), reading)]),
+        single_run,
+        unavailable: BTreeMap::new(),
+    }
+}
 
-    ====================================================================
-            234.04 msec task-clock                       #
-                 1      context-switches                 #    4.273 /sec
-                 0      cpu-migrations                   #    0.000 /sec
-                72      page-faults                      #  307.634 /sec
-       916,694,940      cycles                           #    3.917 GHz
-     3,768,251,802      instructions                     #    4.11   per cycle
+/// What [`print_counts`] prints instead of the counters, when only the wall
+/// clock was measured.
+pub const NO_COUNTERS: &str = "hardware counters unavailable on this platform";
 
-     1,009,884,042      L1-dcache-loads
-            25,093      L1-dcache-load-misses            #    0.00% of all L1-dcache accesses
-            12,925      L1-dcache-prefetches
-            25,098      l2_cache_accesses_from_dc_misses
-            13,680      l2_cache_hits_from_dc_misses
-    ====================================================================
-    */
+/// Whether `--single-run` was passed, to count every group on a single run
+/// of each benchmark, see [`CounterSet::measure_once`]. Otherwise, each
+/// sample runs the benchmark once per group.
+pub fn single_run() -> bool {
+    flag_passed("--single-run")
+}
 
-    let counts = group.read()?;
-    let counts_2 = group_2.read()?;
+thread_local! {
+    static COUNTER_SET: RefCell<Option<CounterSet>> = const { RefCell::new(None) };
+}
 
-    let task_clock_nsec = counts[&task_clock] as f64;
-    let task_clock_msec = counts[&task_clock] as f64 / 1_000_000.0;
-    let task_clock_s = counts[&task_clock] as f64 / 1_000_000_000.0;
+/// A serializing instruction to run around the measured region, selected
+/// with [`BenchConfig::fence`].
+///
+/// Without one, out-of-order execution can overlap the code around the
+/// measured region with the benchmark itself, which blurs the counts of short
+/// benchmarks. Fences make those counts easier to attribute, but real code
+/// doesn't run between fences, so they also make them less realistic.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Fence {
+    #[default]
+    None,
+    /// Wait for every earlier instruction to complete before starting later
+    /// ones.
+    Lfence,
+    /// Like `Lfence`, but also wait for earlier loads and stores to be
+    /// globally visible.
+    Mfence,
+}
 
-    println!(
-        "{count:>16.2} {unit:<4} {name:<30} # {info:.3} {info_unit}",
-        count = task_clock_msec,
-        unit = "msec",
-        name = "task-clock",
-        info = "",
-        info_unit = "",
-    );
-    println!(
-        "{count:>16.2} {unit:<4} {name:<30} # {info:.3} {info_unit}",
-        count = counts[&context_switches].separate_with_underscores(),
-        unit = "",
-        name = "context-switches",
-        info = counts[&context_switches] as f64 / task_clock_s,
-        info_unit = "/sec",
-    );
-    println!(
-        "{count:>16.2} {unit:<4} {name:<30} # {info:.3} {info_unit}",
-        count = counts[&cpu_migrations].separate_with_underscores(),
-        unit = "",
-        name = "cpu-migrations",
-        info = counts[&cpu_migrations] as f64 / task_clock_s,
-        info_unit = "/sec",
-    );
-    println!(
-        "{count:>16.2} {unit:<4} {name:<30} # {info:.3} {info_unit}",
-        count = counts[&page_faults].separate_with_underscores(),
-        unit = "",
-        name = "page-faults",
-        info = counts[&page_faults] as f64 / task_clock_s,
-        info_unit = "/sec",
-    );
-    println!("");
+impl Fence {
+    #[cfg(target_arch = "x86_64")]
+    fn run(self) {
+        match self {
+            Fence::None => {}
+            Fence::Lfence => unsafe { asm!("lfence", options(nostack, preserves_flags)) },
+            Fence::Mfence => unsafe { asm!("mfence", options(nostack, preserves_flags)) },
+        }
+    }
 
-    println!(
-        "{count:>16} {unit:<4} {name:<30} # {info:.3} {info_unit}",
-        count = counts[&cycles].separate_with_underscores(),
-        unit = "",
-        name = "cycles",
-        info = counts[&cycles] as f64 / task_clock_nsec,
-        info_unit = "GHz",
-    );
-    println!(
-        "{count:>16} {unit:<4} {name:<30} # {info:.3} {info_unit}",
-        count = counts[&instructions].separate_with_underscores(),
-        unit = "",
-        name = "instructions",
-        info = counts[&instructions] as f64 / counts[&cycles] as f64,
-        info_unit = "per cycle",
-    );
-    println!("");
+    // aarch64 has no exact equivalent of lfence: isb is the closest, since it
+    // waits for earlier instructions before fetching later ones. dsb sy also
+    // waits for earlier memory accesses, like mfence.
+    #[cfg(target_arch = "aarch64")]
+    fn run(self) {
+        match self {
+            Fence::None => {}
+            Fence::Lfence => unsafe { asm!("isb", options(nostack, preserves_flags)) },
+            Fence::Mfence => unsafe { asm!("dsb sy", options(nostack, preserves_flags)) },
+        }
+    }
+}
 
-    println!(
-        "{count:>16} {unit:<4} {name:<30} # {info:.3} {info_unit}",
-        count = counts[&cache_accesses].separate_with_underscores(),
-        unit = "",
-        name = "cache accesses",
-        info = "",
-        info_unit = "",
-    );
-    println!(
-        "{count:>16} {unit:<4} {name:<30} # {info:.3} {info_unit}",
-        count = counts[&l1_cache_loads].separate_with_underscores(),
-        unit = "",
-        name = "L1D cache loads",
-        info = "",
-        info_unit = "",
-    );
-    println!(
-        "{count:>16} {unit:<4} {name:<30} # {info:.3} {info_unit}",
-        count = counts[&l1_cache_misses].separate_with_underscores(),
-        unit = "",
-        name = "L1D cache misses",
-        info = (counts[&l1_cache_misses] as f64 / counts[&l1_cache_loads] as f64) * 100.0,
-        info_unit = "% of L1D accesses",
-    );
-    println!(
-        "{count:>16} {unit:<4} {name:<30} # {info:.3} {info_unit}",
-        count = counts[&l1_cache_prefetches].separate_with_underscores(),
-        unit = "",
-        name = "L1D cache prefetches",
-        info = "",
-        info_unit = "",
-    );
+/// Escape `value` for use as an OpenMetrics label value.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
 
-    println!(
-        "{count:>16} {unit:<4} {name:<30} # {info:.3} {info_unit}",
-        count = counts_2[&l2_cache_accesses_from_dc_misses].separate_with_underscores(),
-        unit = "",
-        name = "L2 accesses from L1 misses",
-        info = "",
-        info_unit = "",
-    );
-    println!(
-        "{count:>16} {unit:<4} {name:<30} # {info:.3} {info_unit}",
-        count = counts_2[&l2_cache_hits_from_dc_misses].separate_with_underscores(),
-        unit = "",
-        name = "L2 hits from L1 misses",
-        info = (counts_2[&l2_cache_hits_from_dc_misses] as f64
-            / counts_2[&l2_cache_accesses_from_dc_misses] as f64)
-            * 100.0,
-        info_unit = "% of L2 accesses",
-    );
-    println!("");
+/// Format `value` as an OpenMetrics sample value, which spells the ratios of
+/// zero counts `NaN`, `+Inf` and `-Inf`.
+fn metric_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value == f64::INFINITY {
+        "+Inf".to_string()
+    } else if value == f64::NEG_INFINITY {
+        "-Inf".to_string()
+    } else {
+        value.to_string()
+    }
+}
 
-    println!(
-        "Iterations: {count}",
-        count = iterations.separate_with_underscores()
-    );
-    println!(
-        "Cycles per iteration: {count:.3}",
-        count = counts[&cycles] as f64 / iterations as f64
-    );
-    println!(
-        "Instructions per iteration: {count:.3}",
-        count = counts[&instructions] as f64 / iterations as f64
-    );
-    if let Some(data_loads) = data_loads {
-        println!(
-            "Cycles per data load: {count:.3}",
-            count = counts[&cycles] as f64 / data_loads as f64
+/// The OpenMetrics exposition of `results`, given as the name, iteration
+/// count and counts of each benchmark run, on a CPU called `cpu_model`.
+///
+/// Every counter of [`Counts::values`] becomes a `test_prefetch_<key>` gauge,
+/// along with a few derived metrics, with no sample for the runs that
+/// couldn't count what it needs. Samples are labeled with the benchmark,
+/// the CPU, and the index of the run, which only goes past 0 with
+/// `--suite-repeats`. Each change of `comparisons` is a
+/// `test_prefetch_change_percent` sample, labeled with the comparison and
+/// the metric that changed.
+pub fn prometheus_lines(
+    cpu_model: &str,
+    results: &[(&str, usize, &Counts)],
+    comparisons: &[AbComparison],
+) -> Vec<String> {
+    let mut runs = Vec::new();
+    let mut labels = Vec::new();
+    for (name, _, _) in results {
+        let run = runs.iter().filter(|&previous| previous == name).count();
+        runs.push(*name);
+        labels.push(format!(
+            "benchmark=\"{}\",cpu=\"{}\",run=\"{}\"",
+            escape_label(name),
+            escape_label(cpu_model),
+            run
+        ));
+    }
+
+    // Each metric's value for each run, or `None` if an event it needs
+    // couldn't be counted, so that there's no sample for it.
+    let counted = |counts: &Counts, keys: &[&str], value: f64| {
+        keys.iter().all(|key| counts.counted(key)).then_some(value)
+    };
+    let mut metrics: Vec<(String, Vec<Option<f64>>)> = Vec::new();
+    if let Some((_, _, first)) = results.first() {
+        for (index, (key, _)) in first.values().into_iter().enumerate() {
+            let values = results
+                .iter()
+                .map(|(_, _, counts)| {
+                    let (key, value) = &counts.values()[index];
+                    counted(counts, &[key], *value as f64)
+                })
+                .collect();
+            metrics.push((key, values));
+        }
+    }
+    metrics.push((
+        "instructions_per_cycle".to_string(),
+        results
+            .iter()
+            .map(|(_, _, counts)| {
+                counted(
+                    counts,
+                    &["instructions", "cycles"],
+                    counts.instructions as f64 / counts.cycles as f64,
+                )
+            })
+            .collect(),
+    ));
+    metrics.push((
+        "cycles_per_iteration".to_string(),
+        results
+            .iter()
+            .map(|(_, iterations, counts)| {
+                counted(
+                    counts,
+                    &["cycles"],
+                    counts.cycles as f64 / *iterations as f64,
+                )
+            })
+            .collect(),
+    ));
+    metrics.push((
+        "l1_miss_ratio".to_string(),
+        results
+            .iter()
+            .map(|(_, _, counts)| {
+                counted(
+                    counts,
+                    &["l1_cache_misses", "l1_cache_loads"],
+                    counts.l1_miss_rate() / 100.0,
+                )
+            })
+            .collect(),
+    ));
+
+    let mut lines = Vec::new();
+    for (metric, values) in metrics {
+        if values.iter().all(Option::is_none) {
+            continue;
+        }
+        lines.push(format!("# TYPE test_prefetch_{} gauge", metric));
+        for (labels, value) in labels.iter().zip(values) {
+            let Some(value) = value else {
+                continue;
+            };
+            lines.push(format!(
+                "test_prefetch_{}{{{}}} {}",
+                metric,
+                labels,
+                metric_value(value)
+            ));
+        }
+    }
+    if !comparisons.is_empty() {
+        lines.push("# TYPE test_prefetch_change_percent gauge".to_string());
+    }
+    for comparison in comparisons {
+        for (metric, change) in [
+            ("bandwidth", comparison.bandwidth_change()),
+            ("ipc", comparison.ipc_change()),
+            ("l1_miss_rate", comparison.l1_miss_rate_change()),
+        ] {
+            if let Some(change) = change {
+                lines.push(format!(
+                    "test_prefetch_change_percent{{comparison=\"{}\",cpu=\"{}\",metric=\"{}\"}} {}",
+                    escape_label(&comparison.name),
+                    escape_label(cpu_model),
+                    metric,
+                    metric_value(change)
+                ));
+            }
+        }
+    }
+    lines.push("# EOF".to_string());
+    lines
+}
+
+/// The version of the layout of the JSON lines output format, included in
+/// every line as `schema_version`. Bump it whenever a field is renamed,
+/// removed or changes type, so that scripts can tell they're reading a
+/// layout they don't know. Adding a field doesn't need a bump.
+///
+/// `--print-schema` prints the current layout, see [`schema`].
+pub const SCHEMA_VERSION: u32 = 3;
+
+/// The fields of each line [`json_line`] prints, as pairs of a path, with
+/// nested fields separated by dots, and a JSON type.
+pub fn schema() -> Vec<(String, &'static str)> {
+    let mut fields = vec![
+        ("schema_version".to_string(), "integer"),
+        ("benchmark".to_string(), "string"),
+        ("category".to_string(), "string or null"),
+    ];
+    // Every count is an integer, serialized under its field name.
+    if let serde_json::Value::Object(counts) = json!(Counts::default()) {
+        for key in counts.keys() {
+            fields.push((format!("counts.{}", key), "integer"));
+        }
+    }
+    fields.push(("running_fraction.<counter>".to_string(), "number"));
+    // Only with --environment, and each can be null if sysfs doesn't say.
+    for moment in ["start", "end"] {
+        for (key, kind) in [
+            ("temperature_c", "number or null"),
+            ("headroom_c", "number or null"),
+            ("frequency_khz", "integer or null"),
+        ] {
+            fields.push((format!("environment.{}.{}", moment, key), kind));
+        }
+    }
+    fields
+}
+
+/// Print [`schema`], one field per line, for `--print-schema`.
+pub fn print_schema() {
+    println!("# JSON lines schema, version {}", SCHEMA_VERSION);
+    for (field, kind) in schema() {
+        println!("{}: {}", field, kind);
+    }
+}
+
+/// One line of the JSON lines output format, with the fraction of the run
+/// each event was counting next to the counts, and with `--environment`, the
+/// temperature and frequency around the run.
+pub fn json_line(name: &str, category: Option<Category>, counts: &Counts) -> String {
+    let mut line = json!({
+        "schema_version": SCHEMA_VERSION,
+        "benchmark": name,
+        "category": category.map(|category| category.to_string()),
+        "counts": counts,
+        "running_fraction": counts
+            .times
+            .keys()
+            .map(|key| (key.clone(), counts.running_fraction(key)))
+            .collect::<BTreeMap<_, _>>(),
+    });
+    if let Some(environment) = &counts.environment {
+        line["environment"] = json!(environment);
+    }
+    line.to_string()
+}
+
+/// Every benchmark of `results`, given as the name, iteration count and
+/// counts of each benchmark run, as one JSON object keyed by benchmark name.
+///
+/// Each benchmark has its counts as integers, or `null` for the events that
+/// couldn't be counted, and its instructions per cycle and L1D miss ratio as
+/// numbers, or `null` if they're undefined. With `--suite-repeats`, the last
+/// run of each benchmark wins. `comparisons` are keyed by name too, see
+/// [`AbComparison::json`].
+pub fn json_document(
+    results: &[(&str, usize, &Counts)],
+    comparisons: &[AbComparison],
+) -> serde_json::Value {
+    let mut benchmarks = serde_json::Map::new();
+    for (name, iterations, counts) in results {
+        let values: serde_json::Map<String, serde_json::Value> = counts
+            .values()
+            .into_iter()
+            .map(|(key, value)| {
+                let value = if counts.counted(&key) {
+                    json!(value)
+                } else {
+                    serde_json::Value::Null
+                };
+                (key, value)
+            })
+            .collect();
+        // serde_json writes NaN and infinities as null too.
+        let ratio = |a: &str, b: &str, ratio: f64| {
+            if counts.counted(a) && counts.counted(b) {
+                json!(ratio)
+            } else {
+                serde_json::Value::Null
+            }
+        };
+        benchmarks.insert(
+            name.to_string(),
+            json!({
+                "iterations": iterations,
+                "counts": values,
+                "ipc": ratio(
+                    "instructions",
+                    "cycles",
+                    counts.instructions as f64 / counts.cycles as f64,
+                ),
+                "l1_miss_ratio": ratio(
+                    "l1_cache_misses",
+                    "l1_cache_loads",
+                    counts.l1_cache_misses as f64 / counts.l1_cache_loads as f64,
+                ),
+            }),
         );
     }
-    println!("");
+    let comparisons: serde_json::Map<String, serde_json::Value> = comparisons
+        .iter()
+        .map(|comparison| (comparison.name.clone(), comparison.json()))
+        .collect();
+    json!({
+        "schema_version": SCHEMA_VERSION,
+        "benchmarks": benchmarks,
+        "comparisons": comparisons,
+    })
+}
 
-    Ok(())
+/// Write [`json_document`] to `writer`, followed by a newline.
+pub fn write_json(
+    writer: &mut impl std::io::Write,
+    results: &[(&str, usize, &Counts)],
+    comparisons: &[AbComparison],
+) -> std::io::Result<()> {
+    serde_json::to_writer_pretty(&mut *writer, &json_document(results, comparisons))?;
+    writeln!(writer)
+}
+
+/// Set by the SIGINT handler once Ctrl-C was pressed.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_interrupt(_signal: libc::c_int) {
+    // The first Ctrl-C lets the current benchmark finish, the second one
+    // means the user doesn't want to wait for it.
+    if INTERRUPTED.swap(true, Ordering::SeqCst) {
+        unsafe { libc::_exit(130) };
+    }
+}
+
+/// Make Ctrl-C stop the run after the current benchmark instead of killing
+/// the process, so that the results so far can still be printed.
+///
+/// Once Ctrl-C was pressed, [`measure`] and [`run_benchmarks`] fail with
+/// [`BenchError::Interrupted`]. A second Ctrl-C exits immediately.
+#[cfg(unix)]
+pub fn install_interrupt_handler() -> std::io::Result<()> {
+    let handler = on_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    if unsafe { libc::signal(libc::SIGINT, handler) } == libc::SIG_ERR {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Without SIGINT, Ctrl-C keeps killing the process right away.
+#[cfg(not(unix))]
+pub fn install_interrupt_handler() -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Whether Ctrl-C was pressed since [`install_interrupt_handler`] was called.
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+fn check_interrupted() -> Result<(), BenchError> {
+    if interrupted() {
+        return Err(BenchError::Interrupted);
+    }
+    Ok(())
+}
+
+/// Run `callback` with our performance counters enabled, and return their values.
+///
+/// Unlike [`run_benchmarks`], this doesn't print anything or check whether
+/// the benchmark was selected, which makes it usable from tests. It still
+/// reads `--cgroup` and `--single-run` from argv, see below.
+///
+/// The counters are opened on the first call, and reused by later calls on
+/// the same thread. If `--cgroup` was passed, they observe that cgroup. With
+/// `--single-run`, every group counts the same run of `callback`.
+/// Opening or reading them is retried if it fails because another profiler
+/// is using them.
+///
+/// Fails with [`BenchError::Interrupted`] without running `callback` once
+/// Ctrl-C was pressed, see [`install_interrupt_handler`], and with
+/// [`BenchError::PermissionDenied`] or [`BenchError::UnsupportedEvent`] if a
+/// counter can't be opened.
+pub fn measure(callback: impl FnMut()) -> Result<Counts, BenchError> {
+    measure_fenced(Fence::None, callback)
+}
+
+/// Same as [`measure`], with `fence` run around the measured region.
+pub fn measure_fenced(fence: Fence, callback: impl FnMut()) -> Result<Counts, BenchError> {
+    check_interrupted()?;
+    COUNTER_SET.with(|counter_set| {
+        let mut counter_set = counter_set.borrow_mut();
+        if counter_set.is_none() {
+            let cgroup = CGroup::from_args()?;
+            *counter_set = Some(retry_transient("open the counters", || {
+                CounterSet::new(cgroup.as_ref())
+            })?);
+        }
+        let counter_set = counter_set.as_mut().unwrap();
+        let result = if single_run() {
+            counter_set.measure_once(fence, callback)?
+        } else {
+            counter_set.measure(fence, callback)?
+        };
+        Ok(Counts::from_result(&result))
+    })
+}
+
+/// How [`run_benchmarks`] prints its results, selected with `--format`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// A human-readable table, similar to `perf stat`'s.
+    Table,
+    /// One `name=value` line per counter, with plain integers and no derived
+    /// metrics, for consumption by scripts.
+    Raw,
+    /// A header line, then one comma-separated line per benchmark, see
+    /// [`csv_header`].
+    Csv,
+    /// One JSON object per line, printed as soon as each benchmark (or each
+    /// point of a sweep) completes, so that interrupting a long run doesn't
+    /// lose earlier results.
+    JsonLines,
+    /// The machine-readable output of `perf stat -x,`, with a comment line
+    /// naming each benchmark.
+    PerfCsv,
+    /// OpenMetrics text, for Prometheus to scrape or a pushgateway to
+    /// ingest. Printed once every benchmark has run, because each metric's
+    /// samples have to be grouped together.
+    Prometheus,
+    /// A single JSON document with every benchmark keyed by name, see
+    /// [`json_document`]. Printed once every benchmark has run.
+    Json,
+}
+
+impl OutputFormat {
+    /// Read the output format from the command line, defaulting to `Table`.
+    pub fn from_args() -> std::io::Result<OutputFormat> {
+        let Some(value) = option_value("--format") else {
+            return Ok(OutputFormat::Table);
+        };
+        match value.as_str() {
+            "table" => Ok(OutputFormat::Table),
+            "raw" => Ok(OutputFormat::Raw),
+            "csv" => Ok(OutputFormat::Csv),
+            "jsonl" => Ok(OutputFormat::JsonLines),
+            "perf-csv" => Ok(OutputFormat::PerfCsv),
+            "prometheus" => Ok(OutputFormat::Prometheus),
+            "json" => Ok(OutputFormat::Json),
+            other => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("unknown output format {:?}", other),
+            )),
+        }
+    }
+}
+
+/// The microarchitectural resource a benchmark is meant to stress.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Category {
+    Alu,
+    FrontEnd,
+    L1,
+    L2,
+    Dram,
+    Branch,
+    Tlb,
+    Prefetch,
+}
+
+impl Category {
+    pub const ALL: [Category; 8] = [
+        Category::Alu,
+        Category::FrontEnd,
+        Category::L1,
+        Category::L2,
+        Category::Dram,
+        Category::Branch,
+        Category::Tlb,
+        Category::Prefetch,
+    ];
+
+    /// Read the category passed with `--filter-category`, if any.
+    pub fn filter_from_args() -> std::io::Result<Option<Category>> {
+        let Some(value) = option_value("--filter-category") else {
+            return Ok(None);
+        };
+        match value.parse() {
+            Ok(category) => Ok(Some(category)),
+            Err(()) => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("unknown benchmark category {:?}", value),
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for Category {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            Category::Alu => "ALU",
+            Category::FrontEnd => "FrontEnd",
+            Category::L1 => "L1",
+            Category::L2 => "L2",
+            Category::Dram => "DRAM",
+            Category::Branch => "Branch",
+            Category::Tlb => "TLB",
+            Category::Prefetch => "Prefetch",
+        };
+        fmt.write_str(name)
+    }
+}
+
+impl std::str::FromStr for Category {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Category, ()> {
+        Category::ALL
+            .into_iter()
+            .find(|category| category.to_string().eq_ignore_ascii_case(s))
+            .ok_or(())
+    }
+}
+
+/// Per-benchmark parameters for [`run_benchmarks`].
+#[derive(Clone, Debug)]
+pub struct BenchConfig {
+    iterations: usize,
+    data_loads: Option<usize>,
+    peak_ipc: Option<f64>,
+    bytes: Option<usize>,
+    lines_fetched: Option<usize>,
+    line_offset: Option<usize>,
+    fence: Fence,
+    category: Option<Category>,
+    expected_l1_miss_rate: Option<RangeInclusive<f64>>,
+    expected_accesses: Option<usize>,
+    warmup: usize,
+    samples: usize,
+}
+
+/// How many times [`run_benchmarks`] runs a benchmark before measuring it,
+/// unless [`BenchConfig::warmup`] says otherwise.
+pub const DEFAULT_WARMUP: usize = 1;
+
+/// How many times [`run_benchmarks`] measures a benchmark, unless
+/// [`BenchConfig::samples`] or `--samples` says otherwise.
+///
+/// Each sample runs the benchmark once per counter group, up to three times,
+/// so with the warmup run a benchmark runs up to 16 times. Pass `--samples 1`
+/// for a quick run, and `--single-run` to count every group on the same run.
+pub const DEFAULT_SAMPLES: usize = 5;
+
+/// Read the number of samples to measure each benchmark from `--samples`,
+/// if it was passed, to override [`BenchConfig::samples`].
+pub fn samples_from_args() -> std::io::Result<Option<usize>> {
+    let Some(value) = option_value("--samples") else {
+        return Ok(None);
+    };
+    match value.parse() {
+        Ok(samples) if samples > 0 => Ok(Some(samples)),
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid number of samples {:?}", value),
+        )),
+    }
+}
+
+impl BenchConfig {
+    /// `iterations` is the total number of times the benchmark's loop body
+    /// runs, used to compute per-iteration figures.
+    pub fn new(iterations: usize) -> BenchConfig {
+        BenchConfig {
+            iterations,
+            data_loads: None,
+            peak_ipc: None,
+            bytes: None,
+            lines_fetched: None,
+            line_offset: None,
+            fence: Fence::None,
+            category: None,
+            expected_l1_miss_rate: None,
+            expected_accesses: None,
+            warmup: DEFAULT_WARMUP,
+            samples: DEFAULT_SAMPLES,
+        }
+    }
+
+    /// The total number of data loads the benchmark performs.
+    pub fn data_loads(mut self, data_loads: usize) -> BenchConfig {
+        self.data_loads = Some(data_loads);
+        self
+    }
+
+    /// The highest instructions-per-cycle the benchmark can theoretically
+    /// reach, given the architectural limits it's meant to hit.
+    pub fn peak_ipc(mut self, peak_ipc: f64) -> BenchConfig {
+        self.peak_ipc = Some(peak_ipc);
+        self
+    }
+
+    /// The total number of bytes the benchmark reads or writes, used to
+    /// compute its bandwidth.
+    pub fn bytes(mut self, bytes: usize) -> BenchConfig {
+        self.bytes = Some(bytes);
+        self
+    }
+
+    /// Same as [`BenchConfig::bytes`], for a benchmark that processes `count`
+    /// elements of `element_size` bytes in total.
+    pub fn elements(self, count: usize, element_size: usize) -> BenchConfig {
+        self.bytes(count * element_size)
+    }
+
+    /// The total number of cache lines the benchmark has to fetch, used to
+    /// compare its bandwidth with the bandwidth of the lines it moves.
+    pub fn lines_fetched(mut self, lines_fetched: usize) -> BenchConfig {
+        self.lines_fetched = Some(lines_fetched);
+        self
+    }
+
+    /// Set the bytes read and lines fetched by `passes` strided passes over a
+    /// `len`-byte array, as computed by [`strided_traffic`].
+    pub fn strided(self, len: usize, stride: usize, passes: usize) -> BenchConfig {
+        let (bytes, lines) = strided_traffic(len, stride);
+        self.bytes(bytes * passes).lines_fetched(lines * passes)
+    }
+
+    /// The position within a cache line of the benchmark's first access, in
+    /// bytes.
+    pub fn line_offset(mut self, line_offset: usize) -> BenchConfig {
+        self.line_offset = Some(line_offset);
+        self
+    }
+
+    /// A serializing instruction to run around the measured region. This
+    /// costs a few cycles per measurement, so it's off by default.
+    pub fn fence(mut self, fence: Fence) -> BenchConfig {
+        self.fence = fence;
+        self
+    }
+
+    /// The resource the benchmark stresses, used to filter benchmarks with
+    /// `--filter-category` and to group them in the summary.
+    pub fn category(mut self, category: Category) -> BenchConfig {
+        self.category = Some(category);
+        self
+    }
+
+    /// The range the L1D miss rate should fall in, in percent of L1D loads.
+    /// The measured rate is checked against it, and a mismatch fails the
+    /// run with `--strict`.
+    pub fn expected_l1_miss_rate(mut self, expected: RangeInclusive<f64>) -> BenchConfig {
+        self.expected_l1_miss_rate = Some(expected);
+        self
+    }
+
+    /// How many times the benchmark should reference the cache hierarchy,
+    /// as `cache_accesses` counts them. The measured count is checked
+    /// against it, within [`ACCESS_TOLERANCE`], and a mismatch fails the run
+    /// with `--strict`.
+    pub fn expected_accesses(mut self, expected: usize) -> BenchConfig {
+        self.expected_accesses = Some(expected);
+        self
+    }
+
+    /// How many times to run the benchmark before its counters are enabled,
+    /// to fault its pages in, warm the caches up, and let the CPU frequency
+    /// ramp up. After enough of them, the measured run shouldn't take any
+    /// page faults.
+    pub fn warmup(mut self, runs: usize) -> BenchConfig {
+        self.warmup = runs;
+        self
+    }
+
+    /// How many times to measure the benchmark, at least once. The results
+    /// show the spread of the IPC and bandwidth across these samples.
+    pub fn samples(mut self, samples: usize) -> BenchConfig {
+        self.samples = samples.max(1);
+        self
+    }
+}
+
+/// The bytes read by one pass over a `len`-byte array that reads one byte
+/// every `stride` bytes, and the number of 64-byte cache lines it touches.
+///
+/// Below a stride of 64, every line gets touched, so most of the fetched
+/// bytes are never read.
+pub fn strided_traffic(len: usize, stride: usize) -> (usize, usize) {
+    let bytes = len.div_ceil(stride);
+    let lines = if stride >= 64 {
+        bytes
+    } else {
+        len.div_ceil(64)
+    };
+    (bytes, lines)
+}
+
+/// A command line option, and whether the argument after it is its value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Flag {
+    pub name: &'static str,
+    pub takes_value: bool,
+}
+
+impl Flag {
+    const fn switch(name: &'static str) -> Flag {
+        Flag {
+            name,
+            takes_value: false,
+        }
+    }
+
+    const fn valued(name: &'static str) -> Flag {
+        Flag {
+            name,
+            takes_value: true,
+        }
+    }
+}
+
+/// Every command line option. [`flag_passed`] and [`option_value`] only
+/// accept the options listed here, so that the values of the options that
+/// take one can't be mistaken for benchmark names.
+pub const FLAGS: [Flag; 23] = [
+    Flag::switch("--calibrate"),
+    Flag::valued("--cgroup"),
+    Flag::valued("--dump-indices"),
+    Flag::switch("--environment"),
+    Flag::valued("--filter-category"),
+    Flag::valued("--format"),
+    Flag::valued("--gnuplot"),
+    Flag::switch("--list"),
+    Flag::valued("--load-indices"),
+    Flag::valued("--loop"),
+    Flag::valued("--min-runtime"),
+    Flag::valued("--pin-freq"),
+    Flag::valued("--precision"),
+    Flag::valued("--prefetchers"),
+    Flag::switch("--print-schema"),
+    Flag::switch("--profile"),
+    Flag::valued("--samples"),
+    Flag::switch("--single-run"),
+    Flag::switch("--strict"),
+    Flag::valued("--suite-repeats"),
+    Flag::valued("--timeout"),
+    Flag::switch("--verbose"),
+    Flag::valued("--warmup-runs"),
+];
+
+/// The entry of [`FLAGS`] for the option called `name`.
+fn flag(name: &str) -> Flag {
+    match FLAGS.iter().find(|flag| flag.name == name) {
+        Some(&flag) => flag,
+        None => panic!("{} is missing from FLAGS", name),
+    }
+}
+
+/// Whether `name`, an option of [`FLAGS`], was passed on the command line.
+pub fn flag_passed(name: &str) -> bool {
+    let flag = flag(name);
+    std::env::args().any(|arg| arg == flag.name)
+}
+
+/// The value passed to `name`, an option of [`FLAGS`] that takes one, either
+/// as the next argument or after an `=`. An option passed without a value
+/// has an empty one.
+pub fn option_value(name: &str) -> Option<String> {
+    let flag = flag(name);
+    assert!(flag.takes_value, "{} doesn't take a value", name);
+    let mut args = std::env::args();
+    while let Some(arg) = args.next() {
+        if arg == flag.name {
+            return Some(args.next().unwrap_or_default());
+        }
+        if let Some(value) = arg
+            .strip_prefix(flag.name)
+            .and_then(|rest| rest.strip_prefix('='))
+        {
+            return Some(value.to_string());
+        }
+    }
+    None
+}
+
+/// The benchmark names among `args`, the command line arguments after the
+/// program name: the ones that are neither options nor option values.
+pub fn name_filters(args: &[String]) -> Vec<String> {
+    let mut filters = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if FLAGS
+            .iter()
+            .any(|flag| flag.takes_value && flag.name == arg)
+        {
+            args.next();
+        } else if !arg.starts_with("--") {
+            filters.push(arg.clone());
+        }
+    }
+    filters
+}
+
+/// Whether `filter`, a benchmark name passed on the command line, selects
+/// the benchmark called `name`.
+///
+/// A filter with a `*` or `?` is a glob pattern, which has to match the whole
+/// name. Any other filter only has to be part of the name.
+pub fn name_matches(filter: &str, name: &str) -> bool {
+    if !filter.contains(['*', '?']) {
+        return name.contains(filter);
+    }
+
+    // Backtrack to the last `*` on a mismatch, letting it match one more
+    // character each time.
+    let (filter, name) = (filter.as_bytes(), name.as_bytes());
+    let (mut f, mut n) = (0, 0);
+    let mut last_star = None;
+    while n < name.len() {
+        match filter.get(f) {
+            Some(b'*') => {
+                last_star = Some((f, n));
+                f += 1;
+            }
+            Some(&c) if c == b'?' || c == name[n] => {
+                f += 1;
+                n += 1;
+            }
+            _ => match last_star {
+                Some((star, matched)) => {
+                    last_star = Some((star, matched + 1));
+                    f = star + 1;
+                    n = matched + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    filter[f..].iter().all(|&c| c == b'*')
+}
+
+/// Whether the benchmark called `name` was selected on the command line,
+/// either by name or with `--filter-category`. Without either, every
+/// benchmark is.
+///
+/// If "--list" is passed, this prints the name and returns false. Under
+/// "--loop", this always returns false: only [`run_benchmarks`] loops, so
+/// sweeps and calibrations don't run in front of the looped benchmark.
+pub fn is_selected(name: &str, category: Option<Category>) -> std::io::Result<bool> {
+    if loop_from_args().is_some() {
+        return Ok(false);
+    }
+
+    let category_filter = Category::filter_from_args()?;
+    if category_filter.is_some() && category != category_filter {
+        return Ok(false);
+    }
+
+    // if "--list" is passed, we just print the name of the benchmark
+    if flag_passed("--list") {
+        println!("{}", name);
+        return Ok(false);
+    }
+
+    // check that the current benchmark is among those passed to argv
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let filters = name_filters(&args);
+    if filters.is_empty() {
+        return Ok(true);
+    }
+    Ok(filters.iter().any(|filter| name_matches(filter, name)))
+}
+
+/// Whether any of `benchmarks`, by name and category, would run: either
+/// selected like with [`is_selected`], or looped with "--loop". Lets `main`
+/// skip building the buffers that only these benchmarks use.
+///
+/// Like [`is_selected`], under "--list" this prints their names and returns
+/// false.
+pub fn any_selected(benchmarks: &[(&str, Option<Category>)]) -> std::io::Result<bool> {
+    if let Some(looped) = loop_from_args() {
+        return Ok(benchmarks.iter().any(|&(name, _)| name == looped));
+    }
+
+    let mut any = false;
+    for &(name, category) in benchmarks {
+        any |= is_selected(name, category)?;
+    }
+    Ok(any)
+}
+
+/// The mean, extremes and spread of one metric across several samples.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SampleStats {
+    pub mean: f64,
+    pub min: f64,
+    pub max: f64,
+    /// The sample standard deviation, or 0 with a single sample.
+    pub std_dev: f64,
+}
+
+impl SampleStats {
+    /// The statistics of `values`, which mustn't be empty.
+    pub fn from_values(values: &[f64]) -> SampleStats {
+        let count = values.len();
+        let mean = values.iter().sum::<f64>() / count as f64;
+        let variance = if count > 1 {
+            values
+                .iter()
+                .map(|value| (value - mean).powi(2))
+                .sum::<f64>()
+                / (count - 1) as f64
+        } else {
+            0.0
+        };
+        SampleStats {
+            mean,
+            min: values.iter().copied().fold(f64::INFINITY, f64::min),
+            max: values.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+            std_dev: variance.sqrt(),
+        }
+    }
+}
+
+/// Everything [`run_benchmarks`] measured for one benchmark, with the metrics
+/// derived from it.
+#[derive(Clone, Debug)]
+pub struct BenchmarkResults {
+    pub name: String,
+    pub config: BenchConfig,
+    /// The median sample, by time, which the derived metrics come from.
+    pub counts: Counts,
+    /// Every sample, in the order they were measured.
+    pub samples: Vec<Counts>,
+    /// Instructions per cycle.
+    pub ipc: f64,
+    /// L1D misses, as a fraction of L1D loads.
+    pub cache_miss_ratio: f64,
+    /// How many times the benchmark ran before it was measured.
+    pub warmup_runs: usize,
+}
+
+impl BenchmarkResults {
+    pub fn new(name: &str, config: BenchConfig, counts: Counts) -> BenchmarkResults {
+        BenchmarkResults::from_samples(name, config, vec![counts])
+    }
+
+    /// The results of several samples of the same benchmark, which mustn't
+    /// be empty.
+    pub fn from_samples(name: &str, config: BenchConfig, samples: Vec<Counts>) -> BenchmarkResults {
+        let mut by_time: Vec<&Counts> = samples.iter().collect();
+        by_time.sort_by_key(|counts| counts.nanoseconds());
+        let counts = by_time[by_time.len() / 2].clone();
+        BenchmarkResults {
+            name: name.to_string(),
+            ipc: counts.instructions as f64 / counts.cycles as f64,
+            cache_miss_ratio: counts.l1_cache_misses as f64 / counts.l1_cache_loads as f64,
+            warmup_runs: config.warmup,
+            config,
+            counts,
+            samples,
+        }
+    }
+
+    /// The bandwidth of the bytes the benchmark reads or writes, in GB/s, if
+    /// its config says how many it does.
+    pub fn bandwidth(&self) -> Option<f64> {
+        let bytes = self.config.bytes?;
+        Some(bytes as f64 / self.counts.nanoseconds() as f64)
+    }
+
+    /// The instructions per cycle of every sample, if cycles were counted.
+    pub fn ipc_stats(&self) -> Option<SampleStats> {
+        if !self.counts.counted("cycles") {
+            return None;
+        }
+        let ipcs: Vec<f64> = self
+            .samples
+            .iter()
+            .map(|counts| counts.instructions as f64 / counts.cycles as f64)
+            .collect();
+        Some(SampleStats::from_values(&ipcs))
+    }
+
+    /// The bandwidth of every sample, in GB/s, if the config says how many
+    /// bytes the benchmark reads or writes.
+    pub fn bandwidth_stats(&self) -> Option<SampleStats> {
+        let bytes = self.config.bytes?;
+        let bandwidths: Vec<f64> = self
+            .samples
+            .iter()
+            .map(|counts| bytes as f64 / counts.nanoseconds() as f64)
+            .collect();
+        Some(SampleStats::from_values(&bandwidths))
+    }
+
+    /// Print the spread of the IPC and bandwidth across samples, if there
+    /// was more than one.
+    fn print_sample_stats(&self, precision: usize) {
+        if self.samples.len() < 2 {
+            return;
+        }
+        println!(
+            "{:<20} {:>12} {:>12} {:>12} {:>12}",
+            format!("{} samples", self.samples.len()),
+            "mean",
+            "min",
+            "max",
+            "std dev"
+        );
+        for (metric, stats) in [
+            ("IPC", self.ipc_stats()),
+            ("Bandwidth (GB/s)", self.bandwidth_stats()),
+        ] {
+            if let Some(stats) = stats {
+                println!(
+                    "{:<20} {:>12.precision$} {:>12.precision$} {:>12.precision$} {:>12.precision$}",
+                    metric, stats.mean, stats.min, stats.max, stats.std_dev,
+                );
+            }
+        }
+    }
+
+    /// Print the counters as a table, followed by the per-iteration figures
+    /// and whatever else `config` asked for.
+    pub fn print_table(&self) -> std::io::Result<()> {
+        let BenchConfig {
+            iterations,
+            data_loads,
+            peak_ipc,
+            lines_fetched,
+            ref expected_l1_miss_rate,
+            expected_accesses,
+            ..
+        } = self.config;
+        let counts = &self.counts;
+
+        print_counts(counts)?;
+        if let Some(environment) = &counts.environment {
+            environment.print();
+        }
+
+        let precision = precision_from_args()?;
+
+        println!(
+            "Iterations: {count}",
+            count = iterations.separate_with_underscores()
+        );
+        println!("Warmup runs: {}", self.warmup_runs);
+        if !counts.counted("cycles") {
+            println!(
+                "Nanoseconds per iteration: {count:.precision$}",
+                count = counts.wall_clock as f64 / iterations as f64
+            );
+            if let Some(bandwidth) = self.bandwidth() {
+                println!("Bandwidth: {bandwidth:.precision$} GB/s");
+            }
+            self.print_sample_stats(precision);
+            println!();
+            return Ok(());
+        }
+        println!(
+            "Cycles per iteration: {count:.precision$}",
+            count = counts.cycles as f64 / iterations as f64
+        );
+        println!(
+            "Instructions per iteration: {count:.precision$}",
+            count = counts.instructions as f64 / iterations as f64
+        );
+        if let Some(data_loads) = data_loads {
+            println!(
+                "Cycles per data load: {count:.precision$}",
+                count = counts.cycles as f64 / data_loads as f64
+            );
+        }
+        if let Some(peak_ipc) = peak_ipc {
+            println!(
+                "Achieved {percent:.1}% of peak ({ipc:.precision$} / {peak_ipc:.precision$} instructions per cycle)",
+                percent = self.ipc / peak_ipc * 100.0,
+                ipc = self.ipc,
+            );
+        }
+        if let Some(expected) = expected_l1_miss_rate {
+            let verdict = match counts.l1_miss_rate_within(expected) {
+                Some(true) => "PASS",
+                Some(false) => "FAIL",
+                None => "SKIPPED",
+            };
+            println!(
+                "Expected L1D miss rate: {:.1}% to {:.1}%, measured {:.precision$}%: {}",
+                expected.start(),
+                expected.end(),
+                counts.l1_miss_rate(),
+                verdict,
+            );
+        }
+        if let Some(expected) = expected_accesses {
+            match counts.accesses_ratio(expected) {
+                Some(ratio) => println!(
+                    "Expected {} cache accesses, measured {:.precision$}x as many",
+                    expected.separate_with_underscores(),
+                    ratio
+                ),
+                None => println!(
+                    "Expected {} cache accesses, but they couldn't be counted",
+                    expected.separate_with_underscores()
+                ),
+            }
+        }
+        if let Some(bandwidth) = self.bandwidth() {
+            println!("Bandwidth: {bandwidth:.precision$} GB/s");
+        }
+        if let Some(lines_fetched) = lines_fetched {
+            println!(
+                "Bandwidth of fetched lines: {count:.precision$} GB/s",
+                count = (lines_fetched * 64) as f64 / counts.nanoseconds() as f64
+            );
+        }
+        self.print_sample_stats(precision);
+        println!();
+
+        Ok(())
+    }
+}
+
+/// Run the benchmark called `name` if it was selected, print its results in
+/// the format passed with `--format`, and return them.
+///
+/// Returns `None` if the benchmark didn't run, or ran without counters, like
+/// with `--loop` or `--warmup-runs`. A benchmark still running after
+/// `--timeout` is recorded as timed out, and the suite moves on once it
+/// returns, see [`Watchdog`].
+///
+/// With `--format csv`, the row goes to `csv`, which `main` creates once and
+/// passes to every benchmark, so that the header is only written once.
+pub fn run_benchmarks<W: std::io::Write>(
+    name: &str,
+    callback: impl FnMut(),
+    config: BenchConfig,
+    csv: Option<&mut CsvReporter<W>>,
+) -> Result<Option<BenchmarkResults>, BenchError> {
+    match run_benchmark(name, callback, config, csv) {
+        Err(err @ BenchError::TimedOut { .. }) => {
+            eprintln!("Warning: {}", err);
+            TIMED_OUT.lock().unwrap().push(name.to_string());
+            Ok(None)
+        }
+        result => result,
+    }
+}
+
+fn run_benchmark<W: std::io::Write>(
+    name: &str,
+    mut callback: impl FnMut(),
+    config: BenchConfig,
+    csv: Option<&mut CsvReporter<W>>,
+) -> Result<Option<BenchmarkResults>, BenchError> {
+    let BenchConfig {
+        iterations,
+        line_offset,
+        fence,
+        category,
+        warmup,
+        ..
+    } = config;
+
+    // Under an external profiler, run just the one benchmark, for as long as
+    // it takes, without counters of our own.
+    if let Some(looped) = loop_from_args() {
+        if name == looped {
+            eprintln!("Looping {} until Ctrl-C", name);
+            while !interrupted() {
+                callback();
+            }
+            check_interrupted()?;
+        }
+        return Ok(None);
+    }
+
+    if !is_selected(name, category)? {
+        return Ok(None);
+    }
+    check_interrupted()?;
+
+    let skip_all_this = false;
+    if skip_all_this {
+        callback();
+        return Ok(None);
+    }
+
+    if let Some(runs) = warmup_runs_from_args()? {
+        let mut trajectory = Vec::with_capacity(runs);
+        for _ in 0..runs {
+            let counts = measure_fenced(fence, &mut callback)?;
+            trajectory.push(counts.cycles as f64 / iterations as f64);
+        }
+        print_warmup(name, &trajectory);
+        return Ok(None);
+    }
+
+    warm_up(name, warmup, &mut callback)?;
+
+    let format = OutputFormat::from_args()?;
+    if format != OutputFormat::Table {
+        let results = measure_samples(name, &config, callback)?;
+        let counts = &results.counts;
+        match format {
+            OutputFormat::Raw => {
+                for line in raw_lines(name, counts, &raw_event_codes()) {
+                    println!("{}", line);
+                }
+                println!();
+            }
+            OutputFormat::JsonLines => println!("{}", json_line(name, category, counts)),
+            OutputFormat::PerfCsv => {
+                println!("# benchmark {}", name);
+                for line in perf_csv_lines(counts) {
+                    println!("{}", line);
+                }
+            }
+            OutputFormat::Csv => match csv {
+                Some(reporter) => reporter.write_row(name, counts)?,
+                None => {
+                    return Err(std::io::Error::other(
+                        "no CSV reporter to write to, pass one to run_benchmarks",
+                    )
+                    .into())
+                }
+            },
+            OutputFormat::Prometheus | OutputFormat::Json => {
+                SUMMARY.lock().unwrap().push(SummaryRow {
+                    name: name.to_string(),
+                    category,
+                    iterations,
+                    counts: counts.clone(),
+                });
+            }
+            OutputFormat::Table => unreachable!(),
+        }
+        return Ok(Some(results));
+    }
+
+    println!("====================================================================");
+    println!("Benchmarking {}... ", name);
+    if let Some(category) = category {
+        println!("Category: {}", category);
+    }
+    if let Some(line_offset) = line_offset {
+        println!("Offset within cache line: {} bytes", line_offset);
+    }
+
+    let results = measure_samples(name, &config, callback)?;
+    SUMMARY.lock().unwrap().push(SummaryRow {
+        name: name.to_string(),
+        category,
+        iterations,
+        counts: results.counts.clone(),
+    });
+
+    results.print_table()?;
+    Ok(Some(results))
+}
+
+/// Print `counts` as a table similar to `perf stat`'s, with the derived
+/// metrics that only depend on the counters.
+pub fn print_counts(counts: &Counts) -> std::io::Result<()> {
+    /*
+    We want to display something like this:
+
+    ====================================================================
+            234.04 msec task-clock                       #
+                 1      context-switches                 #    4.273 /sec
+                 0      cpu-migrations                   #    0.000 /sec
+                72      page-faults                      #  307.634 /sec
+       916,694,940      cycles                           #    3.917 GHz
+     3,768,251,802      instructions                     #    4.11   per cycle
+
+     1,009,884,042      L1-dcache-loads
+            25,093      L1-dcache-load-misses            #    0.00% of all L1-dcache accesses
+            12,925      L1-dcache-prefetches
+            25,098      l2_cache_accesses_from_dc_misses
+            13,680      l2_cache_hits_from_dc_misses
+    ====================================================================
+    */
+
+    let precision = precision_from_args()?;
+    if !counts.counted("task_clock") {
+        let (wall_clock, unit) = format_duration(counts.wall_clock as f64, precision);
+        print_counter(precision, &wall_clock, unit, "wall-clock", None);
+        println!("({})", NO_COUNTERS);
+        return Ok(());
+    }
+    let task_clock_nsec = counts.task_clock as f64;
+    let task_clock_s = counts.task_clock as f64 / 1_000_000_000.0;
+
+    let (task_clock, unit) = format_duration(task_clock_nsec, precision);
+    print_counter(precision, &task_clock, unit, "task-clock", None);
+    let (wall_clock, unit) = format_duration(counts.wall_clock as f64, precision);
+    print_counter(
+        precision,
+        &wall_clock,
+        unit,
+        "wall-clock",
+        Some((counts.wall_clock as f64 / task_clock_nsec, "x task-clock")),
+    );
+    if counts.looks_preempted() {
+        println!(
+            "Warning: wall-clock exceeds task-clock by more than {:.0}%, the benchmark was likely preempted",
+            PREEMPTION_THRESHOLD * 100.0
+        );
+    }
+    print_counter_with_info(
+        precision,
+        counts.context_switches,
+        "context-switches",
+        counts.context_switches as f64 / task_clock_s,
+        "/sec",
+    );
+    print_counter_with_info(
+        precision,
+        counts.cpu_migrations,
+        "cpu-migrations",
+        counts.cpu_migrations as f64 / task_clock_s,
+        "/sec",
+    );
+    print_counter_with_info(
+        precision,
+        counts.page_faults,
+        "page-faults",
+        counts.page_faults as f64 / task_clock_s,
+        "/sec",
+    );
+    println!();
+
+    print_counter_with_info(
+        precision,
+        counts.cycles,
+        "cycles",
+        counts.cycles as f64 / task_clock_nsec,
+        "GHz",
+    );
+    // Ratios between counters of different groups combine separate runs of
+    // the benchmark. Mark them, and explain the mark below the table.
+    let mut cross_run = false;
+    let mut ratio_unit = |a: &str, b: &str, unit: &str| {
+        if counts.same_run(a, b) {
+            unit.to_string()
+        } else {
+            cross_run = true;
+            format!("{} (*)", unit)
+        }
+    };
+
+    if !counts.counted("ref_cycles") {
+        print_unavailable_counter(counts, "ref_cycles", "ref-cycles");
+    } else if counts.looks_inactive(counts.ref_cycles) {
+        print_inactive_counter(counts.ref_cycles, "ref-cycles");
+    } else {
+        print_ratio(
+            precision,
+            counts,
+            counts.ref_cycles,
+            "ref-cycles",
+            ("cycles", "ref_cycles"),
+            counts.cycles as f64 / counts.ref_cycles as f64,
+            &ratio_unit("cycles", "ref_cycles", "x reference frequency"),
+        );
+    }
+    print_ratio(
+        precision,
+        counts,
+        counts.instructions,
+        "instructions",
+        ("instructions", "cycles"),
+        counts.instructions as f64 / counts.cycles as f64,
+        "per cycle",
+    );
+    println!();
+
+    // Some VMs let us open these counters, but they always read zero. Rather
+    // than print a plausible-looking 0% miss rate, flag them as inactive.
+    if !counts.counted("cache_accesses") {
+        print_unavailable_counter(counts, "cache_accesses", "cache accesses");
+    } else if counts.looks_inactive(counts.cache_accesses) {
+        print_inactive_counter(counts.cache_accesses, "cache accesses");
+    } else {
+        print_counter(
+            precision,
+            &counts.cache_accesses.separate_with_underscores(),
+            "",
+            "cache accesses",
+            None,
+        );
+    }
+    if !counts.counted("l1_cache_loads") || !counts.counted("l1_cache_misses") {
+        print_available_counter(precision, counts, "l1_cache_loads", "L1D cache loads");
+        print_available_counter(precision, counts, "l1_cache_misses", "L1D cache misses");
+    } else if counts.looks_inactive(counts.l1_cache_loads) {
+        print_inactive_counter(counts.l1_cache_loads, "L1D cache loads");
+        print_inactive_counter(counts.l1_cache_misses, "L1D cache misses");
+    } else {
+        print_counter(
+            precision,
+            &counts.l1_cache_loads.separate_with_underscores(),
+            "",
+            "L1D cache loads",
+            None,
+        );
+        print_ratio(
+            precision,
+            counts,
+            counts.l1_cache_misses,
+            "L1D cache misses",
+            ("l1_cache_misses", "l1_cache_loads"),
+            (counts.l1_cache_misses as f64 / counts.l1_cache_loads as f64) * 100.0,
+            "% of L1D accesses",
+        );
+    }
+    print_available_counter(
+        precision,
+        counts,
+        "l1_cache_prefetches",
+        "L1D cache prefetches",
+    );
+    if !counts.counted("l1_cache_store_misses") {
+        print_unavailable_counter(counts, "l1_cache_store_misses", "L1D store misses");
+    } else if counts.looks_inactive(counts.l1_cache_store_misses) {
+        print_inactive_counter(counts.l1_cache_store_misses, "L1D store misses");
+    } else {
+        print_counter(
+            precision,
+            &counts.l1_cache_store_misses.separate_with_underscores(),
+            "",
+            "L1D store misses",
+            None,
+        );
+    }
+    if !counts.counted("l2_cache_accesses_from_store_misses") {
+        print_unavailable_counter(
+            counts,
+            "l2_cache_accesses_from_store_misses",
+            "L2 accesses from store misses",
+        );
+    } else if counts.looks_inactive(counts.l2_cache_accesses_from_store_misses) {
+        print_inactive_counter(
+            counts.l2_cache_accesses_from_store_misses,
+            "L2 accesses from store misses",
+        );
+    } else {
+        print_counter(
+            precision,
+            &counts
+                .l2_cache_accesses_from_store_misses
+                .separate_with_underscores(),
+            "",
+            "L2 accesses from store misses",
+            None,
+        );
+    }
+    if !counts.counted("l1i_cache_misses") {
+        print_unavailable_counter(counts, "l1i_cache_misses", "L1I cache misses");
+    } else {
+        print_ratio(
+            precision,
+            counts,
+            counts.l1i_cache_misses,
+            "L1I cache misses",
+            ("l1i_cache_misses", "instructions"),
+            counts.l1i_cache_misses as f64 / counts.instructions as f64 * 1000.0,
+            &ratio_unit("l1i_cache_misses", "instructions", "per 1k instructions"),
+        );
+    }
+    if !counts.counted("dtlb_load_misses") {
+        print_unavailable_counter(counts, "dtlb_load_misses", "DTLB load misses");
+    } else if counts.looks_inactive(counts.dtlb_load_misses) {
+        print_inactive_counter(counts.dtlb_load_misses, "DTLB load misses");
+    } else {
+        print_ratio(
+            precision,
+            counts,
+            counts.dtlb_load_misses,
+            "DTLB load misses",
+            ("dtlb_load_misses", "l1_cache_loads"),
+            counts.dtlb_load_misses as f64 / counts.l1_cache_loads as f64 * 100.0,
+            &ratio_unit("dtlb_load_misses", "l1_cache_loads", "% of L1D accesses"),
+        );
+    }
+
+    if !counts.counted("l2_cache_accesses_from_dc_misses")
+        || !counts.counted("l2_cache_hits_from_dc_misses")
+    {
+        print_available_counter(
+            precision,
+            counts,
+            "l2_cache_accesses_from_dc_misses",
+            "L2 accesses from L1 misses",
+        );
+        print_available_counter(
+            precision,
+            counts,
+            "l2_cache_hits_from_dc_misses",
+            "L2 hits from L1 misses",
+        );
+    } else if counts.looks_inactive(counts.l2_cache_accesses_from_dc_misses) {
+        print_inactive_counter(
+            counts.l2_cache_accesses_from_dc_misses,
+            "L2 accesses from L1 misses",
+        );
+        print_inactive_counter(
+            counts.l2_cache_hits_from_dc_misses,
+            "L2 hits from L1 misses",
+        );
+    } else {
+        print_ratio(
+            precision,
+            counts,
+            counts.l2_cache_accesses_from_dc_misses,
+            "L2 accesses from L1 misses",
+            ("l2_cache_accesses_from_dc_misses", "l1_cache_misses"),
+            (counts.l2_cache_accesses_from_dc_misses as f64 / counts.l1_cache_misses as f64)
+                * 100.0,
+            &ratio_unit(
+                "l2_cache_accesses_from_dc_misses",
+                "l1_cache_misses",
+                "% of L1D misses",
+            ),
+        );
+        print_ratio(
+            precision,
+            counts,
+            counts.l2_cache_hits_from_dc_misses,
+            "L2 hits from L1 misses",
+            (
+                "l2_cache_hits_from_dc_misses",
+                "l2_cache_accesses_from_dc_misses",
+            ),
+            (counts.l2_cache_hits_from_dc_misses as f64
+                / counts.l2_cache_accesses_from_dc_misses as f64)
+                * 100.0,
+            "% of L2 accesses",
+        );
+    }
+    if cross_run {
+        println!(
+            "(*) Combines counters from separate runs of the benchmark, which may have \
+             started from different cache or frequency states."
+        );
+    }
+    println!();
+
+    if verbose() && !counts.times.is_empty() {
+        println!("Fraction of the run each counter was counting:");
+        for (key, _) in counts.values() {
+            if counts.times.contains_key(&key) {
+                println!("{:>16.1}% {}", counts.running_fraction(&key) * 100.0, key);
+            }
+        }
+        println!();
+    }
+
+    Ok(())
+}
+
+/// A benchmark that ran, for [`print_summary`].
+struct SummaryRow {
+    name: String,
+    category: Option<Category>,
+    iterations: usize,
+    counts: Counts,
+}
+
+static SUMMARY: Mutex<Vec<SummaryRow>> = Mutex::new(Vec::new());
+
+/// The benchmarks that ran for longer than `--timeout`, for [`print_summary`].
+static TIMED_OUT: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Print a compact table of every benchmark that ran so far, grouped by
+/// category.
+///
+/// With `--format prometheus` or `--format json`, print the whole output
+/// instead. The other machine-readable formats already printed their rows, so
+/// they get nothing.
+pub fn print_summary() {
+    let rows = SUMMARY.lock().unwrap();
+    let timed_out = TIMED_OUT.lock().unwrap();
+    let comparisons = COMPARISONS.lock().unwrap();
+    if rows.is_empty() && timed_out.is_empty() && comparisons.is_empty() {
+        return;
+    }
+
+    let format = OutputFormat::from_args();
+    // Other formats are parsed as a single table, so the names of the
+    // benchmarks that timed out go to stderr rather than after their rows.
+    if !matches!(format, Ok(OutputFormat::Table)) && !timed_out.is_empty() {
+        eprintln!("Timed out: {}", timed_out.join(", "));
+    }
+    if matches!(format, Ok(OutputFormat::Prometheus)) {
+        let cpu_model = Topology::detect()
+            .map(|topology| topology.model)
+            .unwrap_or_default();
+        let results: Vec<(&str, usize, &Counts)> = rows
+            .iter()
+            .map(|row| (row.name.as_str(), row.iterations, &row.counts))
+            .collect();
+        for line in prometheus_lines(&cpu_model, &results, &comparisons) {
+            println!("{}", line);
+        }
+        return;
+    }
+    if matches!(format, Ok(OutputFormat::Json)) {
+        let results: Vec<(&str, usize, &Counts)> = rows
+            .iter()
+            .map(|row| (row.name.as_str(), row.iterations, &row.counts))
+            .collect();
+        if let Err(err) = write_json(&mut std::io::stdout().lock(), &results, &comparisons) {
+            eprintln!("Warning: can't write the results: {}", err);
+        }
+        return;
+    }
+    if !matches!(format, Ok(OutputFormat::Table)) {
+        return;
+    }
+
+    println!("====================================================================");
+    println!("Summary");
+    let categories = Category::ALL.into_iter().map(Some).chain([None]);
+    for category in categories {
+        let mut rows = rows
+            .iter()
+            .filter(|row| row.category == category)
+            .peekable();
+        if rows.peek().is_none() {
+            continue;
+        }
+
+        match category {
+            Some(category) => println!("\n[{}]", category),
+            None => println!("\n[Uncategorized]"),
+        }
+        println!(
+            "{:<50} {:>12} {:>8} {:>12}",
+            "benchmark", "cycles/iter", "IPC", "L1D miss %"
+        );
+        for row in rows {
+            let counts = &row.counts;
+            let l1_miss_rate =
+                if counts.counted("l1_cache_loads") && counts.counted("l1_cache_misses") {
+                    format!("{:.3}", counts.l1_miss_rate())
+                } else {
+                    "n/a".to_string()
+                };
+            let (cycles, ipc) = if counts.counted("cycles") && counts.counted("instructions") {
+                (
+                    format!("{:.3}", counts.cycles as f64 / row.iterations as f64),
+                    format!("{:.3}", counts.instructions as f64 / counts.cycles as f64),
+                )
+            } else {
+                ("n/a".to_string(), "n/a".to_string())
+            };
+            println!(
+                "{:<50} {:>12} {:>8} {:>12}",
+                row.name, cycles, ipc, l1_miss_rate,
+            );
+        }
+    }
+    if !timed_out.is_empty() {
+        println!("\nTimed out: {}", timed_out.join(", "));
+    }
+    println!();
+}
+
+/// Read the number of times to run the whole suite from `--suite-repeats`,
+/// defaulting to 1.
+pub fn suite_repeats_from_args() -> std::io::Result<usize> {
+    let Some(value) = option_value("--suite-repeats") else {
+        return Ok(1);
+    };
+    match value.parse() {
+        Ok(repeats) if repeats > 0 => Ok(repeats),
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid number of suite repeats {:?}", value),
+        )),
+    }
+}
+
+/// Read an iteration count from the environment variable `var`, like
+/// `PREFETCH_ITERS`, defaulting to `default` if it isn't set.
+///
+/// Unlike options, these stay set across runs, to fit the suite to a
+/// machine without typing them out each time.
+pub fn iterations_from_env(var: &str, default: usize) -> std::io::Result<usize> {
+    let Some(value) = std::env::var_os(var) else {
+        return Ok(default);
+    };
+    match value.to_str().map(str::parse) {
+        Some(Ok(iterations)) if iterations > 0 => Ok(iterations),
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid number of iterations {:?} in {}", value, var),
+        )),
+    }
+}
+
+/// How long a benchmark may run before it's recorded as timed out, unless
+/// `--timeout` says otherwise. Generous, since the slowest benchmarks of the
+/// suite take a few seconds.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Read the per-benchmark timeout, in seconds, from `--timeout`, defaulting
+/// to [`DEFAULT_TIMEOUT`].
+pub fn timeout_from_args() -> std::io::Result<Duration> {
+    let Some(value) = option_value("--timeout") else {
+        return Ok(DEFAULT_TIMEOUT);
+    };
+    match value.parse::<f64>() {
+        Ok(seconds) if seconds > 0.0 => Ok(Duration::from_secs_f64(seconds)),
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid timeout {:?}", value),
+        )),
+    }
+}
+
+type ExitHook = Box<dyn Fn() + Send>;
+
+/// What to undo before [`Watchdog`] exits the process, keyed by the id
+/// [`add_exit_hook`] returned.
+static EXIT_HOOKS: Mutex<Vec<(u64, ExitHook)>> = Mutex::new(Vec::new());
+static NEXT_EXIT_HOOK: AtomicU64 = AtomicU64::new(0);
+
+/// Run `hook` if [`Watchdog`] gives up on a stuck benchmark and exits the
+/// process, which runs no destructors. Whatever changes machine settings,
+/// like the prefetchers or the CPU frequency, registers how to restore them
+/// here, and removes the hook with [`remove_exit_hook`] once it restored
+/// them itself.
+pub fn add_exit_hook(hook: impl Fn() + Send + 'static) -> u64 {
+    let id = NEXT_EXIT_HOOK.fetch_add(1, Ordering::SeqCst);
+    EXIT_HOOKS.lock().unwrap().push((id, Box::new(hook)));
+    id
+}
+
+/// Forget the hook [`add_exit_hook`] returned `id` for.
+pub fn remove_exit_hook(id: u64) {
+    EXIT_HOOKS
+        .lock()
+        .unwrap()
+        .retain(|(hook_id, _)| *hook_id != id);
+}
+
+/// Run the hooks registered with [`add_exit_hook`], the last one first.
+fn run_exit_hooks() {
+    for (_, hook) in EXIT_HOOKS.lock().unwrap().iter().rev() {
+        hook();
+    }
+}
+
+/// A thread watching one benchmark, since a synchronous callback can't be
+/// interrupted safely. Past the timeout, it flags the benchmark so that its
+/// results are discarded once it returns. Past twice the timeout, the
+/// benchmark is presumably stuck, so it prints the summary so far, runs the
+/// hooks registered with [`add_exit_hook`] and exits the process with status
+/// 124, like `timeout(1)`, rather than wedge an unattended run.
+pub struct Watchdog {
+    /// Set by [`Watchdog::finish`], to wake the thread up early.
+    done: Arc<(Mutex<bool>, Condvar)>,
+    timed_out: Arc<AtomicBool>,
+    thread: std::thread::JoinHandle<()>,
+}
+
+impl Watchdog {
+    pub fn start(name: &str, timeout: Duration) -> Watchdog {
+        let stuck = name.to_string();
+        Watchdog::with_give_up(name, timeout, move || {
+            TIMED_OUT.lock().unwrap().push(stuck);
+            print_summary();
+            run_exit_hooks();
+            std::process::exit(124);
+        })
+    }
+
+    /// Like [`Watchdog::start`], but run `give_up` instead of exiting the
+    /// process past twice the timeout.
+    pub fn with_give_up(
+        name: &str,
+        timeout: Duration,
+        give_up: impl FnOnce() + Send + 'static,
+    ) -> Watchdog {
+        let done = Arc::new((Mutex::new(false), Condvar::new()));
+        let timed_out = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let done = done.clone();
+            let timed_out = timed_out.clone();
+            let name = name.to_string();
+            std::thread::spawn(move || {
+                let (lock, condvar) = &*done;
+                let (finished, _) = condvar
+                    .wait_timeout_while(lock.lock().unwrap(), timeout, |done| !*done)
+                    .unwrap();
+                if *finished {
+                    return;
+                }
+                timed_out.store(true, Ordering::SeqCst);
+                eprintln!(
+                    "Warning: {} is still running after {:.0?}, it will be recorded as timed out",
+                    name, timeout
+                );
+                let (finished, _) = condvar
+                    .wait_timeout_while(finished, timeout, |done| !*done)
+                    .unwrap();
+                if *finished {
+                    return;
+                }
+                drop(finished);
+                eprintln!("{} looks stuck, giving up on the whole run", name);
+                give_up();
+            })
+        };
+        Watchdog {
+            done,
+            timed_out,
+            thread,
+        }
+    }
+
+    /// Stop watching, and return whether the benchmark timed out.
+    pub fn finish(self) -> bool {
+        let (lock, condvar) = &*self.done;
+        *lock.lock().unwrap() = true;
+        condvar.notify_one();
+        self.thread.join().unwrap();
+        self.timed_out.load(Ordering::SeqCst)
+    }
+}
+
+/// Below this much task-clock time, a benchmark's counts are dominated by the
+/// cost of enabling and disabling the counters.
+pub const DEFAULT_MIN_RUNTIME: Duration = Duration::from_millis(1);
+
+/// Read the minimum task-clock time of a benchmark, in milliseconds, from
+/// `--min-runtime`, defaulting to [`DEFAULT_MIN_RUNTIME`].
+pub fn min_runtime_from_args() -> std::io::Result<Duration> {
+    let Some(value) = option_value("--min-runtime") else {
+        return Ok(DEFAULT_MIN_RUNTIME);
+    };
+    match value.parse::<f64>() {
+        Ok(msec) if msec >= 0.0 => Ok(Duration::from_secs_f64(msec / 1000.0)),
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid minimum runtime {:?}", value),
+        )),
+    }
+}
+
+/// If a benchmark of `iterations` iterations spent less than `min_runtime`
+/// on the CPU (or on the wall clock, without counters), say how long it ran
+/// and how many iterations it would need instead.
+pub fn too_short_warning(
+    iterations: usize,
+    counts: &Counts,
+    min_runtime: Duration,
+) -> Option<String> {
+    let min_runtime_nsec = min_runtime.as_nanos() as u64;
+    let runtime_nsec = counts.nanoseconds();
+    if runtime_nsec >= min_runtime_nsec {
+        return None;
+    }
+
+    let scale = min_runtime_nsec as f64 / runtime_nsec.max(1) as f64;
+    let recommended = (iterations as f64 * scale).ceil() as usize;
+    let (runtime, runtime_unit) = format_duration(runtime_nsec as f64, DEFAULT_PRECISION);
+    let (min, min_unit) = format_duration(min_runtime_nsec as f64, DEFAULT_PRECISION);
+    Some(format!(
+        "ran for {} {}, less than the {} {} minimum, use at least {} iterations",
+        runtime,
+        runtime_unit,
+        min,
+        min_unit,
+        recommended.separate_with_underscores()
+    ))
+}
+
+/// The relative change from `baseline` to `variant`, in percent, or `None`
+/// if `baseline` is zero, so that there's nothing to compare with.
+pub fn percent_change(baseline: f64, variant: f64) -> Option<f64> {
+    if baseline == 0.0 {
+        return None;
+    }
+    Some((variant / baseline - 1.0) * 100.0)
+}
+
+/// The comparisons reported by [`AbComparison::report`], for the output
+/// formats that [`print_summary`] prints all at once.
+static COMPARISONS: Mutex<Vec<AbComparison>> = Mutex::new(Vec::new());
+
+/// Two variants of a benchmark, eg without and with a software prefetch,
+/// each measured by [`run_benchmarks`], to tell whether the variant helped
+/// and by how much.
+#[derive(Clone, Debug)]
+pub struct AbComparison {
+    pub name: String,
+    pub baseline: BenchmarkResults,
+    pub variant: BenchmarkResults,
+}
+
+impl AbComparison {
+    /// The change in bandwidth from the baseline to the variant, in percent,
+    /// if the config says how many bytes they read or write.
+    pub fn bandwidth_change(&self) -> Option<f64> {
+        percent_change(self.baseline.bandwidth()?, self.variant.bandwidth()?)
+    }
+
+    /// The change in instructions per cycle, in percent, if cycles were
+    /// counted.
+    pub fn ipc_change(&self) -> Option<f64> {
+        if !self.baseline.counts.counted("cycles") {
+            return None;
+        }
+        percent_change(self.baseline.ipc, self.variant.ipc)
+    }
+
+    /// The change in L1D miss rate, in percent of the baseline's rate, if L1D
+    /// loads were counted.
+    pub fn l1_miss_rate_change(&self) -> Option<f64> {
+        if !self.baseline.counts.counted("l1_cache_loads") {
+            return None;
+        }
+        percent_change(
+            self.baseline.counts.l1_miss_rate(),
+            self.variant.counts.l1_miss_rate(),
+        )
+    }
+
+    /// The metrics both sides have, as the name of the metric, then its
+    /// value for the baseline and the variant, and the change from one to
+    /// the other.
+    fn rows(&self) -> Vec<(&'static str, f64, f64, Option<f64>)> {
+        let mut rows = Vec::new();
+        if let (Some(baseline), Some(variant)) =
+            (self.baseline.bandwidth(), self.variant.bandwidth())
+        {
+            rows.push((
+                "Bandwidth (GB/s)",
+                baseline,
+                variant,
+                self.bandwidth_change(),
+            ));
+        }
+        if self.baseline.counts.counted("cycles") {
+            rows.push((
+                "IPC",
+                self.baseline.ipc,
+                self.variant.ipc,
+                self.ipc_change(),
+            ));
+        }
+        if self.baseline.counts.counted("l1_cache_loads") {
+            rows.push((
+                "L1D miss rate (%)",
+                self.baseline.counts.l1_miss_rate(),
+                self.variant.counts.l1_miss_rate(),
+                self.l1_miss_rate_change(),
+            ));
+        }
+        rows
+    }
+
+    /// Print the bandwidth, IPC and L1D miss rate of both side by side, with
+    /// the change from one to the other.
+    pub fn print(&self) -> std::io::Result<()> {
+        let precision = precision_from_args()?;
+        println!("====================================================================");
+        println!(
+            "Comparing {} with {}...",
+            self.variant.name, self.baseline.name
+        );
+        println!(
+            "{:<20} {:>12} {:>12} {:>10}",
+            "", "baseline", "variant", "change"
+        );
+        for (metric, baseline, variant, change) in self.rows() {
+            let change = match change {
+                Some(change) => format!("{:+.1}%", change),
+                None => "n/a".to_string(),
+            };
+            println!(
+                "{:<20} {:>12.precision$} {:>12.precision$} {:>10}",
+                metric, baseline, variant, change
+            );
+        }
+        println!();
+        Ok(())
+    }
+
+    /// The comparison as JSON, with the names of both benchmarks and each
+    /// change in percent, or `null` where there's nothing to compare.
+    pub fn json(&self) -> serde_json::Value {
+        json!({
+            "baseline": self.baseline.name,
+            "variant": self.variant.name,
+            "bandwidth_change": self.bandwidth_change(),
+            "ipc_change": self.ipc_change(),
+            "l1_miss_rate_change": self.l1_miss_rate_change(),
+        })
+    }
+
+    /// Report the comparison in the output format passed on the command
+    /// line. The JSON and Prometheus formats print it with the summary. The
+    /// CSV formats don't, as they only have room for counts: the two
+    /// benchmarks' own rows are what the comparison is made of.
+    pub fn report(self) -> std::io::Result<()> {
+        match OutputFormat::from_args()? {
+            OutputFormat::Table => self.print()?,
+            OutputFormat::Raw => {
+                println!("comparison={}", self.name);
+                println!("baseline={}", self.baseline.name);
+                println!("variant={}", self.variant.name);
+                for (key, change) in [
+                    ("bandwidth_change", self.bandwidth_change()),
+                    ("ipc_change", self.ipc_change()),
+                    ("l1_miss_rate_change", self.l1_miss_rate_change()),
+                ] {
+                    if let Some(change) = change {
+                        println!("{}={}", key, change);
+                    }
+                }
+                println!();
+            }
+            OutputFormat::JsonLines => {
+                let mut line = self.json();
+                line["schema_version"] = json!(SCHEMA_VERSION);
+                line["comparison"] = json!(self.name);
+                println!("{}", line);
+            }
+            OutputFormat::Json | OutputFormat::Prometheus => {
+                COMPARISONS.lock().unwrap().push(self);
+            }
+            OutputFormat::Csv | OutputFormat::PerfCsv => {}
+        }
+        Ok(())
+    }
+}
+
+/// Run the benchmark `runs` times without measuring it, under the same
+/// [`Watchdog`] as the measured runs.
+fn warm_up(name: &str, runs: usize, mut callback: impl FnMut()) -> Result<(), BenchError> {
+    let watchdog = Watchdog::start(name, timeout_from_args()?);
+    for _ in 0..runs {
+        callback();
+    }
+    if watchdog.finish() {
+        return Err(BenchError::TimedOut {
+            name: name.to_string(),
+        });
+    }
+    check_interrupted()
+}
+
+/// Measure the benchmark `config.samples` times, or as many as `--samples`
+/// says, see [`measure_benchmark`], and check the median sample with
+/// [`check_counts`]. Checking every sample would repeat the same warnings
+/// once per sample.
+fn measure_samples(
+    name: &str,
+    config: &BenchConfig,
+    mut callback: impl FnMut(),
+) -> Result<BenchmarkResults, BenchError> {
+    let config = &BenchConfig {
+        samples: samples_from_args()?.unwrap_or(config.samples),
+        ..config.clone()
+    };
+    let samples = (0..config.samples)
+        .map(|_| measure_benchmark(name, config.fence, &mut callback))
+        .collect::<Result<Vec<_>, _>>()?;
+    let results = BenchmarkResults::from_samples(name, config.clone(), samples);
+    check_counts(
+        name,
+        config.iterations,
+        config.expected_l1_miss_rate.as_ref(),
+        config.expected_accesses,
+        &results.counts,
+    )?;
+    Ok(results)
+}
+
+/// Same as [`measure_fenced`], but fails if the benchmark runs past its
+/// [`Watchdog`], and records how the environment changed if `--environment`
+/// was passed.
+fn measure_benchmark(
+    name: &str,
+    fence: Fence,
+    callback: impl FnMut(),
+) -> Result<Counts, BenchError> {
+    let start = Environment::requested()
+        .then(Environment::current)
+        .transpose()?;
+    let watchdog = Watchdog::start(name, timeout_from_args()?);
+    let measured = measure_fenced(fence, callback);
+    if watchdog.finish() {
+        return Err(BenchError::TimedOut {
+            name: name.to_string(),
+        });
+    }
+    let mut counts = measured?;
+    if let Some(start) = start {
+        counts.environment = Some(EnvironmentChange {
+            start,
+            end: Environment::current()?,
+        });
+    }
+    Ok(counts)
+}
+
+/// Warn if the benchmark was too short to measure reliably or missed its
+/// expected L1D miss rate or cache accesses, or fail if `--strict` was
+/// passed.
+fn check_counts(
+    name: &str,
+    iterations: usize,
+    expected_l1_miss_rate: Option<&RangeInclusive<f64>>,
+    expected_accesses: Option<usize>,
+    counts: &Counts,
+) -> Result<(), BenchError> {
+    let mut warnings = Vec::new();
+    warnings.extend(too_short_warning(
+        iterations,
+        counts,
+        min_runtime_from_args()?,
+    ));
+    if let Some(expected) = expected_l1_miss_rate {
+        if counts.l1_miss_rate_within(expected) == Some(false) {
+            warnings.push(format!(
+                "missed {:.3}% of L1D loads, expected {:.1}% to {:.1}%",
+                counts.l1_miss_rate(),
+                expected.start(),
+                expected.end()
+            ));
+        }
+    }
+    if let Some(expected) = expected_accesses {
+        if let Some(ratio) = counts.accesses_ratio(expected) {
+            if (ratio - 1.0).abs() > ACCESS_TOLERANCE {
+                warnings.push(format!(
+                    "made {:.2}x the {} cache accesses expected",
+                    ratio,
+                    expected.separate_with_underscores()
+                ));
+            }
+        }
+    }
+    if let Some(environment) = &counts.environment {
+        warnings.extend(environment.throttle_warning());
+    }
+
+    // Scaling makes up for multiplexing, but only if the benchmark behaves
+    // the same all along, so say which counts were extrapolated. Under
+    // --strict, the check below decides whether that's too much.
+    let multiplexed = counts.multiplexed();
+    if !multiplexed.is_empty() {
+        let events: Vec<String> = multiplexed
+            .iter()
+            .map(|(key, running_fraction)| format!("{} ({:.0}%)", key, running_fraction * 100.0))
+            .collect();
+        eprintln!(
+            "Warning: {} counters were multiplexed and scaled up from part of the run: {}",
+            name,
+            events.join(", ")
+        );
+    }
+
+    let strict = flag_passed("--strict");
+    if strict {
+        // Ratios already account for multiplexing, but the raw counts of an
+        // event that barely ran are mostly extrapolation.
+        let barely_ran = counts
+            .times
+            .keys()
+            .map(|key| (key, counts.running_fraction(key)))
+            .find(|&(_, running_fraction)| running_fraction < MIN_OVERLAP);
+        if let Some((key, running_fraction)) = barely_ran {
+            return Err(BenchError::MultiplexingUnreliable {
+                event: key.clone(),
+                running_fraction,
+            });
+        }
+    }
+    for warning in warnings {
+        if strict {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{} {}", name, warning),
+            )
+            .into());
+        }
+        eprintln!("Warning: {} {}", name, warning);
+    }
+    Ok(())
+}
+
+/// The name of the benchmark passed with `--loop`, to run forever so that an
+/// external profiler can attach to it.
+pub fn loop_from_args() -> Option<String> {
+    option_value("--loop").filter(|name| !name.is_empty())
+}
+
+/// Whether `--verbose` was passed, to print the full counters of each point
+/// of a sweep after its table.
+pub fn verbose() -> bool {
+    flag_passed("--verbose")
+}
+
+/// The path passed after `option` on the command line, if any.
+pub fn path_from_args(option: &str) -> Option<PathBuf> {
+    option_value(option)
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+}
+
+/// How much one benchmark's cycles per iteration varied across suite runs.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reproducibility {
+    pub name: String,
+    pub runs: usize,
+    pub mean: f64,
+    /// The sample standard deviation, or 0 with a single run.
+    pub std_dev: f64,
+}
+
+impl Reproducibility {
+    /// The standard deviation relative to the mean, between 0 and 1 for any
+    /// reasonably stable benchmark.
+    pub fn coefficient_of_variation(&self) -> f64 {
+        self.std_dev / self.mean
+    }
+}
+
+/// Group `samples`, pairs of a benchmark name and one run's cycles per
+/// iteration, by benchmark, in the order benchmarks first appear.
+pub fn reproducibility(samples: &[(String, f64)]) -> Vec<Reproducibility> {
+    let mut names: Vec<&str> = Vec::new();
+    for (name, _) in samples {
+        if !names.contains(&name.as_str()) {
+            names.push(name);
+        }
+    }
+
+    names
+        .into_iter()
+        .map(|name| {
+            let values: Vec<f64> = samples
+                .iter()
+                .filter(|(sample_name, _)| sample_name == name)
+                .map(|&(_, value)| value)
+                .collect();
+            let stats = SampleStats::from_values(&values);
+            Reproducibility {
+                name: name.to_string(),
+                runs: values.len(),
+                mean: stats.mean,
+                std_dev: stats.std_dev,
+            }
+        })
+        .collect()
+}
+
+/// Print how much each benchmark's cycles per iteration varied across the
+/// runs of the suite requested with `--suite-repeats`, and which benchmark
+/// varied the most.
+///
+/// Unlike repeating a benchmark in place, repeating the whole suite captures
+/// the cache and predictor state each benchmark inherits from the previous
+/// ones. Only the table output format records the results this needs.
+pub fn print_reproducibility() {
+    if !matches!(OutputFormat::from_args(), Ok(OutputFormat::Table)) {
+        return;
+    }
+    let rows = SUMMARY.lock().unwrap();
+    let samples: Vec<(String, f64)> = rows
+        .iter()
+        .map(|row| {
+            (
+                row.name.clone(),
+                row.counts.cycles as f64 / row.iterations as f64,
+            )
+        })
+        .collect();
+    let results = reproducibility(&samples);
+    if results.iter().all(|result| result.runs < 2) {
+        return;
+    }
+
+    println!("====================================================================");
+    println!("Reproducibility across suite runs");
+    println!(
+        "{:<50} {:>6} {:>12} {:>12} {:>8}",
+        "benchmark", "runs", "cycles/iter", "std dev", "CV %"
+    );
+    for result in &results {
+        println!(
+            "{:<50} {:>6} {:>12.3} {:>12.3} {:>8.2}",
+            result.name,
+            result.runs,
+            result.mean,
+            result.std_dev,
+            result.coefficient_of_variation() * 100.0,
+        );
+    }
+
+    let least_reproducible = results
+        .iter()
+        .max_by(|a, b| {
+            a.coefficient_of_variation()
+                .total_cmp(&b.coefficient_of_variation())
+        })
+        .unwrap();
+    println!();
+    println!(
+        "Least reproducible: {} (CV {:.2}%)",
+        least_reproducible.name,
+        least_reproducible.coefficient_of_variation() * 100.0
+    );
+    println!();
+}
+
+/// Read the number of back-to-back runs of each benchmark to measure from
+/// `--warmup-runs`, if it was passed.
+pub fn warmup_runs_from_args() -> std::io::Result<Option<usize>> {
+    let Some(value) = option_value("--warmup-runs") else {
+        return Ok(None);
+    };
+    match value.parse() {
+        Ok(runs) if runs > 1 => Ok(Some(runs)),
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid number of warmup runs {:?}", value),
+        )),
+    }
+}
+
+/// How far from its steady state a run may be and still count as warm.
+pub const WARMUP_TOLERANCE: f64 = 0.05;
+
+/// How a benchmark's cycles per iteration evolved over back-to-back runs
+/// started cold.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Warmup {
+    /// The median of the second half of the runs.
+    pub steady_state: f64,
+    /// The first run, counting from 1, from which every run is within
+    /// [`WARMUP_TOLERANCE`] of the steady state, or `None` if even the
+    /// second half of the runs isn't, meaning the benchmark is noisy rather
+    /// than slow to warm up.
+    pub converged_at: Option<usize>,
+}
+
+impl Warmup {
+    /// Analyze `trajectory`, the cycles per iteration of each run in order.
+    pub fn from_trajectory(trajectory: &[f64]) -> Warmup {
+        let mut tail = trajectory[trajectory.len() / 2..].to_vec();
+        tail.sort_by(f64::total_cmp);
+        let steady_state = tail[tail.len() / 2];
+
+        let is_warm = |value: &f64| (value - steady_state).abs() <= steady_state * WARMUP_TOLERANCE;
+        let cold_runs = trajectory.len()
+            - trajectory
+                .iter()
+                .rev()
+                .take_while(|value| is_warm(value))
+                .count();
+        let converged_at = (cold_runs <= trajectory.len() / 2).then_some(cold_runs + 1);
+
+        Warmup {
+            steady_state,
+            converged_at,
+        }
+    }
+}
+
+/// Print the cycles per iteration of each back-to-back run of `name`, and
+/// how many runs it took to reach its steady state, to choose how much to
+/// warm it up.
+fn print_warmup(name: &str, trajectory: &[f64]) {
+    let warmup = Warmup::from_trajectory(trajectory);
+
+    println!("====================================================================");
+    println!("Warmup of {}", name);
+    println!("{:>6} {:>12} {:>10}", "run", "cycles/iter", "vs steady");
+    for (run, value) in trajectory.iter().enumerate() {
+        println!(
+            "{:>6} {:>12.3} {:>+9.1}%",
+            run + 1,
+            value,
+            (value / warmup.steady_state - 1.0) * 100.0
+        );
+    }
+    println!();
+    match warmup.converged_at {
+        Some(1) => println!("Steady from the first run, no warmup needed"),
+        Some(run) => println!("Steady from run {}, warm up {} times", run, run - 1),
+        None => println!(
+            "Never converged within {:.0}%, the benchmark is noisy",
+            WARMUP_TOLERANCE * 100.0
+        ),
+    }
+    println!();
+}
+
+/// How many decimals the table prints, unless `--precision` says otherwise.
+pub const DEFAULT_PRECISION: usize = 3;
+
+/// Read the number of decimals to print from `--precision`, defaulting to
+/// [`DEFAULT_PRECISION`].
+pub fn precision_from_args() -> std::io::Result<usize> {
+    let Some(value) = option_value("--precision") else {
+        return Ok(DEFAULT_PRECISION);
+    };
+    value.parse().map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid precision {:?}", value),
+        )
+    })
+}
+
+/// Format a duration of `nsec` nanoseconds in the largest unit it's at least
+/// one of, so that short durations keep their resolution. Returns the number
+/// and the unit, in `perf stat`'s spelling.
+pub fn format_duration(nsec: f64, precision: usize) -> (String, &'static str) {
+    let (value, unit) = if nsec < 1e3 {
+        (nsec, "nsec")
+    } else if nsec < 1e6 {
+        (nsec / 1e3, "usec")
+    } else if nsec < 1e9 {
+        (nsec / 1e6, "msec")
+    } else {
+        (nsec / 1e9, "sec")
+    };
+    (format!("{:.*}", precision, value), unit)
+}
+
+/// Print one line of the counter table, in the same layout as `perf stat`.
+fn print_counter(precision: usize, count: &str, unit: &str, name: &str, info: Option<(f64, &str)>) {
+    match info {
+        Some((info, info_unit)) => {
+            println!("{count:>16} {unit:<4} {name:<30} # {info:.precision$} {info_unit}")
+        }
+        None => println!("{count:>16} {unit:<4} {name:<30} #"),
+    }
+}
+
+fn print_inactive_counter(count: u64, name: &str) {
+    println!(
+        "{count:>16} {unit:<4} {name:<30} # (counter inactive — likely virtualized/unsupported)",
+        count = count.separate_with_underscores(),
+        unit = "",
+    );
+}
+
+/// Print a line for the event keyed `key`, whose counter couldn't be
+/// opened, with the reason why.
+fn print_unavailable_counter(counts: &Counts, key: &str, name: &str) {
+    let reason = counts
+        .unavailable
+        .get(key)
+        .map_or("not counted", String::as_str);
+    println!(
+        "{count:>16} {unit:<4} {name:<30} # ({reason})",
+        count = "n/a",
+        unit = "",
+    );
+}
+
+/// Print the count of the event keyed `key`, without any ratio, or "n/a" if
+/// its counter couldn't be opened.
+fn print_available_counter(precision: usize, counts: &Counts, key: &str, name: &str) {
+    let Some((_, value)) = counts.values().into_iter().find(|(k, _)| k == key) else {
+        return;
+    };
+    if counts.counted(key) {
+        print_counter(
+            precision,
+            &value.separate_with_underscores(),
+            "",
+            name,
+            None,
+        );
+    } else {
+        print_unavailable_counter(counts, key, name);
+    }
+}
+
+/// Print `count` with `info`, a ratio between the events keyed `a` and `b`,
+/// or say why not if they didn't count together for long enough.
+fn print_ratio(
+    precision: usize,
+    counts: &Counts,
+    count: u64,
+    name: &str,
+    (a, b): (&str, &str),
+    info: f64,
+    info_unit: &str,
+) {
+    if counts.comparable(a, b) {
+        print_counter_with_info(precision, count, name, info, info_unit);
+    } else {
+        println!(
+            "{count:>16} {unit:<4} {name:<30} # (no ratio, counted together {overlap:.0}% of the run)",
+            count = count.separate_with_underscores(),
+            unit = "",
+            overlap = counts.overlap(a, b) * 100.0,
+        );
+    }
+}
+
+fn print_counter_with_info(precision: usize, count: u64, name: &str, info: f64, info_unit: &str) {
+    print_counter(
+        precision,
+        &count.separate_with_underscores(),
+        "",
+        name,
+        Some((info, info_unit)),
+    );
 }
 
 /*
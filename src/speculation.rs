@@ -0,0 +1,133 @@
+use std::hint::black_box;
+
+#[cfg(target_os = "linux")]
+use perf_event::events::Hardware;
+#[cfg(target_os = "linux")]
+use perf_event::{Builder, Group};
+
+use crate::aliasing::is_intel;
+use crate::benchmarks::bench_branchy;
+use crate::runner::OutputFormat;
+
+/// Raw event code for Intel's `UOPS_ISSUED.ANY`, which counts the uops the
+/// front end issued, including the ones on paths that turn out to be
+/// mispredicted.
+const UOPS_ISSUED_ANY: u64 = 0x010E;
+
+/// Raw event code for Intel's `UOPS_RETIRED.RETIRE_SLOTS`, which counts the
+/// uops that retired. x86 CPUs don't count speculative instructions, only
+/// uops, so this is what we compare the issued uops with.
+const UOPS_RETIRED_RETIRE_SLOTS: u64 = 0x02C2;
+
+/// Retired instructions, and issued and retired uops, counted on the same run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Speculation {
+    pub instructions: u64,
+    pub issued_uops: u64,
+    pub retired_uops: u64,
+}
+
+impl Speculation {
+    /// How many uops were issued for each one that retired. Anything above 1
+    /// is work thrown away after a misspeculation.
+    pub fn ratio(&self) -> f64 {
+        self.issued_uops as f64 / self.retired_uops as f64
+    }
+
+    /// The fraction of issued uops that never retired, between 0 and 1.
+    pub fn wasted(&self) -> f64 {
+        1.0 - self.retired_uops as f64 / self.issued_uops as f64
+    }
+}
+
+/// Count issued and retired uops while running `callback`, or return `None`
+/// if the CPU doesn't have counters for them.
+#[cfg(target_os = "linux")]
+fn count_speculation(mut callback: impl FnMut()) -> Option<Speculation> {
+    if !is_intel() {
+        return None;
+    }
+    let mut group = Group::new().ok()?;
+    let instructions = Builder::new()
+        .group(&mut group)
+        .kind(Hardware::INSTRUCTIONS)
+        .build()
+        .ok()?;
+    let issued = Builder::new()
+        .group(&mut group)
+        .raw_config(UOPS_ISSUED_ANY)
+        .build()
+        .ok()?;
+    let retired = Builder::new()
+        .group(&mut group)
+        .raw_config(UOPS_RETIRED_RETIRE_SLOTS)
+        .build()
+        .ok()?;
+
+    group.enable().ok()?;
+    callback();
+    group.disable().ok()?;
+    let counts = group.read().ok()?;
+    Some(Speculation {
+        instructions: counts[&instructions],
+        issued_uops: counts[&issued],
+        retired_uops: counts[&retired],
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn count_speculation(_callback: impl FnMut()) -> Option<Speculation> {
+    None
+}
+
+/// Count how much work [`bench_branchy`] throws away to misspeculation, on
+/// `random` bytes and on `sorted` ones, and print the issued and retired uops
+/// of each.
+///
+/// Returns the issued-to-retired ratios on random and sorted bytes, or `None`
+/// if the CPU doesn't have counters for them.
+pub fn speculation_ratio(
+    random: &[u8],
+    sorted: &[u8],
+    repeats: usize,
+) -> std::io::Result<Option<(f64, f64)>> {
+    let run = |array: &[u8]| {
+        for _ in 0..repeats {
+            black_box(bench_branchy(array));
+        }
+    };
+
+    let table = OutputFormat::from_args()? == OutputFormat::Table;
+    if table {
+        println!("====================================================================");
+        println!("Speculation in bench_branchy");
+    }
+    let (Some(random), Some(sorted)) = (
+        count_speculation(|| run(random)),
+        count_speculation(|| run(sorted)),
+    ) else {
+        if table {
+            println!("Issued and retired uops: (no counter on this CPU)");
+            println!();
+        }
+        return Ok(None);
+    };
+    if !table {
+        return Ok(Some((random.ratio(), sorted.ratio())));
+    }
+
+    for (name, speculation) in [("random", random), ("sorted", sorted)] {
+        println!(
+            "{} bytes: {} instructions, {} uops issued, {} retired, {:.3} issued per retired ({:.1}% wasted)",
+            name,
+            speculation.instructions,
+            speculation.issued_uops,
+            speculation.retired_uops,
+            speculation.ratio(),
+            speculation.wasted() * 100.0,
+        );
+    }
+    println!();
+
+    Ok(Some((random.ratio(), sorted.ratio())))
+}
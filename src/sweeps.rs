@@ -0,0 +1,1157 @@
+use std::hint::black_box;
+
+use serde::Serialize;
+use serde_json::json;
+
+#[cfg(target_arch = "x86_64")]
+use crate::benchmarks::bench_store_buffer_fill;
+use crate::benchmarks::{
+    bench_atomic_contention, bench_gather, bench_mlp, bench_reuse_distance,
+    bench_sum_of_array_with_stride_prefetch, bench_sum_of_array_with_stride_slice,
+    bench_sum_with_prefetches, bench_sum_with_work, chain_starts, clustered_indices, CacheAligned,
+    Node, SEED,
+};
+use crate::gnuplot::{gnuplot_script, write_gnuplot, Series};
+use crate::runner::{
+    measure, print_counts, strided_traffic, verbose, Counts, OutputFormat, SCHEMA_VERSION,
+};
+
+/// With `--verbose`, print the full counters of each point of a sweep, after
+/// its compact table. JSON lines already include them, and the other formats
+/// only get benchmark rows.
+fn print_details(format: OutputFormat, details: &[(String, Counts)]) -> std::io::Result<()> {
+    if !verbose() || format != OutputFormat::Table {
+        return Ok(());
+    }
+    for (point, counts) in details {
+        println!("--------------------------------------------------------------------");
+        println!("{}", point);
+        print_counts(counts)?;
+    }
+    Ok(())
+}
+
+/// How a [`Column`] shows its values in the table.
+#[derive(Clone, Copy)]
+enum Cell {
+    Integer,
+    Decimal,
+    /// A fraction between 0 and 1, shown in percent.
+    Percent,
+    /// A ratio between two measurements, shown as a multiplier.
+    Speedup,
+}
+
+/// One value of each point of a sweep, as a column of its table, a field of
+/// its JSON lines, or both.
+struct Column<P> {
+    /// The header of the column, or `None` to leave it out of the table.
+    header: Option<&'static str>,
+    /// The key of the field, or `None` to leave it out of JSON lines.
+    key: Option<&'static str>,
+    width: usize,
+    cell: Cell,
+    value: fn(&P) -> f64,
+}
+
+impl<P> Column<P> {
+    /// A column of the table, 16 characters wide, and a field of JSON lines.
+    fn new(header: &'static str, key: &'static str, cell: Cell, value: fn(&P) -> f64) -> Self {
+        Column {
+            header: Some(header),
+            key: Some(key),
+            width: 16,
+            cell,
+            value,
+        }
+    }
+
+    fn table_only(header: &'static str, cell: Cell, value: fn(&P) -> f64) -> Self {
+        Column {
+            key: None,
+            ..Column::new(header, "", cell, value)
+        }
+    }
+
+    fn json_only(key: &'static str, cell: Cell, value: fn(&P) -> f64) -> Self {
+        Column {
+            header: None,
+            ..Column::new("", key, cell, value)
+        }
+    }
+
+    fn width(self, width: usize) -> Self {
+        Column { width, ..self }
+    }
+
+    fn format(&self, point: &P) -> String {
+        let value = (self.value)(point);
+        match self.cell {
+            Cell::Integer => format!("{:>1$}", value as u64, self.width),
+            Cell::Decimal => format!("{:>1$.3}", value, self.width),
+            Cell::Percent => format!("{:>1$.2}%", value * 100.0, self.width - 1),
+            Cell::Speedup => format!("{:>1$.2}x", value, self.width - 1),
+        }
+    }
+
+    fn json(&self, point: &P) -> serde_json::Value {
+        let value = (self.value)(point);
+        match self.cell {
+            Cell::Integer => json!(value as u64),
+            _ => json!(value),
+        }
+    }
+}
+
+/// One axis of a [`Plot`], as its label and the value of each point.
+type Axis<P> = (&'static str, fn(&P) -> f64);
+
+/// The gnuplot chart of a sweep: `ys` against `x`.
+struct Plot<P> {
+    title: String,
+    x: Axis<P>,
+    ys: Vec<Axis<P>>,
+    log_x: bool,
+}
+
+/// Prints a sweep point by point, as a table or as JSON lines, then its
+/// details with `--verbose`, and writes its gnuplot script with `--gnuplot`.
+struct SweepPrinter<P> {
+    format: OutputFormat,
+    /// The name of the sweep, as `benchmark` in JSON lines and in the name
+    /// of the gnuplot script.
+    name: &'static str,
+    /// The fields every JSON line starts with, eg the size of the array.
+    fields: serde_json::Value,
+    columns: Vec<Column<P>>,
+    details: Vec<(String, Counts)>,
+}
+
+impl<P> SweepPrinter<P> {
+    /// Print the banner, `title` and the header of the table, if the output
+    /// format is the table.
+    fn start(
+        name: &'static str,
+        title: String,
+        fields: serde_json::Value,
+        columns: Vec<Column<P>>,
+    ) -> std::io::Result<Self> {
+        let format = OutputFormat::from_args()?;
+        if format == OutputFormat::Table {
+            println!("====================================================================");
+            println!("{}", title);
+            let headers: Vec<String> = columns
+                .iter()
+                .filter_map(|column| Some(format!("{:>1$}", column.header?, column.width)))
+                .collect();
+            println!("{}", headers.join(" "));
+        }
+        Ok(SweepPrinter {
+            format,
+            name,
+            fields,
+            columns,
+            details: Vec::new(),
+        })
+    }
+
+    /// Print `point` as a row followed by `note`, or as a JSON line with
+    /// `counts`, if any. Other formats are parsed as a single table of
+    /// benchmarks, so they get nothing.
+    fn row(&self, point: &P, counts: Option<&Counts>, note: &str) {
+        if self.format == OutputFormat::JsonLines {
+            let mut line = json!({
+                "schema_version": SCHEMA_VERSION,
+                "benchmark": self.name,
+            });
+            if let serde_json::Value::Object(fields) = &self.fields {
+                for (key, value) in fields {
+                    line[key] = value.clone();
+                }
+            }
+            for column in &self.columns {
+                if let Some(key) = column.key {
+                    line[key] = column.json(point);
+                }
+            }
+            if let Some(counts) = counts {
+                line["counts"] = json!(counts);
+            }
+            println!("{}", line);
+        } else if self.format == OutputFormat::Table {
+            let cells: Vec<String> = self
+                .columns
+                .iter()
+                .filter(|column| column.header.is_some())
+                .map(|column| column.format(point))
+                .collect();
+            println!("{}{}", cells.join(" "), note);
+        }
+    }
+
+    /// Keep `counts` to print under `label` with `--verbose`.
+    fn detail(&mut self, label: String, counts: Counts) {
+        self.details.push((label, counts));
+    }
+
+    /// Print `footer` under the table, if any, then the details, and write
+    /// `plot` of `points`.
+    fn finish(self, points: &[P], footer: Option<String>, plot: Plot<P>) -> std::io::Result<()> {
+        if self.format == OutputFormat::Table {
+            if let Some(footer) = footer {
+                println!("{}", footer);
+            }
+            println!();
+        }
+        print_details(self.format, &self.details)?;
+
+        let series = |(label, value): Axis<P>| Series {
+            label,
+            values: points.iter().map(value).collect(),
+        };
+        let ys: Vec<Series> = plot.ys.into_iter().map(series).collect();
+        let script = gnuplot_script(&plot.title, &series(plot.x), &ys, plot.log_x);
+        write_gnuplot(self.name, &script)
+    }
+}
+
+/// One data point of [`sweep_stride`].
+#[derive(Clone, Debug, Serialize)]
+pub struct StridePoint {
+    pub stride: usize,
+    pub cycles_per_access: f64,
+    /// L1D misses as a fraction of L1D loads, between 0 and 1.
+    pub l1_miss_rate: f64,
+}
+
+/// Run `bench_sum_of_array_with_stride_slice` over `array` once per stride in
+/// `strides`, `repeats` times each, and print cycles per accessed element and
+/// L1D miss rate for each.
+///
+/// Once the stride gets larger than what the hardware prefetcher follows,
+/// the cycles per access should jump.
+///
+/// With `--format jsonl`, each point is printed as a JSON object instead.
+pub fn sweep_stride(
+    array: &[u8],
+    strides: &[usize],
+    repeats: usize,
+) -> std::io::Result<Vec<StridePoint>> {
+    let mut printer = SweepPrinter::start(
+        "sweep_stride",
+        format!(
+            "Stride sweep over {} bytes, strides {:?}",
+            array.len(),
+            strides
+        ),
+        json!({ "size": array.len() }),
+        vec![
+            Column::new("stride", "stride", Cell::Integer, |p: &StridePoint| {
+                p.stride as f64
+            })
+            .width(8),
+            Column::new("cycles/access", "cycles_per_access", Cell::Decimal, |p| {
+                p.cycles_per_access
+            }),
+            Column::new("L1D miss rate", "l1_miss_rate", Cell::Percent, |p| {
+                p.l1_miss_rate
+            }),
+        ],
+    )?;
+
+    let mut points = Vec::new();
+    for &stride in strides {
+        let counts = measure(|| {
+            for _ in 0..repeats {
+                black_box(bench_sum_of_array_with_stride_slice(array, stride));
+            }
+        })?;
+
+        let accesses = array.len().div_ceil(stride) * repeats;
+        let point = StridePoint {
+            stride,
+            cycles_per_access: counts.cycles as f64 / accesses as f64,
+            l1_miss_rate: counts.l1_cache_misses as f64 / counts.l1_cache_loads as f64,
+        };
+        printer.row(&point, Some(&counts), "");
+        printer.detail(format!("stride {}", stride), counts);
+        points.push(point);
+    }
+
+    printer.finish(
+        &points,
+        None,
+        Plot {
+            title: format!(
+                "bench_sum_of_array_with_stride_slice over {} bytes, by stride",
+                array.len()
+            ),
+            x: ("stride (bytes)", |point| point.stride as f64),
+            ys: vec![
+                ("cycles per access", |point| point.cycles_per_access),
+                ("L1D miss rate (%)", |point| point.l1_miss_rate * 100.0),
+            ],
+            log_x: true,
+        },
+    )?;
+    Ok(points)
+}
+
+/// One data point of [`sweep_work`].
+#[derive(Clone, Debug, Serialize)]
+pub struct WorkPoint {
+    pub ops_per_element: usize,
+    pub cycles_per_element: f64,
+    pub instructions_per_cycle: f64,
+    /// Bytes summed per second of task-clock, in GB/s.
+    pub bandwidth: f64,
+}
+
+/// Run `bench_sum_with_work` over `array` once per amount of work in
+/// `ops_per_element`, `repeats` times each, and print cycles per element,
+/// IPC and bandwidth for each.
+///
+/// While the loop is memory-bound, extra work is free: bandwidth stays flat
+/// and IPC climbs. Past the crossover, bandwidth starts dropping and IPC
+/// levels off.
+///
+/// With `--format jsonl`, each point is printed as a JSON object instead.
+pub fn sweep_work(
+    array: &[u8],
+    ops_per_element: &[usize],
+    repeats: usize,
+) -> std::io::Result<Vec<WorkPoint>> {
+    let mut printer = SweepPrinter::start(
+        "sweep_work",
+        format!("Compute intensity sweep over {} bytes", array.len()),
+        json!({ "size": array.len() }),
+        vec![
+            Column::new("ops", "ops_per_element", Cell::Integer, |p: &WorkPoint| {
+                p.ops_per_element as f64
+            })
+            .width(8),
+            Column::new("cycles/element", "cycles_per_element", Cell::Decimal, |p| {
+                p.cycles_per_element
+            }),
+            Column::new(
+                "instr/cycle",
+                "instructions_per_cycle",
+                Cell::Decimal,
+                |p| p.instructions_per_cycle,
+            ),
+            Column::new("GB/s", "bandwidth", Cell::Decimal, |p| p.bandwidth),
+        ],
+    )?;
+
+    let mut points = Vec::new();
+    for &ops in ops_per_element {
+        let counts = measure(|| {
+            for _ in 0..repeats {
+                black_box(bench_sum_with_work(array, ops));
+            }
+        })?;
+
+        let elements = array.len() * repeats;
+        let point = WorkPoint {
+            ops_per_element: ops,
+            cycles_per_element: counts.cycles as f64 / elements as f64,
+            instructions_per_cycle: counts.instructions as f64 / counts.cycles as f64,
+            bandwidth: elements as f64 / counts.nanoseconds() as f64,
+        };
+        printer.row(&point, Some(&counts), "");
+        printer.detail(format!("{} ops per element", ops), counts);
+        points.push(point);
+    }
+
+    printer.finish(
+        &points,
+        None,
+        Plot {
+            title: format!(
+                "bench_sum_with_work over {} bytes, by ops per element",
+                array.len()
+            ),
+            x: ("ops per element", |point| point.ops_per_element as f64),
+            ys: vec![
+                ("GB/s", |point| point.bandwidth),
+                ("instructions per cycle", |point| {
+                    point.instructions_per_cycle
+                }),
+            ],
+            log_x: false,
+        },
+    )?;
+    Ok(points)
+}
+
+/// One data point of [`sweep_atomic_contention`].
+#[derive(Clone, Debug, Serialize)]
+pub struct ContentionPoint {
+    pub threads: usize,
+    /// Increments per second of wall-clock time, across all threads, when
+    /// they share one counter.
+    pub shared_ops_per_sec: f64,
+    /// Same, with one counter per thread.
+    pub separate_ops_per_sec: f64,
+}
+
+/// Run `bench_atomic_contention` on the first 1, 2, ... CPUs of `cpus`, with
+/// `rounds` increments per thread, and print how many increments per second
+/// all threads achieve together, with a shared counter and with separate
+/// ones.
+///
+/// With separate counters, throughput should grow with the number of
+/// threads. With a shared one, it collapses as soon as a second thread
+/// contends for the line.
+///
+/// With `--format jsonl`, each point is printed as a JSON object instead.
+pub fn sweep_atomic_contention(
+    cpus: &[usize],
+    rounds: u64,
+) -> std::io::Result<Vec<ContentionPoint>> {
+    let mut printer = SweepPrinter::start(
+        "sweep_atomic_contention",
+        format!("Atomic contention sweep, {} increments per thread", rounds),
+        json!({ "rounds": rounds }),
+        vec![
+            Column::new(
+                "threads",
+                "threads",
+                Cell::Integer,
+                |p: &ContentionPoint| p.threads as f64,
+            )
+            .width(8),
+            Column::table_only("shared Mops/s", Cell::Decimal, |p| {
+                p.shared_ops_per_sec / 1e6
+            }),
+            Column::table_only("separate Mops/s", Cell::Decimal, |p| {
+                p.separate_ops_per_sec / 1e6
+            }),
+            Column::json_only("shared_ops_per_sec", Cell::Decimal, |p| {
+                p.shared_ops_per_sec
+            }),
+            Column::json_only("separate_ops_per_sec", Cell::Decimal, |p| {
+                p.separate_ops_per_sec
+            }),
+        ],
+    )?;
+
+    let mut points = Vec::new();
+    for threads in 1..=cpus.len() {
+        let cpus = &cpus[..threads];
+        let ops = (rounds * threads as u64) as f64;
+        let mut pinning = Ok(());
+        let shared = measure(|| {
+            if let Err(err) = bench_atomic_contention::<true>(rounds, cpus).map(black_box) {
+                pinning = Err(err);
+            }
+        })?;
+        let separate = measure(|| {
+            if let Err(err) = bench_atomic_contention::<false>(rounds, cpus).map(black_box) {
+                pinning = Err(err);
+            }
+        })?;
+        pinning?;
+
+        let point = ContentionPoint {
+            threads,
+            shared_ops_per_sec: ops / shared.wall_clock as f64 * 1e9,
+            separate_ops_per_sec: ops / separate.wall_clock as f64 * 1e9,
+        };
+        printer.row(&point, None, "");
+        printer.detail(format!("{} threads, shared counter", threads), shared);
+        printer.detail(format!("{} threads, separate counters", threads), separate);
+        points.push(point);
+    }
+
+    printer.finish(
+        &points,
+        None,
+        Plot {
+            title: format!("bench_atomic_contention, {} increments per thread", rounds),
+            x: ("threads", |point| point.threads as f64),
+            ys: vec![
+                ("shared counter (Mops/s)", |point| {
+                    point.shared_ops_per_sec / 1e6
+                }),
+                ("separate counters (Mops/s)", |point| {
+                    point.separate_ops_per_sec / 1e6
+                }),
+            ],
+            log_x: false,
+        },
+    )?;
+    Ok(points)
+}
+
+/// One data point of [`sweep_mlp`].
+#[derive(Clone, Debug, Serialize)]
+pub struct MlpPoint {
+    pub chains: usize,
+    pub cycles_per_access: f64,
+    /// The cycles per access with a single chain over `cycles_per_access`.
+    pub speedup: f64,
+}
+
+/// Below this relative improvement over the best cycles per access, adding
+/// chains doesn't count as helping anymore.
+const MLP_PLATEAU: f64 = 0.1;
+
+/// The fewest chains whose cycles per access are within 10% of the best of
+/// `points`: past that, the core can't keep more misses in flight.
+pub fn mlp_limit(points: &[MlpPoint]) -> Option<usize> {
+    let best = points
+        .iter()
+        .map(|point| point.cycles_per_access)
+        .min_by(f64::total_cmp)?;
+    points
+        .iter()
+        .find(|point| point.cycles_per_access <= best * (1.0 + MLP_PLATEAU))
+        .map(|point| point.chains)
+}
+
+fn mlp_point<const N: usize, const K: usize>(
+    nodes: &[Node; N],
+    hops: usize,
+) -> std::io::Result<(MlpPoint, Counts)> {
+    let starts = chain_starts::<N, K>(nodes);
+    let counts = measure(|| {
+        black_box(bench_mlp(nodes, starts, hops));
+    })?;
+    let point = MlpPoint {
+        chains: K,
+        cycles_per_access: counts.cycles as f64 / (hops * K) as f64,
+        speedup: 1.0,
+    };
+    Ok((point, counts))
+}
+
+/// Run `bench_mlp` over `nodes` with more and more independent chains, each
+/// for `hops` hops, and print the cycles per access for each number of
+/// chains, and the number of chains past which they stop improving.
+///
+/// `nodes` should be much larger than the last level cache, so that every
+/// access is a miss.
+///
+/// With `--format jsonl`, each point is printed as a JSON object instead.
+pub fn sweep_mlp<const N: usize>(nodes: &[Node; N], hops: usize) -> std::io::Result<Vec<MlpPoint>> {
+    let megabytes = std::mem::size_of_val(nodes) / (1024 * 1024);
+    let mut printer = SweepPrinter::start(
+        "sweep_mlp",
+        format!("Memory-level parallelism sweep over {} MB", megabytes),
+        json!({ "size": std::mem::size_of_val(nodes) }),
+        vec![
+            Column::new("chains", "chains", Cell::Integer, |p: &MlpPoint| {
+                p.chains as f64
+            })
+            .width(8),
+            Column::new("cycles/access", "cycles_per_access", Cell::Decimal, |p| {
+                p.cycles_per_access
+            }),
+            Column::table_only("speedup", Cell::Speedup, |p| p.speedup),
+        ],
+    )?;
+
+    let (mut points, counts): (Vec<MlpPoint>, Vec<Counts>) = [
+        mlp_point::<N, 1>(nodes, hops)?,
+        mlp_point::<N, 2>(nodes, hops)?,
+        mlp_point::<N, 4>(nodes, hops)?,
+        mlp_point::<N, 6>(nodes, hops)?,
+        mlp_point::<N, 8>(nodes, hops)?,
+        mlp_point::<N, 10>(nodes, hops)?,
+        mlp_point::<N, 12>(nodes, hops)?,
+        mlp_point::<N, 14>(nodes, hops)?,
+        mlp_point::<N, 16>(nodes, hops)?,
+        mlp_point::<N, 20>(nodes, hops)?,
+        mlp_point::<N, 24>(nodes, hops)?,
+    ]
+    .into_iter()
+    .unzip();
+
+    let single_chain = points[0].cycles_per_access;
+    for (point, counts) in points.iter_mut().zip(counts) {
+        point.speedup = single_chain / point.cycles_per_access;
+        printer.row(point, Some(&counts), "");
+        printer.detail(format!("{} chains", point.chains), counts);
+    }
+
+    printer.finish(
+        &points,
+        mlp_limit(&points)
+            .map(|limit| format!("Stops improving at about {} chains in flight", limit)),
+        Plot {
+            title: format!("bench_mlp over {} MB, by independent chains", megabytes),
+            x: ("chains", |point| point.chains as f64),
+            ys: vec![("cycles per access", |point| point.cycles_per_access)],
+            log_x: false,
+        },
+    )?;
+    Ok(points)
+}
+
+/// One data point of [`sweep_prefetches`].
+#[derive(Clone, Debug, Serialize)]
+pub struct PrefetchesPoint {
+    pub prefetches: usize,
+    pub cycles_per_access: f64,
+    pub instructions_per_access: f64,
+    /// L1D prefetches the PMU counted, per access.
+    pub l1_prefetches_per_access: f64,
+    /// L1D misses as a fraction of L1D loads, between 0 and 1.
+    pub l1_miss_rate: f64,
+}
+
+fn prefetches_point<const N: usize, const PREFETCHES: usize>(
+    array: &[u8; N],
+    stride: usize,
+    repeats: usize,
+) -> std::io::Result<(PrefetchesPoint, Counts)> {
+    let counts = measure(|| {
+        for _ in 0..repeats {
+            black_box(bench_sum_with_prefetches::<N, PREFETCHES>(array, stride));
+        }
+    })?;
+    let accesses = (N.div_ceil(stride) * repeats) as f64;
+    let point = PrefetchesPoint {
+        prefetches: PREFETCHES,
+        cycles_per_access: counts.cycles as f64 / accesses,
+        instructions_per_access: counts.instructions as f64 / accesses,
+        l1_prefetches_per_access: counts.l1_cache_prefetches as f64 / accesses,
+        l1_miss_rate: counts.l1_cache_misses as f64 / counts.l1_cache_loads as f64,
+    };
+    Ok((point, counts))
+}
+
+/// Run `bench_sum_with_prefetches` over `array` with 0 to 8 prefetches per
+/// access, `repeats` times each, and print the cycles, instructions and L1D
+/// prefetches per access for each, and how many prefetches were fastest.
+///
+/// Once the useful prefetches are issued, more of them only add
+/// instructions, and eventually slow the demand loads down.
+///
+/// With `--format jsonl`, each point is printed as a JSON object instead.
+pub fn sweep_prefetches<const N: usize>(
+    array: &[u8; N],
+    stride: usize,
+    repeats: usize,
+) -> std::io::Result<Vec<PrefetchesPoint>> {
+    let mut printer = SweepPrinter::start(
+        "sweep_prefetches",
+        format!(
+            "Prefetches per access sweep over {} bytes, stride {}",
+            N, stride
+        ),
+        json!({ "size": N, "stride": stride }),
+        vec![
+            Column::new(
+                "prefetches",
+                "prefetches",
+                Cell::Integer,
+                |p: &PrefetchesPoint| p.prefetches as f64,
+            )
+            .width(10),
+            Column::new("cycles/access", "cycles_per_access", Cell::Decimal, |p| {
+                p.cycles_per_access
+            }),
+            Column::new(
+                "instr/access",
+                "instructions_per_access",
+                Cell::Decimal,
+                |p| p.instructions_per_access,
+            ),
+            Column::new(
+                "L1D pf/access",
+                "l1_prefetches_per_access",
+                Cell::Decimal,
+                |p| p.l1_prefetches_per_access,
+            ),
+            Column::new("L1D miss rate", "l1_miss_rate", Cell::Percent, |p| {
+                p.l1_miss_rate
+            }),
+        ],
+    )?;
+
+    let (points, counts): (Vec<PrefetchesPoint>, Vec<Counts>) = [
+        prefetches_point::<N, 0>(array, stride, repeats)?,
+        prefetches_point::<N, 1>(array, stride, repeats)?,
+        prefetches_point::<N, 2>(array, stride, repeats)?,
+        prefetches_point::<N, 4>(array, stride, repeats)?,
+        prefetches_point::<N, 8>(array, stride, repeats)?,
+    ]
+    .into_iter()
+    .unzip();
+
+    for (point, counts) in points.iter().zip(counts) {
+        printer.row(point, Some(&counts), "");
+        printer.detail(format!("{} prefetches", point.prefetches), counts);
+    }
+
+    let fastest = points
+        .iter()
+        .min_by(|a, b| a.cycles_per_access.total_cmp(&b.cycles_per_access))
+        .map(|fastest| format!("Fastest with {} prefetches per access", fastest.prefetches));
+    printer.finish(
+        &points,
+        fastest,
+        Plot {
+            title: format!(
+                "bench_sum_with_prefetches over {} bytes, stride {}, by prefetches per access",
+                N, stride
+            ),
+            x: ("prefetches per access", |point| point.prefetches as f64),
+            ys: vec![
+                ("cycles per access", |point| point.cycles_per_access),
+                ("L1D prefetches per access", |point| {
+                    point.l1_prefetches_per_access
+                }),
+            ],
+            log_x: false,
+        },
+    )?;
+    Ok(points)
+}
+
+/// One data point of [`sweep_prefetch_distance`].
+#[derive(Clone, Debug, Serialize)]
+pub struct PrefetchDistancePoint {
+    /// How many accesses ahead the prefetch targets.
+    pub distance: usize,
+    pub cycles_per_access: f64,
+    /// The bytes read, in GB/s.
+    pub bandwidth: f64,
+    /// L1D misses as a fraction of L1D loads, between 0 and 1.
+    pub l1_miss_rate: f64,
+}
+
+/// The distance of the point of `points` with the highest bandwidth.
+pub fn best_prefetch_distance(points: &[PrefetchDistancePoint]) -> Option<usize> {
+    points
+        .iter()
+        .max_by(|a, b| a.bandwidth.total_cmp(&b.bandwidth))
+        .map(|point| point.distance)
+}
+
+fn prefetch_distance_point<const N: usize, const P: usize>(
+    array: &[u8; N],
+    stride: usize,
+    repeats: usize,
+) -> std::io::Result<(PrefetchDistancePoint, Counts)> {
+    let counts = measure(|| {
+        for _ in 0..repeats {
+            black_box(bench_sum_of_array_with_stride_prefetch::<N, P>(
+                array, stride,
+            ));
+        }
+    })?;
+    // One byte is read per access.
+    let (bytes, _) = strided_traffic(N, stride);
+    let point = PrefetchDistancePoint {
+        distance: P,
+        cycles_per_access: counts.cycles as f64 / (bytes * repeats) as f64,
+        bandwidth: (bytes * repeats) as f64 / counts.nanoseconds() as f64,
+        l1_miss_rate: counts.l1_cache_misses as f64 / counts.l1_cache_loads as f64,
+    };
+    Ok((point, counts))
+}
+
+/// The distance is a const generic, so each one needs its own instantiation.
+macro_rules! prefetch_distance_points {
+    ($array:expr, $stride:expr, $repeats:expr; $($distance:literal)*) => {
+        [$(prefetch_distance_point::<N, $distance>($array, $stride, $repeats)?,)*]
+    };
+}
+
+/// Run `bench_sum_of_array_with_stride_prefetch` over `array` with prefetch
+/// distances of 0 to 32 accesses, `repeats` times each, and print the
+/// bandwidth and L1D miss rate for each, marking the distance with the
+/// highest bandwidth.
+///
+/// Too short a distance and the line isn't there yet when it's loaded; too
+/// long and it may be evicted before then, or the prefetches run past the
+/// end of the array.
+///
+/// With `--format jsonl`, each point is printed as a JSON object instead.
+pub fn sweep_prefetch_distance<const N: usize>(
+    array: &[u8; N],
+    stride: usize,
+    repeats: usize,
+) -> std::io::Result<Vec<PrefetchDistancePoint>> {
+    let mut printer = SweepPrinter::start(
+        "sweep_prefetch_distance",
+        format!(
+            "Prefetch distance sweep over {} bytes, stride {}",
+            N, stride
+        ),
+        json!({ "size": N, "stride": stride }),
+        vec![
+            Column::new(
+                "distance",
+                "distance",
+                Cell::Integer,
+                |p: &PrefetchDistancePoint| p.distance as f64,
+            )
+            .width(8),
+            Column::new("cycles/access", "cycles_per_access", Cell::Decimal, |p| {
+                p.cycles_per_access
+            }),
+            Column::new("bandwidth GB/s", "bandwidth", Cell::Decimal, |p| {
+                p.bandwidth
+            }),
+            Column::new("L1D miss rate", "l1_miss_rate", Cell::Percent, |p| {
+                p.l1_miss_rate
+            }),
+        ],
+    )?;
+
+    let (points, counts): (Vec<PrefetchDistancePoint>, Vec<Counts>) = prefetch_distance_points!(
+        array, stride, repeats;
+        0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16
+        17 18 19 20 21 22 23 24 25 26 27 28 29 30 31 32
+    )
+    .into_iter()
+    .unzip();
+
+    let best = best_prefetch_distance(&points);
+    for (point, counts) in points.iter().zip(counts) {
+        let note = if Some(point.distance) == best {
+            "  <- best"
+        } else {
+            ""
+        };
+        printer.row(point, Some(&counts), note);
+        printer.detail(format!("distance {}", point.distance), counts);
+    }
+
+    printer.finish(
+        &points,
+        best.map(|best| format!("Highest bandwidth with a prefetch {} accesses ahead", best)),
+        Plot {
+            title: format!(
+                "bench_sum_of_array_with_stride_prefetch over {} bytes, stride {}, by prefetch distance",
+                N, stride
+            ),
+            x: ("prefetch distance (accesses)", |point| point.distance as f64),
+            ys: vec![
+                ("bandwidth (GB/s)", |point| point.bandwidth),
+                ("L1D miss rate (%)", |point| point.l1_miss_rate * 100.0),
+            ],
+            log_x: false,
+        },
+    )?;
+    Ok(points)
+}
+
+/// One data point of [`sweep_store_buffer`].
+#[derive(Clone, Debug, Serialize)]
+pub struct StoreBufferPoint {
+    pub stores: usize,
+    pub cycles_per_burst: f64,
+    pub cycles_per_store: f64,
+}
+
+/// Above this relative increase over the cycles per burst of the shortest
+/// burst, the stores no longer fit in the store buffer.
+const STORE_BUFFER_JUMP: f64 = 0.25;
+
+/// The longest burst of `points` before the cycles per burst jump by more
+/// than 25% over the shortest one's: about the depth of the store buffer.
+/// `None` if they never jump, or already did at the shortest burst.
+pub fn store_buffer_depth(points: &[StoreBufferPoint]) -> Option<usize> {
+    let first = points.first()?;
+    let jump = points.iter().position(|point| {
+        point.cycles_per_burst > first.cycles_per_burst * (1.0 + STORE_BUFFER_JUMP)
+    })?;
+    Some(points[jump.checked_sub(1)?].stores)
+}
+
+#[cfg(target_arch = "x86_64")]
+fn store_buffer_point<const STORES: usize>(
+    bursts: usize,
+) -> std::io::Result<(StoreBufferPoint, Counts)> {
+    let mut lines = Box::new([CacheAligned(0u64); STORES]);
+    // Bring the lines into the L1D first, so that draining the stores is
+    // never what holds them up.
+    bench_store_buffer_fill(&mut lines, 1);
+    let counts = measure(|| {
+        black_box(bench_store_buffer_fill(&mut lines, bursts));
+    })?;
+    let point = StoreBufferPoint {
+        stores: STORES,
+        cycles_per_burst: counts.cycles as f64 / bursts as f64,
+        cycles_per_store: counts.cycles as f64 / (bursts * STORES) as f64,
+    };
+    Ok((point, counts))
+}
+
+/// Run `bench_store_buffer_fill` with longer and longer bursts of stores,
+/// `bursts` times each, and print the cycles per burst and per store for
+/// each burst length, and the length past which the cycles per burst jump.
+///
+/// With `--format jsonl`, each point is printed as a JSON object instead.
+#[cfg(target_arch = "x86_64")]
+pub fn sweep_store_buffer(bursts: usize) -> std::io::Result<Vec<StoreBufferPoint>> {
+    let mut printer = SweepPrinter::start(
+        "sweep_store_buffer",
+        format!("Store buffer sweep, {} bursts per length", bursts),
+        json!({}),
+        vec![
+            Column::new("stores", "stores", Cell::Integer, |p: &StoreBufferPoint| {
+                p.stores as f64
+            })
+            .width(8),
+            Column::new("cycles/burst", "cycles_per_burst", Cell::Decimal, |p| {
+                p.cycles_per_burst
+            }),
+            Column::new("cycles/store", "cycles_per_store", Cell::Decimal, |p| {
+                p.cycles_per_store
+            }),
+        ],
+    )?;
+
+    let (points, counts): (Vec<StoreBufferPoint>, Vec<Counts>) = [
+        store_buffer_point::<8>(bursts)?,
+        store_buffer_point::<16>(bursts)?,
+        store_buffer_point::<24>(bursts)?,
+        store_buffer_point::<32>(bursts)?,
+        store_buffer_point::<40>(bursts)?,
+        store_buffer_point::<48>(bursts)?,
+        store_buffer_point::<56>(bursts)?,
+        store_buffer_point::<64>(bursts)?,
+        store_buffer_point::<72>(bursts)?,
+        store_buffer_point::<80>(bursts)?,
+        store_buffer_point::<96>(bursts)?,
+        store_buffer_point::<112>(bursts)?,
+        store_buffer_point::<128>(bursts)?,
+        store_buffer_point::<160>(bursts)?,
+    ]
+    .into_iter()
+    .unzip();
+
+    for (point, counts) in points.iter().zip(counts) {
+        printer.row(point, None, "");
+        printer.detail(format!("{} stores", point.stores), counts);
+    }
+
+    let depth = store_buffer_depth(&points)
+        .map(|depth| format!("Stores stall past about {} stores in flight", depth));
+    printer.finish(
+        &points,
+        depth,
+        Plot {
+            title: "bench_store_buffer_fill, by stores per burst".to_string(),
+            x: ("stores per burst", |point| point.stores as f64),
+            ys: vec![("cycles per burst", |point| point.cycles_per_burst)],
+            log_x: false,
+        },
+    )?;
+    Ok(points)
+}
+
+/// One data point of [`sweep_reuse_distance`].
+#[derive(Clone, Debug, Serialize)]
+pub struct ReusePoint {
+    /// Lines loaded between the two loads of each line.
+    pub distance: usize,
+    pub cycles_per_access: f64,
+    /// L1D misses on the reloads, as a fraction of the reloads, between 0
+    /// and 1, assuming every first load misses.
+    pub reuse_miss_rate: f64,
+}
+
+/// Run `bench_reuse_distance` over `nodes` once per reuse distance in
+/// `distances`, for about `accesses` loads each, and print the cycles per
+/// access and the L1D miss rate of the reloads for each.
+///
+/// The distance at which the reloads start missing is the number of lines
+/// the L1D effectively holds; the steps in cycles per access give the same
+/// for the other levels. `nodes` should be much larger than the last level
+/// cache, so that the first loads always miss. A distance of 0 reloads
+/// nothing, and fails with `InvalidInput`.
+///
+/// With `--format jsonl`, each point is printed as a JSON object instead.
+pub fn sweep_reuse_distance(
+    nodes: &[Node],
+    distances: &[usize],
+    accesses: usize,
+) -> std::io::Result<Vec<ReusePoint>> {
+    if distances.contains(&0) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "reuse distances must be at least 1",
+        ));
+    }
+
+    let mut printer = SweepPrinter::start(
+        "sweep_reuse_distance",
+        format!(
+            "Reuse distance sweep over {} MB",
+            std::mem::size_of_val(nodes) / (1024 * 1024)
+        ),
+        json!({ "size": std::mem::size_of_val(nodes) }),
+        vec![
+            Column::new("distance", "distance", Cell::Integer, |p: &ReusePoint| {
+                p.distance as f64
+            })
+            .width(10),
+            Column::table_only("bytes", Cell::Integer, |p: &ReusePoint| {
+                (p.distance * std::mem::size_of::<Node>()) as f64
+            })
+            .width(10),
+            Column::new("cycles/access", "cycles_per_access", Cell::Decimal, |p| {
+                p.cycles_per_access
+            }),
+            Column::new("reuse miss rate", "reuse_miss_rate", Cell::Percent, |p| {
+                p.reuse_miss_rate
+            }),
+        ],
+    )?;
+
+    let mut points = Vec::new();
+    for &distance in distances {
+        let blocks = (accesses / (2 * distance)).max(1);
+        let counts = measure(|| {
+            black_box(bench_reuse_distance(nodes, distance, blocks));
+        })?;
+
+        let reloads = (distance * blocks) as f64;
+        let point = ReusePoint {
+            distance,
+            cycles_per_access: counts.cycles as f64 / (2.0 * reloads),
+            reuse_miss_rate: ((counts.l1_cache_misses as f64 - reloads) / reloads).clamp(0.0, 1.0),
+        };
+        printer.row(&point, Some(&counts), "");
+        printer.detail(format!("distance {}", distance), counts);
+        points.push(point);
+    }
+
+    printer.finish(
+        &points,
+        None,
+        Plot {
+            title: format!(
+                "bench_reuse_distance over {} MB, by reuse distance",
+                std::mem::size_of_val(nodes) / (1024 * 1024)
+            ),
+            x: ("reuse distance (lines)", |point| point.distance as f64),
+            ys: vec![
+                ("cycles per access", |point| point.cycles_per_access),
+                ("L1D reuse miss rate (%)", |point| {
+                    point.reuse_miss_rate * 100.0
+                }),
+            ],
+            log_x: true,
+        },
+    )?;
+    Ok(points)
+}
+
+/// One data point of [`sweep_gather_locality`].
+#[derive(Clone, Debug, Serialize)]
+pub struct GatherPoint {
+    /// How many elements each index was drawn from, see `clustered_indices`.
+    pub window: usize,
+    pub cycles_per_access: f64,
+    /// L1D misses as a fraction of L1D loads, between 0 and 1.
+    pub l1_miss_rate: f64,
+    /// Same as `cycles_per_access`, with a software prefetch per access.
+    pub prefetched_cycles_per_access: f64,
+}
+
+impl GatherPoint {
+    /// How many times faster the software prefetches made the gather.
+    pub fn prefetch_speedup(&self) -> f64 {
+        self.cycles_per_access / self.prefetched_cycles_per_access
+    }
+}
+
+/// Run `bench_gather` over `array`, with `count` indices drawn with each
+/// locality window of `windows`, `repeats` times each, with and without
+/// software prefetches, and print the L1D miss rate and cycles per access
+/// for each window.
+///
+/// From a window of 1, where the indices are sequential, to the length of
+/// `array`, where they're uniformly random, the miss rate should climb, and
+/// software prefetches help more and more.
+///
+/// With `--format jsonl`, each point is printed as a JSON object instead.
+pub fn sweep_gather_locality(
+    array: &[u8],
+    count: usize,
+    windows: &[usize],
+    repeats: usize,
+) -> std::io::Result<Vec<GatherPoint>> {
+    let mut printer = SweepPrinter::start(
+        "sweep_gather_locality",
+        format!(
+            "Gather locality sweep over {} bytes, {} indices, windows {:?}",
+            array.len(),
+            count,
+            windows
+        ),
+        json!({ "size": array.len() }),
+        vec![
+            Column::new("window", "window", Cell::Integer, |p: &GatherPoint| {
+                p.window as f64
+            })
+            .width(10),
+            Column::new("L1D miss rate", "l1_miss_rate", Cell::Percent, |p| {
+                p.l1_miss_rate
+            }),
+            Column::new("cycles/access", "cycles_per_access", Cell::Decimal, |p| {
+                p.cycles_per_access
+            }),
+            Column::table_only("prefetch speedup", Cell::Speedup, |p| p.prefetch_speedup()),
+            Column::json_only("prefetched_cycles_per_access", Cell::Decimal, |p| {
+                p.prefetched_cycles_per_access
+            }),
+        ],
+    )?;
+
+    let mut points = Vec::new();
+    for &window in windows {
+        let indices = clustered_indices(array.len(), count, window, SEED);
+        let counts = measure(|| {
+            for _ in 0..repeats {
+                black_box(bench_gather::<false>(array, &indices));
+            }
+        })?;
+        let prefetched = measure(|| {
+            for _ in 0..repeats {
+                black_box(bench_gather::<true>(array, &indices));
+            }
+        })?;
+
+        let accesses = (count * repeats) as f64;
+        let point = GatherPoint {
+            window,
+            cycles_per_access: counts.cycles as f64 / accesses,
+            l1_miss_rate: counts.l1_cache_misses as f64 / counts.l1_cache_loads as f64,
+            prefetched_cycles_per_access: prefetched.cycles as f64 / accesses,
+        };
+        printer.row(&point, Some(&counts), "");
+        printer.detail(format!("window {}", window), counts);
+        printer.detail(format!("window {}, prefetched", window), prefetched);
+        points.push(point);
+    }
+
+    printer.finish(
+        &points,
+        None,
+        Plot {
+            title: format!(
+                "bench_gather over {} bytes, by locality window",
+                array.len()
+            ),
+            x: ("window (elements)", |point| point.window as f64),
+            ys: vec![
+                ("L1D miss rate (%)", |point| point.l1_miss_rate * 100.0),
+                ("cycles per access", |point| point.cycles_per_access),
+                ("cycles per access, prefetched", |point| {
+                    point.prefetched_cycles_per_access
+                }),
+            ],
+            log_x: true,
+        },
+    )?;
+    Ok(points)
+}
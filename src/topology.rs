@@ -0,0 +1,235 @@
+use std::collections::BTreeSet;
+use std::path::Path;
+
+use serde::Serialize;
+use serde_json::json;
+
+use crate::runner::{OutputFormat, SCHEMA_VERSION};
+
+/// One of the caches CPU 0 uses, as reported by sysfs.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct CacheInfo {
+    pub level: u32,
+    /// "Data", "Instruction" or "Unified".
+    pub kind: String,
+    pub size: usize,
+    pub line_size: usize,
+    /// How many logical CPUs share this cache.
+    pub shared_by: usize,
+    /// How many lines each set holds, if sysfs says.
+    pub ways: Option<usize>,
+}
+
+impl CacheInfo {
+    /// The distance between addresses that map to the same set: the size of
+    /// one way. Lines that far apart compete for the same `ways` slots, no
+    /// matter how small the working set is.
+    pub fn way_stride(&self) -> Option<usize> {
+        match self.ways? {
+            0 => None,
+            ways => Some(self.size / ways),
+        }
+    }
+}
+
+/// The CPU the benchmarks run on, to make sense of where the cliffs in
+/// working-set sweeps are.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Topology {
+    pub model: String,
+    pub cores: usize,
+    pub threads: usize,
+    pub caches: Vec<CacheInfo>,
+}
+
+impl Topology {
+    /// Read the topology from `/proc/cpuinfo` and `/sys/devices/system/cpu`.
+    pub fn detect() -> std::io::Result<Topology> {
+        let cpuinfo = std::fs::read_to_string("/proc/cpuinfo")?;
+        let mut topology = Topology::from_cpuinfo(&cpuinfo);
+        topology.caches = read_caches(Path::new("/sys/devices/system/cpu/cpu0/cache"))?;
+        Ok(topology)
+    }
+
+    /// The model and core counts in the contents of `/proc/cpuinfo`, without
+    /// any caches.
+    pub fn from_cpuinfo(cpuinfo: &str) -> Topology {
+        let mut model = String::new();
+        let mut threads = 0;
+        let mut cores = BTreeSet::new();
+
+        // Each logical CPU gets a paragraph, with its core id and the id of
+        // its package, which together identify the physical core.
+        let mut package = None;
+        for line in cpuinfo.lines() {
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            match key.trim() {
+                "processor" => threads += 1,
+                "model name" if model.is_empty() => model = value.to_string(),
+                "physical id" => package = Some(value.to_string()),
+                "core id" => {
+                    cores.insert((package.clone(), value.to_string()));
+                }
+                _ => {}
+            }
+        }
+
+        Topology {
+            model,
+            // Some VMs don't report core ids, in which case every thread
+            // is as good as a core.
+            cores: if cores.is_empty() {
+                threads
+            } else {
+                cores.len()
+            },
+            threads,
+            caches: Vec::new(),
+        }
+    }
+
+    /// Print the topology as a few human-readable lines.
+    pub fn print(&self) {
+        println!("====================================================================");
+        println!("CPU: {}", self.model);
+        println!("{} cores, {} threads", self.cores, self.threads);
+        for cache in &self.caches {
+            let ways = match cache.ways {
+                Some(ways) => format!(", {}-way", ways),
+                None => String::new(),
+            };
+            println!(
+                "L{} {:<12} {:>8}{}, {}-byte lines, shared by {} threads",
+                cache.level,
+                cache.kind,
+                format_size(cache.size),
+                ways,
+                cache.line_size,
+                cache.shared_by
+            );
+        }
+        println!();
+    }
+}
+
+/// The caches described by the `index*` directories of `dir`, sorted by
+/// level.
+fn read_caches(dir: &Path) -> std::io::Result<Vec<CacheInfo>> {
+    let read = |index: &Path, file: &str| -> std::io::Result<String> {
+        Ok(std::fs::read_to_string(index.join(file))?
+            .trim()
+            .to_string())
+    };
+    let invalid = |file: &str, value: &str| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("invalid cache {} {:?}", file, value),
+        )
+    };
+
+    let mut caches = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let index = entry?.path();
+        if !index
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .starts_with("index")
+        {
+            continue;
+        }
+
+        let level = read(&index, "level")?;
+        let size = read(&index, "size")?;
+        let line_size = read(&index, "coherency_line_size")?;
+        let shared_cpu_list = read(&index, "shared_cpu_list")?;
+        // Some VMs don't report the associativity.
+        let ways = read(&index, "ways_of_associativity").ok();
+        caches.push(CacheInfo {
+            level: level.parse().map_err(|_| invalid("level", &level))?,
+            kind: read(&index, "type")?,
+            size: parse_cache_size(&size).ok_or_else(|| invalid("size", &size))?,
+            line_size: line_size
+                .parse()
+                .map_err(|_| invalid("line size", &line_size))?,
+            shared_by: parse_cpu_list(&shared_cpu_list)
+                .ok_or_else(|| invalid("CPU list", &shared_cpu_list))?
+                .len(),
+            ways: match ways {
+                Some(ways) => Some(ways.parse().map_err(|_| invalid("ways", &ways))?),
+                None => None,
+            },
+        });
+    }
+    caches.sort_by(|a, b| (a.level, &a.kind).cmp(&(b.level, &b.kind)));
+
+    Ok(caches)
+}
+
+/// Parse a cache size as sysfs prints it, eg "32K" or "2048K", into bytes.
+pub fn parse_cache_size(size: &str) -> Option<usize> {
+    let (digits, multiplier) = match size.as_bytes().last()? {
+        b'K' => (&size[..size.len() - 1], 1024),
+        b'M' => (&size[..size.len() - 1], 1024 * 1024),
+        _ => (size, 1),
+    };
+    Some(digits.parse::<usize>().ok()? * multiplier)
+}
+
+/// Parse a list of CPUs as sysfs prints it, eg "0-3,8".
+pub fn parse_cpu_list(list: &str) -> Option<Vec<usize>> {
+    let mut cpus = Vec::new();
+    for range in list.split(',') {
+        match range.split_once('-') {
+            Some((first, last)) => {
+                let first: usize = first.parse().ok()?;
+                let last: usize = last.parse().ok()?;
+                cpus.extend(first..=last);
+            }
+            None => cpus.push(range.parse().ok()?),
+        }
+    }
+    Some(cpus)
+}
+
+pub(crate) fn format_size(bytes: usize) -> String {
+    if bytes >= 1024 * 1024 && bytes.is_multiple_of(1024 * 1024) {
+        format!("{} MB", bytes / (1024 * 1024))
+    } else {
+        format!("{} KB", bytes / 1024)
+    }
+}
+
+/// Print the topology before the benchmarks, as a table or, with
+/// `--format jsonl`, as a line of metadata. Other formats are meant to be
+/// parsed as a single table, so they get nothing.
+///
+/// If the topology can't be read, this only warns: it's context, the
+/// benchmarks don't need it.
+pub fn print_topology() -> std::io::Result<()> {
+    let topology = match Topology::detect() {
+        Ok(topology) => topology,
+        Err(err) => {
+            eprintln!("Warning: can't read the CPU topology: {}", err);
+            return Ok(());
+        }
+    };
+
+    match OutputFormat::from_args()? {
+        OutputFormat::Table => topology.print(),
+        OutputFormat::JsonLines => {
+            println!(
+                "{}",
+                json!({
+                    "schema_version": SCHEMA_VERSION,
+                    "metadata": { "topology": topology },
+                })
+            );
+        }
+        _ => {}
+    }
+    Ok(())
+}
@@ -0,0 +1,49 @@
+use std::hint::black_box;
+
+use crate::benchmarks::bench_avx_sse_transition;
+use crate::runner::{measure, OutputFormat};
+
+/// Measure the cost of switching from AVX to legacy SSE instructions with
+/// the upper halves of the ymm registers dirty, by running
+/// [`bench_avx_sse_transition`] for `iterations` iterations with and without
+/// VZEROUPPER.
+///
+/// Returns the extra cycles per transition, which should be about 0 on CPUs
+/// without the penalty, or `None` if the CPU doesn't support AVX.
+pub fn avx_sse_transition_penalty(iterations: u64) -> std::io::Result<Option<f64>> {
+    let table = OutputFormat::from_args()? == OutputFormat::Table;
+    if table {
+        println!("====================================================================");
+        println!("AVX to SSE transition penalty");
+    }
+    if !is_x86_feature_detected!("avx") {
+        if table {
+            println!("Cycles per transition: (no AVX on this CPU)");
+            println!();
+        }
+        return Ok(None);
+    }
+
+    let dirty = measure(|| {
+        black_box(bench_avx_sse_transition::<false>(iterations));
+    })?;
+    let clean = measure(|| {
+        black_box(bench_avx_sse_transition::<true>(iterations));
+    })?;
+
+    let cycles_dirty = dirty.cycles as f64 / iterations as f64;
+    let cycles_clean = clean.cycles as f64 / iterations as f64;
+    let penalty = cycles_dirty - cycles_clean;
+    if !table {
+        return Ok(Some(penalty));
+    }
+    println!(
+        "Cycles per iteration without VZEROUPPER: {:.3}",
+        cycles_dirty
+    );
+    println!("Cycles per iteration with VZEROUPPER: {:.3}", cycles_clean);
+    println!("Cycles per transition: {:.3}", penalty);
+    println!();
+
+    Ok(Some(penalty))
+}
@@ -0,0 +1,902 @@
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::{_MM_HINT_NTA, _MM_HINT_T1, _MM_HINT_T2};
+use std::hint::black_box;
+
+use test_prefetch::benchmarks::*;
+use test_prefetch::runner::{
+    measure, measure_fenced, BenchConfig, BenchmarkResults, Counts, Fence,
+};
+
+/// Measure `callback`, or return `None` if the hardware counters can't be
+/// opened (eg in a VM), in which case the test is skipped.
+fn measure_or_skip(callback: impl Fn()) -> Option<Counts> {
+    match measure(callback) {
+        Ok(counts) => Some(counts),
+        Err(err) => {
+            eprintln!(
+                "skipping test, performance counters are unavailable: {}",
+                err
+            );
+            None
+        }
+    }
+}
+
+#[test]
+fn fences_stay_out_of_the_measured_region() {
+    let array = [1; 10_000];
+    let mut instructions = Vec::new();
+    for fence in [Fence::None, Fence::Lfence, Fence::Mfence] {
+        match measure_fenced(fence, || {
+            black_box(bench_sum_of_array(black_box(&array)));
+        }) {
+            Ok(counts) => instructions.push(counts.instructions as f64),
+            Err(err) => {
+                eprintln!(
+                    "skipping test, performance counters are unavailable: {}",
+                    err
+                );
+                return;
+            }
+        }
+    }
+    for fenced in &instructions[1..] {
+        assert!(
+            (fenced / instructions[0] - 1.0).abs() < 0.01,
+            "{:?}",
+            instructions
+        );
+    }
+}
+
+#[test]
+fn sum_of_array_is_correct() {
+    let array = [1; 200];
+    assert_eq!(bench_sum_of_array(&array), 200);
+    assert_eq!(bench_sum_of_array_unrolled(&array), 200);
+}
+
+#[test]
+fn sum_of_array_runs_at_several_instructions_per_cycle() {
+    let array = vec![1u8; 1 << 16];
+    let Some(counts) = measure_or_skip(|| {
+        black_box(bench_sum_of_array(black_box(&array)));
+    }) else {
+        return;
+    };
+    let results =
+        BenchmarkResults::new("bench_sum_of_array", BenchConfig::new(array.len()), counts);
+    // About 5 instructions per iteration, at 0.6 to 1 iteration per cycle.
+    assert!(
+        (1.0..=6.0).contains(&results.ipc),
+        "IPC {} out of range",
+        results.ipc
+    );
+}
+
+#[test]
+fn sum_of_array_with_stride_is_correct() {
+    let array = [1; 1000];
+    assert_eq!(
+        bench_sum_of_array_with_stride(&array, 1),
+        (1000 % 256) as u8
+    );
+    assert_eq!(bench_sum_of_array_with_stride(&array, 16), 63);
+    assert_eq!(bench_sum_of_array_with_stride(&array, 64), 16);
+    assert_eq!(
+        bench_sum_of_array_with_stride_prefetch::<1000, 4>(&array, 16),
+        63
+    );
+    #[cfg(target_arch = "x86_64")]
+    assert_eq!(
+        bench_sum_of_array_with_stride_prefetch_hint::<1000, 4, _MM_HINT_NTA>(&array, 16),
+        63
+    );
+    #[cfg(target_arch = "x86_64")]
+    assert_eq!(
+        bench_sum_of_array_with_stride_prefetch_hint::<1000, 4, _MM_HINT_T1>(&array, 16),
+        63
+    );
+    #[cfg(target_arch = "x86_64")]
+    assert_eq!(
+        bench_sum_of_array_with_stride_prefetch_hint::<1000, 4, _MM_HINT_T2>(&array, 16),
+        63
+    );
+}
+
+#[test]
+fn slice_versions_match_const_generic_versions() {
+    let array = vec![1; 1000];
+    for stride in [1, 16, 64] {
+        let fixed: &[u8; 1000] = array.as_slice().try_into().unwrap();
+        assert_eq!(
+            bench_sum_of_array_with_stride_slice(&array, stride),
+            bench_sum_of_array_with_stride(fixed, stride)
+        );
+        assert_eq!(
+            bench_sum_of_array_with_stride_prefetch_slice::<4>(&array, stride),
+            bench_sum_of_array_with_stride_prefetch::<1000, 4>(fixed, stride)
+        );
+    }
+}
+
+#[test]
+fn rmw_with_stride_only_touches_every_stride_th_byte() {
+    let mut array = [0xff; 1000];
+    assert_eq!(bench_rmw_array_with_stride(&mut array, 16), 3);
+
+    for (i, &byte) in array.iter().enumerate() {
+        assert_eq!(byte, if i % 16 == 0 { 3 } else { 0xff });
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn prefetchw_does_not_change_the_rmw() {
+    if !has_prefetchw() {
+        eprintln!("skipping test, the CPU doesn't support prefetchw");
+        return;
+    }
+    let mut array = [0xff; 1000];
+    let mut expected = array;
+    assert_eq!(
+        bench_rmw_array_with_stride_prefetchw::<4>(&mut array, 16),
+        bench_rmw_array_with_stride(&mut expected, 16)
+    );
+    assert_eq!(array, expected);
+}
+
+#[test]
+fn sequential_stores_write_every_element() {
+    let mut array = [0; 1000];
+    bench_sequential_store(&mut array);
+    assert!(array.iter().all(|&value| value == 3));
+
+    #[cfg(target_arch = "x86_64")]
+    {
+        let mut array = [0; 1000];
+        bench_sequential_store_nt(&mut array);
+        assert!(array.iter().all(|&value| value == 3));
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn flush_to_zero_drops_denormals() {
+    assert_eq!(bench_sum_floats(&[1.0; 1000]), 500.0);
+
+    let denormals = [f32::MIN_POSITIVE / 8.0; 1000];
+    assert!(bench_sum_floats(&denormals) > 0.0);
+    assert_eq!(bench_sum_floats_ftz(&denormals), 0.0);
+    // The mode is restored afterwards.
+    assert!(bench_sum_floats(&denormals) > 0.0);
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn page_split_loads_read_one_word_pair_per_page() {
+    let buffer = PageAligned([1; 4 * 4096]);
+    assert_eq!(std::ptr::addr_of!(buffer) as usize % 4096, 0);
+
+    let words = 0x0101_0101_0101_0101_u64;
+    assert_eq!(bench_page_split_loads(&buffer, 0), 8 * words);
+    // The last page has no next page to split into.
+    assert_eq!(bench_page_split_loads(&buffer, 4088), 6 * words);
+}
+
+#[test]
+fn page_walk_stress_loads_one_byte_per_stride() {
+    let mapping = test_prefetch::page_walk::SmallPages::new(4 * 4096).unwrap();
+    assert!(mapping.iter().all(|&byte| byte == 0));
+
+    let buffer = [1; 4 * 4096];
+    assert_eq!(bench_page_walk_stress(&buffer, 4096), 4);
+    assert_eq!(bench_page_walk_stress(&buffer, 2 * 4096), 2);
+}
+
+#[test]
+fn aligned_buffers_start_at_a_multiple_of_their_alignment() {
+    for align in [64, 4096, 1 << 16] {
+        let mut buffer = AlignedBuffer::new(1_000, align).unwrap();
+        assert_eq!(buffer.as_ptr() as usize % align, 0);
+        assert_eq!(buffer.len(), 1_000);
+        assert!(buffer.iter().all(|&byte| byte == 0));
+        buffer[999] = 1;
+    }
+    assert_eq!(AlignedBuffer::page_aligned(0).unwrap().len(), 0);
+
+    let err = AlignedBuffer::new(1_000, 48).err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn indirect_access_stays_in_bounds() {
+    let array = [1; 1000];
+    let indices = random_indices::<10_000>(array.len(), SEED);
+    assert!(indices.iter().all(|&index| index < array.len()));
+
+    // One element every 64 indices.
+    let checked = CheckedIndices::new(&indices).unwrap();
+    assert_eq!(bench_sum_array_indirect(&array, checked), 157);
+    assert_eq!(
+        bench_sum_array_indirect_prefetch::<_, _, 8>(&array, checked),
+        157
+    );
+
+    let mut indices = indices;
+    indices[42] = array.len();
+    let err = CheckedIndices::<1000, 10_000>::new(&indices).unwrap_err();
+    assert!(err.to_string().contains("position 42"));
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn aliasing_loads_and_stores_every_word_of_the_window() {
+    let mut buffer = [0; 3 * 4096];
+    buffer[..4096].copy_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0].repeat(512));
+    assert_eq!(bench_4k_aliasing(&mut buffer, 4096 + 64), 512);
+
+    // The stores landed past the window, one word per load.
+    assert_eq!(
+        buffer[4096 + 64..]
+            .iter()
+            .filter(|&&byte| byte == 3)
+            .count(),
+        512
+    );
+}
+
+#[test]
+fn dumped_indices_load_back_identically() {
+    let path = std::env::temp_dir().join("test_prefetch_indices.bin");
+    let indices = random_indices::<1000>(500, SEED);
+    assert_eq!(random_indices::<1000>(500, SEED), indices);
+    write_indices(&path, &*indices).unwrap();
+
+    assert_eq!(read_indices::<1000>(&path, 500).unwrap(), indices);
+    // Wrong count, and indices out of bounds of a smaller array.
+    assert!(read_indices::<999>(&path, 500).is_err());
+    assert!(read_indices::<1000>(&path, 10).is_err());
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn random_permutation_is_a_reproducible_permutation() {
+    let permutation = random_permutation(1000, SEED);
+    let mut sorted = permutation.clone();
+    sorted.sort_unstable();
+    assert_eq!(sorted, (0..1000).collect::<Vec<_>>());
+
+    assert_eq!(random_permutation(1000, SEED), permutation);
+    assert_ne!(random_permutation(1000, SEED + 1), permutation);
+
+    let mut shuffled: Vec<usize> = (0..1000).collect();
+    shuffle_in_place(&mut shuffled, SEED);
+    assert_eq!(shuffled, permutation);
+}
+
+#[test]
+fn random_chain_visits_every_node() {
+    let chain = random_chain::<1000>(SEED);
+
+    let mut visited = [false; 1000];
+    let mut current = 0;
+    for _ in 0..1000 {
+        assert!(!visited[current]);
+        visited[current] = true;
+        current = chain[current].next;
+    }
+    assert_eq!(current, 0);
+}
+
+#[test]
+fn pointer_chase_goes_around_the_chain() {
+    let chain = random_chain::<1000>(SEED);
+    assert_eq!(bench_pointer_chase(&chain, 1000), 0);
+    assert_ne!(bench_pointer_chase(&chain, 999), 0);
+}
+
+#[test]
+fn pointer_chase_lookahead_ends_where_the_plain_chase_does() {
+    let chain = random_chain::<1000>(SEED);
+    for hops in [0, 5, 999, 1000, 2500] {
+        assert_eq!(
+            bench_pointer_chase_lookahead::<1000, 8>(&chain, hops),
+            bench_pointer_chase(&chain, hops)
+        );
+    }
+}
+
+#[test]
+fn mlp_chains_advance_independently() {
+    let chain = random_chain::<1000>(SEED);
+    let starts = chain_starts::<1000, 4>(&chain);
+    assert_eq!(starts[0], 0);
+    assert_eq!(bench_pointer_chase(&chain, 250), starts[1]);
+
+    // Each chain ends where the next one started.
+    let ends = bench_mlp(&chain, starts, 250);
+    assert_eq!(ends, [starts[1], starts[2], starts[3], 0]);
+}
+
+#[test]
+fn dependent_prefetch_does_not_change_sum() {
+    let chain = random_chain::<1000>(SEED);
+    assert_eq!(
+        bench_dependent_prefetch::<1000, false>(&chain, 500),
+        500 * 32
+    );
+    assert_eq!(
+        bench_dependent_prefetch::<1000, true>(&chain, 500),
+        500 * 32
+    );
+}
+
+#[test]
+fn helpers_cost_at_most_one_instruction() {
+    let Ok(costs) = test_prefetch::calibration::ruler(10) else {
+        eprintln!("skipping test, performance counters are unavailable");
+        return;
+    };
+
+    // The macros emit no instructions of their own, and black_box only
+    // stores its argument to the stack.
+    for cost in costs {
+        assert!(
+            (-0.1..1.1).contains(&cost.instructions),
+            "{} costs {:.3} instructions",
+            cost.name,
+            cost.instructions
+        );
+    }
+}
+
+#[test]
+fn black_box_keeps_the_benchmark_alive() {
+    let array = [1; 1000];
+    let Ok(effect) = test_prefetch::calibration::dead_code_elimination(&array, 100) else {
+        eprintln!("skipping test, performance counters are unavailable");
+        return;
+    };
+
+    // Whatever the compiler does without the guard, it can't add work.
+    assert!(effect.guarded_instructions >= 1.0);
+    assert!(effect.unguarded_instructions <= effect.guarded_instructions);
+}
+
+/// Two CPUs the tests can pin threads to, if there are two.
+fn two_cpus() -> Option<[usize; 2]> {
+    match test_prefetch::affinity::Affinity::current().unwrap().cpus()[..] {
+        [first, second, ..] => Some([first, second]),
+        _ => None,
+    }
+}
+
+#[test]
+fn cache_pingpong_completes_every_round() {
+    let Some(cpus) = two_cpus() else {
+        return;
+    };
+    assert_eq!(bench_cache_pingpong(1000, cpus).unwrap(), 2000);
+}
+
+#[test]
+fn false_sharing_counts_every_increment() {
+    let Some(cpus) = two_cpus() else {
+        return;
+    };
+    assert_eq!(bench_false_sharing::<true>(1000, cpus).unwrap(), 2000);
+    assert_eq!(bench_false_sharing::<false>(1000, cpus).unwrap(), 2000);
+}
+
+#[test]
+fn atomic_contention_counts_every_increment() {
+    let cpus = test_prefetch::affinity::Affinity::current().unwrap().cpus();
+    let threads = cpus.len() as u64;
+    assert_eq!(
+        bench_atomic_contention::<true>(1000, &cpus).unwrap(),
+        1000 * threads
+    );
+    assert_eq!(
+        bench_atomic_contention::<false>(1000, &cpus).unwrap(),
+        1000 * threads
+    );
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn code_footprint_runs_every_block() {
+    assert_eq!(bench_code_footprint::<_, 10, 64>(&[(); 10]), 4 * 64 * 10);
+}
+
+#[test]
+fn noops_executes_six_instructions_per_iteration() {
+    const N: usize = 100_000;
+    let array = [(); N];
+
+    let Some(counts) = measure_or_skip(|| {
+        black_box(bench_noops(&array));
+    }) else {
+        return;
+    };
+
+    // Six nops, plus a few instructions of loop overhead.
+    let per_iteration = counts.instructions as f64 / N as f64;
+    assert!(
+        (6.0..9.0).contains(&per_iteration),
+        "expected ~6 instructions per iteration, got {:.3}",
+        per_iteration
+    );
+}
+
+#[test]
+fn prefetch_issues_more_l1_prefetches() {
+    let array: Box<[u8; 1_000_000]> = vec![1; 1_000_000].into_boxed_slice().try_into().unwrap();
+
+    let Some(without_prefetch) = measure_or_skip(|| {
+        for _ in 0..10 {
+            black_box(bench_sum_of_array_with_stride(&array, 16));
+        }
+    }) else {
+        return;
+    };
+    let Some(with_prefetch) = measure_or_skip(|| {
+        for _ in 0..10 {
+            black_box(bench_sum_of_array_with_stride_prefetch::<1_000_000, 4>(
+                &array, 16,
+            ));
+        }
+    }) else {
+        return;
+    };
+
+    assert!(
+        with_prefetch.l1_cache_prefetches > without_prefetch.l1_cache_prefetches,
+        "prefetch variant issued {} L1 prefetches, plain variant issued {}",
+        with_prefetch.l1_cache_prefetches,
+        without_prefetch.l1_cache_prefetches,
+    );
+}
+
+#[test]
+fn prefetch_offset_skips_the_start_of_the_array() {
+    let array = CacheAligned([1; 1000]);
+    assert_eq!(
+        bench_sum_of_array_with_stride_prefetch_offset::<1000, 1>(&array.0, 16, 0),
+        63
+    );
+    assert_eq!(
+        bench_sum_of_array_with_stride_prefetch_offset::<1000, 1>(&array.0, 16, 12),
+        62
+    );
+    assert_eq!(std::ptr::addr_of!(array) as usize % 64, 0);
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn flushed_lines_load_back_unchanged() {
+    let buffer = CacheAligned([1; 4096]);
+    assert_eq!(bench_load_lines(&buffer), 64);
+
+    bench_flush_lines::<4096, false>(&buffer);
+    assert_eq!(bench_load_lines(&buffer), 64);
+    if has_clflushopt() {
+        bench_flush_lines::<4096, true>(&buffer);
+        assert_eq!(bench_load_lines(&buffer), 64);
+    }
+}
+
+#[test]
+fn prefetch_distance_in_lines_does_not_change_sum() {
+    let array = [1; 1000];
+    assert_eq!(
+        bench_sum_of_array_with_stride_prefetch_lines::<1000, 4>(&array, 16),
+        63
+    );
+    assert_eq!(
+        bench_sum_of_array_with_stride_prefetch_lines::<1000, 4>(&array, 64),
+        16
+    );
+}
+
+#[test]
+fn prefetch_distances_convert_between_elements_and_lines() {
+    assert_eq!(prefetch_distance_in_lines(4, 16), 1.0);
+    assert_eq!(prefetch_distance_in_lines(4, 64), 4.0);
+    assert_eq!(prefetch_distance_in_elements(4, 16), 16);
+    assert_eq!(prefetch_distance_in_elements(4, 64), 4);
+    // Rounded up, so the prefetch is never short of the distance.
+    assert_eq!(prefetch_distance_in_elements(1, 48), 2);
+}
+
+#[test]
+fn prefetch_overhead_does_not_change_sum() {
+    let array = [1; 1000];
+    assert_eq!(bench_prefetch_overhead(&array, 16), 63);
+}
+
+#[test]
+fn prefetch_every_k_accesses_does_not_change_sum() {
+    let array = [1; 1000];
+    assert_eq!(
+        bench_sum_of_array_with_stride_prefetch_every::<1000, 4, 1>(&array, 16),
+        63
+    );
+    assert_eq!(
+        bench_sum_of_array_with_stride_prefetch_every::<1000, 4, 4>(&array, 16),
+        63
+    );
+    assert_eq!(
+        bench_sum_of_array_with_stride_prefetch_every::<1000, 64, 16>(&array, 4),
+        250
+    );
+}
+
+#[test]
+fn prefetch_placement_does_not_change_sum() {
+    let array = [1; 1000];
+    assert_eq!(
+        bench_sum_of_array_with_prefetch_placement::<1000, 4, PREFETCH_AT_TOP>(&array, 16),
+        63
+    );
+    assert_eq!(
+        bench_sum_of_array_with_prefetch_placement::<1000, 4, PREFETCH_AFTER_LOAD>(&array, 16),
+        63
+    );
+    assert_eq!(
+        bench_sum_of_array_with_prefetch_placement::<1000, 4, PREFETCH_AFTER_COMPUTE>(&array, 16),
+        63
+    );
+    assert_eq!(
+        bench_sum_of_array_with_prefetch_placement::<1000, 4, PREFETCH_SPLIT>(&array, 16),
+        63
+    );
+}
+
+#[test]
+fn sum_with_work_adds_every_op() {
+    let array = [1; 1000];
+    assert_eq!(bench_sum_with_work(&array, 0), 1000);
+    assert_eq!(bench_sum_with_work(&array, 4), 1000 * (1 + 4 * 3));
+}
+
+#[test]
+fn work_sweep_returns_one_point_per_amount_of_work() {
+    let array = [1; 4096];
+    let points = match test_prefetch::sweeps::sweep_work(&array, &[0, 4, 16], 1) {
+        Ok(points) => points,
+        Err(err) => {
+            eprintln!(
+                "skipping test, performance counters are unavailable: {}",
+                err
+            );
+            return;
+        }
+    };
+
+    let ops: Vec<usize> = points.iter().map(|point| point.ops_per_element).collect();
+    assert_eq!(ops, [0, 4, 16]);
+}
+
+/// Assert that `counts` retired `expected` instructions per iteration, give or
+/// take the few instructions of call overhead around the loop.
+#[cfg(target_arch = "x86_64")]
+fn assert_golden_instructions(counts: &Counts, iterations: usize, expected: f64) {
+    let per_iteration = counts.instructions as f64 / iterations as f64;
+    assert!(
+        (per_iteration - expected).abs() < 0.05,
+        "expected {:.3} instructions per iteration, got {:.3}",
+        expected,
+        per_iteration
+    );
+}
+
+// The golden counts below are the instructions of each asm block, plus the
+// loop overhead the compiler generates around it at the test profile's
+// opt-level. If one of these fails, check the disassembly: the asm block
+// may have been reordered or duplicated, or the loop vectorized.
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn alu_ops_super_unrolled_golden_instruction_count() {
+    const N: usize = 10_000;
+    let array = [(); N];
+
+    let Some(counts) = measure_or_skip(|| {
+        black_box(bench_alu_ops_super_unrolled(&array));
+    }) else {
+        return;
+    };
+
+    // Eight adds, plus the counter decrement and the branch.
+    assert_golden_instructions(&counts, N, 10.0);
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn sum_array_stride_and_pad_golden_instruction_count() {
+    const N: usize = 160_000;
+    let array: Box<[u8; N]> = vec![1; N].into_boxed_slice().try_into().unwrap();
+
+    let Some(counts) = measure_or_skip(|| {
+        black_box(bench_sum_array_stride_and_pad(&array, 16));
+    }) else {
+        return;
+    };
+
+    // Four asm instructions, plus the load, the add, a mov, the comparison
+    // and the branch.
+    assert_golden_instructions(&counts, N / 16, 9.0);
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn sum_array_changing_stride_golden_instruction_count() {
+    const N: usize = 1_000_000;
+    let array: Box<[u8; N]> = vec![1; N].into_boxed_slice().try_into().unwrap();
+
+    let Some(counts) = measure_or_skip(|| {
+        black_box(bench_sum_array_changing_stride(&array));
+    }) else {
+        return;
+    };
+
+    // Replay the asm block's index updates to count the iterations.
+    let mut iterations = 0;
+    let mut stride = 0;
+    let mut i = 0;
+    while i < N {
+        stride = (stride + 9) & 31;
+        i += 110 + stride;
+        iterations += 1;
+    }
+
+    // Five asm instructions, plus the load, the add, a mov, the comparison
+    // and the branch.
+    assert_golden_instructions(&counts, iterations, 10.0);
+}
+
+#[test]
+fn branchy_sums_the_high_bytes() {
+    assert_eq!(bench_branchy(&[0, 127, 128, 255]), 128 + 255);
+
+    let bytes = random_bytes(1000, SEED);
+    let mut sorted = bytes.clone();
+    sorted.sort_unstable();
+    assert_eq!(bench_branchy(&bytes), bench_branchy(&sorted));
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn avx_sse_transition_runs_every_iteration() {
+    if !is_x86_feature_detected!("avx") {
+        return;
+    }
+    assert_eq!(bench_avx_sse_transition::<false>(1000), 1000);
+    assert_eq!(bench_avx_sse_transition::<true>(1000), 1000);
+    assert_eq!(bench_avx_sse_transition::<true>(0), 0);
+}
+
+#[test]
+fn loop_overhead_variants_do_the_same_work() {
+    #[cfg(target_arch = "x86_64")]
+    assert_eq!(
+        bench_loop_overhead_counted(100),
+        100 * LOOP_OVERHEAD_OPS as u64
+    );
+    #[cfg(target_arch = "x86_64")]
+    assert_eq!(bench_loop_overhead_counted(0), 0);
+    #[cfg(target_arch = "x86_64")]
+    assert_eq!(
+        bench_loop_overhead_pointer(&[0; 100]),
+        100 * LOOP_OVERHEAD_OPS as u64
+    );
+    #[cfg(target_arch = "x86_64")]
+    assert_eq!(bench_loop_overhead_pointer(&[]), 0);
+    assert_eq!(
+        bench_loop_overhead_unrolled(),
+        (UNROLLED_BLOCKS * LOOP_OVERHEAD_OPS) as u64
+    );
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn addressing_modes_sum_the_same_elements() {
+    let array: Vec<u64> = (0..64).collect();
+    assert_eq!(bench_addressing_indexed(&array), 64 * 63 / 2);
+    assert_eq!(bench_addressing_pointer(&array), 64 * 63 / 2);
+    assert_eq!(bench_addressing_indexed(&[]), 0);
+    assert_eq!(bench_addressing_pointer(&[]), 0);
+}
+
+#[test]
+fn assoc_conflict_loads_every_line() {
+    let buffer = vec![1; 24 * 4160];
+    assert_eq!(bench_assoc_conflict(&buffer, 4096, 24), 24);
+    assert_eq!(bench_assoc_conflict(&buffer, 4160, 24), 24);
+}
+
+#[test]
+fn prefetches_do_not_change_sum() {
+    let array = [1; 4096];
+    let expected = bench_sum_of_array_with_stride_prefetch::<4096, 0>(&array, 64);
+    assert_eq!(bench_sum_with_prefetches::<4096, 0>(&array, 64), expected);
+    assert_eq!(bench_sum_with_prefetches::<4096, 8>(&array, 64), expected);
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn store_buffer_fill_stores_to_every_line() {
+    let mut lines = [CacheAligned(0u64); 24];
+    bench_store_buffer_fill(&mut lines, 3);
+    // The last burst stores the remaining burst count, 1.
+    assert!(lines.iter().all(|line| line.0 == 1));
+
+    let mut untouched = [CacheAligned(0u64); 8];
+    bench_store_buffer_fill(&mut untouched, 0);
+    assert!(untouched.iter().all(|line| line.0 == 0));
+}
+
+#[test]
+fn reuse_distance_walks_each_block_twice() {
+    let nodes = random_chain::<64>(SEED);
+    let mut expected = 0;
+    for _ in 0..3 * 8 {
+        expected = nodes[expected].next;
+    }
+    assert_eq!(bench_reuse_distance(&*nodes, 8, 3), expected);
+    assert_eq!(bench_reuse_distance(&*nodes, 8, 0), 0);
+}
+
+#[test]
+fn clustered_indices_stay_within_their_window() {
+    let sequential = clustered_indices(1024, 16, 1, SEED);
+    assert_eq!(
+        sequential,
+        (0..16).map(|i| i * 1023 / 16).collect::<Vec<_>>()
+    );
+
+    let clustered = clustered_indices(1 << 20, 1000, 4096, SEED);
+    for (i, &index) in clustered.iter().enumerate() {
+        let base = i * ((1 << 20) - 4096) / 1000;
+        assert!((base..base + 4096).contains(&index));
+    }
+
+    let random = clustered_indices(1024, 1000, usize::MAX, SEED);
+    assert!(random.iter().all(|&index| index < 1024));
+
+    assert!(clustered_indices(0, 16, 4096, SEED).is_empty());
+}
+
+#[test]
+fn gather_sums_the_indexed_elements() {
+    let array: Vec<u8> = (0..=255).collect();
+    let indices = clustered_indices(array.len(), 100, 32, SEED);
+    let expected: u64 = indices.iter().map(|&index| array[index] as u64).sum();
+    assert_eq!(bench_gather::<false>(&array, &indices), expected);
+    assert_eq!(bench_gather::<true>(&array, &indices), expected);
+}
+
+const SENTINEL_LEN: usize = 16 * 1024;
+
+/// A buffer whose every cache line holds a different non-zero value, so that
+/// a checksum which reads it can't come out the same as on a zeroed buffer.
+fn sentinel() -> Box<[u8; SENTINEL_LEN]> {
+    (0..SENTINEL_LEN)
+        .map(|i| (i / 64 % 251 + 1) as u8)
+        .collect::<Vec<_>>()
+        .into_boxed_slice()
+        .try_into()
+        .unwrap()
+}
+
+/// Check that `bench` returns something different on the sentinel buffer than
+/// on a zeroed one, ie that it actually reads the buffer it's given.
+fn assert_reads_buffer(name: &str, bench: impl Fn(&[u8; SENTINEL_LEN]) -> u64) {
+    let zeroed = Box::new([0u8; SENTINEL_LEN]);
+    assert_ne!(
+        bench(&sentinel()),
+        bench(&zeroed),
+        "{} returns the same checksum whatever is in its buffer",
+        name
+    );
+}
+
+// bench_sum_array_stride_and_pad and bench_sum_array_changing_stride are left
+// out: they zero their sum in asm after every load on purpose, so that only
+// the loads are measured, and their result never depends on the buffer.
+#[test]
+fn memory_benchmarks_read_their_buffer() {
+    assert_reads_buffer("bench_sum_of_array", |array| {
+        bench_sum_of_array(array) as u64
+    });
+    assert_reads_buffer("bench_sum_of_array_unrolled", |array| {
+        bench_sum_of_array_unrolled(array) as u64
+    });
+    assert_reads_buffer("bench_sum_of_array_with_stride", |array| {
+        bench_sum_of_array_with_stride(array, 64) as u64
+    });
+    assert_reads_buffer("bench_sum_of_array_with_stride_slice", |array| {
+        bench_sum_of_array_with_stride_slice(array, 64) as u64
+    });
+    assert_reads_buffer("bench_sum_with_work", |array| bench_sum_with_work(array, 2));
+    assert_reads_buffer("bench_sum_of_array_with_stride_prefetch", |array| {
+        bench_sum_of_array_with_stride_prefetch::<SENTINEL_LEN, 4>(array, 64) as u64
+    });
+    assert_reads_buffer("bench_sum_with_prefetches", |array| {
+        bench_sum_with_prefetches::<SENTINEL_LEN, 2>(array, 64) as u64
+    });
+    assert_reads_buffer("bench_sum_of_array_with_stride_prefetch_slice", |array| {
+        bench_sum_of_array_with_stride_prefetch_slice::<4>(array, 64) as u64
+    });
+    assert_reads_buffer("bench_sum_of_array_with_stride_prefetch_lines", |array| {
+        bench_sum_of_array_with_stride_prefetch_lines::<SENTINEL_LEN, 4>(array, 64) as u64
+    });
+    assert_reads_buffer("bench_sum_of_array_with_stride_prefetch_offset", |array| {
+        bench_sum_of_array_with_stride_prefetch_offset::<SENTINEL_LEN, 4>(
+            &CacheAligned(*array).0,
+            64,
+            8,
+        ) as u64
+    });
+    assert_reads_buffer("bench_load_lines", |array| {
+        bench_load_lines(&CacheAligned(*array)) as u64
+    });
+    #[cfg(target_arch = "x86_64")]
+    assert_reads_buffer("bench_sum_of_array_with_stride_prefetch_hint", |array| {
+        bench_sum_of_array_with_stride_prefetch_hint::<SENTINEL_LEN, 4, _MM_HINT_NTA>(array, 64)
+            as u64
+    });
+    assert_reads_buffer("bench_sum_of_array_with_stride_prefetch_every", |array| {
+        bench_sum_of_array_with_stride_prefetch_every::<SENTINEL_LEN, 4, 4>(array, 16) as u64
+    });
+    assert_reads_buffer("bench_prefetch_overhead", |array| {
+        bench_prefetch_overhead(array, 64) as u64
+    });
+    assert_reads_buffer("bench_sum_of_array_with_prefetch_placement", |array| {
+        bench_sum_of_array_with_prefetch_placement::<SENTINEL_LEN, 4, PREFETCH_SPLIT>(array, 64)
+            as u64
+    });
+    assert_reads_buffer("bench_assoc_conflict", |array| {
+        bench_assoc_conflict(array, 4096, 4)
+    });
+    #[cfg(target_arch = "x86_64")]
+    assert_reads_buffer("bench_page_split_loads", |array| {
+        bench_page_split_loads(&PageAligned(*array), 4088)
+    });
+    assert_reads_buffer("bench_page_walk_stress", |array| {
+        bench_page_walk_stress(array, 4096) as u64
+    });
+    let indices = random_indices::<4096>(SENTINEL_LEN, SEED);
+    assert_reads_buffer("bench_sum_array_indirect", |array| {
+        bench_sum_array_indirect(array, CheckedIndices::new(&indices).unwrap()) as u64
+    });
+    assert_reads_buffer("bench_sum_array_indirect_prefetch", |array| {
+        let indices = CheckedIndices::new(&indices).unwrap();
+        bench_sum_array_indirect_prefetch::<_, _, 8>(array, indices) as u64
+    });
+    let indices = clustered_indices(SENTINEL_LEN, 1024, 64, SEED);
+    assert_reads_buffer("bench_gather", |array| {
+        bench_gather::<true>(array, &indices)
+    });
+    assert_reads_buffer("bench_branchy", |array| bench_branchy(array));
+    #[cfg(target_arch = "x86_64")]
+    assert_reads_buffer("bench_addressing_indexed", |array| {
+        bench_addressing_indexed(&words(array))
+    });
+    #[cfg(target_arch = "x86_64")]
+    assert_reads_buffer("bench_addressing_pointer", |array| {
+        bench_addressing_pointer(&words(array))
+    });
+}
+
+/// The buffer as little-endian 64-bit words, for the benchmarks that take
+/// `&[u64]`.
+#[cfg(target_arch = "x86_64")]
+fn words(array: &[u8]) -> Vec<u64> {
+    array
+        .chunks_exact(8)
+        .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()))
+        .collect()
+}
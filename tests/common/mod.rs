@@ -0,0 +1,31 @@
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+/// A directory under the system's temporary directory, removed when dropped,
+/// so that a failing test doesn't leave it behind.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Create an empty directory, named after `name` and this process.
+    pub fn new(name: &str) -> TempDir {
+        let path =
+            std::env::temp_dir().join(format!("test_prefetch_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
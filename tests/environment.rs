@@ -0,0 +1,89 @@
+mod common;
+
+use common::TempDir;
+use test_prefetch::environment::{Environment, EnvironmentChange};
+
+/// A fake sysfs with two thermal zones, and one CPU running at `khz`.
+fn fake_sysfs(name: &str, khz: u64) -> TempDir {
+    let root = TempDir::new(name);
+    for (zone, temp, trips) in [
+        (0, "45000", [("passive", "95000"), ("critical", "105000")]),
+        (1, "61000", [("hot", "80000"), ("critical", "100000")]),
+    ] {
+        let zone = root.join(format!("thermal/thermal_zone{}", zone));
+        std::fs::create_dir_all(&zone).unwrap();
+        std::fs::write(zone.join("temp"), format!("{}\n", temp)).unwrap();
+        for (trip, (kind, temp)) in trips.iter().enumerate() {
+            std::fs::write(zone.join(format!("trip_point_{}_type", trip)), kind).unwrap();
+            std::fs::write(zone.join(format!("trip_point_{}_temp", trip)), temp).unwrap();
+        }
+    }
+    let cpufreq = root.join("cpu/cpu0/cpufreq");
+    std::fs::create_dir_all(&cpufreq).unwrap();
+    std::fs::write(cpufreq.join("scaling_cur_freq"), format!("{}\n", khz)).unwrap();
+    root
+}
+
+#[test]
+fn environment_reads_the_hottest_zone_and_the_smallest_headroom() {
+    let root = fake_sysfs("environment", 3_000_000);
+
+    let environment = Environment::read(&root.join("thermal"), &root.join("cpu"), 0);
+    assert_eq!(environment.temperature_c, Some(61.0));
+    // Zone 1 is hotter, but zone 0 throttles at 95°C and zone 1 only at
+    // 100°C, since its "hot" trip point only notifies.
+    assert_eq!(environment.headroom_c, Some(39.0));
+    assert_eq!(environment.frequency_khz, Some(3_000_000));
+
+    // A cool zone with a low trip point, like a battery's, is far from its
+    // own, even though the hottest zone is past it.
+    let battery = root.join("thermal/thermal_zone2");
+    std::fs::create_dir_all(&battery).unwrap();
+    std::fs::write(battery.join("temp"), "30000\n").unwrap();
+    std::fs::write(battery.join("trip_point_0_type"), "passive").unwrap();
+    std::fs::write(battery.join("trip_point_0_temp"), "50000").unwrap();
+    let environment = Environment::read(&root.join("thermal"), &root.join("cpu"), 0);
+    assert_eq!(environment.headroom_c, Some(20.0));
+    let change = EnvironmentChange {
+        start: environment,
+        end: environment,
+    };
+    assert_eq!(change.throttle_warning(), None);
+
+    // Nothing to read for a CPU sysfs doesn't know.
+    let missing = Environment::read(&root.join("nowhere"), &root.join("cpu"), 7);
+    assert_eq!(missing, Environment::default());
+}
+
+#[test]
+fn throttling_is_flagged_by_temperature_or_frequency_drop() {
+    let cool = Environment {
+        temperature_c: Some(60.0),
+        headroom_c: Some(35.0),
+        frequency_khz: Some(3_000_000),
+    };
+    let steady = EnvironmentChange {
+        start: cool,
+        end: cool,
+    };
+    assert_eq!(steady.throttle_warning(), None);
+
+    let hot = EnvironmentChange {
+        start: cool,
+        end: Environment {
+            temperature_c: Some(92.0),
+            headroom_c: Some(3.0),
+            ..cool
+        },
+    };
+    assert!(hot.throttle_warning().unwrap().contains("throttle point"));
+
+    let slowed = EnvironmentChange {
+        start: cool,
+        end: Environment {
+            frequency_khz: Some(2_400_000),
+            ..cool
+        },
+    };
+    assert!(slowed.throttle_warning().unwrap().contains("dropped"));
+}
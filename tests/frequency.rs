@@ -0,0 +1,42 @@
+mod common;
+
+use std::path::Path;
+
+use common::TempDir;
+use test_prefetch::frequency::PinnedFrequency;
+
+/// A fake sysfs with one CPU using `governor`, among `available`.
+fn fake_cpufreq(name: &str, governor: &str, available: &str) -> TempDir {
+    let root = TempDir::new(name);
+    let cpufreq = root.join("cpu0/cpufreq");
+    std::fs::create_dir_all(&cpufreq).unwrap();
+    std::fs::write(cpufreq.join("scaling_governor"), format!("{}\n", governor)).unwrap();
+    std::fs::write(cpufreq.join("scaling_available_governors"), available).unwrap();
+    std::fs::write(cpufreq.join("scaling_setspeed"), "<unsupported>\n").unwrap();
+    root
+}
+
+fn read(root: &Path, file: &str) -> String {
+    std::fs::read_to_string(root.join("cpu0/cpufreq").join(file)).unwrap()
+}
+
+#[test]
+fn pinning_sets_the_speed_and_restores_the_governor() {
+    let root = fake_cpufreq("pin", "powersave", "userspace powersave performance\n");
+
+    let pinned = PinnedFrequency::pin(&root, &[0], 2_000_000).unwrap();
+    assert_eq!(read(&root, "scaling_governor"), "userspace");
+    assert_eq!(read(&root, "scaling_setspeed"), "2000000");
+
+    drop(pinned);
+    assert_eq!(read(&root, "scaling_governor"), "powersave");
+}
+
+#[test]
+fn pinning_without_the_userspace_governor_is_unsupported() {
+    let root = fake_cpufreq("unsupported", "powersave", "powersave performance\n");
+
+    let err = PinnedFrequency::pin(&root, &[0], 2_000_000).err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+    assert_eq!(read(&root, "scaling_governor"), "powersave\n");
+}
@@ -0,0 +1,45 @@
+use std::path::Path;
+
+use test_prefetch::gnuplot::{gnuplot_path, gnuplot_script, Series};
+
+#[test]
+fn gnuplot_script_inlines_the_data() {
+    let script = gnuplot_script(
+        "sum by stride",
+        &Series {
+            label: "stride (bytes)",
+            values: vec![1.0, 64.0],
+        },
+        &[
+            Series {
+                label: "cycles per access",
+                values: vec![0.5, 4.0],
+            },
+            Series {
+                label: "L1D miss rate (%)",
+                values: vec![1.5, 100.0],
+            },
+        ],
+        true,
+    );
+
+    assert!(script.contains("$data << EOD\n# stride (bytes), cycles per access, L1D miss rate (%)\n1 0.5 1.5\n64 4 100\nEOD\n"));
+    assert!(script.contains("set title \"sum by stride\"\n"));
+    assert!(script.contains("set logscale x 2\n"));
+    // The second series gets the right axis.
+    assert!(
+        script.contains("$data using 1:3 axes x1y2 with linespoints title \"L1D miss rate (%)\"")
+    );
+}
+
+#[test]
+fn gnuplot_paths_are_suffixed_with_the_sweep() {
+    assert_eq!(
+        gnuplot_path(Path::new("plots/out.gp"), "sweep_stride"),
+        Path::new("plots/out_sweep_stride.gp")
+    );
+    assert_eq!(
+        gnuplot_path(Path::new("out"), "sweep_work"),
+        Path::new("out_sweep_work")
+    );
+}
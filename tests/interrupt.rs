@@ -0,0 +1,18 @@
+// Ctrl-C sets a flag for the whole process, which would make every other
+// measurement fail, so this test gets a test binary of its own.
+
+use test_prefetch::runner::{install_interrupt_handler, interrupted, measure};
+
+#[test]
+fn measuring_after_ctrl_c_stops_the_run() {
+    install_interrupt_handler().unwrap();
+    assert!(!interrupted());
+
+    unsafe { libc::raise(libc::SIGINT) };
+    assert!(interrupted());
+
+    let mut ran = false;
+    let err = measure(|| ran = true).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::Interrupted);
+    assert!(!ran);
+}
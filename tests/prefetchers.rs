@@ -0,0 +1,66 @@
+#[cfg(unix)]
+mod common;
+
+#[cfg(unix)]
+use std::os::unix::fs::FileExt;
+
+#[cfg(unix)]
+use common::TempDir;
+use test_prefetch::prefetchers::{prefetch_control, Prefetcher};
+#[cfg(unix)]
+use test_prefetch::prefetchers::{EnabledPrefetchers, MSR_PREFETCH_CONTROL};
+
+/// A fake `/dev/cpu` with one CPU, whose prefetch control MSR is `value`.
+#[cfg(unix)]
+fn fake_msr(name: &str, value: u64) -> TempDir {
+    let root = TempDir::new(name);
+    std::fs::create_dir_all(root.join("0")).unwrap();
+    let msr = std::fs::File::create(root.join("0/msr")).unwrap();
+    msr.write_all_at(&value.to_le_bytes(), MSR_PREFETCH_CONTROL)
+        .unwrap();
+    root
+}
+
+#[cfg(unix)]
+fn read_msr(root: &std::path::Path) -> u64 {
+    let msr = std::fs::File::open(root.join("0/msr")).unwrap();
+    let mut bytes = [0; 8];
+    msr.read_exact_at(&mut bytes, MSR_PREFETCH_CONTROL).unwrap();
+    u64::from_le_bytes(bytes)
+}
+
+#[test]
+fn prefetcher_lists_parse_by_name() {
+    assert_eq!(
+        Prefetcher::parse_list("streamer,adjacent"),
+        Some(vec![Prefetcher::Streamer, Prefetcher::Adjacent])
+    );
+    assert_eq!(Prefetcher::parse_list("none"), Some(vec![]));
+    assert_eq!(
+        Prefetcher::parse_list("all"),
+        Some(Prefetcher::ALL.to_vec())
+    );
+    assert_eq!(Prefetcher::parse_list("streamer,l3"), None);
+}
+
+#[test]
+fn disabled_prefetchers_set_their_bit_and_keep_the_others() {
+    assert_eq!(prefetch_control(0, &Prefetcher::ALL), 0);
+    assert_eq!(prefetch_control(0, &[]), 0xf);
+    assert_eq!(
+        prefetch_control(0x100 | 0xf, &[Prefetcher::Streamer, Prefetcher::DcuIp]),
+        0x100 | 0b0110
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn setting_prefetchers_writes_the_msr_and_restores_it() {
+    let root = fake_msr("prefetchers", 0x100);
+
+    let enabled = EnabledPrefetchers::set(&root, &[0], &[Prefetcher::Streamer]).unwrap();
+    assert_eq!(read_msr(&root), 0x100 | 0b1110);
+
+    drop(enabled);
+    assert_eq!(read_msr(&root), 0x100);
+}
@@ -0,0 +1,25 @@
+use test_prefetch::latency::{LoadToUse, MemoryLevel};
+use test_prefetch::profile::MachineProfile;
+use test_prefetch::topology::Topology;
+
+#[test]
+fn prefetch_speedup_compares_streaming_with_a_dram_load() {
+    let latency = |level, cycles| LoadToUse {
+        level,
+        working_set: 1 << 20,
+        cycles,
+        nanoseconds: cycles / 3.0,
+    };
+    let mut profile = MachineProfile {
+        topology: Topology::from_cpuinfo("processor\t: 0\n"),
+        peak_ipc: 4.0,
+        latencies: vec![latency(MemoryLevel::L1, 5.0)],
+        bandwidth: 10.0,
+        cycles_per_streamed_line: 20.0,
+        mispredict_penalty: 15.0,
+    };
+    assert_eq!(profile.prefetch_speedup(), None);
+
+    profile.latencies.push(latency(MemoryLevel::Dram, 300.0));
+    assert_eq!(profile.prefetch_speedup(), Some(15.0));
+}
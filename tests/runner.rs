@@ -0,0 +1,904 @@
+use test_prefetch::error::{is_unsupported_event, BenchError};
+use test_prefetch::runner::{
+    csv_header, csv_row, format_duration, iterations_from_env, json_document, json_line,
+    l2_miss_events_for, l2_store_miss_event_for, name_filters, name_matches, percent_change,
+    perf_csv_lines, prometheus_lines, raw_lines, reproducibility, retry_transient, schema,
+    strided_traffic, too_short_warning, write_json, AbComparison, BenchConfig, BenchResult,
+    BenchmarkResults, Category, Counts, CsvReporter, Reading, SampleStats, Warmup, Watchdog,
+    DEFAULT_WARMUP, SCHEMA_VERSION,
+};
+
+fn sample_counts() -> Counts {
+    Counts {
+        task_clock: 234_040_000,
+        wall_clock: 235_120_000,
+        context_switches: 1,
+        cpu_migrations: 0,
+        page_faults: 72,
+        cycles: 916_694_940,
+        ref_cycles: 655_496_000,
+        instructions: 3_768_251_802,
+        cache_accesses: 1_009_884_042,
+        l1_cache_loads: 1_009_884_042,
+        l1_cache_misses: 25_093,
+        l1_cache_prefetches: 12_925,
+        l1_cache_store_misses: 0,
+        l1i_cache_misses: 1_402,
+        dtlb_load_misses: 37,
+        l2_cache_accesses_from_dc_misses: 25_098,
+        l2_cache_hits_from_dc_misses: 13_680,
+        l2_cache_accesses_from_store_misses: 0,
+        times: [
+            ("task_clock", (234_100_000, 234_100_000)),
+            ("instructions", (234_100_000, 234_100_000)),
+            ("l1i_cache_misses", (234_100_000, 117_050_000)),
+        ]
+        .into_iter()
+        .map(|(key, times)| (key.to_string(), times))
+        .collect(),
+        groups: Default::default(),
+        environment: None,
+        single_run: false,
+        unavailable: Default::default(),
+    }
+}
+
+#[test]
+fn raw_values_use_plain_keys_and_raw_event_codes() {
+    let values = sample_counts().values();
+
+    assert!(values.contains(&("instructions".to_string(), 3_768_251_802)));
+    assert!(values.contains(&("l2_cache_accesses_from_dc_misses".to_string(), 25_098)));
+    assert!(values.contains(&("l2_cache_hits_from_dc_misses".to_string(), 13_680)));
+
+    // Without any times, every event counts as counted.
+    let counts = Counts {
+        times: Default::default(),
+        ..sample_counts()
+    };
+    let lines = raw_lines(
+        "bench_a",
+        &counts,
+        &[
+            ("l2_cache_accesses_from_dc_misses", 0xc860),
+            ("l2_cache_hits_from_dc_misses", 0x7064),
+        ],
+    );
+    assert_eq!(lines[0], "benchmark=bench_a");
+    assert!(lines.contains(&"instructions=3768251802".to_string()));
+    assert!(lines.contains(&"raw_0xc860=25098".to_string()));
+    assert!(lines.contains(&"raw_0x7064=13680".to_string()));
+}
+
+#[test]
+fn csv_cells_are_plain_integers() {
+    let header = csv_header();
+    let row = csv_row("bench_noops", &sample_counts());
+
+    let columns: Vec<&str> = header.split(',').collect();
+    let cells: Vec<&str> = row.split(',').collect();
+    assert_eq!(columns.len(), cells.len());
+
+    let instructions = columns.iter().position(|&c| c == "instructions").unwrap();
+    assert_eq!(cells[0], "bench_noops");
+    assert_eq!(cells[instructions], "3768251802");
+    let task_clock = columns.iter().position(|&c| c == "task_clock_ms").unwrap();
+    assert_eq!(cells[task_clock], "234.040");
+}
+
+#[test]
+fn csv_reporter_writes_the_header_once() {
+    let mut all_counted = sample_counts();
+    all_counted.times.clear();
+    let mut reporter = CsvReporter::new(Vec::new());
+    reporter.write_row("bench_a", &all_counted).unwrap();
+    reporter.write_row("bench_b", &Counts::default()).unwrap();
+    let written = String::from_utf8(reporter.into_inner()).unwrap();
+
+    let lines: Vec<&str> = written.lines().collect();
+    assert_eq!(
+        lines,
+        [
+            csv_header(),
+            csv_row("bench_a", &all_counted),
+            csv_row("bench_b", &Counts::default())
+        ]
+    );
+    assert_eq!(
+        lines[0],
+        "benchmark,task_clock_ms,cycles,instructions,ipc,l1_loads,l1_misses,l1_miss_pct,\
+         l1_prefetches,l2_accesses,l2_hits"
+    );
+    assert_eq!(
+        lines[1],
+        "bench_a,234.040,916694940,3768251802,4.111,1009884042,25093,0.002,12925,25098,13680"
+    );
+    // Nothing was counted, so there's no ratio to write.
+    assert_eq!(lines[2], "bench_b,0.000,0,0,,0,0,,0,0,0");
+}
+
+#[test]
+fn zero_cache_accesses_with_nonzero_cycles_look_inactive() {
+    let mut counts = sample_counts();
+    assert!(!counts.looks_inactive(counts.cache_accesses));
+
+    counts.cache_accesses = 0;
+    assert!(counts.looks_inactive(counts.cache_accesses));
+
+    // A run that did nothing at all isn't evidence of a broken counter.
+    let idle = Counts::default();
+    assert!(!idle.looks_inactive(idle.cache_accesses));
+}
+
+#[test]
+fn json_lines_are_independently_parseable() {
+    let line = json_line("bench_noops", Some(Category::FrontEnd), &sample_counts());
+    assert!(!line.contains('\n'));
+
+    let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+    assert_eq!(value["benchmark"], "bench_noops");
+    assert_eq!(value["category"], "FrontEnd");
+    assert_eq!(value["counts"]["instructions"], 3_768_251_802_u64);
+    assert_eq!(value["running_fraction"]["l1i_cache_misses"], 0.5);
+    assert_eq!(value["schema_version"], SCHEMA_VERSION);
+}
+
+#[test]
+fn schema_lists_every_json_field() {
+    let fields = schema();
+    let line = json_line("bench_noops", None, &sample_counts());
+    let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+
+    for key in value.as_object().unwrap().keys() {
+        assert!(
+            fields
+                .iter()
+                .any(|(field, _)| field.split('.').next() == Some(key)),
+            "{} missing from the schema",
+            key
+        );
+    }
+    for key in value["counts"].as_object().unwrap().keys() {
+        let field = format!("counts.{}", key);
+        assert!(fields
+            .iter()
+            .any(|(name, kind)| *name == field && *kind == "integer"));
+    }
+}
+
+#[test]
+fn ratios_need_counters_that_counted_together() {
+    let mut counts = sample_counts();
+    assert_eq!(counts.running_fraction("instructions"), 1.0);
+    assert_eq!(counts.running_fraction("l1i_cache_misses"), 0.5);
+    // Without group information, half the run is all we can be sure of.
+    assert_eq!(counts.overlap("instructions", "l1i_cache_misses"), 0.5);
+    assert!(counts.comparable("instructions", "l1i_cache_misses"));
+
+    counts
+        .times
+        .insert("l1_cache_misses".to_string(), (100, 30));
+    counts.times.insert("l1_cache_loads".to_string(), (100, 40));
+    assert_eq!(counts.overlap("l1_cache_misses", "l1_cache_loads"), 0.0);
+    assert!(!counts.comparable("l1_cache_misses", "l1_cache_loads"));
+    assert_eq!(counts.l1_miss_rate_within(&(0.0..=100.0)), None);
+
+    // Events of one group count at the same times.
+    counts.groups.insert("l1_cache_misses".to_string(), 0);
+    counts.groups.insert("l1_cache_loads".to_string(), 0);
+    assert_eq!(counts.overlap("l1_cache_misses", "l1_cache_loads"), 0.3);
+    assert!(!counts.comparable("l1_cache_misses", "l1_cache_loads"));
+}
+
+#[test]
+fn categories_parse_case_insensitively() {
+    assert_eq!("l1".parse(), Ok(Category::L1));
+    assert_eq!("DRAM".parse(), Ok(Category::Dram));
+    assert_eq!("frontend".parse(), Ok(Category::FrontEnd));
+    assert_eq!("L4".parse::<Category>(), Err(()));
+
+    for category in Category::ALL {
+        assert_eq!(category.to_string().parse(), Ok(category));
+    }
+}
+
+#[test]
+fn wall_clock_much_longer_than_task_clock_looks_preempted() {
+    let mut counts = sample_counts();
+    assert!(!counts.looks_preempted());
+
+    counts.wall_clock = counts.task_clock * 2;
+    assert!(counts.looks_preempted());
+}
+
+#[test]
+fn reproducibility_groups_runs_by_benchmark() {
+    let samples = [
+        ("bench_a".to_string(), 1.0),
+        ("bench_b".to_string(), 5.0),
+        ("bench_a".to_string(), 3.0),
+        ("bench_b".to_string(), 5.0),
+    ];
+    let results = reproducibility(&samples);
+
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[0].name, "bench_a");
+    assert_eq!(results[0].runs, 2);
+    assert_eq!(results[0].mean, 2.0);
+    assert_eq!(results[0].std_dev, 2.0_f64.sqrt());
+    assert_eq!(results[1].name, "bench_b");
+    assert_eq!(results[1].coefficient_of_variation(), 0.0);
+}
+
+#[test]
+fn strided_traffic_fetches_whole_lines() {
+    // Every line, but only a quarter of each.
+    assert_eq!(strided_traffic(1_000_000, 4), (250_000, 15_625));
+    // One byte per line.
+    assert_eq!(strided_traffic(1_000_000, 64), (15_625, 15_625));
+    // One byte every other line.
+    assert_eq!(strided_traffic(1_000_000, 128), (7_813, 7_813));
+}
+
+#[test]
+fn perf_csv_matches_perf_stat_layout() {
+    let lines = perf_csv_lines(&sample_counts());
+
+    assert_eq!(lines[0], "234.04,msec,task-clock,234100000,100.00,,");
+    assert!(lines.contains(&"3768251802,,instructions,234100000,100.00,,".to_string()));
+    // The second group only counted half the time.
+    assert!(lines.contains(&"1402,,L1-icache-load-misses,117050000,50.00,,".to_string()));
+    // The sample has no times for the cycles, as if they couldn't be opened.
+    assert!(lines.contains(&"<not supported>,,cycles,0,100.00,,".to_string()));
+    assert!(lines.iter().all(|line| line.split(',').count() == 7));
+
+    // Opened, but never scheduled on the PMU.
+    let mut counts = sample_counts();
+    counts.times.insert("cycles".to_string(), (234_100_000, 0));
+    let lines = perf_csv_lines(&counts);
+    assert!(lines.contains(&"<not counted>,,cycles,0,0.00,,".to_string()));
+}
+
+#[test]
+fn bench_results_merge_into_one_view() {
+    let mut result = BenchResult {
+        wall_clock: 1_000,
+        ..BenchResult::default()
+    };
+    // Two events, read from different groups.
+    let readings = [
+        ("instructions", 5_000, 900, 0),
+        ("l2_cache_accesses_from_dc_misses", 40, 450, 1),
+    ];
+    for (name, value, time_running, group) in readings {
+        let reading = Reading {
+            value,
+            time_enabled: 900,
+            time_running,
+            group,
+        };
+        result.readings.insert(name.to_string(), reading);
+    }
+
+    let counts = Counts::from_result(&result);
+    assert_eq!(counts.wall_clock, 1_000);
+    assert_eq!(counts.instructions, 5_000);
+    // Counted for half of the run, so scaled up to the whole run.
+    assert_eq!(counts.l2_cache_accesses_from_dc_misses, 80);
+    assert_eq!(counts.times["l2_cache_accesses_from_dc_misses"], (900, 450));
+    assert_eq!(
+        counts.multiplexed(),
+        [("l2_cache_accesses_from_dc_misses".to_string(), 0.5)]
+    );
+    // Events no group counted read zero.
+    assert_eq!(counts.cycles, 0);
+    assert!(!counts.same_run("instructions", "l2_cache_accesses_from_dc_misses"));
+    assert!(counts.same_run("instructions", "instructions"));
+    // Without a group, we can't tell, so we don't flag it.
+    assert!(counts.same_run("instructions", "cycles"));
+    assert_eq!(
+        counts.overlap("instructions", "l2_cache_accesses_from_dc_misses"),
+        0.0
+    );
+
+    // With --single-run, the groups took turns on the same run.
+    result.single_run = true;
+    let counts = Counts::from_result(&result);
+    assert!(counts.same_run("instructions", "l2_cache_accesses_from_dc_misses"));
+    assert_eq!(
+        counts.overlap("instructions", "l2_cache_accesses_from_dc_misses"),
+        0.5
+    );
+}
+
+#[test]
+fn short_runs_recommend_enough_iterations() {
+    let min_runtime = std::time::Duration::from_millis(1);
+    assert_eq!(too_short_warning(1000, &sample_counts(), min_runtime), None);
+
+    let mut counts = sample_counts();
+    counts.task_clock = 250_000;
+    let warning = too_short_warning(1000, &counts, min_runtime).unwrap();
+    assert!(warning.contains("250.000 usec"), "{}", warning);
+    // Four times as many iterations would run for long enough.
+    assert!(warning.contains("at least 4_000 iterations"), "{}", warning);
+
+    // Without counters, the wall clock is what counts.
+    let mut counts = Counts {
+        wall_clock: 2_000_000,
+        times: [("wall_clock", (2_000_000, 2_000_000))]
+            .into_iter()
+            .map(|(key, times)| (key.to_string(), times))
+            .collect(),
+        ..Default::default()
+    };
+    assert_eq!(too_short_warning(1000, &counts, min_runtime), None);
+    counts.wall_clock = 500_000;
+    let warning = too_short_warning(1000, &counts, min_runtime).unwrap();
+    assert!(warning.contains("500.000 usec"), "{}", warning);
+    assert!(warning.contains("at least 2_000 iterations"), "{}", warning);
+}
+
+#[test]
+fn miss_rate_is_checked_against_the_expected_range() {
+    let mut counts = sample_counts();
+    counts.l1_cache_loads = 1000;
+    counts.l1_cache_misses = 250;
+    assert_eq!(counts.l1_miss_rate(), 25.0);
+    assert_eq!(counts.l1_miss_rate_within(&(15.0..=35.0)), Some(true));
+    assert_eq!(counts.l1_miss_rate_within(&(75.0..=100.0)), Some(false));
+
+    // Inactive counters can't fail the check.
+    counts.l1_cache_loads = 0;
+    counts.l1_cache_misses = 0;
+    assert_eq!(counts.l1_miss_rate_within(&(75.0..=100.0)), None);
+}
+
+#[test]
+fn cache_accesses_are_compared_with_the_expected_count() {
+    let mut counts = sample_counts();
+    counts.times.clear();
+    counts.cache_accesses = 1_100;
+    assert_eq!(counts.accesses_ratio(1_000), Some(1.1));
+
+    // An inactive counter says nothing about the accesses.
+    counts.cache_accesses = 0;
+    assert_eq!(counts.accesses_ratio(1_000), None);
+}
+
+#[test]
+fn durations_scale_to_the_most_readable_unit() {
+    assert_eq!(format_duration(850.0, 3), ("850.000".to_string(), "nsec"));
+    assert_eq!(
+        format_duration(200_000.0, 2),
+        ("200.00".to_string(), "usec")
+    );
+    assert_eq!(
+        format_duration(234_040_000.0, 2),
+        ("234.04".to_string(), "msec")
+    );
+    assert_eq!(
+        format_duration(2_500_000_000.0, 1),
+        ("2.5".to_string(), "sec")
+    );
+}
+
+#[test]
+fn prometheus_lines_group_samples_by_metric() {
+    let counts = sample_counts();
+    let lines = prometheus_lines(
+        "Some \"CPU\"",
+        &[("bench_a", 1000, &counts), ("bench_a", 1000, &counts)],
+        &[],
+    );
+
+    let labels = r#"benchmark="bench_a",cpu="Some \"CPU\"""#;
+    assert!(lines.contains(&"# TYPE test_prefetch_instructions gauge".to_string()));
+    assert!(lines.contains(&format!(
+        "test_prefetch_instructions{{{},run=\"0\"}} 3768251802",
+        labels
+    )));
+    // A second run of the same benchmark gets its own label set.
+    assert!(lines.contains(&format!(
+        "test_prefetch_instructions{{{},run=\"1\"}} 3768251802",
+        labels
+    )));
+    assert_eq!(
+        lines
+            .iter()
+            .filter(|line| line.starts_with("# TYPE test_prefetch_instructions "))
+            .count(),
+        1
+    );
+    assert_eq!(lines.last().unwrap(), "# EOF");
+
+    // Without any cycles, the instructions per cycle are infinite.
+    let counts = Counts {
+        cycles: 0,
+        times: Default::default(),
+        ..sample_counts()
+    };
+    let lines = prometheus_lines("cpu", &[("bench_a", 1000, &counts)], &[]);
+    assert!(lines.contains(
+        &r#"test_prefetch_instructions_per_cycle{benchmark="bench_a",cpu="cpu",run="0"} +Inf"#
+            .to_string()
+    ));
+}
+
+#[test]
+fn only_transient_errors_are_retried() {
+    let mut attempts = 0;
+    let result = retry_transient("open the counters", || {
+        attempts += 1;
+        match attempts {
+            1 | 2 => Err(std::io::Error::from_raw_os_error(libc::EBUSY)),
+            _ => Ok(attempts),
+        }
+    });
+    assert_eq!(result.unwrap(), 3);
+
+    let mut attempts = 0;
+    let result: std::io::Result<()> = retry_transient("open the counters", || {
+        attempts += 1;
+        Err(std::io::Error::from_raw_os_error(libc::ENOENT))
+    });
+    assert!(result.is_err());
+    assert_eq!(attempts, 1);
+
+    let result: std::io::Result<()> = retry_transient("open the counters", || {
+        Err(std::io::Error::from_raw_os_error(libc::EAGAIN))
+    });
+    assert!(result.unwrap_err().to_string().contains("after 5 retries"));
+}
+
+#[test]
+fn warmup_converges_once_runs_stay_near_the_steady_state() {
+    let warmup = Warmup::from_trajectory(&[30.0, 12.0, 10.2, 10.0, 9.9, 10.1]);
+    assert_eq!(warmup.steady_state, 10.0);
+    assert_eq!(warmup.converged_at, Some(3));
+
+    let warm = Warmup::from_trajectory(&[10.0, 10.1, 9.9, 10.0]);
+    assert_eq!(warm.converged_at, Some(1));
+
+    // A spike at the end means the benchmark never settled.
+    let noisy = Warmup::from_trajectory(&[10.0, 14.0, 9.0, 12.0]);
+    assert_eq!(noisy.converged_at, None);
+}
+
+#[test]
+fn watchdog_flags_benchmarks_that_outlive_the_timeout() {
+    let watchdog = Watchdog::start("quick", std::time::Duration::from_millis(500));
+    assert!(!watchdog.finish());
+
+    // Past twice the timeout, the watchdog would exit the test binary, so
+    // have it report giving up instead.
+    let timeout = std::time::Duration::from_millis(100);
+    let (gave_up, given_up) = std::sync::mpsc::channel();
+    let watchdog = Watchdog::with_give_up("stuck", timeout, move || {
+        gave_up.send(()).unwrap();
+    });
+    given_up
+        .recv_timeout(std::time::Duration::from_secs(10))
+        .unwrap();
+    assert!(watchdog.finish());
+}
+
+#[test]
+fn bench_errors_survive_a_round_trip_through_io_errors() {
+    let err: std::io::Error = BenchError::TimedOut {
+        name: "bench_noops".to_string(),
+    }
+    .into();
+    assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    assert!(matches!(
+        BenchError::from(err),
+        BenchError::TimedOut { name } if name == "bench_noops"
+    ));
+
+    let denied = BenchError::opening("cycles", std::io::Error::from_raw_os_error(libc::EACCES));
+    assert!(matches!(denied, BenchError::PermissionDenied { ref event, .. } if event == "cycles"));
+    let unsupported = BenchError::opening(
+        "ref_cycles",
+        std::io::Error::from_raw_os_error(libc::ENOENT),
+    );
+    assert_eq!(unsupported.kind(), std::io::ErrorKind::Unsupported);
+    assert!(
+        matches!(unsupported, BenchError::UnsupportedEvent { ref event, .. } if event == "ref_cycles")
+    );
+    // What many PMUs return for a raw event code they don't know, which
+    // optional counters skip like the others.
+    let invalid = std::io::Error::from_raw_os_error(libc::EINVAL);
+    assert!(is_unsupported_event(&invalid));
+    assert!(matches!(
+        BenchError::opening("l2_cache_hits_from_dc_misses", invalid),
+        BenchError::UnsupportedEvent { .. }
+    ));
+    assert!(!is_unsupported_event(&std::io::Error::from_raw_os_error(
+        libc::EACCES
+    )));
+
+    let io = BenchError::from(std::io::Error::other("unreadable"));
+    assert_eq!(
+        std::error::Error::source(&io).unwrap().to_string(),
+        "unreadable"
+    );
+
+    // Transient errors stay plain, so that they can still be retried.
+    let busy: std::io::Error =
+        BenchError::opening("cycles", std::io::Error::from_raw_os_error(libc::EBUSY)).into();
+    assert_eq!(busy.raw_os_error(), Some(libc::EBUSY));
+}
+
+#[test]
+fn benchmark_results_derive_ipc_and_miss_ratio() {
+    let results = BenchmarkResults::new("bench", BenchConfig::new(1_000), sample_counts());
+    assert_eq!(results.name, "bench");
+    assert!((results.ipc - 4.11).abs() < 0.01);
+    assert!((results.cache_miss_ratio - 25_093.0 / 1_009_884_042.0).abs() < 1e-12);
+    assert_eq!(results.bandwidth(), None);
+
+    // 1e9 bytes of 4-byte elements, over 0.234 s of task clock.
+    let config = BenchConfig::new(1_000).elements(250_000_000, 4);
+    let results = BenchmarkResults::new("bench", config, sample_counts());
+    assert!((results.bandwidth().unwrap() - 4.27).abs() < 0.01);
+}
+
+#[test]
+fn sample_stats_summarize_the_spread() {
+    let stats = SampleStats::from_values(&[2.0, 4.0, 4.0, 4.0, 6.0]);
+    assert_eq!(stats.mean, 4.0);
+    assert_eq!(stats.min, 2.0);
+    assert_eq!(stats.max, 6.0);
+    assert!((stats.std_dev - 2.0_f64.sqrt()).abs() < 1e-12);
+    assert_eq!(SampleStats::from_values(&[3.0]).std_dev, 0.0);
+}
+
+#[test]
+fn benchmark_results_keep_every_sample_and_derive_from_the_median() {
+    let slow = Counts {
+        instructions: 1_000,
+        cycles: 1_000,
+        task_clock: 300,
+        ..Counts::default()
+    };
+    let median = Counts {
+        cycles: 500,
+        task_clock: 200,
+        ..slow.clone()
+    };
+    let fast = Counts {
+        cycles: 250,
+        task_clock: 100,
+        ..slow.clone()
+    };
+    let config = BenchConfig::new(1_000).bytes(1_000);
+    let samples = vec![slow.clone(), fast, median];
+    let results = BenchmarkResults::from_samples("bench", config, samples);
+    assert_eq!(results.samples.len(), 3);
+    assert_eq!(results.samples[0].cycles, slow.cycles);
+    assert_eq!(results.ipc, 2.0);
+    assert_eq!(results.bandwidth(), Some(5.0));
+
+    let ipc = results.ipc_stats().unwrap();
+    assert_eq!((ipc.min, ipc.mean, ipc.max), (1.0, 7.0 / 3.0, 4.0));
+    let bandwidth = results.bandwidth_stats().unwrap();
+    assert_eq!((bandwidth.min, bandwidth.max), (1_000.0 / 300.0, 10.0));
+}
+
+#[test]
+fn benchmark_results_report_the_warmup_runs() {
+    let results = BenchmarkResults::new("bench", BenchConfig::new(1_000), sample_counts());
+    assert_eq!(results.warmup_runs, DEFAULT_WARMUP);
+    let config = BenchConfig::new(1_000).warmup(3);
+    let results = BenchmarkResults::new("bench", config, sample_counts());
+    assert_eq!(results.warmup_runs, 3);
+}
+
+#[test]
+fn wall_clock_only_results_count_nothing_else() {
+    // What the runner records without perf_event.
+    let reading = Reading {
+        value: 500_000_000,
+        time_enabled: 500_000_000,
+        time_running: 500_000_000,
+        group: 0,
+    };
+    let result = BenchResult {
+        wall_clock: 500_000_000,
+        readings: [("wall_clock".to_string(), reading)].into_iter().collect(),
+        single_run: false,
+        unavailable: Default::default(),
+    };
+    let counts = Counts::from_result(&result);
+    assert!(counts.counted("wall_clock"));
+    assert!(!counts.counted("task_clock"));
+    assert!(!counts.counted("cycles"));
+
+    // 1e9 bytes over 0.5 s of wall clock.
+    let config = BenchConfig::new(1_000).elements(250_000_000, 4);
+    let results = BenchmarkResults::new("bench", config, counts);
+    assert!((results.bandwidth().unwrap() - 2.0).abs() < 1e-9);
+    results.print_table().unwrap();
+
+    // The exports time the run by the wall clock too.
+    assert!(csv_row("bench", &results.counts).starts_with("bench,500.000,"));
+    assert_eq!(
+        perf_csv_lines(&results.counts)[0],
+        "500.00,msec,task-clock,0,100.00,,"
+    );
+}
+
+#[test]
+fn json_document_keys_benchmarks_by_name_with_null_for_missing_counters() {
+    let counts = sample_counts();
+    let document = json_document(&[("bench_sum_of_array", 1_000, &counts)], &[]);
+    assert_eq!(document["schema_version"], SCHEMA_VERSION);
+    let bench = &document["benchmarks"]["bench_sum_of_array"];
+    assert_eq!(bench["iterations"], 1_000);
+    assert_eq!(bench["counts"]["instructions"], 3_768_251_802u64);
+    assert_eq!(bench["counts"]["wall_clock"], 235_120_000);
+    // The sample only has times for some events, the others weren't opened,
+    // and neither were the ratios between them.
+    assert!(bench["counts"]["dtlb_load_misses"].is_null());
+    assert!(bench["ipc"].is_null());
+
+    let mut all_counted = sample_counts();
+    all_counted.times.clear();
+    let document = json_document(&[("bench_sum_of_array", 1_000, &all_counted)], &[]);
+    let ipc = &document["benchmarks"]["bench_sum_of_array"]["ipc"];
+    assert!((ipc.as_f64().unwrap() - 4.11).abs() < 0.01);
+
+    // Nothing was counted at all, so the ratios are undefined.
+    let empty = Counts::default();
+    let document = json_document(&[("empty", 1, &empty)], &[]);
+    assert!(document["benchmarks"]["empty"]["ipc"].is_null());
+
+    let mut written = Vec::new();
+    write_json(&mut written, &[("bench_sum_of_array", 1_000, &counts)], &[]).unwrap();
+    let parsed: serde_json::Value = serde_json::from_slice(&written).unwrap();
+    assert_eq!(
+        parsed,
+        json_document(&[("bench_sum_of_array", 1_000, &counts)], &[])
+    );
+}
+
+#[test]
+fn readings_scale_up_to_the_time_enabled() {
+    let reading = Reading {
+        value: 1_000,
+        time_enabled: 300,
+        time_running: 100,
+        group: 0,
+    };
+    assert_eq!(reading.scaled_value(), 3_000);
+    // Never running means nothing to extrapolate from.
+    let never_ran = Reading {
+        time_running: 0,
+        ..reading
+    };
+    assert_eq!(never_ran.scaled_value(), 1_000);
+    let always_ran = Reading {
+        time_running: 300,
+        ..reading
+    };
+    assert_eq!(always_ran.scaled_value(), 1_000);
+}
+
+#[test]
+fn events_without_times_were_not_counted() {
+    let counts = sample_counts();
+    assert!(counts.counted("instructions"));
+    assert!(counts.counted("wall_clock"));
+    // The sample has times for a few events only, as if the others had
+    // failed to open.
+    assert!(!counts.counted("dtlb_load_misses"));
+    // Counts built by hand, without any times, count everything.
+    assert!(Counts::default().counted("dtlb_load_misses"));
+}
+
+#[test]
+fn l2_miss_events_depend_on_the_microarchitecture() {
+    assert_eq!(
+        l2_miss_events_for(b"AuthenticAMD", 0x19, 0x21),
+        Some((0xc860, 0x7064))
+    );
+    // Skylake, then Ice Lake, which changed the unit mask of the hits.
+    assert_eq!(
+        l2_miss_events_for(b"GenuineIntel", 6, 0x5e),
+        Some((0xe124, 0x4124))
+    );
+    assert_eq!(
+        l2_miss_events_for(b"GenuineIntel", 6, 0x7e),
+        Some((0xe124, 0xc124))
+    );
+    // Pre-Zen AMD, and an Intel core we don't know the events of.
+    assert_eq!(l2_miss_events_for(b"AuthenticAMD", 0x15, 0x02), None);
+    assert_eq!(l2_miss_events_for(b"GenuineIntel", 6, 0x3c), None);
+}
+
+#[test]
+fn l2_store_miss_event_depends_on_the_microarchitecture() {
+    assert_eq!(
+        l2_store_miss_event_for(b"AuthenticAMD", 0x19, 0x21),
+        Some(0x4060)
+    );
+    // Skylake and Ice Lake share L2_RQSTS.ALL_RFO.
+    assert_eq!(
+        l2_store_miss_event_for(b"GenuineIntel", 6, 0x5e),
+        Some(0xe224)
+    );
+    assert_eq!(
+        l2_store_miss_event_for(b"GenuineIntel", 6, 0x7e),
+        Some(0xe224)
+    );
+    assert_eq!(l2_store_miss_event_for(b"GenuineIntel", 6, 0x3c), None);
+}
+
+#[test]
+fn name_filters_match_substrings_or_whole_globs() {
+    assert!(name_matches("1MB", "bench_sum_array_1MB_stride_16"));
+    assert!(!name_matches("2MB", "bench_sum_array_1MB_stride_16"));
+    assert!(name_matches("bench_sum_array_1MB*", "bench_sum_array_1MB"));
+    assert!(name_matches(
+        "bench_sum_array_1MB*",
+        "bench_sum_array_1MB_stride_16"
+    ));
+    // A glob has to match from the start to the end of the name.
+    assert!(!name_matches("sum_array_1MB*", "bench_sum_array_1MB"));
+    assert!(!name_matches(
+        "bench_*_1MB",
+        "bench_sum_array_1MB_stride_16"
+    ));
+    assert!(name_matches("*stride_?4*", "bench_sum_array_1MB_stride_64"));
+    assert!(!name_matches(
+        "*stride_?4*",
+        "bench_sum_array_1MB_stride_16"
+    ));
+    assert!(name_matches("*_*_*", "bench_sum_array"));
+    assert!(!name_matches("bench_?", "bench_"));
+}
+
+#[test]
+fn option_values_are_not_benchmark_names() {
+    let args: Vec<String> = [
+        "--format",
+        "jsonl",
+        "bench_noops",
+        "--verbose",
+        "--loop",
+        "bench_alu_ops",
+        "--prefetchers=l2_stream",
+        "*stride_16*",
+        "--timeout",
+    ]
+    .into_iter()
+    .map(String::from)
+    .collect();
+    assert_eq!(name_filters(&args), ["bench_noops", "*stride_16*"]);
+}
+
+#[test]
+fn iteration_counts_come_from_the_environment() {
+    assert_eq!(
+        iterations_from_env("TEST_PREFETCH_UNSET", 1_000).unwrap(),
+        1_000
+    );
+    std::env::set_var("TEST_PREFETCH_ITERS", "50");
+    assert_eq!(
+        iterations_from_env("TEST_PREFETCH_ITERS", 1_000).unwrap(),
+        50
+    );
+    for invalid in ["0", "-3", "many", ""] {
+        std::env::set_var("TEST_PREFETCH_INVALID_ITERS", invalid);
+        let err = iterations_from_env("TEST_PREFETCH_INVALID_ITERS", 1_000).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+}
+
+#[test]
+fn ab_comparisons_report_the_change_in_percent() {
+    assert_eq!(percent_change(4.0, 5.0), Some(25.0));
+    assert_eq!(percent_change(4.0, 3.0), Some(-25.0));
+    assert_eq!(percent_change(0.0, 3.0), None);
+
+    let baseline = Counts {
+        instructions: 2_000,
+        cycles: 1_000,
+        task_clock: 400,
+        l1_cache_loads: 1_000,
+        l1_cache_misses: 200,
+        ..Counts::default()
+    };
+    let variant = Counts {
+        instructions: 2_400,
+        task_clock: 320,
+        l1_cache_misses: 50,
+        ..baseline.clone()
+    };
+    let config = BenchConfig::new(1_000).bytes(1_000);
+    let comparison = AbComparison {
+        name: "bench".to_string(),
+        baseline: BenchmarkResults::new("bench", config.clone(), baseline),
+        variant: BenchmarkResults::new("bench", config, variant),
+    };
+    assert_eq!(comparison.bandwidth_change(), Some(25.0));
+    assert!((comparison.ipc_change().unwrap() - 20.0).abs() < 1e-9);
+    assert_eq!(comparison.l1_miss_rate_change(), Some(-75.0));
+
+    let json = comparison.json();
+    assert_eq!(json["bandwidth_change"], 25.0);
+    let document = json_document(&[], std::slice::from_ref(&comparison));
+    assert_eq!(document["comparisons"]["bench"], json);
+    let lines = prometheus_lines("cpu", &[], std::slice::from_ref(&comparison));
+    assert!(lines.contains(
+        &r#"test_prefetch_change_percent{comparison="bench",cpu="cpu",metric="l1_miss_rate"} -75"#
+            .to_string()
+    ));
+
+    let no_bytes = AbComparison {
+        baseline: BenchmarkResults::new("bench", BenchConfig::new(1_000), sample_counts()),
+        ..comparison
+    };
+    assert_eq!(no_bytes.bandwidth_change(), None);
+    assert!(no_bytes.json()["bandwidth_change"].is_null());
+}
+
+#[test]
+fn counts_keep_why_events_were_not_counted() {
+    let result = BenchResult {
+        unavailable: [(
+            "l1_cache_store_misses".to_string(),
+            "No such file or directory (os error 2)".to_string(),
+        )]
+        .into_iter()
+        .collect(),
+        ..BenchResult::default()
+    };
+    let counts = Counts::from_result(&result);
+    assert_eq!(
+        counts.unavailable["l1_cache_store_misses"],
+        "No such file or directory (os error 2)"
+    );
+}
+
+#[test]
+fn exports_leave_out_events_that_were_not_counted() {
+    // Only the instructions, the task clock and the L1I misses have times,
+    // as if every other counter had failed to open.
+    let counts = sample_counts();
+
+    let columns: Vec<String> = csv_header().split(',').map(String::from).collect();
+    let row = csv_row("bench_a", &counts);
+    let cells: Vec<&str> = row.split(',').collect();
+    let cell = |column: &str| cells[columns.iter().position(|c| c == column).unwrap()];
+    assert_eq!(cell("instructions"), "3768251802");
+    assert_eq!(cell("cycles"), "");
+    assert_eq!(cell("ipc"), "");
+    assert_eq!(cell("l1_miss_pct"), "");
+
+    let lines = raw_lines(
+        "bench_a",
+        &counts,
+        &[("l2_cache_hits_from_dc_misses", 0x7064)],
+    );
+    assert!(lines.contains(&"instructions=3768251802".to_string()));
+    assert!(!lines.iter().any(|line| line.starts_with("cycles=")));
+    assert!(!lines.iter().any(|line| line.starts_with("raw_0x7064=")));
+
+    let lines = perf_csv_lines(&counts);
+    assert!(lines.contains(&"<not supported>,,cycles,0,100.00,,".to_string()));
+
+    let lines = prometheus_lines("cpu", &[("bench_a", 1000, &counts)], &[]);
+    assert!(lines
+        .iter()
+        .any(|line| line.starts_with("test_prefetch_instructions{")));
+    assert!(!lines
+        .iter()
+        .any(|line| line.starts_with("test_prefetch_cycles")));
+    assert!(!lines
+        .iter()
+        .any(|line| line.starts_with("test_prefetch_instructions_per_cycle")));
+    assert!(!lines
+        .iter()
+        .any(|line| line.starts_with("test_prefetch_l1_miss_ratio")));
+}
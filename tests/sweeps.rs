@@ -0,0 +1,89 @@
+use test_prefetch::sweeps::{
+    best_prefetch_distance, mlp_limit, store_buffer_depth, sweep_reuse_distance, sweep_stride,
+    GatherPoint, MlpPoint, PrefetchDistancePoint, StoreBufferPoint,
+};
+
+#[test]
+fn mlp_limit_is_where_more_chains_stop_helping() {
+    let points: Vec<MlpPoint> = [(1, 300.0), (2, 150.0), (4, 80.0), (8, 42.0), (16, 40.0)]
+        .into_iter()
+        .map(|(chains, cycles_per_access)| MlpPoint {
+            chains,
+            cycles_per_access,
+            speedup: 300.0 / cycles_per_access,
+        })
+        .collect();
+    assert_eq!(mlp_limit(&points), Some(8));
+    assert_eq!(mlp_limit(&[]), None);
+}
+
+#[test]
+fn store_buffer_depth_is_the_last_burst_before_the_jump() {
+    let points: Vec<StoreBufferPoint> = [
+        (8, 200.0),
+        (32, 205.0),
+        (56, 210.0),
+        (64, 390.0),
+        (80, 420.0),
+    ]
+    .into_iter()
+    .map(|(stores, cycles_per_burst)| StoreBufferPoint {
+        stores,
+        cycles_per_burst,
+        cycles_per_store: cycles_per_burst / stores as f64,
+    })
+    .collect();
+    assert_eq!(store_buffer_depth(&points), Some(56));
+    assert_eq!(store_buffer_depth(&points[..3]), None);
+    assert_eq!(store_buffer_depth(&[]), None);
+}
+
+#[test]
+fn gather_prefetch_speedup_compares_both_runs() {
+    let point = GatherPoint {
+        window: 4096,
+        cycles_per_access: 30.0,
+        l1_miss_rate: 0.5,
+        prefetched_cycles_per_access: 12.0,
+    };
+    assert_eq!(point.prefetch_speedup(), 2.5);
+}
+
+#[test]
+fn best_prefetch_distance_has_the_highest_bandwidth() {
+    let points: Vec<PrefetchDistancePoint> = [(0, 5.0), (1, 6.5), (4, 9.0), (8, 8.5), (32, 4.0)]
+        .into_iter()
+        .map(|(distance, bandwidth)| PrefetchDistancePoint {
+            distance,
+            cycles_per_access: 1.0 / bandwidth,
+            bandwidth,
+            l1_miss_rate: 0.1,
+        })
+        .collect();
+    assert_eq!(best_prefetch_distance(&points), Some(4));
+    assert_eq!(best_prefetch_distance(&[]), None);
+}
+
+#[test]
+fn stride_sweep_returns_one_point_per_stride() {
+    let array = [1; 4096];
+    let points = match sweep_stride(&array, &[1, 16, 64], 1) {
+        Ok(points) => points,
+        Err(err) => {
+            eprintln!(
+                "skipping test, performance counters are unavailable: {}",
+                err
+            );
+            return;
+        }
+    };
+
+    let strides: Vec<usize> = points.iter().map(|point| point.stride).collect();
+    assert_eq!(strides, [1, 16, 64]);
+}
+
+#[test]
+fn reuse_distance_sweep_rejects_a_zero_distance() {
+    let err = sweep_reuse_distance(&[], &[64, 0], 1 << 10).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+}
@@ -0,0 +1,113 @@
+use test_prefetch::latency::MemoryLevel;
+use test_prefetch::topology::{parse_cache_size, parse_cpu_list, CacheInfo, Topology};
+
+#[test]
+fn cache_sizes_parse_into_bytes() {
+    assert_eq!(parse_cache_size("48K"), Some(48 * 1024));
+    assert_eq!(parse_cache_size("2048K"), Some(2 * 1024 * 1024));
+    assert_eq!(parse_cache_size("32M"), Some(32 * 1024 * 1024));
+    assert_eq!(parse_cache_size("K"), None);
+}
+
+#[test]
+fn cpu_lists_expand_ranges() {
+    assert_eq!(parse_cpu_list("0"), Some(vec![0]));
+    assert_eq!(parse_cpu_list("0-3,8"), Some(vec![0, 1, 2, 3, 8]));
+    assert_eq!(parse_cpu_list("0-"), None);
+}
+
+#[test]
+fn cpuinfo_counts_cores_and_threads() {
+    // Two cores with two threads each.
+    let mut cpuinfo = String::new();
+    for (processor, core) in [(0, 0), (1, 1), (2, 0), (3, 1)] {
+        cpuinfo += &format!(
+            "processor\t: {}\nmodel name\t: Some CPU\nphysical id\t: 0\ncore id\t\t: {}\n\n",
+            processor, core
+        );
+    }
+
+    let topology = Topology::from_cpuinfo(&cpuinfo);
+    assert_eq!(topology.model, "Some CPU");
+    assert_eq!(topology.cores, 2);
+    assert_eq!(topology.threads, 4);
+}
+
+/// A single-CPU topology with `caches`, as their level, kind and size.
+fn topology_with(caches: &[(u32, &str, usize)]) -> Topology {
+    let mut topology = Topology::from_cpuinfo("processor\t: 0\n");
+    for &(level, kind, size) in caches {
+        topology.caches.push(CacheInfo {
+            level,
+            kind: kind.to_string(),
+            size,
+            line_size: 64,
+            shared_by: 1,
+            ways: Some(12),
+        });
+    }
+    topology
+}
+
+#[test]
+fn working_sets_fit_in_the_smallest_data_cache_that_holds_them() {
+    let topology = topology_with(&[
+        (1, "Data", 48 * 1024),
+        (1, "Instruction", 64 * 1024),
+        (2, "Unified", 2 * 1024 * 1024),
+        (3, "Unified", 32 * 1024 * 1024),
+    ]);
+
+    assert_eq!(
+        MemoryLevel::for_working_set(&topology, 16 * 1024),
+        MemoryLevel::L1
+    );
+    // Bigger than the L1D, even though it would fit in the L1I.
+    assert_eq!(
+        MemoryLevel::for_working_set(&topology, 64 * 1024),
+        MemoryLevel::L2
+    );
+    assert_eq!(
+        MemoryLevel::for_working_set(&topology, 16 * 1024 * 1024),
+        MemoryLevel::L3
+    );
+    assert_eq!(
+        MemoryLevel::for_working_set(&topology, 128 * 1024 * 1024),
+        MemoryLevel::Dram
+    );
+}
+
+#[test]
+fn load_to_use_working_sets_only_fit_their_level() {
+    let topology = topology_with(&[
+        (1, "Data", 48 * 1024),
+        (1, "Instruction", 64 * 1024),
+        (2, "Unified", 2 * 1024 * 1024),
+        (3, "Unified", 32 * 1024 * 1024),
+    ]);
+    for level in [MemoryLevel::L1, MemoryLevel::L2, MemoryLevel::L3] {
+        let working_set = level.working_set(&topology).unwrap();
+        assert_eq!(MemoryLevel::for_working_set(&topology, working_set), level);
+    }
+    assert_eq!(MemoryLevel::Dram.working_set(&topology), None);
+
+    // Half of this L2 would still fit in the L1D.
+    let small_l2 = topology_with(&[(1, "Data", 48 * 1024), (2, "Unified", 64 * 1024)]);
+    assert_eq!(MemoryLevel::L2.working_set(&small_l2), None);
+}
+
+#[test]
+fn way_stride_is_the_size_of_one_way() {
+    let mut cache = CacheInfo {
+        level: 1,
+        kind: "Data".to_string(),
+        size: 48 * 1024,
+        line_size: 64,
+        shared_by: 2,
+        ways: Some(12),
+    };
+    assert_eq!(cache.way_stride(), Some(4096));
+
+    cache.ways = None;
+    assert_eq!(cache.way_stride(), None);
+}